@@ -0,0 +1,166 @@
+use heapless::Vec;
+
+use crate::{note::Note, note_stack::NoteStack, MAX_PORT_COUNT};
+
+/// Spreads the notes played on a channel across the drives mapped to it (used by
+/// `ParallelMode::Distribute`)
+///
+/// Which notes each drive is holding is tracked by the drives' note stacks, so releasing a note is
+/// the same as in `ParallelMode::Collapse`.
+#[derive(Debug, Default)]
+pub struct DriveAllocator {
+    drives: Vec<usize, MAX_PORT_COUNT>,
+    /// Index into `drives` to start looking for an idle drive from
+    next: usize,
+    /// Whether to cut off the oldest note when every drive is busy (`SetConfig::steal_oldest`)
+    steal_oldest: bool,
+    /// The value of `counter` when each drive in `drives` last started a note
+    started_at: Vec<u32, MAX_PORT_COUNT>,
+    /// Incremented on every note on, used to find the oldest sounding note
+    counter: u32,
+}
+
+impl DriveAllocator {
+    pub fn new(drives: &[usize], steal_oldest: bool) -> Self {
+        Self {
+            drives: drives.iter().copied().collect(),
+            next: 0,
+            steal_oldest,
+            started_at: drives.iter().map(|_| 0).collect(),
+            counter: 0,
+        }
+    }
+
+    /// Picks the drive that should play a new note given the notes each drive is holding
+    ///
+    /// A pitch that is already held is retriggered on the same drive, otherwise the note goes to
+    /// the next idle drive round-robin. If every drive is busy, the note is stacked on top of the
+    /// next drive in round-robin order (falling back to `ParallelMode::Collapse`), or when stealing
+    /// the oldest note, the drive that started its note longest ago has its notes cleared.
+    pub fn note_on(&mut self, note: Note, note_stacks: &mut [NoteStack]) -> Option<usize> {
+        if self.drives.is_empty() {
+            return None;
+        }
+
+        self.counter = self.counter.wrapping_add(1);
+
+        let count = self.drives.len();
+        let index = match self
+            .drives
+            .iter()
+            .position(|drive| note_stacks[*drive].contains(note))
+        {
+            Some(index) => index,
+            None => {
+                let idle = (0..count)
+                    .map(|offset| (self.next + offset) % count)
+                    .find(|i| note_stacks[self.drives[*i]].is_empty());
+
+                let index = match idle {
+                    Some(index) => index,
+                    None if self.steal_oldest => {
+                        let oldest = (0..count)
+                            .max_by_key(|i| self.counter.wrapping_sub(self.started_at[*i]))
+                            .unwrap();
+
+                        note_stacks[self.drives[oldest]].clear();
+                        oldest
+                    }
+                    None => self.next % count,
+                };
+
+                self.next = (index + 1) % count;
+                index
+            }
+        };
+
+        self.started_at[index] = self.counter;
+
+        Some(self.drives[index])
+    }
+
+    /// Starts the round-robin over from the first drive
+    pub fn reset(&mut self) {
+        self.next = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(allocator: &mut DriveAllocator, stacks: &mut [NoteStack], note: Note) -> usize {
+        let drive = allocator.note_on(note, stacks).unwrap();
+        stacks[drive].push(note);
+        drive
+    }
+
+    #[test]
+    fn spreads_chord_across_idle_drives() {
+        let mut stacks = [NoteStack::new(), NoteStack::new(), NoteStack::new()];
+        let mut allocator = DriveAllocator::new(&[2, 0, 1], false);
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::C4), 2);
+        assert_eq!(play(&mut allocator, &mut stacks, Note::E4), 0);
+        assert_eq!(play(&mut allocator, &mut stacks, Note::G4), 1);
+    }
+
+    #[test]
+    fn retriggers_held_note_on_same_drive() {
+        let mut stacks = [NoteStack::new(), NoteStack::new()];
+        let mut allocator = DriveAllocator::new(&[0, 1], false);
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::C4), 0);
+        assert_eq!(play(&mut allocator, &mut stacks, Note::C4), 0);
+        assert_eq!(stacks[0].len(), 1);
+    }
+
+    #[test]
+    fn reuses_released_drive() {
+        let mut stacks = [NoteStack::new(), NoteStack::new()];
+        let mut allocator = DriveAllocator::new(&[0, 1], false);
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::C4), 0);
+        assert_eq!(play(&mut allocator, &mut stacks, Note::E4), 1);
+
+        stacks[0].remove(Note::C4);
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::G4), 0);
+    }
+
+    #[test]
+    fn collapses_when_all_drives_are_busy() {
+        let mut stacks = [NoteStack::new(), NoteStack::new()];
+        let mut allocator = DriveAllocator::new(&[0, 1], false);
+
+        play(&mut allocator, &mut stacks, Note::C4);
+        play(&mut allocator, &mut stacks, Note::E4);
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::G4), 0);
+        assert_eq!(stacks[0].top(), Some(Note::G4));
+
+        // Releasing the collapsed note falls back to the note underneath it
+        stacks[0].remove(Note::G4);
+        assert_eq!(stacks[0].top(), Some(Note::C4));
+    }
+
+    #[test]
+    fn steals_oldest_note_when_all_drives_are_busy() {
+        let mut stacks = [NoteStack::new(), NoteStack::new()];
+        let mut allocator = DriveAllocator::new(&[0, 1], true);
+
+        play(&mut allocator, &mut stacks, Note::C4);
+        play(&mut allocator, &mut stacks, Note::E4);
+
+        // Retriggering C4 makes E4 the oldest note
+        assert_eq!(play(&mut allocator, &mut stacks, Note::C4), 0);
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::G4), 1);
+        assert_eq!(stacks[1].len(), 1);
+        assert_eq!(stacks[1].top(), Some(Note::G4));
+
+        assert_eq!(play(&mut allocator, &mut stacks, Note::B4), 0);
+        assert_eq!(stacks[0].top(), Some(Note::B4));
+        assert_eq!(stacks[0].len(), 1);
+    }
+}
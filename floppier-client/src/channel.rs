@@ -0,0 +1,146 @@
+use crate::{allocator::DriveAllocator, note::Note};
+
+/// General MIDI programs from this one onwards are percussive instruments and sound effects
+///
+/// https://www.midi.org/specifications-old/item/gm-level-1-sound-set
+pub const FIRST_PERCUSSIVE_PROGRAM: u8 = 112;
+
+/// Control change numbers handled by the client
+///
+/// https://www.midi.org/specifications-old/item/table-3-control-change-messages-data-bytes-2
+pub const CONTROL_VOLUME: u8 = 7;
+pub const CONTROL_SUSTAIN: u8 = 64;
+pub const CONTROL_ALL_SOUND_OFF: u8 = 120;
+pub const CONTROL_ALL_NOTES_OFF: u8 = 123;
+
+/// The channel volume a channel starts with (the General MIDI default)
+pub const DEFAULT_VOLUME: u8 = 100;
+
+/// Channels with a volume below this are silenced, since the drives can't play quietly
+pub const MIN_AUDIBLE_VOLUME: u8 = 8;
+
+/// Control values from this one onwards turn a pedal (like sustain) on
+pub const PEDAL_ON_VALUE: u8 = 64;
+
+/// The number of semitones a full pitch bend moves a note by (the General MIDI default)
+pub const PITCH_BEND_RANGE_SEMITONES: f32 = 2.0;
+
+/// Converts a 14-bit MIDI pitch bend centered on 0 (-8192 to 8191) into semitones
+pub fn pitch_bend_to_semitones(value: i16) -> f32 {
+    value as f32 / 8192.0 * PITCH_BEND_RANGE_SEMITONES
+}
+
+/// State of a (track, channel) pair that persists between MIDI events
+#[derive(Debug)]
+pub struct ChannelState {
+    /// Assigns notes to the mapped drives when using `ParallelMode::Distribute`
+    pub allocator: DriveAllocator,
+
+    /// The current General MIDI program (instrument) of the channel
+    pub program: u8,
+
+    /// The channel volume (CC 7). The drives can't play quieter, so this only silences the channel
+    /// when it is turned (almost) all the way down.
+    pub volume: u8,
+
+    /// Whether the sustain pedal (CC 64) is held
+    pub sustain: bool,
+
+    /// The notes released while the sustain pedal was held, as one bit per MIDI note number
+    sustained: u128,
+}
+
+impl ChannelState {
+    pub fn new(drives: &[usize], steal_oldest: bool) -> Self {
+        Self {
+            allocator: DriveAllocator::new(drives, steal_oldest),
+            program: 0,
+            volume: DEFAULT_VOLUME,
+            sustain: false,
+            sustained: 0,
+        }
+    }
+
+    /// Whether the channel is loud enough to play notes on
+    pub fn is_audible(&self) -> bool {
+        self.volume >= MIN_AUDIBLE_VOLUME
+    }
+
+    /// Holds back the release of a note until the sustain pedal is lifted, if it is held
+    ///
+    /// Returns whether the release was held back.
+    pub fn defer_release(&mut self, note: Note) -> bool {
+        if self.sustain {
+            self.sustained |= 1 << note.midi_number();
+        }
+
+        self.sustain
+    }
+
+    /// Forgets a held back release (for a note that was played again)
+    pub fn cancel_release(&mut self, note: Note) {
+        self.sustained &= !(1 << note.midi_number());
+    }
+
+    /// Lifts the sustain pedal, returning the notes whose release was held back
+    pub fn lift_sustain(&mut self) -> impl Iterator<Item = Note> {
+        self.sustain = false;
+
+        let sustained = core::mem::take(&mut self.sustained);
+
+        (0..=127)
+            .filter(move |number| sustained & (1 << number) != 0)
+            .filter_map(|number| Note::try_from(number).ok())
+    }
+
+    /// Whether the channel's current program is something the drives can sensibly play
+    pub fn is_melodic(&self) -> bool {
+        self.program < FIRST_PERCUSSIVE_PROGRAM
+    }
+
+    /// Forget any sounding notes (the program is kept)
+    ///
+    /// The held notes themselves live in the drives' note stacks, which need clearing separately.
+    pub fn release_all(&mut self) {
+        self.allocator.reset();
+        self.sustained = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustain_holds_releases_until_lifted() {
+        let mut state = ChannelState::new(&[0], false);
+
+        assert!(!state.defer_release(Note::C4));
+
+        state.sustain = true;
+
+        assert!(state.defer_release(Note::C4));
+        assert!(state.defer_release(Note::E4));
+        assert!(state.defer_release(Note::G4));
+
+        // Playing a note again means it is held by its key rather than the pedal
+        state.cancel_release(Note::E4);
+
+        let released = state.lift_sustain().collect::<heapless::Vec<Note, 4>>();
+
+        assert_eq!(released, [Note::C4, Note::G4]);
+        assert!(!state.sustain);
+        assert_eq!(state.lift_sustain().count(), 0);
+    }
+
+    #[test]
+    fn pitch_bend_range() {
+        assert_eq!(pitch_bend_to_semitones(0), 0.0);
+        assert_eq!(pitch_bend_to_semitones(-8192), -PITCH_BEND_RANGE_SEMITONES);
+        assert_eq!(
+            pitch_bend_to_semitones(4096),
+            PITCH_BEND_RANGE_SEMITONES / 2.0
+        );
+        assert!(pitch_bend_to_semitones(8191) < PITCH_BEND_RANGE_SEMITONES);
+    }
+}
@@ -1,7 +1,10 @@
 use core::fmt::Debug;
 use defmt::Format;
 
-use crate::note::Note;
+use crate::{
+    instrument::Instrument,
+    note::{Note, TICK_FRACTIONS},
+};
 
 /// Floppy drive specification: http://www.bitsavers.org/pdf/mitsubishi/floppy/MF355/UGD-0489A_MF355B_Specifications_Sep86.pdf
 #[derive(Debug, Format)]
@@ -13,7 +16,25 @@ pub struct FloppyDrive {
     current_position: u8,
     current_direction: Direction,
     current_direction_tick: u32,
+    /// The time between toggling the step pin, in fractions of a tick (see `Note::bent_half_ticks`)
+    current_half_ticks: u32,
+    /// How many ticks of each duty window the drive is selected for
+    select_ticks: u32,
     movement: bool,
+    program: u8,
+    pitch_bend: f32,
+    /// Cents to tune the drive by, on top of the pitch bend
+    detune_cents: i16,
+    /// How often the drive is ticked in µs
+    timer_resolution_us: u64,
+    /// How many ticks are left before the note dies away, if it has been released
+    release_ticks: u32,
+    /// How much longer each half period is than the note's while it dies away, in fractions of a
+    /// tick
+    release_extra_ticks: u32,
+    /// The position the head is stepping back to after the end of a song, if it's parking
+    park_position: Option<u8>,
+    park_tick: u32,
 }
 
 impl FloppyDrive {
@@ -22,52 +43,100 @@ impl FloppyDrive {
     pub const MIN_POSITION_MOVEMENT: u8 = 2;
     pub const MAX_POSITION_STILL: u8 = 81;
     pub const MIN_POSITION_STILL: u8 = 79;
+    /// Where a drive that stays still starts, in the middle of its range
+    pub const START_POSITION_STILL: u8 = 80;
+
+    /// The number of ticks over which the drive select duty cycle repeats (1ms at the default
+    /// timer resolution)
+    pub const DUTY_WINDOW_TICKS: u32 = 50;
+
+    /// How long a note dies away for after a note off with the highest release velocity
+    pub const MAX_RELEASE_US: u64 = 50_000;
+
+    /// Each half period of a note dying away is longer than the last by this power of two of it
+    /// (an eighth, a little more than two semitones)
+    const RELEASE_SLOWDOWN_SHIFT: u32 = 3;
+
+    /// The time between toggling the step pin while parking, so the head crosses the whole disk in
+    /// under a second
+    pub const PARK_TOGGLE_US: u64 = 6_000;
+
+    pub fn new(movement: bool, timer_resolution_us: u64) -> Self {
+        let current_position = if movement {
+            0
+        } else {
+            Self::START_POSITION_STILL
+        };
 
-    pub fn new(movement: bool) -> Self {
         Self {
             current_note: None,
             current_note_tick: 0,
             current_period_tick: 0,
-            current_position: 0,
+            current_position,
             current_state: false,
             current_direction: Direction::Forward,
             current_direction_tick: 0,
+            current_half_ticks: 0,
+            select_ticks: Self::DUTY_WINDOW_TICKS,
             movement,
+            program: 0,
+            pitch_bend: 0.0,
+            detune_cents: 0,
+            timer_resolution_us,
+            release_ticks: 0,
+            release_extra_ticks: 0,
+            park_position: None,
+            park_tick: 0,
         }
     }
 
-    pub fn set_note(&mut self, note: Option<Note>) {
-        self.current_note = note.filter(|note| note.is_playable());
-        self.current_period_tick = 0;
-        self.current_note_tick = 0;
-        self.current_direction_tick = 0;
+    pub fn tick(&mut self) -> DriveState {
+        if self.release_ticks > 0 {
+            self.release_ticks -= 1;
 
-        if !self.current_state {
-            self.toggle_step();
+            if self.release_ticks == 0 {
+                self.set_note(None);
+            }
         }
 
-        assert!(self.current_state);
-    }
+        if let Some(park_position) = self.park_position {
+            return self.tick_park(park_position);
+        }
 
-    pub fn tick(&mut self) -> DriveState {
-        let Some(note) = self.current_note else {
+        if self.current_note.is_none() {
             return DriveState {
                 drive_select: false,
                 step: self.current_state,
                 direction: self.current_direction,
             };
-        };
+        }
 
         self.current_note_tick += 1;
         self.current_direction_tick += 1;
-        let drive_select = self.current_note_tick > 1;
+        let sounding = self.current_note_tick > 1;
+        let drive_select =
+            sounding && self.current_note_tick % Self::DUTY_WINDOW_TICKS < self.select_ticks;
 
-        if drive_select {
-            self.current_period_tick += 1;
+        if sounding {
+            self.current_period_tick += TICK_FRACTIONS;
 
-            if self.current_period_tick >= note.half_ticks() {
-                self.toggle_step();
-                self.current_period_tick = 0;
+            let half_ticks = self.current_half_ticks + self.release_extra_ticks;
+
+            if self.current_period_tick >= half_ticks {
+                // The drive ignores steps while it isn't selected, so they're skipped rather than
+                // losing track of the head position
+                if drive_select {
+                    self.toggle_step();
+                }
+
+                // Carry over the fraction of a tick the step was late by, so the half periods
+                // alternate between the ticks either side and average out to the exact length
+                self.current_period_tick -= half_ticks;
+
+                // Slow the steps down a little more each half period while the note dies away
+                if self.release_ticks > 0 {
+                    self.release_extra_ticks += half_ticks >> Self::RELEASE_SLOWDOWN_SHIFT;
+                }
             }
         }
 
@@ -84,30 +153,174 @@ impl FloppyDrive {
         }
     }
 
-    fn toggle_step(&mut self) {
-        let (min_position, max_position) = if self.movement {
+    /// Recalculates the time between toggling the step pin for the current note, pitch bend and
+    /// detune (done ahead of time to keep the float math out of `tick`)
+    fn update_half_ticks(&mut self) {
+        let semitones = self.pitch_bend + self.detune_cents as f32 / 100.0;
+
+        self.current_half_ticks = self.current_note.map_or(0, |note| {
+            note.bent_half_ticks(self.timer_resolution_us, semitones)
+                .max(TICK_FRACTIONS)
+        });
+    }
+
+    /// Silences the drive and steps the head back to a track, keeping within the range the head
+    /// moves over while playing
+    pub fn park(&mut self, home_track: u8) {
+        self.set_note(None);
+
+        let (min_position, max_position) = self.position_range();
+
+        // The position counts toggles of the step pin, and the head steps on every other one
+        let park_position = home_track
+            .saturating_mul(2)
+            .clamp(min_position, max_position);
+
+        self.current_direction = if park_position < self.current_position {
+            Direction::Reverse
+        } else {
+            Direction::Forward
+        };
+
+        self.park_position = Some(park_position);
+        self.park_tick = 0;
+    }
+
+    /// Whether the head is still stepping back to where it's parking
+    pub fn is_parking(&self) -> bool {
+        self.park_position.is_some()
+    }
+
+    /// Toggles the step pin towards where the head is parking every `PARK_TOGGLE_US`, keeping the
+    /// drive selected until it gets there
+    fn tick_park(&mut self, park_position: u8) -> DriveState {
+        self.park_tick += 1;
+
+        if self.park_tick as u64 * self.timer_resolution_us >= Self::PARK_TOGGLE_US {
+            self.park_tick = 0;
+
+            if self.current_position == park_position {
+                self.park_position = None;
+            } else {
+                self.toggle_step();
+            }
+        }
+
+        DriveState {
+            drive_select: self.park_position.is_some(),
+            step: self.current_state,
+            direction: self.current_direction,
+        }
+    }
+
+    /// The lowest and highest positions the head moves between
+    fn position_range(&self) -> (u8, u8) {
+        if self.movement {
             (Self::MIN_POSITION_MOVEMENT, Self::MAX_POSITION_MOVEMENT)
         } else {
             (Self::MIN_POSITION_STILL, Self::MAX_POSITION_STILL)
-        };
+        }
+    }
+
+    fn toggle_step(&mut self) {
+        let (min_position, max_position) = self.position_range();
 
+        // A position below the range (like the home track of a moving drive) walks forward into it
         if self.current_position >= max_position {
             self.current_direction = Direction::Reverse;
             self.current_direction_tick = 0;
-        } else if self.current_position == min_position {
+        } else if self.current_position <= min_position {
             self.current_direction = Direction::Forward;
             self.current_direction_tick = 0;
         }
 
         match self.current_direction {
             Direction::Forward => self.current_position += 1,
-            Direction::Reverse => self.current_position -= 1,
+            Direction::Reverse => self.current_position = self.current_position.saturating_sub(1),
         }
 
         self.current_state = !self.current_state;
     }
 }
 
+impl Instrument for FloppyDrive {
+    fn program(&self) -> u8 {
+        self.program
+    }
+
+    fn set_program(&mut self, program: u8) {
+        self.program = program;
+    }
+
+    fn note(&self) -> Option<Note> {
+        self.current_note
+    }
+
+    /// The note is retuned in place, so the step timing and head position carry on from where they
+    /// are rather than restarting the note.
+    fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+        self.update_half_ticks();
+    }
+
+    fn set_detune(&mut self, cents: i16) {
+        self.detune_cents = cents;
+        self.update_half_ticks();
+    }
+
+    /// Scales how much of the time the drive is selected by a note velocity, so quieter notes
+    /// skip some of their steps and sound softer
+    fn set_velocity(&mut self, velocity: u8) {
+        let velocity = velocity.min(127) as u32;
+
+        self.select_ticks = (Self::DUTY_WINDOW_TICKS * velocity).div_ceil(127).max(1);
+    }
+
+    fn set_note(&mut self, note: Option<Note>) {
+        self.current_note = note.filter(|note| note.is_playable());
+
+        if self.current_note.is_none() {
+            self.pitch_bend = 0.0;
+        }
+
+        self.update_half_ticks();
+        self.current_period_tick = 0;
+        self.current_note_tick = 0;
+        self.current_direction_tick = 0;
+        self.release_ticks = 0;
+        self.release_extra_ticks = 0;
+        self.park_position = None;
+
+        if !self.current_state {
+            self.toggle_step();
+        }
+
+        assert!(self.current_state);
+    }
+
+    /// Holds off a new note until the current one has played for `min_note_ticks`, unless it's
+    /// already dying away
+    fn can_retrigger(&self, min_note_ticks: u32) -> bool {
+        self.current_note.is_none()
+            || self.release_ticks > 0
+            || self.current_note_tick >= min_note_ticks
+    }
+
+    /// Keeps the note stepping for up to `MAX_RELEASE_US` (scaled by the release velocity) while
+    /// slowing the steps down, so the note falls away rather than stopping dead
+    fn release(&mut self, velocity: u8) {
+        let release_ticks =
+            Self::MAX_RELEASE_US * velocity.min(127) as u64 / 127 / self.timer_resolution_us;
+
+        if self.current_note.is_none() || release_ticks == 0 {
+            self.set_note(None);
+            return;
+        }
+
+        self.release_ticks = release_ticks as u32;
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
 pub enum Direction {
     #[default]
@@ -124,29 +337,350 @@ impl Direction {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
 pub struct DriveState {
     pub drive_select: bool,
     pub step: bool,
     pub direction: Direction,
 }
 
+/// The bit of a drive's shift register output wired to its drive select pin
+pub const DRIVE_SELECT_BIT: u8 = 0x1;
+
+/// The bit of a drive's shift register output wired to its step pin
+pub const STEP_BIT: u8 = 0x2;
+
+/// The bit of a drive's shift register output wired to its direction pin (set for
+/// `Direction::Reverse`)
+pub const DIRECTION_BIT: u8 = 0x4;
+
+/// Whether each signal's bit is cleared when it's asserted, as the floppy drive interface expects
+/// (the `invert_*` features flip these for boards wired through inverting buffers)
+const DRIVE_SELECT_ACTIVE_LOW: bool = !cfg!(feature = "invert_drive_select");
+pub(crate) const STEP_ACTIVE_LOW: bool = !cfg!(feature = "invert_step");
+pub(crate) const DIRECTION_ACTIVE_LOW: bool = cfg!(feature = "invert_direction");
+
+/// The bit for a signal at the given polarity, if it should be set
+pub(crate) const fn signal_bit(asserted: bool, bit: u8, active_low: bool) -> u8 {
+    if asserted != active_low {
+        bit
+    } else {
+        0
+    }
+}
+
+/// Whether the signal for a bit is asserted in a byte at the given polarity
+const fn is_asserted(byte: u8, bit: u8, active_low: bool) -> bool {
+    (byte & bit != 0) != active_low
+}
+
 impl From<DriveState> for u8 {
     fn from(value: DriveState) -> Self {
-        let mut byte = 0;
+        signal_bit(
+            value.drive_select,
+            DRIVE_SELECT_BIT,
+            DRIVE_SELECT_ACTIVE_LOW,
+        ) | signal_bit(value.step, STEP_BIT, STEP_ACTIVE_LOW)
+            | signal_bit(
+                value.direction == Direction::Reverse,
+                DIRECTION_BIT,
+                DIRECTION_ACTIVE_LOW,
+            )
+    }
+}
+
+/// A shift register byte had bits set besides the ones wired to a drive's signals, so it can't
+/// have come from a `DriveState`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct UnknownDriveBits(pub u8);
+
+/// Decodes what was shifted out to a drive (for tests and debugging)
+impl TryFrom<u8> for DriveState {
+    type Error = UnknownDriveBits;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        let unknown = byte & !(DRIVE_SELECT_BIT | STEP_BIT | DIRECTION_BIT);
+
+        if unknown != 0 {
+            return Err(UnknownDriveBits(unknown));
+        }
+
+        let direction = if is_asserted(byte, DIRECTION_BIT, DIRECTION_ACTIVE_LOW) {
+            Direction::Reverse
+        } else {
+            Direction::Forward
+        };
+
+        Ok(Self {
+            drive_select: is_asserted(byte, DRIVE_SELECT_BIT, DRIVE_SELECT_ACTIVE_LOW),
+            step: is_asserted(byte, STEP_BIT, STEP_ACTIVE_LOW),
+            direction,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use floppier_proto::{MAX_PLAYABLE_NOTE, MIN_PLAYABLE_NOTE};
+
+    use super::*;
+    use crate::DEFAULT_TIMER_RESOLUTION_US;
+
+    fn count_steps(drive: &mut FloppyDrive, ticks: u32) -> usize {
+        let mut steps = 0;
+        let mut step = drive.tick().step;
+
+        for _ in 1..ticks {
+            let state = drive.tick();
+
+            if state.step != step {
+                steps += 1;
+                step = state.step;
+            }
+        }
+
+        steps
+    }
+
+    #[test]
+    fn drive_states_round_trip() {
+        for drive_select in [false, true] {
+            for step in [false, true] {
+                for direction in [Direction::Forward, Direction::Reverse] {
+                    let state = DriveState {
+                        drive_select,
+                        step,
+                        direction,
+                    };
+
+                    assert_eq!(DriveState::try_from(u8::from(state)), Ok(state));
+                }
+            }
+        }
+
+        // Every byte is either a drive state or has stray bits
+        for byte in 0..=u8::MAX {
+            match DriveState::try_from(byte) {
+                Ok(state) => assert_eq!(u8::from(state), byte),
+                Err(UnknownDriveBits(bits)) => {
+                    assert_eq!(bits, byte & !(DRIVE_SELECT_BIT | STEP_BIT | DIRECTION_BIT));
+                    assert_ne!(bits, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(any(
+        feature = "invert_drive_select",
+        feature = "invert_step",
+        feature = "invert_direction"
+    )))]
+    fn drive_select_and_step_are_active_low() {
+        assert_eq!(u8::from(DriveState::default()), DRIVE_SELECT_BIT | STEP_BIT);
 
-        if !value.drive_select {
-            byte |= 0x1;
+        let stepping_in_reverse = DriveState {
+            drive_select: true,
+            step: true,
+            direction: Direction::Reverse,
+        };
+
+        assert_eq!(u8::from(stepping_in_reverse), DIRECTION_BIT);
+    }
+
+    #[test]
+    fn still_drives_start_in_range() {
+        let mut drive = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        drive.set_note(Some(Note::try_from(69).unwrap()));
+
+        let range = FloppyDrive::MIN_POSITION_STILL..=FloppyDrive::MAX_POSITION_STILL;
+
+        for _ in 0..1_000 {
+            let state = drive.tick();
+
+            assert!(range.contains(&drive.current_position));
+            assert_eq!(state.step, drive.current_state);
         }
+    }
+
+    #[test]
+    fn still_drives_step_into_range() {
+        let mut drive = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+
+        // As if the head had been left reversing from below the range
+        drive.current_position = 0;
+        drive.current_direction = Direction::Reverse;
+        drive.set_note(Some(Note::try_from(69).unwrap()));
+
+        count_steps(&mut drive, 10_000);
+
+        assert!(drive.current_position >= FloppyDrive::MIN_POSITION_STILL);
+        assert!(drive.current_position <= FloppyDrive::MAX_POSITION_STILL);
+    }
+
+    #[test]
+    fn plays_every_note_in_tune_on_average() {
+        // Including a finer timer resolution than the default, as a small stack might use
+        for (number, timer_resolution_us) in (MIN_PLAYABLE_NOTE..=MAX_PLAYABLE_NOTE)
+            .flat_map(|number| [(number, 10), (number, DEFAULT_TIMER_RESOLUTION_US)])
+        {
+            let note = Note::try_from(number).unwrap();
+
+            let mut drive = FloppyDrive::new(false, timer_resolution_us);
+            drive.set_note(Some(note));
+
+            // Time a thousand periods from the first step, so the error of any single period
+            // (up to a whole tick) is averaged out
+            let mut step = drive.tick().step;
+            let mut first_toggle = None;
+            let mut toggles = 0;
+            let mut tick = 0u32;
+
+            while toggles < 2_000 {
+                tick += 1;
+
+                let state = drive.tick();
 
-        if !value.step {
-            byte |= 0x2;
+                if state.step != step {
+                    step = state.step;
+
+                    match first_toggle {
+                        None => first_toggle = Some(tick),
+                        Some(_) => toggles += 1,
+                    }
+                }
+            }
+
+            let ticks = (tick - first_toggle.unwrap()) as f32;
+            let frequency = 1_000.0 * 1_000_000.0 / (ticks * timer_resolution_us as f32);
+
+            let error = (frequency / note.frequency() - 1.0).abs();
+
+            assert!(error < 0.001, "{:?} is out by {}", note, error);
         }
+    }
+
+    #[test]
+    fn detuned_drives_drift_apart() {
+        let note = Note::try_from(69).unwrap();
+
+        let mut in_tune = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        in_tune.set_note(Some(note));
+
+        // A few cents is less than a tick per period, but still adds up
+        let mut detuned = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        detuned.set_detune(10);
+        detuned.set_note(Some(note));
+
+        let in_tune_steps = count_steps(&mut in_tune, 100_000);
+        let detuned_steps = count_steps(&mut detuned, 100_000);
+
+        let ratio = detuned_steps as f32 / in_tune_steps as f32;
+
+        assert!((ratio - libm::exp2f(10.0 / 1200.0)).abs() < 0.001);
+    }
+
+    #[test]
+    fn released_notes_slow_down_before_stopping() {
+        let note = Note::try_from(69).unwrap();
+        let release_ticks = (FloppyDrive::MAX_RELEASE_US / DEFAULT_TIMER_RESOLUTION_US) as u32;
+
+        let mut held = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        held.set_note(Some(note));
+
+        let mut released = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        released.set_note(Some(note));
+        count_steps(&mut released, 10_000);
+        released.release(127);
+
+        let held_steps = count_steps(&mut held, release_ticks);
+        let released_steps = count_steps(&mut released, release_ticks);
+
+        assert!(released_steps > 0);
+        assert!(released_steps < held_steps);
+        assert_eq!(released.note(), None);
+        assert_eq!(count_steps(&mut released, 1_000), 0);
 
-        if value.direction == Direction::Reverse {
-            byte |= 0x4;
+        // Without a release velocity the note stops straight away
+        held.release(0);
+        assert_eq!(held.note(), None);
+    }
+
+    #[test]
+    fn parks_the_head_on_the_home_track() {
+        let note = Note::try_from(40).unwrap();
+        let park_ticks = (1_000_000 / DEFAULT_TIMER_RESOLUTION_US) as u32;
+
+        for home_track in [0, 10, 40, 79] {
+            let mut drive = FloppyDrive::new(true, DEFAULT_TIMER_RESOLUTION_US);
+            drive.set_note(Some(note));
+            count_steps(&mut drive, 25_000);
+
+            drive.park(home_track);
+
+            assert_eq!(drive.note(), None);
+            assert!(drive.is_parking());
+
+            count_steps(&mut drive, park_ticks);
+
+            // Kept within the range the head moves over while playing
+            let position = (home_track * 2).clamp(
+                FloppyDrive::MIN_POSITION_MOVEMENT,
+                FloppyDrive::MAX_POSITION_MOVEMENT,
+            );
+
+            assert!(!drive.is_parking());
+            assert_eq!(drive.current_position, position);
+            assert!(!drive.tick().drive_select);
         }
 
-        byte
+        // A new note cancels parking
+        let mut drive = FloppyDrive::new(true, DEFAULT_TIMER_RESOLUTION_US);
+        drive.park(40);
+        drive.set_note(Some(note));
+
+        assert!(!drive.is_parking());
+    }
+
+    #[test]
+    fn holds_off_retriggering_until_the_note_has_played() {
+        let mut drive = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+
+        assert!(drive.can_retrigger(100));
+
+        drive.set_note(Some(Note::try_from(69).unwrap()));
+        count_steps(&mut drive, 99);
+
+        assert!(!drive.can_retrigger(100));
+        assert!(drive.can_retrigger(0));
+
+        count_steps(&mut drive, 1);
+
+        assert!(drive.can_retrigger(100));
+    }
+
+    #[test]
+    fn quieter_notes_step_less() {
+        let note = Note::try_from(69).unwrap();
+
+        let mut loud = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        loud.set_note(Some(note));
+
+        let mut quiet = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        quiet.set_velocity(32);
+        quiet.set_note(Some(note));
+
+        let loud_steps = count_steps(&mut loud, 10_000);
+        let quiet_steps = count_steps(&mut quiet, 10_000);
+
+        assert!(quiet_steps > 0);
+        assert!(quiet_steps < loud_steps / 2);
+
+        // Full velocity is the same as not setting one
+        let mut full = FloppyDrive::new(false, DEFAULT_TIMER_RESOLUTION_US);
+        full.set_velocity(127);
+        full.set_note(Some(note));
+
+        assert_eq!(count_steps(&mut full, 10_000), loud_steps);
     }
 }
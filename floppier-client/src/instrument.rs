@@ -0,0 +1,332 @@
+use core::ops::{Deref, DerefMut, RangeInclusive};
+
+use defmt::Format;
+
+use crate::{floppy_drive::FloppyDrive, note::Note, stepper::StepperInstrument};
+
+/// Something that plays the notes sent to a port of the config
+///
+/// Each kind of instrument also has a `tick` method, called every timer tick, which says what to
+/// write to its hardware (the kinds are driven too differently to share one).
+pub trait Instrument {
+    /// The note currently being played, if any
+    fn note(&self) -> Option<Note>;
+
+    fn set_note(&mut self, note: Option<Note>);
+
+    /// Stops the current note after a note off, letting it die away over a time set by the release
+    /// velocity where the instrument can (it's cut off straight away otherwise)
+    fn release(&mut self, _velocity: u8) {
+        self.set_note(None);
+    }
+
+    /// Whether a new note can replace the current one yet, which instruments that sound
+    /// retriggered (like a drive) hold off until the note has played for `min_note_ticks`
+    fn can_retrigger(&self, _min_note_ticks: u32) -> bool {
+        true
+    }
+
+    /// Bends the pitch of the current note by a number of semitones
+    fn set_pitch_bend(&mut self, semitones: f32);
+
+    /// Tunes the instrument by a number of cents on top of any pitch bend, for a chorus effect
+    /// between ports doubling the same part
+    fn set_detune(&mut self, cents: i16);
+
+    /// Scales how loud the instrument plays by a note velocity
+    fn set_velocity(&mut self, velocity: u8);
+
+    /// The General MIDI program (instrument) last assigned to the port
+    fn program(&self) -> u8;
+
+    fn set_program(&mut self, program: u8);
+}
+
+/// The instrument playing a port of the config
+#[derive(Debug, Format)]
+pub enum Voice {
+    /// A floppy drive, with its index in the shift register chain
+    Drive {
+        index: u8,
+        drive: FloppyDrive,
+    },
+    /// A stepper motor, with the index of its output in the shift register chain
+    Stepper {
+        index: u8,
+        stepper: StepperInstrument,
+    },
+    Pwm(PwmInstrument),
+}
+
+impl Deref for Voice {
+    type Target = dyn Instrument;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Voice::Drive { drive, .. } => drive,
+            Voice::Stepper { stepper, .. } => stepper,
+            Voice::Pwm(pwm) => pwm,
+        }
+    }
+}
+
+impl DerefMut for Voice {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Voice::Drive { drive, .. } => drive,
+            Voice::Stepper { stepper, .. } => stepper,
+            Voice::Pwm(pwm) => pwm,
+        }
+    }
+}
+
+/// The number of GPIO pins on the RP2040
+const GPIO_COUNT: u8 = 30;
+
+/// The GPIO pins the shift register is connected to, which can't be PWM ports
+const SHIFT_REGISTER_GPIOS: RangeInclusive<u8> = 2..=5;
+
+/// The number of PWM slices on the RP2040, each of which can play one PWM port
+pub const PWM_SLICE_COUNT: usize = 8;
+
+/// The PWM slice a GPIO pin is on (pins 16 and up wrap around to the first slice again)
+pub const fn pwm_slice(gpio: u8) -> usize {
+    (gpio as usize >> 1) % PWM_SLICE_COUNT
+}
+
+/// Whether a GPIO pin is on channel B of its PWM slice (channel A otherwise)
+pub const fn is_pwm_channel_b(gpio: u8) -> bool {
+    gpio & 1 == 1
+}
+
+/// Checks that the GPIO pins of the PWM ports in a config can be used, returning the first one
+/// that can't
+///
+/// A pin can't be used if it doesn't exist, drives the shift register or is on the same PWM slice
+/// as an earlier pin (since the frequency is set for the whole slice).
+pub fn check_pwm_gpios(gpios: impl IntoIterator<Item = u8>) -> Result<(), u8> {
+    let mut used_slices = [false; PWM_SLICE_COUNT];
+
+    for gpio in gpios {
+        if gpio >= GPIO_COUNT || SHIFT_REGISTER_GPIOS.contains(&gpio) {
+            return Err(gpio);
+        }
+
+        let slice = &mut used_slices[pwm_slice(gpio)];
+
+        if *slice {
+            return Err(gpio);
+        }
+
+        *slice = true;
+    }
+
+    Ok(())
+}
+
+/// A piezo buzzer or speaker on a GPIO pin, played by setting the frequency of the pin's PWM slice
+/// to the frequency of the note
+///
+/// Unlike a drive, the PWM slice plays the note by itself, so this can play any note (including the
+/// low ones the drives can't).
+#[derive(Debug, Format)]
+pub struct PwmInstrument {
+    gpio: u8,
+    note: Option<Note>,
+    pitch_bend: f32,
+    detune_cents: i16,
+    velocity: u8,
+    program: u8,
+    setting: PwmSetting,
+}
+
+impl PwmInstrument {
+    pub fn new(gpio: u8) -> Self {
+        Self {
+            gpio,
+            note: None,
+            pitch_bend: 0.0,
+            detune_cents: 0,
+            velocity: 127,
+            program: 0,
+            setting: PwmSetting::SILENT,
+        }
+    }
+
+    /// The GPIO pin the instrument is connected to
+    pub fn gpio(&self) -> u8 {
+        self.gpio
+    }
+
+    /// How to program the PWM slice for the current note
+    pub fn tick(&mut self) -> PwmSetting {
+        self.setting
+    }
+
+    /// Recalculates how to program the PWM slice for the current note, pitch bend, detune and
+    /// velocity (done ahead of time to keep the float math out of `tick`)
+    fn update_setting(&mut self) {
+        let Some(note) = self.note else {
+            self.setting = PwmSetting::SILENT;
+            return;
+        };
+
+        let semitones = self.pitch_bend + self.detune_cents as f32 / 100.0;
+        let frequency = note.frequency() * libm::exp2f(semitones / 12.0);
+
+        self.setting = PwmSetting::new(frequency, self.velocity);
+    }
+}
+
+impl Instrument for PwmInstrument {
+    fn note(&self) -> Option<Note> {
+        self.note
+    }
+
+    fn set_note(&mut self, note: Option<Note>) {
+        self.note = note;
+
+        if self.note.is_none() {
+            self.pitch_bend = 0.0;
+        }
+
+        self.update_setting();
+    }
+
+    fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+        self.update_setting();
+    }
+
+    fn set_detune(&mut self, cents: i16) {
+        self.detune_cents = cents;
+        self.update_setting();
+    }
+
+    /// Narrows the pulses of quieter notes, which sounds softer on a piezo
+    fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = velocity.min(127);
+        self.update_setting();
+    }
+
+    fn program(&self) -> u8 {
+        self.program
+    }
+
+    fn set_program(&mut self, program: u8) {
+        self.program = program;
+    }
+}
+
+/// How to program a PWM slice to play a square wave on one of its pins
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PwmSetting {
+    /// The clock divider in sixteenths (the integer part goes in `DIV.INT` and the fraction in
+    /// `DIV.FRAC`)
+    pub divider: u16,
+    /// The value the counter wraps after, so each period is `top + 1` divided clock cycles
+    pub top: u16,
+    /// The compare level of the pin's channel (how many counts of each period the pin is high)
+    pub level: u16,
+}
+
+impl PwmSetting {
+    /// The clock the PWM slices count (the system clock set up by `init_clocks_and_plls`)
+    pub const CLOCK_HZ: u32 = 125_000_000;
+
+    const MIN_DIVIDER: u16 = 16;
+    const MAX_DIVIDER: u16 = 0xfff;
+
+    /// Keeps the pin low
+    pub const SILENT: Self = Self {
+        divider: Self::MIN_DIVIDER,
+        top: u16::MAX,
+        level: 0,
+    };
+
+    /// The setting for a square wave of a frequency in Hz, with a 50% duty cycle at full velocity
+    ///
+    /// The divider is kept as small as possible so `top` (and the accuracy of the frequency) is as
+    /// large as possible.
+    pub fn new(frequency: f32, velocity: u8) -> Self {
+        let clock_sixteenths = Self::CLOCK_HZ as f32 * 16.0;
+
+        let divider = libm::ceilf(clock_sixteenths / (frequency * (u16::MAX as f32 + 1.0)));
+        let divider = (divider as u16).clamp(Self::MIN_DIVIDER, Self::MAX_DIVIDER);
+
+        let counts = libm::roundf(clock_sixteenths / (divider as f32 * frequency));
+        let top = (counts as u32).clamp(2, u16::MAX as u32 + 1) - 1;
+
+        let level = top.div_ceil(2) * velocity.min(127) as u32 / 127;
+
+        Self {
+            divider,
+            top: top as u16,
+            level: level as u16,
+        }
+    }
+
+    /// The frequency the slice plays at in Hz
+    pub fn frequency(self) -> f32 {
+        Self::CLOCK_HZ as f32 * 16.0 / (self.divider as f32 * (self.top as f32 + 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_every_note_in_tune() {
+        for number in 0..=127 {
+            let note = Note::try_from(number).unwrap();
+            let setting = PwmSetting::new(note.frequency(), 127);
+
+            let error = (setting.frequency() / note.frequency() - 1.0).abs();
+
+            assert!(error < 0.001, "{:?} is out by {}", note, error);
+            assert_eq!(setting.level, setting.top.div_ceil(2));
+        }
+    }
+
+    #[test]
+    fn quieter_notes_have_narrower_pulses() {
+        let loud = PwmSetting::new(440.0, 127);
+        let quiet = PwmSetting::new(440.0, 32);
+
+        assert_eq!((loud.divider, loud.top), (quiet.divider, quiet.top));
+        assert!(quiet.level > 0);
+        assert!(quiet.level < loud.level / 2);
+    }
+
+    #[test]
+    fn tracks_the_note() {
+        let mut pwm = PwmInstrument::new(16);
+
+        assert_eq!(pwm.tick(), PwmSetting::SILENT);
+
+        pwm.set_note(Some(Note::try_from(69).unwrap()));
+
+        let a4 = pwm.tick();
+        assert!((a4.frequency() - 440.0).abs() < 0.5);
+
+        pwm.set_pitch_bend(12.0);
+        assert!((pwm.tick().frequency() - 880.0).abs() < 1.0);
+
+        pwm.set_note(None);
+        assert_eq!(pwm.tick(), PwmSetting::SILENT);
+    }
+
+    #[test]
+    fn rejects_unusable_gpios() {
+        assert_eq!(check_pwm_gpios([0, 6, 15, 28]), Ok(()));
+
+        // Drives the shift register
+        assert_eq!(check_pwm_gpios([3]), Err(3));
+        // Doesn't exist
+        assert_eq!(check_pwm_gpios([30]), Err(30));
+        // On the same slice as 6, both directly and by wrapping around
+        assert_eq!(check_pwm_gpios([6, 7]), Err(7));
+        assert_eq!(check_pwm_gpios([6, 22]), Err(22));
+    }
+}
@@ -1,89 +1,206 @@
-use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+
+use alloc::{format, string::String, vec::Vec};
+use critical_section::Mutex;
 
 use rp_pico::hal::usb::UsbBus;
 use usbd_serial::SerialPort;
 
-use floppier_proto::{FloppierC2SMessage, FloppierS2CMessage};
+use floppier_client::read_buffer::{InvalidFrame, ReadBuffer};
+use floppier_proto::{
+    frame::{self, FrameHeader},
+    FloppierC2SMessage, FloppierErrorKind, FloppierS2CMessage,
+};
+
+/// The largest payload the client will accept. Anything longer must be a corrupted length prefix.
+pub const MAX_FRAME_LEN: usize = 2048;
+
+/// The most unprocessed bytes the client will hold onto before giving up on them
+const MAX_READ_BUFFER_LEN: usize = 2 * MAX_FRAME_LEN;
+
+/// Raw bytes received from the server that haven't been turned into messages yet
+static READ_BUFFER: Mutex<RefCell<ReadBuffer>> = Mutex::new(RefCell::new(ReadBuffer::new()));
+
+/// Whether to checksum the frames sent to the server (negotiated in the hello handshake)
+static CHECKSUM_FRAMES: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Whether frames are being dropped since one was corrupted, until the server marks the start of
+/// sending them again with `FloppierS2CMessage::Retransmit` (only on a checksummed link)
+static DISCARDING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// A frame from the server couldn't be turned into a message
+pub enum FrameError {
+    /// The frame had a bad checksum, so should be sent again (along with every frame after it,
+    /// which are dropped until then on a checksummed link)
+    Corrupted,
+    /// The frame arrived intact (or has no checksum to tell otherwise) but didn't hold a message
+    /// the client understands, or bytes arrived that weren't a frame at all, so sending it again
+    /// won't help
+    DeserializeFailed(String),
+}
 
-static mut READ_BUFFER: Vec<u8> = Vec::new();
-static mut READ_BUFFER_LEN: usize = 0;
+pub fn set_checksum_frames(enabled: bool) {
+    critical_section::with(|cs| CHECKSUM_FRAMES.borrow(cs).set(enabled));
+}
 
 /// Update the read buffer with any new data from the serial port
 ///
 /// This gets called during USB event interrupts because data packets are sometimes split across
-/// multiple USB packets. This function will read the data from the serial port and append it to the
-/// internal read buffer until a full message has been received.
+/// multiple USB packets, and the server may also send several frames back to back. This function
+/// reads everything available from the serial port and appends it to the internal read buffer,
+/// which `get_received_message` then splits into frames.
 pub fn update_read_buffer(serial: &mut SerialPort<UsbBus>) {
     let mut buf = [0u8; 64];
-    let count = match serial.read(&mut buf) {
-        Err(_) | Ok(0) => return,
-        Ok(count) => count,
-    };
-
-    #[cfg(feature = "io_debug")]
-    {
-        defmt::debug!("received {} bytes", count);
-        defmt::debug!("buf: {:?}", &buf[..count]);
-    }
 
-    let read_buffer = unsafe { &mut READ_BUFFER };
-    let read_buffer_len = unsafe { &mut READ_BUFFER_LEN };
+    loop {
+        let count = match serial.read(&mut buf) {
+            Err(_) | Ok(0) => break,
+            Ok(count) => count,
+        };
 
-    // If a length hasn't been read yet, read the first two bytes as a length, and the rest as data
-    if *read_buffer_len == 0 {
-        assert!(
-            count >= 2,
-            "Expected at least 2 bytes when read buffer is empty. Got {}",
-            count
-        );
+        #[cfg(feature = "io_debug")]
+        {
+            defmt::debug!("received {} bytes", count);
+            defmt::debug!("buf: {:?}", &buf[..count]);
+        }
 
-        let len_bytes = &buf[..2];
+        critical_section::with(|cs| READ_BUFFER.borrow(cs).borrow_mut().extend(&buf[..count]));
+    }
 
-        let len = u16::from_le_bytes([len_bytes[0], len_bytes[1]]) as usize;
+    let overflowed = critical_section::with(|cs| {
+        let mut read_buffer = READ_BUFFER.borrow(cs).borrow_mut();
 
+        if read_buffer.len() <= MAX_READ_BUFFER_LEN {
+            return false;
+        }
+
+        defmt::warn!(
+            "Caught read buffer overflow! Discarding {} bytes",
+            read_buffer.len()
+        );
         read_buffer.clear();
-        read_buffer.reserve(len);
 
-        *read_buffer_len = len;
+        true
+    });
 
-        read_buffer.extend_from_slice(&buf[2..count]);
-    } else {
-        read_buffer.extend_from_slice(&buf[..count]);
+    if overflowed {
+        let _ = send_message(
+            serial,
+            FloppierC2SMessage::Error {
+                kind: FloppierErrorKind::BufferOverflow,
+                detail: None,
+            },
+        );
     }
-
-    assert!(
-        read_buffer.len() <= *read_buffer_len,
-        "Caught read buffer overflow!"
-    );
 }
 
-/// Get the received message from the read buffer if one has been fully received
-///
-/// Must be called after a call to `update_read_buffer` to ensure that the read buffer
-/// does not overflow and is up to date
-pub fn get_received_message() -> Option<FloppierS2CMessage> {
-    let read_buffer = unsafe { &mut READ_BUFFER };
-    let read_buffer_len = unsafe { &mut READ_BUFFER_LEN };
-
-    if read_buffer.is_empty() || read_buffer.len() != *read_buffer_len {
-        return None;
-    }
+/// How many more bytes can be received before the read buffer overflows
+pub fn read_buffer_free() -> usize {
+    let len = critical_section::with(|cs| READ_BUFFER.borrow(cs).borrow().len());
 
-    // debug!("read buffer: {:?}", read_buffer);
-    // debug!("read buffer len: {}", read_buffer.len());
+    MAX_READ_BUFFER_LEN.saturating_sub(len)
+}
 
-    let message = ciborium::from_reader(&read_buffer[..])
-        .expect("Failed to parse a message from the read buffer!");
+/// Throws away any bytes that haven't been turned into messages yet
+pub fn clear_read_buffer() {
+    critical_section::with(|cs| {
+        READ_BUFFER.borrow(cs).borrow_mut().clear();
+        DISCARDING.borrow(cs).set(false);
+    });
+}
+
+/// Get the next message from the read buffer if one has been fully received
+///
+/// Must be called after a call to `update_read_buffer` to ensure that the read buffer is up to
+/// date. This should be called until it returns `None`, since there may be several frames in the
+/// buffer.
+///
+/// On a checksummed link, only the first of the frames from a corrupted one up to the server's
+/// `FloppierS2CMessage::Retransmit` is reported, and the rest are dropped, since the server sends
+/// them all again in order.
+pub fn get_received_message() -> Option<Result<FloppierS2CMessage, FrameError>> {
+    loop {
+        let (frame, checksummed, discarding) = critical_section::with(|cs| {
+            let frame = READ_BUFFER
+                .borrow(cs)
+                .borrow_mut()
+                .pop_frame(MAX_FRAME_LEN, parse_frame);
+
+            (
+                frame,
+                CHECKSUM_FRAMES.borrow(cs).get(),
+                DISCARDING.borrow(cs).get(),
+            )
+        });
 
-    #[cfg(feature = "io_debug")]
-    {
-        defmt::debug!("received message: {:?}", message);
+        let message = match frame? {
+            Ok(message) => message,
+            Err(InvalidFrame::Length { len, skipped }) => {
+                defmt::warn!(
+                    "Skipped {} bytes after frame with invalid length {}",
+                    skipped,
+                    len
+                );
+
+                // The skipped bytes may have held a frame, which the server can send again once
+                // frames are checksummed (without checksums, there's no telling whether anything
+                // was lost, but the server should know something was)
+                if checksummed {
+                    Err(FrameError::Corrupted)
+                } else {
+                    Err(FrameError::DeserializeFailed(format!(
+                        "skipped {} bytes after a frame with invalid length {}",
+                        skipped, len
+                    )))
+                }
+            }
+            Err(InvalidFrame::Checksum { skipped }) => {
+                defmt::warn!("Skipped {} bytes after frame with a bad checksum!", skipped);
+
+                Err(FrameError::Corrupted)
+            }
+        };
+
+        match &message {
+            Ok(FloppierS2CMessage::Retransmit) => {
+                if discarding {
+                    defmt::info!("Server is sending the dropped frames again");
+                    critical_section::with(|cs| DISCARDING.borrow(cs).set(false));
+                }
+
+                continue;
+            }
+            // A new session doesn't resend anything from the old one
+            Ok(FloppierS2CMessage::Hello { .. }) if discarding => {
+                critical_section::with(|cs| DISCARDING.borrow(cs).set(false));
+            }
+            _ if discarding => continue,
+            Err(FrameError::Corrupted) if checksummed => {
+                critical_section::with(|cs| DISCARDING.borrow(cs).set(true));
+            }
+            _ => {}
+        }
+
+        #[cfg(feature = "io_debug")]
+        if let Ok(message) = &message {
+            defmt::debug!("received message: {:?}", message);
+        }
+
+        return Some(message);
     }
+}
 
-    read_buffer.clear();
-    *read_buffer_len = 0;
+fn parse_frame(header: &FrameHeader, payload: &[u8]) -> Result<FloppierS2CMessage, FrameError> {
+    ciborium::from_reader(payload).map_err(|error| {
+        defmt::warn!("Failed to parse a message from the read buffer!");
 
-    Some(message)
+        // Without a checksum there's no telling whether the frame was corrupted in transit
+        if !header.checksummed {
+            return FrameError::Corrupted;
+        }
+
+        FrameError::DeserializeFailed(format!("{:?}", error))
+    })
 }
 
 /// Send a message to the server over USB serial
@@ -94,10 +211,8 @@ pub fn send_message(
     let mut data = Vec::new();
     ciborium::into_writer(&message, &mut data).map_err(|_| ())?;
 
-    let mut buf = Vec::with_capacity(data.len() + 2);
-
-    buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
-    buf.extend(data);
+    let checksummed = critical_section::with(|cs| CHECKSUM_FRAMES.borrow(cs).get());
+    let buf = frame::encode(&data, checksummed);
 
     let mut wr_ptr = &buf[..];
     while !wr_ptr.is_empty() {
@@ -0,0 +1,160 @@
+use defmt::Format;
+
+/// How often the status LED is updated in µs
+pub const LED_TICK_US: u32 = 10_000;
+
+/// How many LED ticks each half of a slow blink lasts (so it blinks once a second)
+const SLOW_BLINK_TICKS: u32 = 50;
+
+/// How many LED ticks the flash for a MIDI event lasts
+const FLASH_TICKS: u32 = 3;
+
+/// How many LED ticks each half of a blink of the error pattern lasts
+const ERROR_BLINK_TICKS: u32 = 10;
+
+/// How many times the LED blinks when an error is sent
+const ERROR_BLINKS: u32 = 3;
+
+/// What the status LED shows for the state of the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LedPattern {
+    /// Blinks once a second while waiting for the server to connect
+    SlowBlink,
+    /// Stays on while connected but not playing
+    Solid,
+    /// Stays off, flashing for each MIDI event, while playing
+    Heartbeat,
+}
+
+/// Works out whether the onboard LED should be lit, ticked every `LED_TICK_US`
+///
+/// The pattern for the state is passed in to each tick, while flashes for MIDI events and the
+/// blinks for errors are one-offs shown on top of it.
+#[derive(Debug, Format)]
+pub struct LedStatus {
+    pattern: LedPattern,
+    /// LED ticks since the pattern changed, so a blink starts from the beginning
+    pattern_ticks: u32,
+    flash_ticks: u32,
+    error_ticks: u32,
+}
+
+impl LedStatus {
+    pub const fn new() -> Self {
+        Self {
+            pattern: LedPattern::SlowBlink,
+            pattern_ticks: 0,
+            flash_ticks: 0,
+            error_ticks: 0,
+        }
+    }
+
+    /// Flashes the LED (only seen while the pattern is `Heartbeat`)
+    pub fn flash(&mut self) {
+        self.flash_ticks = FLASH_TICKS;
+    }
+
+    /// Blinks the LED rapidly a few times, over whatever the pattern is showing
+    pub fn blink_error(&mut self) {
+        self.error_ticks = 2 * ERROR_BLINKS * ERROR_BLINK_TICKS;
+    }
+
+    /// Moves on by an LED tick, returning whether the LED should be lit
+    pub fn tick(&mut self, pattern: LedPattern) -> bool {
+        if pattern != self.pattern {
+            self.pattern = pattern;
+            self.pattern_ticks = 0;
+        }
+
+        let lit = if self.error_ticks > 0 {
+            (self.error_ticks - 1) / ERROR_BLINK_TICKS % 2 == 1
+        } else {
+            match self.pattern {
+                LedPattern::SlowBlink => (self.pattern_ticks / SLOW_BLINK_TICKS).is_multiple_of(2),
+                LedPattern::Solid => true,
+                LedPattern::Heartbeat => self.flash_ticks > 0,
+            }
+        };
+
+        self.pattern_ticks = self.pattern_ticks.wrapping_add(1);
+        self.flash_ticks = self.flash_ticks.saturating_sub(1);
+        self.error_ticks = self.error_ticks.saturating_sub(1);
+
+        lit
+    }
+}
+
+impl Default for LedStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(led: &mut LedStatus, pattern: LedPattern, ticks: u32) -> [bool; 2] {
+        let mut seen = [false; 2];
+
+        for _ in 0..ticks {
+            seen[led.tick(pattern) as usize] = true;
+        }
+
+        seen
+    }
+
+    #[test]
+    fn shows_the_state() {
+        let mut led = LedStatus::new();
+
+        assert_eq!(
+            show(&mut led, LedPattern::SlowBlink, SLOW_BLINK_TICKS),
+            [false, true]
+        );
+        assert_eq!(
+            show(&mut led, LedPattern::SlowBlink, SLOW_BLINK_TICKS),
+            [true, false]
+        );
+        assert_eq!(show(&mut led, LedPattern::Solid, 200), [false, true]);
+        assert_eq!(show(&mut led, LedPattern::Heartbeat, 200), [true, false]);
+    }
+
+    #[test]
+    fn flashes_for_events_while_playing() {
+        let mut led = LedStatus::new();
+
+        led.flash();
+
+        for _ in 0..FLASH_TICKS {
+            assert!(led.tick(LedPattern::Heartbeat));
+        }
+
+        assert!(!led.tick(LedPattern::Heartbeat));
+    }
+
+    #[test]
+    fn blinks_three_times_for_an_error() {
+        let mut led = LedStatus::new();
+
+        led.blink_error();
+
+        let mut blinks = 0;
+        let mut lit = false;
+
+        for _ in 0..2 * ERROR_BLINKS * ERROR_BLINK_TICKS {
+            let now = led.tick(LedPattern::Solid);
+
+            if now && !lit {
+                blinks += 1;
+            }
+
+            lit = now;
+        }
+
+        assert_eq!(blinks, ERROR_BLINKS);
+
+        // Back to the pattern once it's done
+        assert!(led.tick(LedPattern::Solid));
+    }
+}
@@ -1,7 +1,34 @@
 #![no_std]
 
+extern crate alloc;
+
+pub mod allocator;
+pub mod channel;
 pub mod floppy_drive;
+pub mod instrument;
+pub mod led;
 pub mod note;
+pub mod note_stack;
+pub mod read_buffer;
 pub mod shift_register;
+pub mod stepper;
+
+/// How often the instruments are ticked in µs if the server doesn't specify a tick interval
+pub const DEFAULT_TIMER_RESOLUTION_US: u64 = 20;
+
+pub const MAX_DRIVE_COUNT: usize = 8;
+
+/// The most ports a config can have: every drive, plus a PWM voice on each PWM slice
+pub const MAX_PORT_COUNT: usize = MAX_DRIVE_COUNT + instrument::PWM_SLICE_COUNT;
+
+/// How long each note of a chord is played for in `ParallelMode::Synthesize` if the server doesn't
+/// specify an interval
+pub const DEFAULT_SYNTHESIZE_INTERVAL_US: u32 = 25_000;
+
+/// The track the drive heads are parked on after the end of a song if the server doesn't specify
+/// one (the middle of the disk)
+pub const DEFAULT_HOME_TRACK: u8 = 40;
 
-pub const TIMER_RESOLUTION_US: u64 = 20;
+/// How long the timer interrupt can go without running while playing before the watchdog resets
+/// the client
+pub const WATCHDOG_TIMEOUT_US: u32 = 2_000_000;
@@ -7,25 +7,33 @@ extern crate alloc;
 
 use core::cell::{Cell, RefCell};
 
-use alloc::{collections::BTreeMap, string::ToString};
-use critical_section::Mutex;
+use alloc::{collections::BTreeMap, string::String};
+use critical_section::{CriticalSection, Mutex};
 use defmt_rtt as _;
-use embedded_hal::delay::DelayNs;
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{OutputPin, PinState},
+};
 use floppier_proto::{
-    FloppierC2SMessage, FloppierS2CMessage, LimitedMidiMessage, MidiEvent, SetConfig,
+    is_compatible_version, ClientState, FloppierC2SMessage, FloppierErrorKind, FloppierS2CMessage,
+    FloppierS2CMessageKind, LimitedMidiMessage, MidiEvent, ParallelMode, PortKind, SetConfig,
+    StatusReport, DEFAULT_RESET_DWELL_MS, DEFAULT_RESET_PASSES, DEFAULT_RESET_STEP_DELAY_US,
+    EXTENSIONS_VERSION, MAX_TICK_US, MAX_TIMED_EVENTS, MIN_TICK_US, PROTO_VERSION,
 };
 
 use embedded_alloc::LlffHeap as Heap;
-use heapless::Vec;
+use heapless::{Deque, Vec};
 use panic_probe as _;
 use rp_pico::{
     entry,
     hal::{
         self,
         clocks::UsbClock,
+        dma::DMAExt,
         fugit::{ExtU32, ExtU64},
+        gpio::{bank0::Gpio25, FunctionSioOutput, Pin, PullDown},
         pio::PIOExt,
-        timer::{Alarm, Alarm0},
+        timer::{Alarm, Alarm0, Alarm1},
         Timer,
     },
     pac::{RESETS, USBCTRL_DPRAM, USBCTRL_REGS},
@@ -42,19 +50,47 @@ use hal::{
 
 mod io;
 
-use crate::io::{get_received_message, send_message, update_read_buffer};
+use crate::io::{
+    clear_read_buffer, get_received_message, read_buffer_free, send_message, set_checksum_frames,
+    update_read_buffer, FrameError,
+};
 use floppier_client::{
+    channel::{
+        pitch_bend_to_semitones, ChannelState, CONTROL_ALL_NOTES_OFF, CONTROL_ALL_SOUND_OFF,
+        CONTROL_SUSTAIN, CONTROL_VOLUME, PEDAL_ON_VALUE,
+    },
     floppy_drive::{Direction, DriveState, FloppyDrive},
+    instrument::{check_pwm_gpios, is_pwm_channel_b, pwm_slice, PwmInstrument, PwmSetting, Voice},
+    led::{LedPattern, LedStatus, LED_TICK_US},
     note::Note,
-    shift_register::SN74HC595,
-    TIMER_RESOLUTION_US,
+    note_stack::NoteStack,
+    shift_register::{Frame, SN74HC595},
+    stepper::StepperInstrument,
+    DEFAULT_HOME_TRACK, DEFAULT_SYNTHESIZE_INTERVAL_US, DEFAULT_TIMER_RESOLUTION_US,
+    MAX_DRIVE_COUNT, MAX_PORT_COUNT, WATCHDOG_TIMEOUT_US,
 };
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
 
-// This can be static mut because it gets set once and only ever gets cloned
-static mut TIMER: Option<Timer> = None;
+// These are shared between the interrupts, so are only accessed in critical sections
+static TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static ALARM0: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
+static SHIFT_REGISTER: Mutex<RefCell<Option<SN74HC595>>> = Mutex::new(RefCell::new(None));
+
+/// Resets the client if the timer interrupt stops running while playing (armed by `set_state`)
+static WATCHDOG: Mutex<RefCell<Option<Watchdog>>> = Mutex::new(RefCell::new(None));
+
+type LedPin = Pin<Gpio25, FunctionSioOutput, PullDown>;
+
+/// The onboard LED, which shows the state of the client (see `LedStatus`)
+static LED_PIN: Mutex<RefCell<Option<LedPin>>> = Mutex::new(RefCell::new(None));
+static LED_STATUS: Mutex<RefCell<LedStatus>> = Mutex::new(RefCell::new(LedStatus::new()));
+static LED_PATTERN: Mutex<Cell<LedPattern>> = Mutex::new(Cell::new(LedPattern::SlowBlink));
+/// µs the timer interrupt has ticked for since the LED was last ticked
+static LED_DIVIDER_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+/// Ticks the LED while the timer interrupt isn't running
+static ALARM1: Mutex<RefCell<Option<Alarm1>>> = Mutex::new(RefCell::new(None));
 
 // These can be static mut because they're set once and only ever accessed in
 // the usb interrupt
@@ -62,42 +98,128 @@ static mut USB_DEVICE: Option<UsbDevice<hal::usb::UsbBus>> = None;
 static mut USB_BUS: Option<UsbBusAllocator<hal::usb::UsbBus>> = None;
 static mut USB_SERIAL: Option<SerialPort<hal::usb::UsbBus>> = None;
 
-// These can be static mut because they're set once and only ever accessed in
-// the timer interrupt
-static mut ALARM0: Option<Alarm0> = None;
-static mut SHIFT_REGISTER: Option<SN74HC595> = None;
-
 /* State */
 
 static CLIENT_STATE: Mutex<Cell<ClientState>> = Mutex::new(Cell::new(ClientState::WaitingForHello));
 
-const MAX_DRIVE_COUNT: usize = 8;
-
 type TrackMap = BTreeMap<u16, ChannelMap>;
-type ChannelMap = BTreeMap<u8, Vec<usize, MAX_DRIVE_COUNT>>;
+type ChannelMap = BTreeMap<u8, Vec<usize, MAX_PORT_COUNT>>;
 
 static TRACK_MAP: Mutex<RefCell<Option<TrackMap>>> = Mutex::new(RefCell::new(None));
 
-type FloppyDriveStack = Vec<FloppyDrive, MAX_DRIVE_COUNT>;
+static PARALLEL_MODE: Mutex<Cell<ParallelMode>> = Mutex::new(Cell::new(ParallelMode::Collapse));
 
-static FLOPPY_DRIVES: Mutex<RefCell<FloppyDriveStack>> = Mutex::new(RefCell::new(Vec::new()));
+/// Notes with a lower velocity than this are ignored
+static VELOCITY_THRESHOLD: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
 
-#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
-enum ClientState {
-    WaitingForHello,
-    WaitingForSetConfig,
-    PlayingMidiStream,
-}
+/// Whether note velocities scale the drive select duty cycle
+static VELOCITY_DYNAMICS: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Whether notes die away over a time set by their release velocity instead of stopping dead
+static RELEASE_RAMP: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Number of timer ticks a drive plays a note for before a new note on can replace it
+static MIN_NOTE_TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Whether to park the drive heads on the home track after the end of a song
+static PARK_ON_END: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static HOME_TRACK: Mutex<Cell<u8>> = Mutex::new(Cell::new(DEFAULT_HOME_TRACK));
+
+type ChannelStateMap = BTreeMap<(u16, u8), ChannelState>;
+
+static CHANNEL_STATES: Mutex<RefCell<ChannelStateMap>> = Mutex::new(RefCell::new(BTreeMap::new()));
+
+/// The instrument playing each port, by port number
+type Voices = Vec<Voice, MAX_PORT_COUNT>;
+
+static VOICES: Mutex<RefCell<Voices>> = Mutex::new(RefCell::new(Vec::new()));
+
+/// The number of drives in the stack, which the drives' places in the shift register chain depend on
+static DRIVE_COUNT: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+static NOTE_STACKS: Mutex<RefCell<Vec<NoteStack, MAX_PORT_COUNT>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Number of timer ticks without a message from the server before giving up on the connection (0
+/// to never give up)
+static LINK_TIMEOUT_TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static TICKS_SINCE_MESSAGE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Whether the server expects diagnostics when ending the session and MIDI events to be
+/// acknowledged with a status (negotiated in the hello handshake)
+static SERVER_EXTENDED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Number of timer ticks that overran the timer resolution since the hello, and the worst overrun
+static OVERRUNS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static MAX_OVERRUN_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Number of timer ticks that overran, the longest tick and the number of MIDI events played since
+/// the client started, for `StatusReport`
+static TOTAL_OVERRUNS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static LONGEST_TICK_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static EVENTS_PROCESSED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Number of timer ticks between switching chord notes in `ParallelMode::Synthesize`
+static SYNTHESIZE_INTERVAL_TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static SYNTHESIZE_TICK: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// How `reset_drives` homes the drive heads
+static RESET_PASSES: Mutex<Cell<u8>> = Mutex::new(Cell::new(DEFAULT_RESET_PASSES));
+static RESET_STEP_DELAY_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(DEFAULT_RESET_STEP_DELAY_US));
+static RESET_DWELL_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(DEFAULT_RESET_DWELL_MS));
+
+type TimedEventQueue = Deque<(u64, MidiEvent), MAX_TIMED_EVENTS>;
+
+/// Events waiting for the clock to reach their timestamp, oldest first
+static TIMED_EVENTS: Mutex<RefCell<TimedEventQueue>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// The timer counter (in µs) when the clock for timed events read zero, moved forward by the time
+/// spent paused so the clock stops while paused
+static CLOCK_BASE_US: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+static PAUSED_AT_US: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// How often the instruments are ticked in µs, as set by the last config
+static TIMER_RESOLUTION_US: Mutex<Cell<u64>> = Mutex::new(Cell::new(DEFAULT_TIMER_RESOLUTION_US));
+
+/// Number of timer ticks left before the test tone played while calibrating stops
+static TEST_TONE_TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
 
 #[entry]
 fn main() -> ! {
     defmt::info!("Floppier Client v{}", env!("CARGO_PKG_VERSION"));
 
+    let mut pac = pac::Peripherals::take().unwrap();
+    let sio = Sio::new(pac.SIO);
+
+    /* Let go of the drives before anything else */
+
+    // A reset leaves the shift registers latched on the last frame, and the output enable pin pulled
+    // down (enabled) until it's driven, so a drive could be left selected and stepping
+    let pins = hal::gpio::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    );
+
+    let output_enable = pins.gpio5.into_push_pull_output_in_state(PinState::High);
+
     init_heap();
 
-    let mut pac = pac::Peripherals::take().unwrap();
+    critical_section::with(|cs| {
+        LED_PIN
+            .borrow(cs)
+            .replace(Some(pins.gpio25.into_push_pull_output()))
+    });
+
+    if pac.WATCHDOG.reason().read().timer().bit_is_set() {
+        defmt::warn!("Reset by the watchdog, the last session stopped responding!");
+    } else {
+        defmt::info!("Not reset by the watchdog");
+    }
+
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
+    watchdog.pause_on_debug(true);
 
     let clocks = init_clocks_and_plls(
         rp_pico::XOSC_CRYSTAL_FREQ,
@@ -111,12 +233,12 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
+    critical_section::with(|cs| WATCHDOG.borrow(cs).replace(Some(watchdog)));
+
     /* Set up the timer */
 
     let mut timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
-    unsafe {
-        TIMER = Some(timer);
-    }
+    critical_section::with(|cs| TIMER.borrow(cs).set(Some(timer)));
 
     /* Set up the USB device */
 
@@ -132,16 +254,9 @@ fn main() -> ! {
 
     /* Set up the shift register */
 
-    let pins = hal::gpio::Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
-
     let (pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
 
-    let shift_register = SN74HC595::new(
+    let mut shift_register = SN74HC595::new(
         pio,
         sm0,
         (
@@ -149,12 +264,20 @@ fn main() -> ! {
             pins.gpio3.reconfigure(),
             pins.gpio4.reconfigure(),
         ),
-        pins.gpio5.reconfigure(),
+        output_enable,
     );
 
-    unsafe {
-        SHIFT_REGISTER = Some(shift_register);
-    }
+    // The tick copies each frame into the PIO with DMA rather than writing it word by word
+    let dma = pac.DMA.split(&mut pac.RESETS);
+    let frame = cortex_m::singleton!(: Frame = [0; 2]).unwrap();
+
+    shift_register.enable_dma(dma.ch0, frame);
+
+    critical_section::with(|cs| SHIFT_REGISTER.borrow(cs).replace(Some(shift_register)));
+
+    /* Bring up the PWM slices (the pins are connected to them by the config) */
+
+    hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS);
 
     /* Set up the tick alarm */
 
@@ -163,8 +286,19 @@ fn main() -> ! {
     alarm0.schedule(0u32.micros()).unwrap();
     alarm0.enable_interrupt();
 
+    critical_section::with(|cs| ALARM0.borrow(cs).replace(Some(alarm0)));
+
+    /* Set up the LED alarm */
+
+    let mut alarm1 = timer.alarm_1().unwrap();
+
+    alarm1.schedule(LED_TICK_US.micros()).unwrap();
+    alarm1.enable_interrupt();
+
+    critical_section::with(|cs| ALARM1.borrow(cs).replace(Some(alarm1)));
+
     unsafe {
-        ALARM0 = Some(alarm0);
+        pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_1);
     };
 
     /* Do nothing on the main thread */
@@ -249,43 +383,113 @@ unsafe fn USBCTRL_IRQ() {
     // If we get here, we have a USB event to handle
     update_read_buffer(serial);
 
-    // Check if we have received a full message
-    let Some(message) = get_received_message() else {
-        return;
-    };
+    // Handle every full message that has been received
+    while let Some(message) = get_received_message() {
+        match message {
+            Ok(message) => handle_message(serial, message),
+            // Ask the server to send the frame (and any dropped after it) again
+            Err(FrameError::Corrupted) => {
+                let _ = send_message(serial, FloppierC2SMessage::FrameError);
+            }
+            Err(FrameError::DeserializeFailed(detail)) => {
+                send_error(serial, FloppierErrorKind::DeserializeFailed, Some(detail));
+            }
+        }
+    }
+}
 
+/// Runs the client state machine for a message received from the server
+fn handle_message(serial: &mut SerialPort<hal::usb::UsbBus>, message: FloppierS2CMessage) {
     critical_section::with(|cs| {
+        TICKS_SINCE_MESSAGE.borrow(cs).set(0);
+
+        let state = CLIENT_STATE.borrow(cs).get();
+
+        if !is_expected(state, message.kind()) {
+            defmt::warn!("Discarding {} in state {}", message, state);
+
+            reject_packet(cs, serial, state, message.kind());
+            return;
+        }
+
         match message {
-            FloppierS2CMessage::Hello => {
-                if !is_state(ClientState::WaitingForHello) {
+            FloppierS2CMessage::Hello { proto_version } => {
+                if state != ClientState::WaitingForHello {
                     defmt::warn!("Resetting state due to new hello packet!");
 
                     pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
 
-                    let mut floppy_drives = FLOPPY_DRIVES.borrow(cs).borrow_mut();
+                    silence_drives(cs);
+                    clear_timed_events(cs);
+                } else if pac::NVIC::is_enabled(hal::pac::Interrupt::TIMER_IRQ_0) {
+                    defmt::info!("Stopping parking the drives for the new hello");
 
-                    for drive in floppy_drives.iter_mut() {
-                        drive.set_note(None);
-                    }
+                    // The timer only runs while waiting for a hello to park the drives after the
+                    // end of a song, so they're left where they've got to
+                    pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+                    silence_drives(cs);
+                    deselect_drives(cs);
+                }
+
+                // The server might not be the one we negotiated checksums with last time
+                set_checksum_frames(false);
+
+                if !is_compatible_version(proto_version) {
+                    defmt::warn!(
+                        "Rejecting server with protocol version {:#06x} (client is {:#06x})",
+                        proto_version,
+                        PROTO_VERSION
+                    );
+
+                    send_error(
+                        serial,
+                        FloppierErrorKind::IncompatibleVersion {
+                            server: proto_version,
+                            client: PROTO_VERSION,
+                        },
+                        None,
+                    );
+                    set_state(ClientState::WaitingForHello);
+                    return;
                 }
 
                 defmt::info!("Connected to server!");
 
-                let _ = send_message(serial, FloppierC2SMessage::HelloAck);
+                let _ = send_message(
+                    serial,
+                    FloppierC2SMessage::HelloAck {
+                        proto_version: PROTO_VERSION,
+                    },
+                );
+                set_checksum_frames(proto_version >= EXTENSIONS_VERSION);
+                SERVER_EXTENDED
+                    .borrow(cs)
+                    .set(proto_version >= EXTENSIONS_VERSION);
+                OVERRUNS.borrow(cs).set(0);
+                MAX_OVERRUN_US.borrow(cs).set(0);
                 set_state(ClientState::WaitingForSetConfig);
             }
             FloppierS2CMessage::SetConfig(config) => {
-                if !is_state(ClientState::WaitingForSetConfig) {
-                    let _ = send_message(
-                        serial,
-                        FloppierC2SMessage::Error("Unexpected set config packet!".to_string()),
-                    );
-                    panic!("Unexpected set config packet!");
-                }
+                /* Stop playing the previous song (if switching songs) */
+
+                pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+                // Resetting the drives can take longer than the watchdog waits
+                supervise(cs, false);
+
+                silence_drives(cs);
 
                 /* Set configuration */
 
-                set_config(config);
+                if let Err(kind) = set_config(config) {
+                    defmt::warn!("Rejecting config: {}", kind);
+
+                    // Wait for a config the server can fix and send again
+                    set_state(ClientState::WaitingForSetConfig);
+                    send_error(serial, kind, None);
+                    return;
+                }
 
                 defmt::info!("Configured successfully!");
 
@@ -295,8 +499,7 @@ unsafe fn USBCTRL_IRQ() {
 
                 defmt::info!("Resetting drives...");
 
-                let shift_register = unsafe { SHIFT_REGISTER.as_mut().unwrap() };
-                shift_register.set_output_enabled(true);
+                with_shift_register(cs, |shift_register| shift_register.set_output_enabled(true));
 
                 reset_drives();
 
@@ -305,97 +508,364 @@ unsafe fn USBCTRL_IRQ() {
                 defmt::info!("Drives reset!");
 
                 set_state(ClientState::PlayingMidiStream);
+                restart_clock(cs);
                 let _ = send_message(serial, FloppierC2SMessage::Ready);
 
-                pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_0);
+                unsafe {
+                    // Note (safety): The drive state is only shared through critical sections
+                    pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_0);
+                }
 
                 defmt::info!("Started timer interrupt!")
             }
             FloppierS2CMessage::MidiEvent(event) => {
-                if !is_state(ClientState::PlayingMidiStream) {
+                handle_midi_event(cs, event);
+
+                acknowledge_events(cs, serial, FloppierC2SMessage::MidiEventAck);
+            }
+            FloppierS2CMessage::MidiEventBatch(events) => {
+                for event in events {
+                    handle_midi_event(cs, event);
+                }
+
+                acknowledge_events(cs, serial, FloppierC2SMessage::MidiEventBatchAck);
+            }
+            FloppierS2CMessage::TimedMidiEventBatch {
+                timestamp_us,
+                events,
+            } => {
+                let queued = queue_timed_events(cs, timestamp_us, events);
+
+                acknowledge_events(
+                    cs,
+                    serial,
+                    FloppierC2SMessage::TimedMidiEventBatchAck { queued },
+                );
+            }
+            FloppierS2CMessage::Pause => {
+                pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+                silence_drives(cs);
+
+                // Deselect the drives since the timer won't be writing to them
+                deselect_drives(cs);
+
+                PAUSED_AT_US.borrow(cs).set(timer_us(cs));
+
+                set_state(ClientState::Paused);
+                let _ = send_message(serial, FloppierC2SMessage::PauseAck);
+
+                defmt::info!("Paused!");
+            }
+            FloppierS2CMessage::Resume => {
+                // Stop the clock for the time spent paused, so the queued events keep their timing
+                let clock_base = CLOCK_BASE_US.borrow(cs);
+                clock_base.set(clock_base.get() + (timer_us(cs) - PAUSED_AT_US.borrow(cs).get()));
+
+                set_state(ClientState::PlayingMidiStream);
+                let _ = send_message(serial, FloppierC2SMessage::ResumeAck);
+
+                unsafe {
+                    // Note (safety): The drive state is only shared through critical sections
+                    pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_0);
+                }
+
+                defmt::info!("Resumed!");
+            }
+            FloppierS2CMessage::End => {
+                pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+                silence_drives(cs);
+                clear_timed_events(cs);
+
+                with_shift_register(cs, |shift_register| shift_register.set_output_enabled(true));
+
+                if SERVER_EXTENDED.borrow(cs).get() {
                     let _ = send_message(
                         serial,
-                        FloppierC2SMessage::Error("Unexpected midi event packet!".to_string()),
+                        FloppierC2SMessage::Diagnostics {
+                            overruns: OVERRUNS.borrow(cs).get(),
+                            max_overrun_us: MAX_OVERRUN_US.borrow(cs).get(),
+                        },
                     );
-                    panic!("Unexpected midi event packet!");
                 }
 
-                let MidiEvent {
-                    track,
-                    channel,
-                    message,
-                } = event;
-
-                let track_map = TRACK_MAP.borrow(cs).borrow();
-                let track_map = track_map.as_ref().unwrap();
-                let mut floppy_drives = FLOPPY_DRIVES.borrow(cs).borrow_mut();
-
-                if let Some(drives) = track_map.get(&track).and_then(|track| track.get(&channel)) {
-                    match message {
-                        LimitedMidiMessage::NoteOn { note, velocity } => {
-                            for i in drives {
-                                if velocity > 0 {
-                                    floppy_drives[*i].set_note(Some(Note::try_from(note).unwrap()));
-                                } else {
-                                    floppy_drives[*i].set_note(None);
-                                }
-                            }
-                        }
-                        LimitedMidiMessage::NoteOff { .. } => {
-                            for i in drives {
-                                floppy_drives[*i].set_note(None)
-                            }
-                        }
-                        LimitedMidiMessage::ProgramChange { .. } => todo!(),
-                        LimitedMidiMessage::ControlChange { .. } => todo!(),
-                        LimitedMidiMessage::PitchBend { .. } => todo!(),
+                let _ = send_message(serial, FloppierC2SMessage::EndAck);
+                set_state(ClientState::WaitingForHello);
+
+                if PARK_ON_END.borrow(cs).get() && park_drives(cs) {
+                    unsafe {
+                        // Note (safety): The drive state is only shared through critical sections
+                        pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_0);
                     }
-                } else {
-                    defmt::warn!(
-                        "No drives found for track {} and channel {}",
-                        track,
-                        channel
-                    );
+
+                    defmt::info!("Parking the drives...");
                 }
+            }
+            FloppierS2CMessage::GetStatus => {
+                let report = StatusReport {
+                    uptime_us: timer_us(cs),
+                    tick_overruns: TOTAL_OVERRUNS.borrow(cs).get(),
+                    max_tick_us: LONGEST_TICK_US.borrow(cs).get(),
+                    heap_free: HEAP.free() as u32,
+                    state,
+                    events_processed: EVENTS_PROCESSED.borrow(cs).get(),
+                };
+
+                let _ = send_message(serial, FloppierC2SMessage::StatusReport(report));
+            }
+            FloppierS2CMessage::Ping(cookie) => {
+                let _ = send_message(serial, FloppierC2SMessage::Pong(cookie));
+            }
+            FloppierS2CMessage::AllNotesOff => {
+                silence_drives(cs);
+
+                let _ = send_message(serial, FloppierC2SMessage::AllNotesOffAck);
 
-                let _ = send_message(serial, FloppierC2SMessage::MidiEventAck);
+                defmt::info!("Silenced all drives!");
             }
-            FloppierS2CMessage::End => {
-                if !is_state(ClientState::PlayingMidiStream) {
-                    let _ = send_message(
+            FloppierS2CMessage::TestDrive {
+                index,
+                note,
+                duration_ms,
+                drive_count,
+            } => {
+                if drive_count as usize > MAX_DRIVE_COUNT || index >= drive_count {
+                    defmt::warn!("Rejecting test of drive {} of {}", index, drive_count);
+
+                    send_error(
                         serial,
-                        FloppierC2SMessage::Error("Unexpected end packet!".to_string()),
+                        FloppierErrorKind::DriveIndexOutOfRange {
+                            index,
+                            count: drive_count,
+                        },
+                        None,
                     );
-                    panic!("Unexpected end packet!");
+                    return;
                 }
 
                 pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
 
-                let mut floppy_drives = FLOPPY_DRIVES.borrow(cs).borrow_mut();
+                start_test_tone(cs, index as usize, note, duration_ms, drive_count);
+
+                with_shift_register(cs, |shift_register| shift_register.set_output_enabled(true));
+
+                set_state(ClientState::Calibrating);
+                let _ = send_message(serial, FloppierC2SMessage::TestDriveAck);
 
-                for drive in floppy_drives.iter_mut() {
-                    drive.set_note(None);
+                unsafe {
+                    // Note (safety): The drive state is only shared through critical sections
+                    pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_0);
                 }
 
-                let shift_register = unsafe { SHIFT_REGISTER.as_mut().unwrap() };
-                shift_register.set_output_enabled(true);
+                defmt::info!("Testing drive {} with note {}", index, note);
+            }
+            FloppierS2CMessage::ResetDrives => {
+                defmt::info!("Resetting drives...");
 
-                let _ = send_message(serial, FloppierC2SMessage::EndAck);
-                set_state(ClientState::WaitingForHello);
+                pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+                supervise(cs, false);
+
+                silence_drives(cs);
+                reset_drives();
+
+                let _ = send_message(serial, FloppierC2SMessage::ResetDrivesAck);
+
+                supervise(cs, true);
+
+                unsafe {
+                    // Note (safety): The drive state is only shared through critical sections
+                    pac::NVIC::unmask(hal::pac::Interrupt::TIMER_IRQ_0);
+                }
+
+                defmt::info!("Drives reset!");
             }
+            // Taken care of while reading frames, since it only marks where frames start again
+            FloppierS2CMessage::Retransmit => {}
         }
     });
 }
 
-fn is_state(state: ClientState) -> bool {
-    critical_section::with(|cs| CLIENT_STATE.borrow(cs).get() == state)
+/// Acknowledges MIDI events with `ack`, or with how much room is left if the server wants a status
+/// instead
+fn acknowledge_events(
+    cs: CriticalSection,
+    serial: &mut SerialPort<hal::usb::UsbBus>,
+    ack: FloppierC2SMessage,
+) {
+    let message = if SERVER_EXTENDED.borrow(cs).get() {
+        let queued = TIMED_EVENTS.borrow(cs).borrow().len();
+
+        FloppierC2SMessage::Status {
+            queue_free: (MAX_TIMED_EVENTS - queued) as u16,
+            buffer_free: read_buffer_free().min(u16::MAX as usize) as u16,
+        }
+    } else {
+        ack
+    };
+
+    let _ = send_message(serial, message);
+}
+
+/// Reports an unexpected packet to the server and goes back to waiting for a hello packet
+///
+/// This leaves the client in a state the server can re-handshake with instead of needing a power
+/// cycle to recover.
+fn reject_packet(
+    cs: CriticalSection,
+    serial: &mut SerialPort<hal::usb::UsbBus>,
+    state: ClientState,
+    got: FloppierS2CMessageKind,
+) {
+    send_error(
+        serial,
+        FloppierErrorKind::UnexpectedMessage { state, got },
+        None,
+    );
+
+    // The timer only runs while waiting for a hello to park the drives after the end of a song
+    let parking = state == ClientState::WaitingForHello
+        && pac::NVIC::is_enabled(hal::pac::Interrupt::TIMER_IRQ_0);
+
+    pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+    silence_drives(cs);
+    clear_timed_events(cs);
+
+    if parking {
+        // The last frame the timer wrote still has the drives selected
+        deselect_drives(cs);
+    }
+
+    // Anything sent after the bad packet was sent for the wrong state too
+    clear_read_buffer();
+
+    set_state(ClientState::WaitingForHello);
+}
+
+/// Reports an error to the server, blinking the LED so it can be seen without a debug probe
+fn send_error(
+    serial: &mut SerialPort<hal::usb::UsbBus>,
+    kind: FloppierErrorKind,
+    detail: Option<String>,
+) {
+    critical_section::with(|cs| LED_STATUS.borrow(cs).borrow_mut().blink_error());
+
+    let _ = send_message(serial, FloppierC2SMessage::Error { kind, detail });
+}
+
+/// Whether a message of the given kind can be handled in the given state
+///
+/// A hello can always be handled since it resets the client, and so can pings, all notes off,
+/// status queries and retransmit markers since they don't affect the state.
+fn is_expected(state: ClientState, kind: FloppierS2CMessageKind) -> bool {
+    match kind {
+        FloppierS2CMessageKind::Hello
+        | FloppierS2CMessageKind::Ping
+        | FloppierS2CMessageKind::AllNotesOff
+        | FloppierS2CMessageKind::GetStatus
+        | FloppierS2CMessageKind::Retransmit => true,
+        // A config sent while playing switches to a new song
+        FloppierS2CMessageKind::SetConfig => matches!(
+            state,
+            ClientState::WaitingForSetConfig
+                | ClientState::PlayingMidiStream
+                | ClientState::Calibrating
+        ),
+        FloppierS2CMessageKind::TestDrive => matches!(
+            state,
+            ClientState::WaitingForSetConfig | ClientState::Calibrating
+        ),
+        FloppierS2CMessageKind::MidiEvent
+        | FloppierS2CMessageKind::MidiEventBatch
+        | FloppierS2CMessageKind::TimedMidiEventBatch
+        | FloppierS2CMessageKind::Pause
+        | FloppierS2CMessageKind::ResetDrives => state == ClientState::PlayingMidiStream,
+        FloppierS2CMessageKind::Resume => state == ClientState::Paused,
+        FloppierS2CMessageKind::End => matches!(
+            state,
+            ClientState::PlayingMidiStream | ClientState::Paused | ClientState::Calibrating
+        ),
+    }
 }
 
 fn set_state(state: ClientState) {
-    critical_section::with(|cs| CLIENT_STATE.borrow(cs).set(state))
+    critical_section::with(|cs| {
+        CLIENT_STATE.borrow(cs).set(state);
+
+        let pattern = match state {
+            ClientState::WaitingForHello => LedPattern::SlowBlink,
+            ClientState::WaitingForSetConfig | ClientState::Paused => LedPattern::Solid,
+            ClientState::PlayingMidiStream | ClientState::Calibrating => LedPattern::Heartbeat,
+        };
+        LED_PATTERN.borrow(cs).set(pattern);
+
+        supervise(cs, state == ClientState::PlayingMidiStream);
+    })
+}
+
+/// Arms the watchdog, which the timer interrupt feeds, or disarms it
+///
+/// It's only armed while playing, since the timer interrupt isn't running (or doesn't need to be)
+/// in the other states. If an interrupt wedges while playing, the client resets, silencing the
+/// drives and waiting for a new hello.
+fn supervise(cs: CriticalSection, armed: bool) {
+    let mut watchdog = WATCHDOG.borrow(cs).borrow_mut();
+    let Some(watchdog) = watchdog.as_mut() else {
+        return;
+    };
+
+    if armed {
+        watchdog.start(WATCHDOG_TIMEOUT_US.micros());
+    } else {
+        watchdog.disable();
+    }
 }
 
-fn set_config(config: SetConfig) {
+fn set_config(config: SetConfig) -> Result<(), FloppierErrorKind> {
+    let drive_count = config.drive_count;
+
+    if drive_count as usize > MAX_DRIVE_COUNT {
+        return Err(FloppierErrorKind::DriveIndexOutOfRange {
+            index: drive_count - 1,
+            count: MAX_DRIVE_COUNT as u8,
+        });
+    }
+
+    let ports: Vec<PortKind, MAX_PORT_COUNT> = if config.ports.is_empty() {
+        Vec::from_iter((0..drive_count).map(PortKind::Drive))
+    } else {
+        Vec::from_slice(&config.ports).map_err(|_| FloppierErrorKind::DriveIndexOutOfRange {
+            index: MAX_PORT_COUNT as u8,
+            count: MAX_PORT_COUNT as u8,
+        })?
+    };
+
+    for port in &ports {
+        if let PortKind::Drive(index) | PortKind::Stepper { index, .. } = *port {
+            if index >= drive_count {
+                return Err(FloppierErrorKind::DriveIndexOutOfRange {
+                    index,
+                    count: drive_count,
+                });
+            }
+        }
+    }
+
+    check_pwm_gpios(ports.iter().filter_map(|port| match port {
+        PortKind::Drive(_) | PortKind::Stepper { .. } => None,
+        PortKind::Pwm { gpio } => Some(*gpio),
+    }))
+    .map_err(|gpio| FloppierErrorKind::InvalidPwmPort { gpio })?;
+
+    let timer_resolution_us = match config.tick_us {
+        None => DEFAULT_TIMER_RESOLUTION_US,
+        Some(tick_us @ MIN_TICK_US..=MAX_TICK_US) => tick_us as u64,
+        Some(tick_us) => return Err(FloppierErrorKind::InvalidTickInterval { tick_us }),
+    };
+
+    let port_count = ports.len() as u8;
+
     let track_map = config
         .tracks
         .into_iter()
@@ -403,37 +873,529 @@ fn set_config(config: SetConfig) {
             let channels = track
                 .into_iter()
                 .map(|(channel_number, drives)| {
-                    (
-                        channel_number,
-                        Vec::from_iter(drives.into_iter().map(|drive_index| {
-                            assert!(
-                                drive_index < config.drive_count,
-                                "Supplied drive index exceeded drive count!"
-                            );
-
-                            drive_index as usize
-                        })),
-                    )
+                    let drives = drives
+                        .into_iter()
+                        .map(|index| {
+                            if index < port_count {
+                                Ok(index as usize)
+                            } else {
+                                Err(FloppierErrorKind::DriveIndexOutOfRange {
+                                    index,
+                                    count: port_count,
+                                })
+                            }
+                        })
+                        .collect::<Result<_, _>>()?;
+
+                    Ok((channel_number, drives))
                 })
-                .collect::<ChannelMap>();
+                .collect::<Result<ChannelMap, _>>()?;
 
-            (track_number, channels)
+            Ok((track_number, channels))
         })
-        .collect::<TrackMap>();
+        .collect::<Result<TrackMap, _>>()?;
+
+    let channel_states = track_map
+        .iter()
+        .flat_map(|(track_number, channels)| {
+            channels.iter().map(|(channel_number, drives)| {
+                (
+                    (*track_number, *channel_number),
+                    ChannelState::new(drives, config.steal_oldest),
+                )
+            })
+        })
+        .collect::<ChannelStateMap>();
+
+    let mut voices: Voices = Vec::from_iter(ports.iter().map(|port| match *port {
+        PortKind::Drive(index) => {
+            let movement = config.drive_movement.get(index as usize);
+
+            Voice::Drive {
+                index,
+                drive: FloppyDrive::new(
+                    movement.copied().unwrap_or(config.movement),
+                    timer_resolution_us,
+                ),
+            }
+        }
+        PortKind::Stepper {
+            index,
+            steps_per_toggle,
+        } => Voice::Stepper {
+            index,
+            stepper: StepperInstrument::new(steps_per_toggle, timer_resolution_us),
+        },
+        PortKind::Pwm { gpio } => {
+            connect_pwm_pin(gpio);
+
+            Voice::Pwm(PwmInstrument::new(gpio))
+        }
+    }));
+
+    for (voice, cents) in voices.iter_mut().zip(&config.detune_cents) {
+        voice.set_detune(*cents);
+    }
+
+    let note_stacks = Vec::from_iter(ports.iter().map(|_| NoteStack::new()));
+
+    let synthesize_interval_ticks = config
+        .synthesize_interval_us
+        .unwrap_or(DEFAULT_SYNTHESIZE_INTERVAL_US)
+        / timer_resolution_us as u32;
+
+    let min_note_ticks = config.min_note_us / timer_resolution_us as u32;
 
-    let floppy_drives: FloppyDriveStack =
-        Vec::from_iter((0..config.drive_count).map(|_| FloppyDrive::new(config.movement)));
+    let link_timeout_ticks = config
+        .link_timeout_ms
+        .map(|ms| (ms as u64 * 1000 / timer_resolution_us).min(u32::MAX as u64) as u32);
 
     critical_section::with(|cs| {
         TRACK_MAP.borrow(cs).replace(Some(track_map));
-        *FLOPPY_DRIVES.borrow(cs).borrow_mut() = floppy_drives;
+        PARALLEL_MODE.borrow(cs).set(config.parallel_mode);
+        VELOCITY_THRESHOLD.borrow(cs).set(config.velocity_threshold);
+        VELOCITY_DYNAMICS.borrow(cs).set(config.velocity_dynamics);
+        RELEASE_RAMP.borrow(cs).set(config.release_ramp);
+        PARK_ON_END.borrow(cs).set(config.park_on_end);
+        HOME_TRACK
+            .borrow(cs)
+            .set(config.home_track.unwrap_or(DEFAULT_HOME_TRACK));
+        MIN_NOTE_TICKS.borrow(cs).set(min_note_ticks);
+        *CHANNEL_STATES.borrow(cs).borrow_mut() = channel_states;
+        *VOICES.borrow(cs).borrow_mut() = voices;
+        DRIVE_COUNT.borrow(cs).set(drive_count);
+        *NOTE_STACKS.borrow(cs).borrow_mut() = note_stacks;
+        TIMER_RESOLUTION_US.borrow(cs).set(timer_resolution_us);
+        SYNTHESIZE_INTERVAL_TICKS
+            .borrow(cs)
+            .set(synthesize_interval_ticks.max(1));
+        SYNTHESIZE_TICK.borrow(cs).set(0);
+        LINK_TIMEOUT_TICKS
+            .borrow(cs)
+            .set(link_timeout_ticks.unwrap_or(0));
+        TICKS_SINCE_MESSAGE.borrow(cs).set(0);
+        RESET_PASSES
+            .borrow(cs)
+            .set(config.reset.passes.unwrap_or(DEFAULT_RESET_PASSES));
+        RESET_STEP_DELAY_US.borrow(cs).set(
+            config
+                .reset
+                .step_delay_us
+                .unwrap_or(DEFAULT_RESET_STEP_DELAY_US),
+        );
+        RESET_DWELL_MS
+            .borrow(cs)
+            .set(config.reset.dwell_ms.unwrap_or(DEFAULT_RESET_DWELL_MS));
     });
+
+    Ok(())
+}
+
+/// Runs `f` with the shift register the drives are connected through
+fn with_shift_register<R>(cs: CriticalSection, f: impl FnOnce(&mut SN74HC595) -> R) -> R {
+    f(SHIFT_REGISTER.borrow(cs).borrow_mut().as_mut().unwrap())
+}
+
+/// Deselects every drive
+fn deselect_drives(cs: CriticalSection) {
+    with_shift_register(cs, |shift_register| {
+        shift_register.write_bytes(&[DriveState::default().into(); MAX_DRIVE_COUNT])
+    });
+}
+
+/// Connects a GPIO pin to its PWM slice, which keeps it low until a note is played
+fn connect_pwm_pin(gpio: u8) {
+    write_pwm(gpio, PwmSetting::SILENT);
+
+    // Note (safety): The pins used for PWM aren't used by anything else (see `check_pwm_gpios`)
+    let io_bank0 = unsafe { &*pac::IO_BANK0::ptr() };
+
+    io_bank0
+        .gpio(gpio as usize)
+        .gpio_ctrl()
+        .write(|w| w.funcsel().pwm());
+}
+
+/// Programs the PWM slice of a GPIO pin
+fn write_pwm(gpio: u8, setting: PwmSetting) {
+    // Note (safety): Each slice belongs to a single PWM voice, and is only written to inside
+    // critical sections
+    let pwm = unsafe { &*pac::PWM::ptr() };
+    let slice = pwm.ch(pwm_slice(gpio));
+
+    slice.div().write(|w| unsafe {
+        w.int()
+            .bits((setting.divider >> 4) as u8)
+            .frac()
+            .bits((setting.divider & 0xf) as u8)
+    });
+    slice.top().write(|w| unsafe { w.top().bits(setting.top) });
+
+    if is_pwm_channel_b(gpio) {
+        slice
+            .cc()
+            .modify(|_, w| unsafe { w.b().bits(setting.level) });
+    } else {
+        slice
+            .cc()
+            .modify(|_, w| unsafe { w.a().bits(setting.level) });
+    }
+
+    slice.csr().write(|w| w.en().set_bit());
+}
+
+fn silence_drives(cs: CriticalSection) {
+    for voice in VOICES.borrow(cs).borrow_mut().iter_mut() {
+        voice.set_note(None);
+
+        // The slice keeps playing by itself while the timer is stopped
+        if let Voice::Pwm(pwm) = voice {
+            write_pwm(pwm.gpio(), PwmSetting::SILENT);
+        }
+    }
+
+    for channel_state in CHANNEL_STATES.borrow(cs).borrow_mut().values_mut() {
+        channel_state.release_all();
+    }
+
+    for stack in NOTE_STACKS.borrow(cs).borrow_mut().iter_mut() {
+        stack.clear();
+    }
+}
+
+/// Starts stepping the drive heads back to the home track, returning whether there are any drives
+/// to park
+fn park_drives(cs: CriticalSection) -> bool {
+    let home_track = HOME_TRACK.borrow(cs).get();
+    let mut parking = false;
+
+    for voice in VOICES.borrow(cs).borrow_mut().iter_mut() {
+        if let Voice::Drive { drive, .. } = voice {
+            drive.park(home_track);
+            parking = true;
+        }
+    }
+
+    parking
+}
+
+fn timer(cs: CriticalSection) -> Timer {
+    TIMER.borrow(cs).get().unwrap()
+}
+
+/// The timer counter in microseconds
+fn timer_us(cs: CriticalSection) -> u64 {
+    timer(cs).get_counter().ticks()
+}
+
+/// The time on the clock timed events are played by, which started when the client was last ready
+/// and doesn't count the time spent paused
+fn clock_us(cs: CriticalSection) -> u64 {
+    timer_us(cs).saturating_sub(CLOCK_BASE_US.borrow(cs).get())
+}
+
+/// Starts the clock for timed events again from zero, forgetting any that are still queued
+fn restart_clock(cs: CriticalSection) {
+    CLOCK_BASE_US.borrow(cs).set(timer_us(cs));
+
+    clear_timed_events(cs);
+}
+
+fn clear_timed_events(cs: CriticalSection) {
+    TIMED_EVENTS.borrow(cs).borrow_mut().clear();
+}
+
+/// Queues events to be played once the clock reaches `timestamp_us`, returning how many events
+/// are queued
+fn queue_timed_events(
+    cs: CriticalSection,
+    timestamp_us: u64,
+    events: impl IntoIterator<Item = MidiEvent>,
+) -> u16 {
+    let mut timed_events = TIMED_EVENTS.borrow(cs).borrow_mut();
+
+    for event in events {
+        // Playing the oldest event early is better than losing one
+        if timed_events.is_full() {
+            defmt::warn!("Timed event queue is full, playing an event early!");
+
+            if let Some((_, event)) = timed_events.pop_front() {
+                handle_midi_event(cs, event);
+            }
+        }
+
+        let _ = timed_events.push_back((timestamp_us, event));
+    }
+
+    timed_events.len() as u16
+}
+
+/// Plays the queued events whose time has come
+fn dispatch_timed_events(cs: CriticalSection) {
+    let now = clock_us(cs);
+    let mut timed_events = TIMED_EVENTS.borrow(cs).borrow_mut();
+
+    while let Some(&(timestamp_us, event)) = timed_events.front() {
+        if timestamp_us > now {
+            break;
+        }
+
+        timed_events.pop_front();
+        handle_midi_event(cs, event);
+    }
+}
+
+fn handle_midi_event(cs: CriticalSection, event: MidiEvent) {
+    LED_STATUS.borrow(cs).borrow_mut().flash();
+
+    let events_processed = EVENTS_PROCESSED.borrow(cs);
+    events_processed.set(events_processed.get().wrapping_add(1));
+
+    let MidiEvent {
+        track,
+        channel,
+        message,
+    } = event;
+
+    let track_map = TRACK_MAP.borrow(cs).borrow();
+    let track_map = track_map.as_ref().unwrap();
+    let mut voices = VOICES.borrow(cs).borrow_mut();
+    let mut note_stacks = NOTE_STACKS.borrow(cs).borrow_mut();
+
+    let Some(drives) = track_map.get(&track).and_then(|track| track.get(&channel)) else {
+        defmt::warn!(
+            "No drives found for track {} and channel {}",
+            track,
+            channel
+        );
+        return;
+    };
+
+    let parallel_mode = PARALLEL_MODE.borrow(cs).get();
+    let mut channel_states = CHANNEL_STATES.borrow(cs).borrow_mut();
+    let channel_state = channel_states.get_mut(&(track, channel)).unwrap();
+
+    match message {
+        LimitedMidiMessage::NoteOn { note, velocity } if velocity > 0 => {
+            if !channel_state.is_melodic() {
+                defmt::debug!(
+                    "Ignoring note on track {} and channel {} with non-melodic program {}",
+                    track,
+                    channel,
+                    channel_state.program
+                );
+                return;
+            }
+
+            if !channel_state.is_audible() {
+                defmt::debug!(
+                    "Ignoring note on track {} and channel {} with volume {}",
+                    track,
+                    channel,
+                    channel_state.volume
+                );
+                return;
+            }
+
+            if velocity < VELOCITY_THRESHOLD.borrow(cs).get() {
+                defmt::debug!(
+                    "Ignoring note on track {} and channel {} with velocity {}",
+                    track,
+                    channel,
+                    velocity
+                );
+                return;
+            }
+
+            let note = Note::try_from(note).unwrap();
+            let velocity_dynamics = VELOCITY_DYNAMICS.borrow(cs).get();
+            let min_note_ticks = MIN_NOTE_TICKS.borrow(cs).get();
+
+            channel_state.cancel_release(note);
+
+            let playing_drives: Vec<usize, MAX_PORT_COUNT> = match parallel_mode {
+                ParallelMode::Distribute => channel_state
+                    .allocator
+                    .note_on(note, &mut note_stacks)
+                    .into_iter()
+                    .collect(),
+                ParallelMode::Collapse | ParallelMode::Synthesize => drives.clone(),
+            };
+
+            for i in playing_drives {
+                note_stacks[i].push(note);
+
+                // A drive that only just changed note keeps it, falling back to this one if it's
+                // still held once the current note is released
+                if !voices[i].can_retrigger(min_note_ticks) {
+                    continue;
+                }
+
+                if velocity_dynamics {
+                    voices[i].set_velocity(velocity);
+                }
+
+                voices[i].set_note(Some(note));
+            }
+        }
+        LimitedMidiMessage::NoteOn { note, .. } | LimitedMidiMessage::NoteOff { note, .. } => {
+            let note = Note::try_from(note).unwrap();
+
+            if channel_state.defer_release(note) {
+                return;
+            }
+
+            // A note on with no velocity has no release velocity, so it cuts the note off
+            let release_velocity = match message {
+                LimitedMidiMessage::NoteOff { velocity, .. } if RELEASE_RAMP.borrow(cs).get() => {
+                    velocity
+                }
+                _ => 0,
+            };
+
+            release_note(
+                drives,
+                &mut note_stacks,
+                &mut voices,
+                note,
+                release_velocity,
+            );
+        }
+        LimitedMidiMessage::ProgramChange { program } => {
+            channel_state.program = program;
+
+            for i in drives {
+                voices[*i].set_program(program);
+            }
+        }
+        LimitedMidiMessage::ControlChange { control, value } => match control {
+            CONTROL_VOLUME => {
+                channel_state.volume = value;
+
+                if !channel_state.is_audible() {
+                    silence_channel(channel_state, drives, &mut note_stacks, &mut voices);
+                }
+            }
+            CONTROL_SUSTAIN if value >= PEDAL_ON_VALUE => channel_state.sustain = true,
+            CONTROL_SUSTAIN => {
+                for note in channel_state.lift_sustain() {
+                    release_note(drives, &mut note_stacks, &mut voices, note, 0);
+                }
+            }
+            CONTROL_ALL_SOUND_OFF | CONTROL_ALL_NOTES_OFF => {
+                silence_channel(channel_state, drives, &mut note_stacks, &mut voices);
+            }
+            _ => defmt::warn!(
+                "Ignoring unsupported control change {} (value {}) on track {} and channel {}",
+                control,
+                value,
+                track,
+                channel
+            ),
+        },
+        LimitedMidiMessage::PitchBend { value } => {
+            let semitones = pitch_bend_to_semitones(value);
+
+            for i in drives {
+                voices[*i].set_pitch_bend(semitones);
+            }
+        }
+    }
+}
+
+/// Releases a note on the drives of a channel, falling back to the next held note on each drive
+///
+/// Drives left with nothing to play let the note die away over a time set by `release_velocity`
+/// (0 stops them straight away).
+fn release_note(
+    drives: &[usize],
+    note_stacks: &mut [NoteStack],
+    voices: &mut [Voice],
+    note: Note,
+    release_velocity: u8,
+) {
+    for i in drives {
+        let stack = &mut note_stacks[*i];
+        let drive = &mut voices[*i];
+
+        if !stack.contains(note) {
+            continue;
+        }
+
+        stack.remove(note);
+
+        // Only retune the drive if the sounding note was released
+        if !drive.note().is_some_and(|note| stack.contains(note)) {
+            match stack.top() {
+                Some(top) => drive.set_note(Some(top)),
+                None => drive.release(release_velocity),
+            }
+        }
+    }
+}
+
+/// Releases every note on the drives of a channel
+fn silence_channel(
+    channel_state: &mut ChannelState,
+    drives: &[usize],
+    note_stacks: &mut [NoteStack],
+    voices: &mut [Voice],
+) {
+    channel_state.release_all();
+
+    for i in drives {
+        note_stacks[*i].clear();
+        voices[*i].set_note(None);
+    }
+}
+
+/// Plays a note on one drive of a stack of `drive_count` drives (replacing any drives from a config)
+/// until `duration_ms` has passed
+fn start_test_tone(cs: CriticalSection, index: usize, note: u8, duration_ms: u16, drive_count: u8) {
+    let timer_resolution_us = TIMER_RESOLUTION_US.borrow(cs).get();
+
+    let mut voices: Voices = Vec::from_iter((0..drive_count).map(|index| Voice::Drive {
+        index,
+        drive: FloppyDrive::new(false, timer_resolution_us),
+    }));
+
+    match Note::try_from(note) {
+        Ok(note) => voices[index].set_note(Some(note)),
+        Err(_) => defmt::warn!("Can't play test note {}", note),
+    }
+
+    *VOICES.borrow(cs).borrow_mut() = voices;
+    DRIVE_COUNT.borrow(cs).set(drive_count);
+
+    // The note stacks and timed events belong to the drives of the last config
+    NOTE_STACKS.borrow(cs).borrow_mut().clear();
+    clear_timed_events(cs);
+
+    let ticks = duration_ms as u64 * 1000 / timer_resolution_us;
+    TEST_TONE_TICKS.borrow(cs).set(ticks as u32);
+}
+
+/// Counts down a timer tick of the test tone, returning whether it has just finished
+fn test_tone_finished(cs: CriticalSection) -> bool {
+    let ticks = TEST_TONE_TICKS.borrow(cs);
+
+    match ticks.get() {
+        0 => false,
+        remaining => {
+            ticks.set(remaining - 1);
+            remaining == 1
+        }
+    }
 }
 
 fn reset_drives() {
-    critical_section::with(|_| {
-        let mut timer = unsafe { TIMER }.unwrap();
-        let shift_register = unsafe { SHIFT_REGISTER.as_mut().unwrap() };
+    critical_section::with(|cs| {
+        let mut timer = timer(cs);
+        let mut shift_register = SHIFT_REGISTER.borrow(cs).borrow_mut();
+        let shift_register = shift_register.as_mut().unwrap();
+
+        let step_delay_us = RESET_STEP_DELAY_US.borrow(cs).get();
+        let dwell_ms = RESET_DWELL_MS.borrow(cs).get();
 
         let mut state = DriveState {
             drive_select: true,
@@ -441,15 +1403,15 @@ fn reset_drives() {
             direction: Direction::Reverse,
         };
 
-        for _ in 0..3 {
+        for _ in 0..RESET_PASSES.borrow(cs).get() {
             for _ in 0..FloppyDrive::NUM_TRACKS {
                 state.step = true;
                 shift_register.write_byte_to_all(state.into());
-                timer.delay_ms(3);
+                timer.delay_us(step_delay_us);
 
                 state.step = false;
                 shift_register.write_byte_to_all(state.into());
-                timer.delay_ms(3);
+                timer.delay_us(step_delay_us);
             }
 
             state.direction = match state.direction {
@@ -457,32 +1419,139 @@ fn reset_drives() {
                 Direction::Reverse => Direction::Forward,
             };
 
-            timer.delay_ms(200);
+            timer.delay_ms(dwell_ms);
         }
     })
 }
 
+/// Counts a timer tick without a message from the server, returning whether the link timeout has
+/// run out
+fn link_timed_out(cs: CriticalSection) -> bool {
+    let ticks = TICKS_SINCE_MESSAGE.borrow(cs).get().saturating_add(1);
+    TICKS_SINCE_MESSAGE.borrow(cs).set(ticks);
+
+    let timeout = LINK_TIMEOUT_TICKS.borrow(cs).get();
+
+    timeout != 0 && ticks >= timeout
+}
+
+/// Switches every drive holding a chord to the next note of the chord once the synthesize
+/// interval has elapsed
+fn cycle_chords(cs: CriticalSection, voices: &mut Voices) {
+    let synthesize_tick = SYNTHESIZE_TICK.borrow(cs);
+    synthesize_tick.set(synthesize_tick.get() + 1);
+
+    if synthesize_tick.get() < SYNTHESIZE_INTERVAL_TICKS.borrow(cs).get() {
+        return;
+    }
+
+    synthesize_tick.set(0);
+
+    let note_stacks = NOTE_STACKS.borrow(cs).borrow();
+
+    for (voice, stack) in voices.iter_mut().zip(note_stacks.iter()) {
+        if stack.len() > 1 {
+            let note = stack.next_after(voice.note());
+            voice.set_note(note);
+        }
+    }
+}
+
 #[interrupt]
 fn TIMER_IRQ_0() {
-    let alarm = unsafe { ALARM0.as_mut().unwrap() };
-    let timer = unsafe { TIMER }.unwrap();
+    let timer = critical_section::with(timer);
 
     let start_time = timer.get_counter();
 
     critical_section::with(|cs| {
-        /* Tick all the drives and write their values to the shift registers */
+        if let Some(watchdog) = WATCHDOG.borrow(cs).borrow().as_ref() {
+            watchdog.feed();
+        }
 
-        let mut floppy_drives = FLOPPY_DRIVES.borrow(cs).borrow_mut();
-        let shift_register = unsafe { SHIFT_REGISTER.as_mut().unwrap() };
+        // The timer only runs while waiting for a hello to park the drives
+        let parking = CLIENT_STATE.borrow(cs).get() == ClientState::WaitingForHello;
+
+        /* Give up on the connection if the server has gone quiet */
+
+        if !parking && link_timed_out(cs) {
+            defmt::warn!("Nothing received from the server within the link timeout, resetting!");
+
+            // The alarm is left pending so the timer starts again as soon as it is unmasked
+            pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+            silence_drives(cs);
+            clear_timed_events(cs);
+
+            deselect_drives(cs);
+
+            // Any partial frame was cut off with the connection
+            clear_read_buffer();
+
+            set_state(ClientState::WaitingForHello);
+            return;
+        }
+
+        /* Play the timed events that are due */
+
+        dispatch_timed_events(cs);
+
+        /* Stop the test tone once it has played for long enough */
+
+        if CLIENT_STATE.borrow(cs).get() == ClientState::Calibrating && test_tone_finished(cs) {
+            silence_drives(cs);
+        }
+
+        /* Tick the LED every `LED_TICK_US` */
+
+        let led_divider = LED_DIVIDER_US.borrow(cs);
+        led_divider.set(led_divider.get() + TIMER_RESOLUTION_US.borrow(cs).get() as u32);
+
+        if led_divider.get() >= LED_TICK_US {
+            led_divider.set(led_divider.get() - LED_TICK_US);
+            tick_led(cs);
+        }
+
+        /* Tick all the instruments, writing the drives' values to the shift registers */
+
+        let mut voices = VOICES.borrow(cs).borrow_mut();
+
+        if PARALLEL_MODE.borrow(cs).get() == ParallelMode::Synthesize {
+            cycle_chords(cs, &mut voices);
+        }
 
         let mut data = [DriveState::default().into(); MAX_DRIVE_COUNT];
-        let start_idx = MAX_DRIVE_COUNT - floppy_drives.len();
+        let start_idx = MAX_DRIVE_COUNT - DRIVE_COUNT.borrow(cs).get() as usize;
 
-        for (i, drive) in floppy_drives.iter_mut().enumerate() {
-            data[start_idx + i] = drive.tick().into();
+        for voice in voices.iter_mut() {
+            match voice {
+                Voice::Drive { index, drive } => {
+                    data[start_idx + *index as usize] = drive.tick().into()
+                }
+                Voice::Stepper { index, stepper } => {
+                    data[start_idx + *index as usize] = stepper.tick().into()
+                }
+                Voice::Pwm(pwm) => write_pwm(pwm.gpio(), pwm.tick()),
+            }
         }
 
-        shift_register.write_bytes(&data);
+        with_shift_register(cs, |shift_register| shift_register.write_frame_dma(&data));
+
+        /* Stop the timer once the drives have parked */
+
+        let parked = voices.iter().all(|voice| match voice {
+            Voice::Drive { drive, .. } => !drive.is_parking(),
+            _ => true,
+        });
+
+        if parking && parked {
+            defmt::info!("Drives parked!");
+
+            // The alarm is left pending so the timer starts again as soon as it is unmasked
+            pac::NVIC::mask(hal::pac::Interrupt::TIMER_IRQ_0);
+
+            deselect_drives(cs);
+            return;
+        }
 
         /* Schedule the next alarm */
 
@@ -490,14 +1559,21 @@ fn TIMER_IRQ_0() {
 
         let elapsed_time = end_time - start_time;
 
-        let time_to_next = TIMER_RESOLUTION_US
-            .micros()
+        let elapsed_us = elapsed_time.to_micros().min(u32::MAX as u64) as u32;
+
+        if elapsed_us > LONGEST_TICK_US.borrow(cs).get() {
+            LONGEST_TICK_US.borrow(cs).set(elapsed_us);
+        }
+
+        let timer_resolution = TIMER_RESOLUTION_US.borrow(cs).get().micros();
+
+        let time_to_next = timer_resolution
             .checked_sub(elapsed_time)
             .unwrap_or(0u64.micros());
 
         if time_to_next.is_zero() {
             let overrun_us = elapsed_time
-                .checked_sub(TIMER_RESOLUTION_US.micros::<1, 1_000_000>())
+                .checked_sub(timer_resolution)
                 .unwrap()
                 .to_micros();
             defmt::warn!(
@@ -505,10 +1581,56 @@ fn TIMER_IRQ_0() {
                 overrun_us, 
                 elapsed_time.to_micros(),
             );
+
+            let overrun_us = overrun_us.min(u32::MAX as u64) as u32;
+
+            OVERRUNS
+                .borrow(cs)
+                .set(OVERRUNS.borrow(cs).get().saturating_add(1));
+            TOTAL_OVERRUNS
+                .borrow(cs)
+                .set(TOTAL_OVERRUNS.borrow(cs).get().saturating_add(1));
+
+            if overrun_us > MAX_OVERRUN_US.borrow(cs).get() {
+                MAX_OVERRUN_US.borrow(cs).set(overrun_us);
+            }
         }
 
+        let mut alarm = ALARM0.borrow(cs).borrow_mut();
+        let alarm = alarm.as_mut().unwrap();
+
         alarm.clear_interrupt();
         alarm.schedule(time_to_next.try_into().unwrap()).unwrap();
         alarm.enable_interrupt();
     });
 }
+
+/// Shows the next LED tick of the pattern for the client state
+fn tick_led(cs: CriticalSection) {
+    let lit = LED_STATUS
+        .borrow(cs)
+        .borrow_mut()
+        .tick(LED_PATTERN.borrow(cs).get());
+
+    if let Some(pin) = LED_PIN.borrow(cs).borrow_mut().as_mut() {
+        let _ = pin.set_state(PinState::from(lit));
+    }
+}
+
+/// Ticks the LED while the timer interrupt is masked (it's ticked from there otherwise, so it
+/// doesn't delay the drives)
+#[interrupt]
+fn TIMER_IRQ_1() {
+    critical_section::with(|cs| {
+        if !pac::NVIC::is_enabled(hal::pac::Interrupt::TIMER_IRQ_0) {
+            tick_led(cs);
+        }
+
+        let mut alarm = ALARM1.borrow(cs).borrow_mut();
+        let alarm = alarm.as_mut().unwrap();
+
+        alarm.clear_interrupt();
+        alarm.schedule(LED_TICK_US.micros()).unwrap();
+        alarm.enable_interrupt();
+    });
+}
@@ -1,8 +1,6 @@
 use defmt::Format;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use crate::TIMER_RESOLUTION_US;
-
 /// An enum of all the possible notes representable in MIDI
 /// 
 /// https://www.music.mcgill.ca/~ich/classes/mumt306/StandardMIDIfileformat.html#BMA1_3
@@ -26,6 +24,31 @@ pub enum Note {
 }
 
 impl Note {
+    /// The MIDI note number of the note
+    pub const fn midi_number(self) -> u8 {
+        self as u8
+    }
+
+    /// The frequency of the note in Hz (using A4 = 440Hz equal temperament)
+    pub fn frequency(self) -> f32 {
+        440.0 * libm::exp2f((self.midi_number() as f32 - 69.0) / 12.0)
+    }
+
+    /// Finds the note closest to a frequency in Hz, if it is within the range of MIDI notes
+    pub fn from_frequency(hz: f32) -> Option<Note> {
+        if !(hz.is_finite() && hz > 0.0) {
+            return None;
+        }
+
+        let number = libm::roundf(69.0 + 12.0 * libm::log2f(hz / 440.0));
+
+        if !(0.0..=127.0).contains(&number) {
+            return None;
+        }
+
+        Note::try_from(number as u8).ok()
+    }
+
     /// Convert a note to a period in microseconds
     pub const fn period_us(self) -> u32 {
         NOTE_TO_PERIOD_TABLE[self as usize]
@@ -39,14 +62,50 @@ impl Note {
         self.period_us() != 0
     }
 
-    /// Convert a note to half the number of ticks required to play that note.
+    /// Convert a note to half the number of ticks required to play that note at the given timer
+    /// resolution.
     ///
     /// i.e. the number of ticks to play half a period (the time between toggling the step pin).
-    pub const fn half_ticks(self) -> u32 {
-        NOTE_TO_HALF_TICKS_TABLE[self as usize]
+    pub const fn half_ticks(self, timer_resolution_us: u64) -> u32 {
+        self.period_us() / (2 * timer_resolution_us as u32)
+    }
+
+    /// Like `half_ticks`, but in fractions of a tick (`TICK_FRACTIONS` to a tick), worked out from
+    /// the frequency of the note rather than its rounded period
+    ///
+    /// Whole ticks are too coarse for the high notes (half a period of B8 is about 3.15 ticks at
+    /// 20µs), so the caller carries the fraction left over from each half period into the next to
+    /// play the exact frequency on average.
+    pub fn fractional_half_ticks(self, timer_resolution_us: u64) -> u32 {
+        if !self.is_playable() {
+            return 0;
+        }
+
+        let half_period_us = 500_000.0 / self.frequency();
+
+        libm::roundf(half_period_us * TICK_FRACTIONS as f32 / timer_resolution_us as f32) as u32
+    }
+
+    /// Like `fractional_half_ticks`, but for the note bent up or down by a number of semitones
+    ///
+    /// The fractions also keep small bends (like a few cents of detune) from being rounded away.
+    pub fn bent_half_ticks(self, timer_resolution_us: u64, semitones: f32) -> u32 {
+        let half_ticks = self.fractional_half_ticks(timer_resolution_us);
+
+        if semitones == 0.0 {
+            return half_ticks;
+        }
+
+        let frequency_ratio = libm::powf(2.0, semitones / 12.0);
+
+        libm::roundf(half_ticks as f32 / frequency_ratio) as u32
     }
 }
 
+/// How many parts each timer tick is split into by `Note::fractional_half_ticks` (so they're
+/// 16.16 fixed point)
+pub const TICK_FRACTIONS: u32 = 1 << 16;
+
 /// Table that maps MIDI note numbers to period in microseconds
 /// 
 /// https://www.sensorsone.com/frequency-to-period-calculator/
@@ -97,115 +156,69 @@ const NOTE_TO_PERIOD_TABLE: [u32;128] = [
     0,      0,      0,      0, 
 ];
 
-const TIMER_RESOLUTION_US_U32: u32 = TIMER_RESOLUTION_US as u32;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// Table that maps MIDI note numbers to the number of ticks required to play that note
-/// 
-/// = period / timer_resolution
-#[rustfmt::skip]
-const NOTE_TO_TICKS_TABLE: [u32;128] = [
-    NOTE_TO_PERIOD_TABLE[0] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[1] / TIMER_RESOLUTION_US_U32, 
-    NOTE_TO_PERIOD_TABLE[2] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[3] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[4] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[5] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[6] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[7] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[8] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[9] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[10] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[11] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[12] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[13] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[14] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[15] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[16] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[17] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[18] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[19] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[20] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[21] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[22] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[23] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[24] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[25] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[26] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[27] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[28] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[29] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[30] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[31] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[32] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[33] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[34] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[35] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[36] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[37] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[38] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[39] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[40] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[41] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[42] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[43] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[44] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[45] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[46] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[47] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[48] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[49] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[50] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[51] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[52] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[53] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[54] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[55] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[56] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[57] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[58] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[59] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[60] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[61] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[62] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[63] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[64] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[65] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[66] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[67] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[68] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[69] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[70] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[71] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[72] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[73] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[74] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[75] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[76] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[77] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[78] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[79] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[80] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[81] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[82] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[83] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[84] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[85] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[86] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[87] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[88] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[89] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[90] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[91] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[92] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[93] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[94] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[95] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[96] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[97] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[98] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[99] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[100] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[101] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[102] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[103] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[104] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[105] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[106] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[107] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[108] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[109] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[110] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[111] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[112] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[113] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[114] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[115] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[116] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[117] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[118] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[119] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[120] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[121] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[122] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[123] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[124] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[125] / TIMER_RESOLUTION_US_U32,
-    NOTE_TO_PERIOD_TABLE[126] / TIMER_RESOLUTION_US_U32, NOTE_TO_PERIOD_TABLE[127] / TIMER_RESOLUTION_US_U32,
-];
+    #[test]
+    fn half_ticks_at_timer_resolution() {
+        // A4 = 440Hz = 2272µs
+        assert_eq!(Note::A4.half_ticks(20), 56);
+        assert_eq!(Note::A4.half_ticks(40), 28);
+        assert_eq!(Note::C4.half_ticks(20), 95);
+        assert_eq!(Note::C_1.half_ticks(20), 0);
+    }
 
-/// Table that maps MIDI note numbers to half the numbr of ticks required to play that note.
-/// This corresponds to the number of ticks to play half a period (the time between toggling the step pin).
-/// 
-/// = period / (timer_resolution * 2)
-#[rustfmt::skip]
-const NOTE_TO_HALF_TICKS_TABLE: [u32;128] = [
-    NOTE_TO_TICKS_TABLE[0] / 2, NOTE_TO_TICKS_TABLE[1] / 2, NOTE_TO_TICKS_TABLE[2] / 2, NOTE_TO_TICKS_TABLE[3] / 2,
-    NOTE_TO_TICKS_TABLE[4] / 2, NOTE_TO_TICKS_TABLE[5] / 2, NOTE_TO_TICKS_TABLE[6] / 2, NOTE_TO_TICKS_TABLE[7] / 2,
-    NOTE_TO_TICKS_TABLE[8] / 2, NOTE_TO_TICKS_TABLE[9] / 2, NOTE_TO_TICKS_TABLE[10] / 2, NOTE_TO_TICKS_TABLE[11] / 2,
-    NOTE_TO_TICKS_TABLE[12] / 2, NOTE_TO_TICKS_TABLE[13] / 2, NOTE_TO_TICKS_TABLE[14] / 2, NOTE_TO_TICKS_TABLE[15] / 2,
-    NOTE_TO_TICKS_TABLE[16] / 2, NOTE_TO_TICKS_TABLE[17] / 2, NOTE_TO_TICKS_TABLE[18] / 2, NOTE_TO_TICKS_TABLE[19] / 2,
-    NOTE_TO_TICKS_TABLE[20] / 2, NOTE_TO_TICKS_TABLE[21] / 2, NOTE_TO_TICKS_TABLE[22] / 2, NOTE_TO_TICKS_TABLE[23] / 2,
-    NOTE_TO_TICKS_TABLE[24] / 2, NOTE_TO_TICKS_TABLE[25] / 2, NOTE_TO_TICKS_TABLE[26] / 2, NOTE_TO_TICKS_TABLE[27] / 2,
-    NOTE_TO_TICKS_TABLE[28] / 2, NOTE_TO_TICKS_TABLE[29] / 2, NOTE_TO_TICKS_TABLE[30] / 2, NOTE_TO_TICKS_TABLE[31] / 2,
-    NOTE_TO_TICKS_TABLE[32] / 2, NOTE_TO_TICKS_TABLE[33] / 2, NOTE_TO_TICKS_TABLE[34] / 2, NOTE_TO_TICKS_TABLE[35] / 2,
-    NOTE_TO_TICKS_TABLE[36] / 2, NOTE_TO_TICKS_TABLE[37] / 2, NOTE_TO_TICKS_TABLE[38] / 2, NOTE_TO_TICKS_TABLE[39] / 2,
-    NOTE_TO_TICKS_TABLE[40] / 2, NOTE_TO_TICKS_TABLE[41] / 2, NOTE_TO_TICKS_TABLE[42] / 2, NOTE_TO_TICKS_TABLE[43] / 2,
-    NOTE_TO_TICKS_TABLE[44] / 2, NOTE_TO_TICKS_TABLE[45] / 2, NOTE_TO_TICKS_TABLE[46] / 2, NOTE_TO_TICKS_TABLE[47] / 2,
-    NOTE_TO_TICKS_TABLE[48] / 2, NOTE_TO_TICKS_TABLE[49] / 2, NOTE_TO_TICKS_TABLE[50] / 2, NOTE_TO_TICKS_TABLE[51] / 2,
-    NOTE_TO_TICKS_TABLE[52] / 2, NOTE_TO_TICKS_TABLE[53] / 2, NOTE_TO_TICKS_TABLE[54] / 2, NOTE_TO_TICKS_TABLE[55] / 2,
-    NOTE_TO_TICKS_TABLE[56] / 2, NOTE_TO_TICKS_TABLE[57] / 2, NOTE_TO_TICKS_TABLE[58] / 2, NOTE_TO_TICKS_TABLE[59] / 2,
-    NOTE_TO_TICKS_TABLE[60] / 2, NOTE_TO_TICKS_TABLE[61] / 2, NOTE_TO_TICKS_TABLE[62] / 2, NOTE_TO_TICKS_TABLE[63] / 2,
-    NOTE_TO_TICKS_TABLE[64] / 2, NOTE_TO_TICKS_TABLE[65] / 2, NOTE_TO_TICKS_TABLE[66] / 2, NOTE_TO_TICKS_TABLE[67] / 2,
-    NOTE_TO_TICKS_TABLE[68] / 2, NOTE_TO_TICKS_TABLE[69] / 2, NOTE_TO_TICKS_TABLE[70] / 2, NOTE_TO_TICKS_TABLE[71] / 2,
-    NOTE_TO_TICKS_TABLE[72] / 2, NOTE_TO_TICKS_TABLE[73] / 2, NOTE_TO_TICKS_TABLE[74] / 2, NOTE_TO_TICKS_TABLE[75] / 2,
-    NOTE_TO_TICKS_TABLE[76] / 2, NOTE_TO_TICKS_TABLE[77] / 2, NOTE_TO_TICKS_TABLE[78] / 2, NOTE_TO_TICKS_TABLE[79] / 2,
-    NOTE_TO_TICKS_TABLE[80] / 2, NOTE_TO_TICKS_TABLE[81] / 2, NOTE_TO_TICKS_TABLE[82] / 2, NOTE_TO_TICKS_TABLE[83] / 2,
-    NOTE_TO_TICKS_TABLE[84] / 2, NOTE_TO_TICKS_TABLE[85] / 2, NOTE_TO_TICKS_TABLE[86] / 2, NOTE_TO_TICKS_TABLE[87] / 2,
-    NOTE_TO_TICKS_TABLE[88] / 2, NOTE_TO_TICKS_TABLE[89] / 2, NOTE_TO_TICKS_TABLE[90] / 2, NOTE_TO_TICKS_TABLE[91] / 2,
-    NOTE_TO_TICKS_TABLE[92] / 2, NOTE_TO_TICKS_TABLE[93] / 2, NOTE_TO_TICKS_TABLE[94] / 2, NOTE_TO_TICKS_TABLE[95] / 2,
-    NOTE_TO_TICKS_TABLE[96] / 2, NOTE_TO_TICKS_TABLE[97] / 2, NOTE_TO_TICKS_TABLE[98] / 2, NOTE_TO_TICKS_TABLE[99] / 2,
-    NOTE_TO_TICKS_TABLE[100] / 2, NOTE_TO_TICKS_TABLE[101] / 2, NOTE_TO_TICKS_TABLE[102] / 2, NOTE_TO_TICKS_TABLE[103] / 2,
-    NOTE_TO_TICKS_TABLE[104] / 2, NOTE_TO_TICKS_TABLE[105] / 2, NOTE_TO_TICKS_TABLE[106] / 2, NOTE_TO_TICKS_TABLE[107] / 2,
-    NOTE_TO_TICKS_TABLE[108] / 2, NOTE_TO_TICKS_TABLE[109] / 2, NOTE_TO_TICKS_TABLE[110] / 2, NOTE_TO_TICKS_TABLE[111] / 2,
-    NOTE_TO_TICKS_TABLE[112] / 2, NOTE_TO_TICKS_TABLE[113] / 2, NOTE_TO_TICKS_TABLE[114] / 2, NOTE_TO_TICKS_TABLE[115] / 2,
-    NOTE_TO_TICKS_TABLE[116] / 2, NOTE_TO_TICKS_TABLE[117] / 2, NOTE_TO_TICKS_TABLE[118] / 2, NOTE_TO_TICKS_TABLE[119] / 2,
-    NOTE_TO_TICKS_TABLE[120] / 2, NOTE_TO_TICKS_TABLE[121] / 2, NOTE_TO_TICKS_TABLE[122] / 2, NOTE_TO_TICKS_TABLE[123] / 2,
-    NOTE_TO_TICKS_TABLE[124] / 2, NOTE_TO_TICKS_TABLE[125] / 2, NOTE_TO_TICKS_TABLE[126] / 2, NOTE_TO_TICKS_TABLE[127] / 2,
-];
+    #[test]
+    fn fractional_half_ticks_are_exact() {
+        // A4 = 440Hz = 2272.73µs = 56.82 ticks
+        let a4 = Note::A4.fractional_half_ticks(20);
+
+        assert_eq!(a4 / TICK_FRACTIONS, 56);
+        assert!(a4.abs_diff((56.818 * TICK_FRACTIONS as f32) as u32) < TICK_FRACTIONS / 1000);
+        assert_eq!(Note::C_1.fractional_half_ticks(20), 0);
+
+        assert_eq!(Note::A4.bent_half_ticks(20, 0.0), a4);
+
+        // 10 cents flat is about a third of a tick longer, which whole ticks would round away
+        let detuned = Note::A4.bent_half_ticks(20, -0.1);
+
+        assert!(detuned > a4 + TICK_FRACTIONS / 4);
+        assert!(detuned < a4 + TICK_FRACTIONS / 2);
+    }
+
+    #[test]
+    fn playable_range_matches_proto() {
+        use floppier_proto::{MAX_PLAYABLE_NOTE, MIN_PLAYABLE_NOTE};
+
+        for number in 0..=127 {
+            let note = Note::try_from(number).unwrap();
+
+            assert_eq!(
+                note.is_playable(),
+                (MIN_PLAYABLE_NOTE..=MAX_PLAYABLE_NOTE).contains(&number)
+            );
+        }
+    }
+
+    #[test]
+    fn frequency_round_trips() {
+        assert_eq!(Note::A4.midi_number(), 69);
+        assert_eq!(Note::A4.frequency(), 440.0);
+        assert!((Note::C4.frequency() - 261.63).abs() < 0.01);
+
+        for number in 0..=127 {
+            let note = Note::try_from(number).unwrap();
+            assert_eq!(Note::from_frequency(note.frequency()), Some(note));
+        }
+    }
+
+    #[test]
+    fn from_frequency_snaps_to_nearest_note() {
+        assert_eq!(Note::from_frequency(445.0), Some(Note::A4));
+        assert_eq!(Note::from_frequency(460.0), Some(Note::As4));
+        assert_eq!(Note::from_frequency(0.0), None);
+        assert_eq!(Note::from_frequency(f32::NAN), None);
+        assert_eq!(Note::from_frequency(20_000.0), None);
+    }
+}
@@ -0,0 +1,162 @@
+use heapless::Vec;
+
+use crate::note::Note;
+
+/// The maximum number of held notes remembered per drive
+pub const NOTE_STACK_DEPTH: usize = 8;
+
+/// A stack of the notes currently held on a drive, with the most recent note on top
+///
+/// Releasing the sounding note falls back to the most recent note that is still held instead of
+/// silencing the drive. This also records which drive owns which note in `ParallelMode::Distribute`.
+#[derive(Debug, Default, Clone)]
+pub struct NoteStack {
+    notes: Vec<Note, NOTE_STACK_DEPTH>,
+}
+
+impl NoteStack {
+    pub const fn new() -> Self {
+        Self { notes: Vec::new() }
+    }
+
+    /// Pushes a note onto the top of the stack, evicting the oldest note if the stack is full
+    ///
+    /// A note that is already held is moved to the top rather than being duplicated.
+    pub fn push(&mut self, note: Note) {
+        self.remove(note);
+
+        if self.notes.is_full() {
+            self.notes.remove(0);
+        }
+
+        // Can't fail since we just made room
+        let _ = self.notes.push(note);
+    }
+
+    /// Removes a note from anywhere in the stack
+    pub fn remove(&mut self, note: Note) {
+        self.notes.retain(|held| *held != note);
+    }
+
+    /// The most recently pushed note that is still held
+    pub fn top(&self) -> Option<Note> {
+        self.notes.last().copied()
+    }
+
+    pub fn contains(&self, note: Note) -> bool {
+        self.notes.contains(&note)
+    }
+
+    /// The held note that comes after the given one, wrapping around to the oldest note
+    ///
+    /// Used by `ParallelMode::Synthesize` to cycle through the notes of a chord.
+    pub fn next_after(&self, note: Option<Note>) -> Option<Note> {
+        let next_index = note
+            .and_then(|note| self.notes.iter().position(|held| *held == note))
+            .map_or(0, |i| (i + 1) % self.notes.len());
+
+        self.notes.get(next_index).copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.notes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty()
+    }
+
+    /// Iterates over the held notes from oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = &Note> {
+        self.notes.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_previous_note() {
+        let mut stack = NoteStack::new();
+
+        stack.push(Note::C4);
+        stack.push(Note::E4);
+        assert_eq!(stack.top(), Some(Note::E4));
+
+        stack.remove(Note::E4);
+        assert_eq!(stack.top(), Some(Note::C4));
+
+        stack.remove(Note::C4);
+        assert_eq!(stack.top(), None);
+    }
+
+    #[test]
+    fn releasing_a_buried_note_keeps_the_top() {
+        let mut stack = NoteStack::new();
+
+        stack.push(Note::C4);
+        stack.push(Note::E4);
+        stack.push(Note::G4);
+
+        stack.remove(Note::E4);
+        assert_eq!(stack.top(), Some(Note::G4));
+
+        stack.remove(Note::G4);
+        assert_eq!(stack.top(), Some(Note::C4));
+    }
+
+    #[test]
+    fn retriggered_note_moves_to_top() {
+        let mut stack = NoteStack::new();
+
+        stack.push(Note::C4);
+        stack.push(Note::E4);
+        stack.push(Note::C4);
+
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.top(), Some(Note::C4));
+
+        stack.remove(Note::C4);
+        assert_eq!(stack.top(), Some(Note::E4));
+    }
+
+    #[test]
+    fn cycles_through_held_notes() {
+        let mut stack = NoteStack::new();
+
+        assert_eq!(stack.next_after(None), None);
+
+        stack.push(Note::C4);
+        stack.push(Note::E4);
+        stack.push(Note::G4);
+
+        assert_eq!(stack.next_after(Some(Note::C4)), Some(Note::E4));
+        assert_eq!(stack.next_after(Some(Note::E4)), Some(Note::G4));
+        assert_eq!(stack.next_after(Some(Note::G4)), Some(Note::C4));
+
+        // A note that is no longer held restarts the cycle
+        assert_eq!(stack.next_after(Some(Note::B4)), Some(Note::C4));
+        assert_eq!(stack.next_after(None), Some(Note::C4));
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut stack = NoteStack::new();
+
+        for note in 60..60 + NOTE_STACK_DEPTH as u8 + 1 {
+            stack.push(Note::try_from(note).unwrap());
+        }
+
+        assert_eq!(stack.len(), NOTE_STACK_DEPTH);
+        assert_eq!(stack.iter().next(), Some(&Note::Cs4));
+        assert_eq!(
+            stack.top(),
+            Some(Note::try_from(60 + NOTE_STACK_DEPTH as u8).unwrap())
+        );
+    }
+}
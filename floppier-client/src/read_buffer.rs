@@ -0,0 +1,270 @@
+use alloc::vec::Vec;
+
+use floppier_proto::frame::{self, FrameHeader};
+
+/// The front of the buffer wasn't a frame the client can take
+///
+/// Bytes are skipped until the buffer starts with a plausible length prefix again, and `skipped`
+/// says how many.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidFrame {
+    /// The length prefix was zero or longer than the largest frame the client accepts, so it
+    /// can't have been the start of a frame (like when junk arrives on the serial line)
+    Length { len: usize, skipped: usize },
+    /// The frame's checksum didn't match. Its length prefix may be what was corrupted, so only the
+    /// first byte is skipped rather than the length it claims, in case the next frame starts
+    /// within it.
+    Checksum { skipped: usize },
+}
+
+/// A FIFO of the raw bytes received from the server that haven't been turned into messages yet
+///
+/// Bytes are appended as they arrive, however the USB packets happen to split them, and whole
+/// frames are peeled off the front one at a time. A partial frame stays in the buffer until the
+/// rest of it arrives.
+#[derive(Debug, Default)]
+pub struct ReadBuffer {
+    bytes: Vec<u8>,
+}
+
+impl ReadBuffer {
+    pub const fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Takes the first frame off the buffer if it has been fully received, passing its header and
+    /// payload to `parse` if its checksum matches (or it has none)
+    ///
+    /// This should be called until it returns `None`, since there may be several frames in the
+    /// buffer.
+    pub fn pop_frame<T>(
+        &mut self,
+        max_payload_len: usize,
+        parse: impl FnOnce(&FrameHeader, &[u8]) -> T,
+    ) -> Option<Result<T, InvalidFrame>> {
+        if self.bytes.len() < frame::LEN_LEN {
+            return None;
+        }
+
+        let header = self.header();
+
+        if !is_plausible(&header, max_payload_len) {
+            return Some(Err(InvalidFrame::Length {
+                len: header.payload_len,
+                skipped: self.resync(max_payload_len),
+            }));
+        }
+
+        if self.bytes.len() < header.frame_len() {
+            return None;
+        }
+
+        let Some(payload) = header.payload(&self.bytes[..header.frame_len()]) else {
+            return Some(Err(InvalidFrame::Checksum {
+                skipped: self.resync(max_payload_len),
+            }));
+        };
+
+        let parsed = parse(&header, payload);
+
+        self.bytes.drain(..header.frame_len());
+
+        Some(Ok(parsed))
+    }
+
+    /// The header of the frame at the front of the buffer, which must hold a length prefix
+    fn header(&self) -> FrameHeader {
+        FrameHeader::parse([self.bytes[0], self.bytes[1]])
+    }
+
+    /// Skips bytes until the buffer starts with a plausible length prefix, returning how many were
+    /// skipped
+    ///
+    /// The last byte is kept if there's no room for a whole length prefix, since it may be the
+    /// start of one.
+    fn resync(&mut self, max_payload_len: usize) -> usize {
+        let mut skipped = 1;
+
+        while skipped + frame::LEN_LEN <= self.bytes.len() {
+            let header = FrameHeader::parse([self.bytes[skipped], self.bytes[skipped + 1]]);
+
+            if is_plausible(&header, max_payload_len) {
+                break;
+            }
+
+            skipped += 1;
+        }
+
+        self.bytes.drain(..skipped);
+
+        skipped
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+fn is_plausible(header: &FrameHeader, max_payload_len: usize) -> bool {
+    (1..=max_payload_len).contains(&header.payload_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_PAYLOAD_LEN: usize = 64;
+
+    /// A few frames of different lengths, alternating between the checksummed and plain layouts
+    fn payloads_and_stream() -> (Vec<Vec<u8>>, Vec<u8>) {
+        let payloads = [&[1u8][..], &[2, 3, 4, 5, 6], &[7; 20], &[8, 9]]
+            .map(|payload| payload.to_vec())
+            .to_vec();
+
+        let stream = payloads
+            .iter()
+            .enumerate()
+            .flat_map(|(i, payload)| frame::encode(payload, i % 2 == 0))
+            .collect();
+
+        (payloads, stream)
+    }
+
+    /// Feeds the stream to a buffer in the given chunks, popping every frame after each chunk
+    fn receive(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut buffer = ReadBuffer::new();
+        let mut received = Vec::new();
+
+        for chunk in chunks {
+            buffer.extend(chunk);
+
+            while let Some(frame) = buffer.pop_frame(MAX_PAYLOAD_LEN, |_, payload| payload.to_vec())
+            {
+                received.push(frame.unwrap());
+            }
+        }
+
+        assert!(buffer.is_empty());
+
+        received
+    }
+
+    #[test]
+    fn reads_frames_split_at_any_point() {
+        let (payloads, stream) = payloads_and_stream();
+
+        for i in 0..=stream.len() {
+            for j in i..=stream.len() {
+                let (head, rest) = stream.split_at(i);
+                let (middle, tail) = rest.split_at(j - i);
+
+                assert_eq!(receive(&[head, middle, tail]), payloads);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_frames_a_byte_at_a_time() {
+        let (payloads, stream) = payloads_and_stream();
+
+        let chunks = stream.chunks(1).collect::<Vec<_>>();
+
+        assert_eq!(receive(&chunks), payloads);
+    }
+
+    #[test]
+    fn keeps_partial_frames() {
+        let (payloads, stream) = payloads_and_stream();
+
+        let mut buffer = ReadBuffer::new();
+        buffer.extend(&stream[..6]);
+
+        // The whole first frame plus the first byte of the second
+        let frame = buffer.pop_frame(MAX_PAYLOAD_LEN, |_, payload| payload.to_vec());
+
+        assert_eq!(frame, Some(Ok(payloads[0].clone())));
+        assert!(buffer
+            .pop_frame(MAX_PAYLOAD_LEN, |_, _| unreachable!())
+            .is_none());
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn resyncs_after_junk() {
+        let (payloads, stream) = payloads_and_stream();
+
+        let mut buffer = ReadBuffer::new();
+
+        // None of the junk reads as a plausible length prefix, even with the first frame's bytes
+        buffer.extend(&[0, 0, 0xff, 0xff]);
+        buffer.extend(&stream);
+
+        assert_eq!(
+            buffer.pop_frame(MAX_PAYLOAD_LEN, |_, _| unreachable!()),
+            Some(Err(InvalidFrame::Length { len: 0, skipped: 4 }))
+        );
+
+        let mut received = Vec::new();
+
+        while let Some(frame) = buffer.pop_frame(MAX_PAYLOAD_LEN, |_, payload| payload.to_vec()) {
+            received.push(frame.unwrap());
+        }
+
+        assert_eq!(received, payloads);
+    }
+
+    #[test]
+    fn resyncs_after_a_corrupted_length() {
+        let payloads = [&[1u8][..], &[2, 3, 4, 5, 6], &[7; 20], &[8, 9]];
+        let mut frames = payloads.map(|payload| frame::encode(payload, true));
+
+        // The second frame claims to be a byte shorter than it is, which only the checksum shows
+        frames[1][0] -= 1;
+
+        let mut buffer = ReadBuffer::new();
+        buffer.extend(&frames.concat());
+
+        let mut received = Vec::new();
+        let mut bad_checksums = 0;
+
+        while let Some(frame) = buffer.pop_frame(MAX_PAYLOAD_LEN, |_, payload| payload.to_vec()) {
+            match frame {
+                Ok(payload) => received.push(payload),
+                Err(InvalidFrame::Checksum { .. }) => bad_checksums += 1,
+                Err(InvalidFrame::Length { .. }) => {}
+            }
+        }
+
+        assert_eq!(bad_checksums, 1);
+        assert_eq!(received, [payloads[0], payloads[2], payloads[3]]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_last_byte_of_junk() {
+        let mut buffer = ReadBuffer::new();
+
+        buffer.extend(&frame::encode(&[0; MAX_PAYLOAD_LEN + 1], false)[..4]);
+
+        assert_eq!(
+            buffer.pop_frame(MAX_PAYLOAD_LEN, |_, _| ()),
+            Some(Err(InvalidFrame::Length {
+                len: MAX_PAYLOAD_LEN + 1,
+                skipped: 3
+            }))
+        );
+        assert_eq!(buffer.len(), 1);
+    }
+}
@@ -2,6 +2,7 @@ use embedded_hal::digital::OutputPin;
 use pio::ProgramWithDefines;
 use rp_pico::{
     hal::{
+        dma::{single_buffer, Channel, CH0},
         gpio::{
             bank0::{Gpio2, Gpio3, Gpio4, Gpio5},
             FunctionPio0, FunctionSio, Pin, PullDown, SioOutput,
@@ -22,10 +23,22 @@ type PioTx = Tx<(PIO0, SM0)>;
 
 type OutputEnablePin = Pin<Gpio5, FunctionSio<SioOutput>, PullDown>;
 
+/// The words the PIO program shifts out for each write, with a byte for each drive
+pub type Frame = [u32; 2];
+
+type DmaChannel = Channel<CH0>;
+type FrameTransfer = single_buffer::Transfer<DmaChannel, &'static mut Frame, PioTx>;
+
 /// https://www.ti.com/lit/ds/symlink/sn74hc595.pdf
 pub struct SN74HC595 {
     output_enable: OutputEnablePin,
-    tx: PioTx,
+    /// The TX FIFO, while a DMA transfer isn't using it
+    tx: Option<PioTx>,
+    /// The DMA channel and the buffer it copies frames from, if `enable_dma` has been called and
+    /// no transfer is using them
+    dma: Option<(DmaChannel, &'static mut Frame)>,
+    /// The frame being copied into the TX FIFO, if any
+    transfer: Option<FrameTransfer>,
 }
 
 impl SN74HC595 {
@@ -60,7 +73,29 @@ impl SN74HC595 {
         ]);
         sm.start();
 
-        Self { output_enable, tx }
+        Self {
+            output_enable,
+            tx: Some(tx),
+            dma: None,
+            transfer: None,
+        }
+    }
+
+    /// Lets `write_frame_dma` copy frames into the TX FIFO with a DMA channel, from a buffer in RAM
+    pub fn enable_dma(&mut self, channel: DmaChannel, frame: &'static mut Frame) {
+        self.dma = Some((channel, frame));
+    }
+
+    /// The TX FIFO, once any frame being copied by DMA has been copied
+    fn tx(&mut self) -> &mut PioTx {
+        if let Some(transfer) = self.transfer.take() {
+            let (channel, frame, tx) = transfer.wait();
+
+            self.dma = Some((channel, frame));
+            self.tx = Some(tx);
+        }
+
+        self.tx.as_mut().unwrap()
     }
 
     #[inline]
@@ -70,22 +105,55 @@ impl SN74HC595 {
     }
 
     pub fn write_byte_to_all(&mut self, data: u8) {
-        self.tx.write_u8_replicated(data.reverse_bits());
-        self.tx.write_u8_replicated(data.reverse_bits());
+        let tx = self.tx();
+
+        tx.write_u8_replicated(data.reverse_bits());
+        tx.write_u8_replicated(data.reverse_bits());
     }
 
     pub fn write_bytes(&mut self, data: &[u8; 8]) {
-        self.tx.write(u32::from_le_bytes([
+        let tx = self.tx();
+
+        for word in frame(data) {
+            tx.write(word);
+        }
+    }
+
+    /// Writes the bytes like `write_bytes`, but has the DMA channel copy them into the TX FIFO so
+    /// the CPU doesn't have to (falling back to `write_bytes` if DMA isn't enabled)
+    ///
+    /// The frame before is waited for first, which will have long been copied if this is called
+    /// once a tick.
+    pub fn write_frame_dma(&mut self, data: &[u8; 8]) {
+        self.tx();
+
+        let Some((channel, buffer)) = self.dma.take() else {
+            self.write_bytes(data);
+            return;
+        };
+
+        *buffer = frame(data);
+
+        let tx = self.tx.take().unwrap();
+
+        self.transfer = Some(single_buffer::Config::new(channel, buffer, tx).start());
+    }
+}
+
+/// Packs a byte for each drive into the words the PIO program shifts out
+fn frame(data: &[u8; 8]) -> Frame {
+    [
+        u32::from_le_bytes([
             data[0].reverse_bits(),
             data[1].reverse_bits(),
             data[2].reverse_bits(),
             data[3].reverse_bits(),
-        ]));
-        self.tx.write(u32::from_le_bytes([
+        ]),
+        u32::from_le_bytes([
             data[4].reverse_bits(),
             data[5].reverse_bits(),
             data[6].reverse_bits(),
             data[7].reverse_bits(),
-        ]));
-    }
+        ]),
+    ]
 }
@@ -0,0 +1,184 @@
+use defmt::Format;
+
+use crate::{
+    floppy_drive::{
+        signal_bit, Direction, DIRECTION_ACTIVE_LOW, DIRECTION_BIT, STEP_ACTIVE_LOW, STEP_BIT,
+    },
+    instrument::Instrument,
+    note::{Note, TICK_FRACTIONS},
+};
+
+/// A stepper motor (like a NEMA 17) on a shift register output, through a driver that takes step
+/// and direction signals
+///
+/// Unlike a floppy drive, the motor has no drive select and no end to its travel, so it steps
+/// continuously in one direction while a note plays.
+#[derive(Debug, Format)]
+pub struct StepperInstrument {
+    note: Option<Note>,
+    pitch_bend: f32,
+    detune_cents: i16,
+    program: u8,
+    /// How many steps the motor takes for each step a floppy drive would (for drivers set to
+    /// microstep)
+    steps_per_toggle: u8,
+    step: bool,
+    period_tick: u32,
+    /// The time between toggling the step pin for the current note, in fractions of a tick (see
+    /// `Note::bent_half_ticks`)
+    toggle_ticks: u32,
+    /// How often the stepper is ticked in µs
+    timer_resolution_us: u64,
+}
+
+impl StepperInstrument {
+    pub fn new(steps_per_toggle: u8, timer_resolution_us: u64) -> Self {
+        Self {
+            note: None,
+            pitch_bend: 0.0,
+            detune_cents: 0,
+            program: 0,
+            steps_per_toggle: steps_per_toggle.max(1),
+            step: false,
+            period_tick: 0,
+            toggle_ticks: 0,
+            timer_resolution_us,
+        }
+    }
+
+    pub fn tick(&mut self) -> StepperState {
+        if self.note.is_some() {
+            self.period_tick += TICK_FRACTIONS;
+
+            if self.period_tick >= self.toggle_ticks {
+                self.step = !self.step;
+                self.period_tick -= self.toggle_ticks;
+            }
+        }
+
+        StepperState {
+            step: self.step,
+            direction: Direction::Forward,
+        }
+    }
+
+    /// Recalculates the time between toggling the step pin for the current note, pitch bend and
+    /// detune (done ahead of time to keep the float math out of `tick`)
+    fn update_toggle_ticks(&mut self) {
+        let semitones = self.pitch_bend + self.detune_cents as f32 / 100.0;
+
+        self.toggle_ticks = self.note.map_or(0, |note| {
+            let half_ticks = note.bent_half_ticks(self.timer_resolution_us, semitones);
+
+            (half_ticks / self.steps_per_toggle as u32).max(TICK_FRACTIONS)
+        });
+    }
+}
+
+impl Instrument for StepperInstrument {
+    fn note(&self) -> Option<Note> {
+        self.note
+    }
+
+    fn set_note(&mut self, note: Option<Note>) {
+        self.note = note.filter(|note| note.is_playable());
+
+        if self.note.is_none() {
+            self.pitch_bend = 0.0;
+        }
+
+        self.update_toggle_ticks();
+        self.period_tick = 0;
+    }
+
+    fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+        self.update_toggle_ticks();
+    }
+
+    fn set_detune(&mut self, cents: i16) {
+        self.detune_cents = cents;
+        self.update_toggle_ticks();
+    }
+
+    /// Steppers have no drive select to play softer with, so the velocity is ignored
+    fn set_velocity(&mut self, _velocity: u8) {}
+
+    fn program(&self) -> u8 {
+        self.program
+    }
+
+    fn set_program(&mut self, program: u8) {
+        self.program = program;
+    }
+}
+
+/// What to shift out to a stepper driver, which only uses the step and direction bits of a drive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct StepperState {
+    pub step: bool,
+    pub direction: Direction,
+}
+
+impl From<StepperState> for u8 {
+    fn from(value: StepperState) -> Self {
+        signal_bit(value.step, STEP_BIT, STEP_ACTIVE_LOW)
+            | signal_bit(
+                value.direction == Direction::Reverse,
+                DIRECTION_BIT,
+                DIRECTION_ACTIVE_LOW,
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_TIMER_RESOLUTION_US;
+
+    fn count_toggles(stepper: &mut StepperInstrument, ticks: u32) -> usize {
+        let mut toggles = 0;
+        let mut step = stepper.tick().step;
+
+        for _ in 1..ticks {
+            let state = stepper.tick();
+
+            if state.step != step {
+                toggles += 1;
+                step = state.step;
+            }
+        }
+
+        toggles
+    }
+
+    #[test]
+    fn steps_continuously_at_the_note_period() {
+        let note = Note::try_from(69).unwrap();
+
+        let mut single = StepperInstrument::new(1, DEFAULT_TIMER_RESOLUTION_US);
+        single.set_note(Some(note));
+
+        let mut quadruple = StepperInstrument::new(4, DEFAULT_TIMER_RESOLUTION_US);
+        quadruple.set_note(Some(note));
+
+        let half_ticks = note.fractional_half_ticks(DEFAULT_TIMER_RESOLUTION_US) as u64;
+        let ticks = (half_ticks * 1_000 / TICK_FRACTIONS as u64) as u32;
+
+        // Never bounces back at the end of a range like a drive
+        assert!(count_toggles(&mut single, ticks) >= 999);
+        assert!(count_toggles(&mut quadruple, ticks) >= 4 * 999);
+        assert_eq!(single.tick().direction, Direction::Forward);
+    }
+
+    #[test]
+    fn only_uses_the_step_and_direction_bits() {
+        for step in [false, true] {
+            for direction in [Direction::Forward, Direction::Reverse] {
+                let byte = u8::from(StepperState { step, direction });
+
+                assert_eq!(byte & !(STEP_BIT | DIRECTION_BIT), 0);
+            }
+        }
+    }
+}
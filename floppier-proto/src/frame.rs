@@ -0,0 +1,161 @@
+//! Framing of the messages sent over the serial link
+//!
+//! Each frame is `len: u16 | crc16: u16 | payload`, all little-endian, where the payload is the
+//! CBOR encoded message. The top bit of `len` marks a checksummed frame, and frames without it
+//! have no checksum (the framing used with peers older than `EXTENSIONS_VERSION`). The checksum
+//! covers the length prefix as well as the payload, so a corrupted length is caught too.
+
+use alloc::vec::Vec;
+
+/// The bit of the length prefix that marks a frame as checksummed
+pub const CHECKSUM_FLAG: u16 = 0x8000;
+
+/// The length of the length prefix
+pub const LEN_LEN: usize = 2;
+
+/// The length of the checksum in a checksummed frame
+pub const CHECKSUM_LEN: usize = 2;
+
+/// Frames a payload, with a checksum if `checksummed` is set
+pub fn encode(payload: &[u8], checksummed: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(LEN_LEN + CHECKSUM_LEN + payload.len());
+
+    if checksummed {
+        let len_bytes = (payload.len() as u16 | CHECKSUM_FLAG).to_le_bytes();
+
+        frame.extend_from_slice(&len_bytes);
+        frame.extend_from_slice(&checksum(len_bytes, payload).to_le_bytes());
+    } else {
+        frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    }
+
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+/// The information in the length prefix of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub payload_len: usize,
+    pub checksummed: bool,
+}
+
+impl FrameHeader {
+    pub fn parse(len_bytes: [u8; LEN_LEN]) -> Self {
+        let len = u16::from_le_bytes(len_bytes);
+
+        Self {
+            payload_len: (len & !CHECKSUM_FLAG) as usize,
+            checksummed: len & CHECKSUM_FLAG != 0,
+        }
+    }
+
+    /// The number of bytes before the payload
+    pub fn header_len(&self) -> usize {
+        if self.checksummed {
+            LEN_LEN + CHECKSUM_LEN
+        } else {
+            LEN_LEN
+        }
+    }
+
+    /// The length of the whole frame
+    pub fn frame_len(&self) -> usize {
+        self.header_len() + self.payload_len
+    }
+
+    /// Returns the payload of a whole frame, or `None` if its checksum doesn't match
+    pub fn payload<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let payload = &frame[self.header_len()..self.frame_len()];
+
+        if self.checksummed {
+            let len_bytes = [frame[0], frame[1]];
+            let expected = u16::from_le_bytes([frame[LEN_LEN], frame[LEN_LEN + 1]]);
+
+            if checksum(len_bytes, payload) != expected {
+                return None;
+            }
+        }
+
+        Some(payload)
+    }
+}
+
+/// The checksum of a frame, over its length prefix followed by its payload
+fn checksum(len_bytes: [u8; LEN_LEN], payload: &[u8]) -> u16 {
+    crc16_update(crc16(&len_bytes), payload)
+}
+
+/// Calculates the CRC-16/CCITT-FALSE checksum of some data (polynomial 0x1021, initial value
+/// 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xFFFF, data)
+}
+
+/// Carries on a CRC-16/CCITT-FALSE checksum with more data
+fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(frame: &[u8]) -> Option<&[u8]> {
+        let header = FrameHeader::parse([frame[0], frame[1]]);
+
+        assert_eq!(header.frame_len(), frame.len());
+
+        header.payload(frame)
+    }
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn round_trips_both_layouts() {
+        let payload = [1, 2, 3];
+
+        let frame = encode(&payload, false);
+        assert_eq!(frame.len(), 5);
+        assert_eq!(decode(&frame), Some(&payload[..]));
+
+        let frame = encode(&payload, true);
+        assert_eq!(frame.len(), 7);
+        assert_eq!(decode(&frame), Some(&payload[..]));
+    }
+
+    #[test]
+    fn rejects_corrupted_frame() {
+        let mut frame = encode(&[1, 2, 3], true);
+        frame[5] ^= 0x10;
+
+        assert_eq!(decode(&frame), None);
+    }
+
+    #[test]
+    fn rejects_corrupted_length() {
+        let mut frame = encode(&[1, 2, 3], true);
+        frame[0] = 2;
+
+        // The frame now looks a byte shorter than it is
+        let header = FrameHeader::parse([frame[0], frame[1]]);
+
+        assert_eq!(header.payload(&frame[..header.frame_len()]), None);
+    }
+}
@@ -8,44 +8,406 @@ use alloc::vec::Vec;
 
 use serde::{Deserialize, Serialize};
 
+pub mod frame;
+
+/// The version of the protocol, exchanged in the hello handshake
+///
+/// The high byte is the major version, which must match between the client and server. The low
+/// byte is the minor version, which is bumped for backwards compatible changes.
+pub const PROTO_VERSION: u16 = 0x0201;
+
+/// The first protocol version with the framing, messages and settings added in 2.1
+///
+/// That's checksummed frames (see `frame`), every control message past the basic playback ones (pings, all notes off, resetting and
+/// testing drives, status reports, timed event batches and configs sent while playing), the
+/// `SetConfig` settings older clients ignore, PWM and stepper ports, and the diagnostics and
+/// status the client sends back to servers at least this new.
+pub const EXTENSIONS_VERSION: u16 = 0x0201;
+
+/// Whether a peer using the given protocol version can talk to this one
+pub const fn is_compatible_version(proto_version: u16) -> bool {
+    proto_version >> 8 == PROTO_VERSION >> 8
+}
+
+/// The shortest tick interval a config can ask for in µs
+pub const MIN_TICK_US: u16 = 5;
+
+/// The longest tick interval a config can ask for in µs
+pub const MAX_TICK_US: u16 = 100;
+
+/// The lowest MIDI note the drives can play (C0)
+pub const MIN_PLAYABLE_NOTE: u8 = 12;
+
+/// The highest MIDI note the drives can play (B8)
+pub const MAX_PLAYABLE_NOTE: u8 = 119;
+
+/// The number of tracks a drive head moves over, which `SetConfig::home_track` must be below
+pub const FLOPPY_TRACKS: u8 = 80;
+
+/// How the drive heads are homed if the server doesn't specify it: three sweeps across every track
+/// and back, with 3ms step pulses and a 200ms pause between sweeps
+pub const DEFAULT_RESET_PASSES: u8 = 3;
+pub const DEFAULT_RESET_STEP_DELAY_US: u32 = 3_000;
+pub const DEFAULT_RESET_DWELL_MS: u32 = 200;
+
+/// The longest a config can make resetting the drives take in ms
+///
+/// The client doesn't answer anything else while it resets the drives, so this keeps well inside
+/// the time the server waits for a response.
+pub const MAX_RESET_MS: u64 = 5_000;
+
+/// The most events the server puts in a single `FloppierS2CMessage::MidiEventBatch`, which keeps
+/// the frames small enough for the client's read buffer
+pub const MAX_MIDI_EVENT_BATCH: usize = 64;
+
+/// The most timed events the client can hold on to before their time comes (the server should
+/// throttle using the occupancy the client reports, since the oldest events are played early to
+/// make room once it's full)
+pub const MAX_TIMED_EVENTS: usize = 256;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum FloppierS2CMessage {
-    Hello,
+    Hello {
+        proto_version: u16,
+    },
     SetConfig(SetConfig),
     MidiEvent(MidiEvent),
+    /// Events that happen at the same time, applied together and acknowledged with a single
+    /// `FloppierC2SMessage::MidiEventBatchAck`
+    MidiEventBatch(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] Vec<MidiEvent>),
+    /// Stop playing without forgetting the configuration
+    Pause,
+    Resume,
     End,
+    /// Checks that the client is still responding, answered with a `FloppierC2SMessage::Pong`
+    /// holding the same cookie. Valid in any state.
+    Ping(u32),
+    /// Silence every drive without changing the state (the song carries on with the next note).
+    /// Valid in any state.
+    AllNotesOff,
+    /// Move the drive heads back to their starting position (like after `SetConfig`). Only valid
+    /// while playing.
+    ResetDrives,
+    /// Events to apply once the client's clock reaches `timestamp_us`, acknowledged with a
+    /// `FloppierC2SMessage::TimedMidiEventBatchAck` as soon as they're queued
+    ///
+    /// The clock counts microseconds from when the client sent `FloppierC2SMessage::Ready`, and
+    /// stops while it's paused. Only valid while playing.
+    TimedMidiEventBatch {
+        timestamp_us: u64,
+        #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+        events: Vec<MidiEvent>,
+    },
+    /// Play a single note on a single drive for `duration_ms`, to check that the drive works and is
+    /// where the config expects it. Acknowledged with a `FloppierC2SMessage::TestDriveAck` as soon
+    /// as the note starts.
+    ///
+    /// The client goes into `ClientState::Calibrating`, so this can be sent straight after the
+    /// hello handshake without a config (or between test tones).
+    TestDrive {
+        index: u8,
+        note: u8,
+        duration_ms: u16,
+        /// The number of drives in the stack, needed to find the drive in the shift register
+        /// chain since there may not be a config yet (like `SetConfig::drive_count`)
+        drive_count: u8,
+    },
+    /// Asks how the client has been running, answered with a `FloppierC2SMessage::StatusReport`.
+    /// Valid in any state, and without a hello handshake first, so it doesn't disturb a session.
+    GetStatus,
+    /// Sent on a checksummed link in answer to a `FloppierC2SMessage::FrameError`, ahead of every
+    /// frame the client hasn't responded to yet, oldest first
+    ///
+    /// The client drops every frame after a corrupted one until it sees this, so the frames that
+    /// follow pick up exactly where it left off.
+    Retransmit,
+}
+
+impl FloppierS2CMessage {
+    pub fn kind(&self) -> FloppierS2CMessageKind {
+        match self {
+            Self::Hello { .. } => FloppierS2CMessageKind::Hello,
+            Self::SetConfig(_) => FloppierS2CMessageKind::SetConfig,
+            Self::MidiEvent(_) => FloppierS2CMessageKind::MidiEvent,
+            Self::MidiEventBatch(_) => FloppierS2CMessageKind::MidiEventBatch,
+            Self::Pause => FloppierS2CMessageKind::Pause,
+            Self::Resume => FloppierS2CMessageKind::Resume,
+            Self::End => FloppierS2CMessageKind::End,
+            Self::Ping(_) => FloppierS2CMessageKind::Ping,
+            Self::AllNotesOff => FloppierS2CMessageKind::AllNotesOff,
+            Self::ResetDrives => FloppierS2CMessageKind::ResetDrives,
+            Self::TimedMidiEventBatch { .. } => FloppierS2CMessageKind::TimedMidiEventBatch,
+            Self::TestDrive { .. } => FloppierS2CMessageKind::TestDrive,
+            Self::GetStatus => FloppierS2CMessageKind::GetStatus,
+            Self::Retransmit => FloppierS2CMessageKind::Retransmit,
+        }
+    }
+}
+
+/// Which `FloppierS2CMessage` was sent, without its contents (used for error reporting)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FloppierS2CMessageKind {
+    Hello,
+    SetConfig,
+    MidiEvent,
+    MidiEventBatch,
+    Pause,
+    Resume,
+    End,
+    Ping,
+    AllNotesOff,
+    ResetDrives,
+    TimedMidiEventBatch,
+    TestDrive,
+    GetStatus,
+    Retransmit,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum FloppierC2SMessage {
-    HelloAck,
+    HelloAck {
+        proto_version: u16,
+    },
     SetConfigAck,
     Ready,
     MidiEventAck,
+    MidiEventBatchAck,
+    PauseAck,
+    ResumeAck,
+    /// A frame was corrupted, so it should be sent again (on a checksummed link, along with every
+    /// frame after it, see `FloppierS2CMessage::Retransmit`)
+    FrameError,
     EndAck,
-    Error(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] String),
+    Error {
+        kind: FloppierErrorKind,
+        /// Extra human readable information about the error, if the client has any
+        #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+        detail: Option<String>,
+    },
+    Pong(u32),
+    AllNotesOffAck,
+    ResetDrivesAck,
+    /// How the client kept up with its drive timer since the hello, sent just before `EndAck`
+    Diagnostics {
+        /// The number of timer ticks that took longer than the timer resolution
+        overruns: u32,
+        /// The most any tick went over the timer resolution by
+        max_overrun_us: u32,
+    },
+    TimedMidiEventBatchAck {
+        /// How many timed events the client has queued, including the ones just received
+        queued: u16,
+    },
+    /// Acknowledges a `MidiEvent`, `MidiEventBatch` or `TimedMidiEventBatch` in place of its usual
+    /// ack, with how much more the client can take so the server can hold back until there's room
+    Status {
+        /// How many more timed events the client can queue
+        queue_free: u16,
+        /// How many more bytes the client's read buffer can hold before it overflows
+        buffer_free: u16,
+    },
+    TestDriveAck,
+    StatusReport(StatusReport),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Why the client rejected a message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FloppierErrorKind {
+    /// The message isn't valid in the client's current state. The client goes back to waiting for
+    /// a hello message.
+    UnexpectedMessage {
+        state: ClientState,
+        got: FloppierS2CMessageKind,
+    },
+    /// The server's protocol version isn't compatible with the client's
+    IncompatibleVersion { server: u16, client: u16 },
+    /// A drive in the config doesn't exist. The client is still waiting for a valid config.
+    DriveIndexOutOfRange { index: u8, count: u8 },
+    /// A PWM port in the config is on a GPIO pin the client can't use for it (one that doesn't
+    /// exist, drives the shift register or shares its PWM slice with another port). The client is
+    /// still waiting for a valid config.
+    InvalidPwmPort { gpio: u8 },
+    /// The tick interval in the config is outside `MIN_TICK_US..=MAX_TICK_US`. The client is still
+    /// waiting for a valid config.
+    InvalidTickInterval { tick_us: u16 },
+    /// The client received more data than it could buffer and had to throw it away
+    BufferOverflow,
+    /// A frame arrived intact but didn't hold a message the client understands
+    DeserializeFailed,
+}
+
+/// How the client has been running since it started, for debugging without a debug probe
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StatusReport {
+    /// How long the client has been running for
+    pub uptime_us: u64,
+    /// The number of timer ticks that took longer than the timer resolution
+    pub tick_overruns: u32,
+    /// The longest any timer tick took
+    pub max_tick_us: u32,
+    /// How many bytes of the heap are free
+    pub heap_free: u32,
+    pub state: ClientState,
+    /// How many MIDI events the client has played
+    pub events_processed: u32,
+}
+
+/// The state of the client's connection with the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClientState {
+    WaitingForHello,
+    WaitingForSetConfig,
+    PlayingMidiStream,
+    Paused,
+    /// Playing test tones on the drives one at a time, without a song
+    Calibrating,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct SetConfig {
     /// Strategy to use to resolve parallel notes
     pub parallel_mode: ParallelMode,
 
-    /// Whether or not to move the drive heads while playing
+    /// Whether `ParallelMode::Distribute` cuts off the oldest note on a channel's drives to play a
+    /// new one when they are all busy, instead of stacking the new note on one of them
+    #[serde(default)]
+    pub steal_oldest: bool,
+
+    /// Whether or not to move the drive heads while playing (for the drives not in
+    /// `drive_movement`)
     pub movement: bool,
 
+    /// Whether to move the head of each drive while playing, by index, overriding `movement`
+    #[serde(default)]
+    pub drive_movement: Vec<bool>,
+
     /// The number of drives in the stack (used for bit timing)
     pub drive_count: u8,
 
     /// Map of track numbers to tracks which map channel numbers to ports
     #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub tracks: BTreeMap<u16, BTreeMap<u8, Vec<u8>>>,
+
+    /// What plays each port in `tracks`, by port number (port `n` is drive `n` if empty)
+    #[serde(default)]
+    pub ports: Vec<PortKind>,
+
+    /// How many cents to tune each port by, by port number (for a chorus effect between ports
+    /// doubling the same part, with the ports past the end in tune)
+    #[serde(default)]
+    pub detune_cents: Vec<i16>,
+
+    /// How long each note of a chord is played for before switching to the next one when using
+    /// `ParallelMode::Synthesize` (the client picks a default if not set)
+    #[serde(default)]
+    pub synthesize_interval_us: Option<u32>,
+
+    /// Notes played with a lower velocity than this are ignored (to skip ghost notes)
+    #[serde(default)]
+    pub velocity_threshold: u8,
+
+    /// Whether to play quieter notes softer by selecting the drive for less of the time
+    #[serde(default)]
+    pub velocity_dynamics: bool,
+
+    /// Whether to let notes die away after a note off, slowing the steps over a time set by its
+    /// release velocity (a note off with no velocity still cuts the note off)
+    #[serde(default)]
+    pub release_ramp: bool,
+
+    /// How long a drive plays a note before a new note on can replace it, so notes piling onto
+    /// one drive don't retrigger it so fast it sounds like a machine gun (never held back if 0)
+    ///
+    /// A note on that comes too soon is still held, so the drive falls back to it once the
+    /// current note is released.
+    #[serde(default)]
+    pub min_note_us: u32,
+
+    /// How long the client waits without receiving anything before it decides the connection is
+    /// dead, silences its drives and waits for a new hello (never if not set)
+    ///
+    /// The server must ping the client more often than this while playing.
+    #[serde(default)]
+    pub link_timeout_ms: Option<u32>,
+
+    /// How the client homes the drive heads after a config and on `FloppierS2CMessage::ResetDrives`
+    #[serde(default)]
+    pub reset: ResetTiming,
+
+    /// Whether to step the drive heads back to `home_track` over the second after
+    /// `FloppierS2CMessage::End`, since the track a head is left on changes how the next song
+    /// sounds. A hello cancels parking, leaving the heads where they are.
+    #[serde(default)]
+    pub park_on_end: bool,
+
+    /// The track to park the drive heads on (the client picks the middle of the disk if not set)
+    #[serde(default)]
+    pub home_track: Option<u8>,
+
+    /// How often the client ticks its instruments in µs (the client picks a default if not set)
+    ///
+    /// Shorter ticks time the notes more accurately, but leave less time to tick each drive in.
+    #[serde(default)]
+    pub tick_us: Option<u16>,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+/// What plays the notes sent to a port
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "lowercase")]
+pub enum PortKind {
+    /// A floppy drive, by its index in the shift register chain
+    Drive(u8),
+
+    /// A stepper motor driver on an output of the shift register chain (by its index, like a
+    /// drive), which only uses the step and direction signals
+    Stepper {
+        index: u8,
+        /// How many steps the motor takes for each step a drive would take
+        steps_per_toggle: u8,
+    },
+
+    /// A piezo buzzer or speaker driven by PWM from a GPIO pin (which gets its PWM slice to
+    /// itself)
+    Pwm { gpio: u8 },
+}
+
+/// How the drive heads are homed, to suit slower or faster drives (the client picks a default for
+/// anything not set)
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(default)]
+pub struct ResetTiming {
+    /// How many times the heads sweep across every track and back
+    pub passes: Option<u8>,
+
+    /// How long each half of a step pulse lasts
+    pub step_delay_us: Option<u32>,
+
+    /// How long to wait at the end of each sweep before changing direction
+    pub dwell_ms: Option<u32>,
+}
+
+impl ResetTiming {
+    /// How long resetting the drives takes in µs, with the defaults for anything not set
+    pub fn duration_us(&self) -> u64 {
+        let passes = self.passes.unwrap_or(DEFAULT_RESET_PASSES) as u64;
+        let step_delay_us = self.step_delay_us.unwrap_or(DEFAULT_RESET_STEP_DELAY_US) as u64;
+        let dwell_ms = self.dwell_ms.unwrap_or(DEFAULT_RESET_DWELL_MS) as u64;
+
+        // Each step is a pulse of two halves
+        passes * (FLOPPY_TRACKS as u64 * 2 * step_delay_us + dwell_ms * 1_000)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[serde(rename_all = "lowercase")]
 pub enum ParallelMode {
@@ -53,7 +415,8 @@ pub enum ParallelMode {
     #[default]
     Collapse,
 
-    /// Synthesize a chord by combining the notes and sampling the composed sinusoid
+    /// Arpeggiate a chord on each drive, switching to the next held note every
+    /// `SetConfig::synthesize_interval_us` so it sounds like the notes are played together
     Synthesize,
 
     /// Distribute the notes across the available drives
@@ -61,7 +424,7 @@ pub enum ParallelMode {
 }
 
 /// An event sent to the client with midi data
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct MidiEvent {
     pub track: u16,
@@ -70,7 +433,7 @@ pub struct MidiEvent {
 }
 
 /// A limited set of MIDI messages that can be sent to the client
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum LimitedMidiMessage {
     NoteOn { note: u8, velocity: u8 },
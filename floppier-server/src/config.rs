@@ -1,12 +1,19 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use jsonc_parser::ParseOptions;
-use serde::Deserialize;
+use serde::{de::Error as _, Deserialize, Deserializer};
 
-use floppier_proto::ParallelMode;
-
-use crate::FloppierArgs;
+use floppier_proto::{
+    LimitedMidiMessage, ParallelMode, PortKind, ResetTiming, FLOPPY_TRACKS, MAX_PLAYABLE_NOTE,
+    MAX_RESET_MS, MAX_TICK_US, MIN_PLAYABLE_NOTE, MIN_TICK_US,
+};
+use floppier_server::midi::{fold_note, AbsoluteMidiEvent, PercussionMode};
 
 #[derive(Deserialize, Debug)]
 pub struct SongConfig {
@@ -25,33 +32,1735 @@ pub struct MidiConfig {
     /// Strategy to use to resolve parallel notes
     #[serde(default)]
     pub parallel_mode: ParallelMode,
+
+    /// Whether the distribute parallel mode cuts off the oldest note when every drive is busy,
+    /// rather than stacking the new note on a drive like the collapse mode
+    #[serde(default)]
+    pub steal_oldest: bool,
+
+    /// How long to play each note of a chord for when using the synthesize parallel mode
+    #[serde(default)]
+    pub synthesize_interval_us: Option<u32>,
+
+    /// Whether to send control change messages (volume, all notes off, etc.) to the client
+    #[serde(default)]
+    pub control_changes: bool,
+
+    /// Notes played with a lower velocity than this are ignored by the client
+    #[serde(default)]
+    pub velocity_threshold: u8,
+
+    /// Notes played with a lower velocity than this are dropped from the MIDI file, along with
+    /// their note offs (to filter out ghost notes)
+    #[serde(default)]
+    pub min_velocity: u8,
+
+    /// Whether to hold notes while the sustain pedal (control 64) is down, since the drives can't
+    /// sustain notes themselves
+    #[serde(default)]
+    pub sustain: bool,
+
+    /// Whether to release the notes a track never releases at the end of the track, instead of
+    /// leaving them playing until the end of the song
+    #[serde(default)]
+    pub release_dangling_notes: bool,
+
+    /// Whether to play quieter notes softer
+    #[serde(default)]
+    pub velocity_dynamics: bool,
+
+    /// Whether to let notes die away after their note offs instead of stopping dead, for longer the
+    /// higher the release velocity
+    #[serde(default)]
+    pub release_ramp: bool,
+
+    /// How long a drive plays a note before the next note on can replace it, to stop notes piling
+    /// onto one drive from retriggering it too fast (never held back if 0)
+    #[serde(default)]
+    pub min_note_us: u32,
+
+    /// Semitones to transpose every note by, unless a track or channel gives its own
+    #[serde(default)]
+    pub transpose: Option<i8>,
+
+    /// Whether to move notes outside of the fold window by whole octaves until they're inside it
+    /// (after transposing)
+    #[serde(default)]
+    pub fold_octaves: bool,
+
+    /// The notes to fold notes into, unless a track gives its own
+    #[serde(default)]
+    pub fold_window: FoldWindow,
+
+    /// How to play the drums on channel 10 (`"drop"`, `"map"`, `{ "fixed_pitch": <note> }`,
+    /// `"rhythm"` or `{ "click": { "note": <note>, "gate_ticks": <ticks> } }`). They're dropped if
+    /// not set.
+    #[serde(default)]
+    pub percussion: PercussionMode,
 }
 
+/// A client and how the tracks of the MIDI file are mapped to its drives
+///
+/// Tracks are given by their number or by their name in the MIDI file. Named tracks are kept aside
+/// until `SongConfig::resolve_track_names` finds their numbers.
 #[derive(Deserialize, Debug)]
+#[serde(from = "FloppyDriveRepr")]
 pub struct FloppyDrive {
     pub id: u16,
     pub drive_count: u8,
-    pub movement: bool,
-    pub tracks: BTreeMap<u16, BTreeMap<u8, Vec<u8>>>,
+    pub movement: Movement,
+    pub tracks: BTreeMap<u16, TrackConfig>,
+    pub named_tracks: BTreeMap<String, TrackConfig>,
+    /// Whether to map the channels of the MIDI file to the drives automatically (the tracks are
+    /// left out when this is set)
+    pub auto: bool,
+    /// How the drive heads are homed, for drives that need longer to seek or can go faster
+    pub reset: ResetTiming,
+    /// The GPIO pins of the piezo buzzers or speakers played by PWM, which take the ports after
+    /// the drives
+    pub pwm: Vec<u8>,
+    /// What's on each output of the shift register chain that isn't a floppy drive, by port
+    pub ports: BTreeMap<u8, PortConfig>,
+    /// How often the client ticks its instruments in µs, shorter for better pitch accuracy on
+    /// small stacks or longer to give big stacks time to tick every drive
+    pub tick_us: Option<u16>,
+    /// Whether to step the heads back to the home track when a song ends, so every song starts
+    /// from the same place
+    pub park_on_end: bool,
+    /// The track to park the heads on (the middle of the disk if not set)
+    pub home_track: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct FloppyDriveRepr {
+    id: u16,
+    drive_count: u8,
+    movement: Movement,
+    #[serde(default)]
+    tracks: BTreeMap<String, TrackConfig>,
+    #[serde(default)]
+    auto: bool,
+    #[serde(default)]
+    reset: ResetTiming,
+    #[serde(default)]
+    pwm: Vec<u8>,
+    #[serde(default)]
+    ports: BTreeMap<u8, PortConfig>,
+    #[serde(default)]
+    tick_us: Option<u16>,
+    #[serde(default)]
+    park_on_end: bool,
+    #[serde(default)]
+    home_track: Option<u8>,
+}
+
+/// The instrument on an output of the shift register chain
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortConfig {
+    pub kind: InstrumentKind,
+    /// How many steps a stepper takes for each step a drive would take, for drivers set to
+    /// microstep (1 if not set)
+    #[serde(default)]
+    pub steps_per_toggle: Option<u8>,
 }
 
-pub fn parse_song_config(args: &FloppierArgs) -> Result<SongConfig> {
-    if !args.path.exists() {
-        return Err(anyhow::anyhow!(
-            "song configuration file `{}` does not exist",
-            args.path.display()
-        ));
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstrumentKind {
+    #[default]
+    Floppy,
+    Stepper,
+}
+
+/// Whether to move the drive heads while playing, for every drive or for each drive by index (to
+/// keep some drives quiet while others put on a show)
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Movement {
+    All(bool),
+    PerDrive(Vec<bool>),
+}
+
+impl From<FloppyDriveRepr> for FloppyDrive {
+    fn from(repr: FloppyDriveRepr) -> Self {
+        let mut tracks = BTreeMap::new();
+        let mut named_tracks = BTreeMap::new();
+
+        for (key, track_config) in repr.tracks {
+            match key.parse::<u16>() {
+                Ok(track) => {
+                    tracks.insert(track, track_config);
+                }
+                Err(_) => {
+                    named_tracks.insert(key, track_config);
+                }
+            }
+        }
+
+        Self {
+            id: repr.id,
+            drive_count: repr.drive_count,
+            movement: repr.movement,
+            tracks,
+            named_tracks,
+            auto: repr.auto,
+            reset: repr.reset,
+            pwm: repr.pwm,
+            ports: repr.ports,
+            tick_us: repr.tick_us,
+            park_on_end: repr.park_on_end,
+            home_track: repr.home_track,
+        }
+    }
+}
+
+impl FloppyDrive {
+    /// The number of ports the tracks can be mapped to: one for each drive, then one for each PWM
+    /// voice
+    pub fn port_count(&self) -> u8 {
+        self.drive_count.saturating_add(self.pwm.len() as u8)
+    }
+
+    /// Whether any of the outputs of the shift register chain are steppers rather than drives
+    pub fn has_steppers(&self) -> bool {
+        self.ports
+            .values()
+            .any(|port| port.kind == InstrumentKind::Stepper)
     }
 
-    let config_file = std::fs::read_to_string(&args.path)
-        .with_context(|| format!("could not read file `{}`", args.path.display()))?;
+    /// What plays each port, or nothing if the ports are just the drives (which every client
+    /// assumes)
+    pub fn port_kinds(&self) -> Vec<PortKind> {
+        if self.pwm.is_empty() && !self.has_steppers() {
+            return Vec::new();
+        }
+
+        (0..self.drive_count)
+            .map(|index| match self.ports.get(&index) {
+                Some(PortConfig {
+                    kind: InstrumentKind::Stepper,
+                    steps_per_toggle,
+                }) => PortKind::Stepper {
+                    index,
+                    steps_per_toggle: steps_per_toggle.unwrap_or(1),
+                },
+                _ => PortKind::Drive(index),
+            })
+            .chain(self.pwm.iter().map(|&gpio| PortKind::Pwm { gpio }))
+            .collect()
+    }
+
+    /// How many cents each port is tuned by, or nothing if every port is in tune
+    ///
+    /// Each port takes its detune from the channels mapped to it (which `SongConfig::validate`
+    /// checks agree).
+    pub fn detune_cents(&self) -> Vec<i16> {
+        let mut detune_cents = vec![0; self.port_count() as usize];
+
+        let channels = self
+            .tracks
+            .values()
+            .chain(self.named_tracks.values())
+            .flat_map(|track_config| track_config.channels.values());
+
+        for channel_config in channels {
+            for (&port, &cents) in channel_config.ports.iter().zip(&channel_config.detune) {
+                if let Some(detune) = detune_cents.get_mut(port as usize) {
+                    *detune = cents;
+                }
+            }
+        }
+
+        if detune_cents.iter().all(|cents| *cents == 0) {
+            return Vec::new();
+        }
+
+        detune_cents
+    }
+
+    /// The ports of each channel as they'd be written in a configuration file's `tracks`
+    ///
+    /// Only the ports are written, so this is meant for mappings without any other settings.
+    pub fn tracks_json(&self) -> String {
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|(track, track_config)| {
+                let channels = track_config
+                    .channels
+                    .iter()
+                    .map(|(channel, channel_config)| {
+                        format!("\"{}\": {:?}", channel, channel_config.ports)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                format!("  \"{}\": {{ {} }}", track, channels)
+            })
+            .collect::<Vec<_>>()
+            .join(",\n");
+
+        format!("{{\n{}\n}}", tracks)
+    }
+}
+
+/// A range of notes to fold notes into, inclusive
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FoldWindow {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl Default for FoldWindow {
+    /// C2 to B5, where the drives sound best
+    fn default() -> Self {
+        Self { low: 36, high: 83 }
+    }
+}
+
+impl FoldWindow {
+    pub fn notes(self) -> RangeInclusive<u8> {
+        self.low..=self.high
+    }
+
+    fn validate(self) -> Result<()> {
+        if self.low < MIN_PLAYABLE_NOTE || self.high > MAX_PLAYABLE_NOTE {
+            bail!(
+                "fold window {}-{} goes outside of the playable notes {}-{}",
+                self.low,
+                self.high,
+                MIN_PLAYABLE_NOTE,
+                MAX_PLAYABLE_NOTE
+            );
+        }
+
+        // Every note has to have an octave inside the window
+        if self.high < self.low || self.high - self.low < 11 {
+            bail!(
+                "fold window {}-{} is narrower than an octave",
+                self.low,
+                self.high
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The channels of a track that are mapped to ports, and how to transpose the track
+///
+/// Written as a map of channel numbers to channels, with optional `transpose` and `octave_shift`
+/// entries alongside the channels.
+#[derive(Debug, Default)]
+pub struct TrackConfig {
+    pub channels: BTreeMap<u8, ChannelConfig>,
+
+    /// Semitones to transpose the track by, unless a channel gives its own
+    pub transpose: Option<i8>,
+
+    /// Octaves to shift the track by, unless a channel gives its own
+    pub octave_shift: Option<i8>,
+
+    /// The notes to fold the track into, instead of the global fold window
+    pub fold_window: Option<FoldWindow>,
+}
 
-    let config: SongConfig = serde_json::from_value(
-        jsonc_parser::parse_to_serde_value(&config_file, &ParseOptions::default())
-            .with_context(|| format!("could not parse file `{}`", args.path.display()))?
-            .unwrap(),
-    )
-    .with_context(|| "configuration file format is invalid")?;
+/// The ports a channel is played on, and how to transpose and filter the channel
+///
+/// Written as just the list of ports, or as an object with the ports under `ports`.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(from = "ChannelConfigRepr")]
+pub struct ChannelConfig {
+    pub ports: Vec<u8>,
+
+    /// Semitones to transpose the channel by
+    pub transpose: Option<i8>,
+
+    /// Octaves to shift the channel by
+    pub octave_shift: Option<i8>,
+
+    /// Notes quieter than this are dropped, instead of using the global minimum velocity
+    pub min_velocity: Option<u8>,
+
+    /// Cents to tune each of the ports by, in the same order as `ports` (for a chorus effect
+    /// between ports doubling the channel)
+    pub detune: Vec<i16>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChannelConfigRepr {
+    Ports(Vec<u8>),
+    Full {
+        ports: Vec<u8>,
+        #[serde(default)]
+        transpose: Option<i8>,
+        #[serde(default)]
+        octave_shift: Option<i8>,
+        #[serde(default)]
+        min_velocity: Option<u8>,
+        #[serde(default)]
+        detune: Vec<i16>,
+    },
+}
+
+impl From<ChannelConfigRepr> for ChannelConfig {
+    fn from(repr: ChannelConfigRepr) -> Self {
+        match repr {
+            ChannelConfigRepr::Ports(ports) => Self {
+                ports,
+                ..Default::default()
+            },
+            ChannelConfigRepr::Full {
+                ports,
+                transpose,
+                octave_shift,
+                min_velocity,
+                detune,
+            } => Self {
+                ports,
+                transpose,
+                octave_shift,
+                min_velocity,
+                detune,
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TrackConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // The channel numbers share the map with the named entries, which serde can't derive
+        let entries = serde_json::Map::<String, serde_json::Value>::deserialize(deserializer)?;
+
+        let mut track = TrackConfig::default();
+
+        for (key, value) in entries {
+            match key.as_str() {
+                "transpose" => {
+                    track.transpose = serde_json::from_value(value).map_err(D::Error::custom)?;
+                }
+                "octave_shift" => {
+                    track.octave_shift = serde_json::from_value(value).map_err(D::Error::custom)?;
+                }
+                "fold_window" => {
+                    track.fold_window = serde_json::from_value(value).map_err(D::Error::custom)?;
+                }
+                _ => {
+                    let channel = key.parse::<u8>().map_err(|_| {
+                        D::Error::custom(format!("invalid channel number `{}`", key))
+                    })?;
+
+                    let channel_config = serde_json::from_value(value).map_err(D::Error::custom)?;
+
+                    track.channels.insert(channel, channel_config);
+                }
+            }
+        }
+
+        Ok(track)
+    }
+}
+
+/// A list of songs to play back to back
+#[derive(Deserialize, Debug)]
+pub struct PlaylistConfig {
+    /// The songs to play, in order unless shuffled
+    pub songs: Vec<PlaylistEntry>,
+
+    /// Whether to play the songs in a random order
+    #[serde(default)]
+    pub shuffle: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlaylistEntry {
+    /// Path to the song configuration file
+    pub path: PathBuf,
+
+    /// How long to wait after the song before playing the next one
+    #[serde(default)]
+    pub gap_seconds: Option<f64>,
+}
+
+pub fn parse_song_config(path: &Path) -> Result<SongConfig> {
+    let value = read_config_file(path)?;
+
+    let config: SongConfig =
+        serde_json::from_value(value).with_context(|| "configuration file format is invalid")?;
+
+    config.validate()?;
 
     Ok(config)
 }
+
+/// Reads a playlist from either a playlist file or a directory of song configuration files (played
+/// in order of their file names)
+///
+/// Returns `None` if the path is a song configuration file rather than a playlist.
+pub fn parse_playlist(path: &Path) -> Result<Option<PlaylistConfig>> {
+    if path.is_dir() {
+        let mut songs = std::fs::read_dir(path)
+            .with_context(|| format!("could not read directory `{}`", path.display()))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+
+        songs.retain(|path| {
+            path.extension().is_some_and(|extension| {
+                SUPPORTED_EXTENSIONS.contains(&format!(".{}", extension.to_string_lossy()).as_str())
+            })
+        });
+        songs.sort();
+
+        return Ok(Some(PlaylistConfig {
+            songs: songs
+                .into_iter()
+                .map(|path| PlaylistEntry {
+                    path,
+                    gap_seconds: None,
+                })
+                .collect(),
+            shuffle: false,
+        }));
+    }
+
+    let value = read_config_file(path)?;
+
+    // Song configurations never have a list of songs
+    if value.get("songs").is_none() {
+        return Ok(None);
+    }
+
+    let playlist: PlaylistConfig =
+        serde_json::from_value(value).with_context(|| "playlist file format is invalid")?;
+
+    Ok(Some(playlist))
+}
+
+fn read_config_file(path: &Path) -> Result<serde_json::Value> {
+    if !path.exists() {
+        bail!("configuration file `{}` does not exist", path.display());
+    }
+
+    let config_file = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read file `{}`", path.display()))?;
+
+    parse_config_value(path, &config_file)
+        .with_context(|| format!("could not parse file `{}`", path.display()))
+}
+
+impl SongConfig {
+    /// Checks the parts of the configuration the client would otherwise reject after connecting
+    fn validate(&self) -> Result<()> {
+        self.midi.fold_window.validate()?;
+
+        for floppy_drive in &self.floppy_drives {
+            if let Movement::PerDrive(movement) = &floppy_drive.movement {
+                if movement.len() != floppy_drive.drive_count as usize {
+                    bail!(
+                        "floppy drive {} gives the movement of {} drives, but has {} drives",
+                        floppy_drive.id,
+                        movement.len(),
+                        floppy_drive.drive_count
+                    );
+                }
+            }
+
+            if let Some(tick_us) = floppy_drive.tick_us {
+                if !(MIN_TICK_US..=MAX_TICK_US).contains(&tick_us) {
+                    bail!(
+                        "floppy drive {} ticks every {}µs, but the tick interval must be from {}µs to {}µs",
+                        floppy_drive.id,
+                        tick_us,
+                        MIN_TICK_US,
+                        MAX_TICK_US
+                    );
+                }
+            }
+
+            let reset_ms = floppy_drive.reset.duration_us() / 1_000;
+
+            if reset_ms > MAX_RESET_MS {
+                bail!(
+                    "floppy drive {} takes {}ms to reset, but resetting can take at most {}ms",
+                    floppy_drive.id,
+                    reset_ms,
+                    MAX_RESET_MS
+                );
+            }
+
+            if let Some(home_track) = floppy_drive.home_track {
+                if home_track >= FLOPPY_TRACKS {
+                    bail!(
+                        "floppy drive {} parks on track {}, but the drives only have {} tracks",
+                        floppy_drive.id,
+                        home_track,
+                        FLOPPY_TRACKS
+                    );
+                }
+            }
+
+            for (port, port_config) in &floppy_drive.ports {
+                if *port >= floppy_drive.drive_count {
+                    bail!(
+                        "floppy drive {} gives the kind of port {}, but only has {} drives (PWM voices can't have a kind)",
+                        floppy_drive.id,
+                        port,
+                        floppy_drive.drive_count
+                    );
+                }
+
+                match (port_config.kind, port_config.steps_per_toggle) {
+                    (InstrumentKind::Floppy, Some(_)) => bail!(
+                        "floppy drive {} gives port {} steps per toggle, but it isn't a stepper",
+                        floppy_drive.id,
+                        port
+                    ),
+                    (InstrumentKind::Stepper, Some(0)) => bail!(
+                        "floppy drive {} gives port {} zero steps per toggle",
+                        floppy_drive.id,
+                        port
+                    ),
+                    _ => {}
+                }
+            }
+
+            if floppy_drive.auto
+                && !(floppy_drive.tracks.is_empty() && floppy_drive.named_tracks.is_empty())
+            {
+                bail!(
+                    "floppy drive {} maps its tracks automatically, so it can't map any itself",
+                    floppy_drive.id
+                );
+            }
+
+            let tracks = floppy_drive
+                .tracks
+                .iter()
+                .map(|(track, track_config)| (track.to_string(), track_config))
+                .chain(
+                    floppy_drive
+                        .named_tracks
+                        .iter()
+                        .map(|(name, track_config)| (format!("`{}`", name), track_config)),
+                );
+
+            // The detune of each port, and the track and channel that gave it
+            let mut port_detunes = BTreeMap::new();
+
+            for (track, track_config) in tracks {
+                if let Some(fold_window) = track_config.fold_window {
+                    fold_window
+                        .validate()
+                        .with_context(|| format!("invalid config for track {}", track))?;
+                }
+
+                for (channel, channel_config) in &track_config.channels {
+                    if !channel_config.detune.is_empty()
+                        && channel_config.detune.len() != channel_config.ports.len()
+                    {
+                        bail!(
+                            "floppy drive {} gives track {} channel {} the detune of {} ports, but maps it to {} ports",
+                            floppy_drive.id,
+                            track,
+                            channel,
+                            channel_config.detune.len(),
+                            channel_config.ports.len()
+                        );
+                    }
+
+                    for (&port, &cents) in channel_config.ports.iter().zip(&channel_config.detune) {
+                        let (other_cents, other_track, other_channel) = port_detunes
+                            .entry(port)
+                            .or_insert_with(|| (cents, track.clone(), *channel));
+
+                        if *other_cents != cents {
+                            bail!(
+                                "floppy drive {} detunes port {} by {} cents for track {} channel {}, but by {} cents for track {} channel {}",
+                                floppy_drive.id,
+                                port,
+                                cents,
+                                track,
+                                channel,
+                                other_cents,
+                                other_track,
+                                other_channel
+                            );
+                        }
+                    }
+
+                    let Some(port) = channel_config
+                        .ports
+                        .iter()
+                        .find(|port| **port >= floppy_drive.port_count())
+                    else {
+                        continue;
+                    };
+
+                    let ports = match floppy_drive.pwm.len() {
+                        0 => format!("{} drives", floppy_drive.drive_count),
+                        pwm => {
+                            format!("{} drives and {} PWM voices", floppy_drive.drive_count, pwm)
+                        }
+                    };
+
+                    bail!(
+                        "floppy drive {} maps track {} channel {} to port {}, but only has {}",
+                        floppy_drive.id,
+                        track,
+                        channel,
+                        port,
+                        ports
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SongConfig {
+    /// Maps the tracks given by name to the numbers of the tracks with those names in the MIDI file
+    ///
+    /// Fails if a name matches no track or more than one, or if the track is also given by number.
+    pub fn resolve_track_names(&mut self, track_names: &BTreeMap<u16, String>) -> Result<()> {
+        for floppy_drive in &mut self.floppy_drives {
+            for (name, track_config) in std::mem::take(&mut floppy_drive.named_tracks) {
+                let matches = track_names
+                    .iter()
+                    .filter(|(_, track_name)| **track_name == name)
+                    .map(|(&track, _)| track)
+                    .collect::<Vec<_>>();
+
+                let track = match matches[..] {
+                    [track] => track,
+                    [] if track_names.is_empty() => {
+                        bail!(
+                            "there is no track named `{}`, the MIDI file's tracks have no names",
+                            name
+                        )
+                    }
+                    [] => bail!(
+                        "there is no track named `{}`, the MIDI file has tracks {}",
+                        name,
+                        list_track_names(track_names)
+                    ),
+                    _ => bail!(
+                        "the track name `{}` is ambiguous, the MIDI file has tracks {}",
+                        name,
+                        list_track_names(track_names)
+                    ),
+                };
+
+                if floppy_drive.tracks.contains_key(&track) {
+                    bail!(
+                        "track {} is mapped both by its number and as `{}`",
+                        track,
+                        name
+                    );
+                }
+
+                floppy_drive.tracks.insert(track, track_config);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The number of semitones to move the notes of a channel by
+    ///
+    /// The transpose and octave shift of a channel override those of its track, which override the
+    /// global transpose.
+    pub fn note_shift(&self, track: u16, channel: u8) -> i16 {
+        let track_config = self.floppy_drives[0].tracks.get(&track);
+        let channel_config = track_config.and_then(|track| track.channels.get(&channel));
+
+        let transpose = channel_config
+            .and_then(|channel| channel.transpose)
+            .or(track_config.and_then(|track| track.transpose))
+            .or(self.midi.transpose)
+            .unwrap_or(0);
+
+        let octave_shift = channel_config
+            .and_then(|channel| channel.octave_shift)
+            .or(track_config.and_then(|track| track.octave_shift))
+            .unwrap_or(0);
+
+        transpose as i16 + octave_shift as i16 * 12
+    }
+
+    /// The channels that override the global minimum velocity, keyed by (track, channel)
+    pub fn channel_min_velocity(&self) -> BTreeMap<(u16, u8), u8> {
+        let mut min_velocity = BTreeMap::new();
+
+        for (&track, track_config) in &self.floppy_drives[0].tracks {
+            for (&channel, channel_config) in &track_config.channels {
+                if let Some(velocity) = channel_config.min_velocity {
+                    min_velocity.insert((track, channel), velocity);
+                }
+            }
+        }
+
+        min_velocity
+    }
+
+    /// Applies the transposition of each track and channel to the notes of the events
+    pub fn transpose_events(&self, events: &mut [AbsoluteMidiEvent]) {
+        for event in events {
+            if let LimitedMidiMessage::NoteOn { note, .. }
+            | LimitedMidiMessage::NoteOff { note, .. } = &mut event.message
+            {
+                *note = shift_note(*note, self.note_shift(event.track, event.channel));
+            }
+        }
+    }
+
+    /// Moves the notes of the events outside of their track's fold window by whole octaves until
+    /// they're inside it, returning how many notes were moved and by how much
+    pub fn fold_events(&self, events: &mut [AbsoluteMidiEvent]) -> FoldReport {
+        let mut report = FoldReport::default();
+
+        for event in events {
+            let fold_window = self.floppy_drives[0]
+                .tracks
+                .get(&event.track)
+                .and_then(|track| track.fold_window)
+                .unwrap_or(self.midi.fold_window);
+
+            match &mut event.message {
+                LimitedMidiMessage::NoteOn { note, velocity } => {
+                    let folded = fold_note(*note, fold_window.notes());
+
+                    if folded != *note && *velocity > 0 {
+                        let octaves = (folded as i8 - *note as i8) / 12;
+                        *report.octaves.entry(octaves).or_default() += 1;
+                    }
+
+                    *note = folded;
+                }
+                LimitedMidiMessage::NoteOff { note, .. } => {
+                    *note = fold_note(*note, fold_window.notes());
+                }
+                _ => {}
+            }
+        }
+
+        report
+    }
+}
+
+/// Lists track names like `1 "Piano", 2 "Bass"`
+fn list_track_names(track_names: &BTreeMap<u16, String>) -> String {
+    track_names
+        .iter()
+        .map(|(track, name)| format!("{} {:?}", track, name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// How many notes were folded into their fold windows
+#[derive(Debug, Default)]
+pub struct FoldReport {
+    /// The number of notes moved by each number of octaves (negative being down)
+    pub octaves: BTreeMap<i8, usize>,
+}
+
+impl FoldReport {
+    pub fn folded_notes(&self) -> usize {
+        self.octaves.values().sum()
+    }
+}
+
+impl Display for FoldReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.octaves.is_empty() {
+            return write!(f, "No notes needed folding");
+        }
+
+        write!(f, "Folded {} notes:", self.folded_notes())?;
+
+        for (i, (octaves, count)) in self.octaves.iter().enumerate() {
+            let direction = if *octaves > 0 { "up" } else { "down" };
+            let plural = if octaves.abs() == 1 { "" } else { "s" };
+            let separator = if i == 0 { "" } else { "," };
+
+            write!(
+                f,
+                "{} {} {} {} octave{}",
+                separator,
+                count,
+                direction,
+                octaves.abs(),
+                plural
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Moves a note by some semitones, bringing it back by whole octaves if that takes it outside of the
+/// MIDI note range
+fn shift_note(note: u8, semitones: i16) -> u8 {
+    let mut shifted = note as i16 + semitones;
+
+    while shifted < 0 {
+        shifted += 12;
+    }
+
+    while shifted > 127 {
+        shifted -= 12;
+    }
+
+    shifted as u8
+}
+
+/// How the tracks and channels of a MIDI file line up with the ones a song configuration maps to
+/// drives
+#[derive(Debug, Default)]
+pub struct MappingReport {
+    /// Every (track, channel) pair that is either mapped or has notes in the file
+    pub rows: Vec<MappingRow>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MappingRow {
+    pub track: u16,
+    pub channel: u8,
+    /// The number of notes played on the channel in the file
+    pub notes: usize,
+    /// The ports the channel is played on, if it is mapped
+    pub ports: Option<Vec<u8>>,
+}
+
+impl MappingReport {
+    /// Channels with notes that aren't mapped to any drives (they will be silent)
+    pub fn unmapped(&self) -> impl Iterator<Item = &MappingRow> {
+        self.rows
+            .iter()
+            .filter(|row| row.ports.is_none() && row.notes > 0)
+    }
+
+    /// Mapped channels without any notes in the file (most likely a typo in the configuration)
+    pub fn unused(&self) -> impl Iterator<Item = &MappingRow> {
+        self.rows
+            .iter()
+            .filter(|row| row.ports.is_some() && row.notes == 0)
+    }
+}
+
+impl Display for MappingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<8}{:<10}{:<8}Ports", "Track", "Channel", "Notes")?;
+
+        for row in &self.rows {
+            let ports = match &row.ports {
+                Some(ports) if row.notes == 0 => format!("{:?} (no notes in file)", ports),
+                Some(ports) => format!("{:?}", ports),
+                None => "unmapped (silent)".to_string(),
+            };
+
+            writeln!(
+                f,
+                "{:<8}{:<10}{:<8}{}",
+                row.track, row.channel, row.notes, ports
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The number of notes played on each (track, channel) by the events
+fn count_notes(events: &[AbsoluteMidiEvent]) -> BTreeMap<(u16, u8), usize> {
+    let mut notes = BTreeMap::<(u16, u8), usize>::new();
+
+    for event in events {
+        if let LimitedMidiMessage::NoteOn { velocity, .. } = event.message {
+            if velocity > 0 {
+                *notes.entry((event.track, event.channel)).or_default() += 1;
+            }
+        }
+    }
+
+    notes
+}
+
+impl SongConfig {
+    /// Maps the channels that play notes in the events to the drives of the floppy drives set to
+    /// `auto`, returning whether any were
+    ///
+    /// The drives are dealt out round-robin, so each channel gets a drive when there are more
+    /// channels than drives, and the spare drives are shared out when there are fewer. Any PWM
+    /// voices are dealt out after the drives, like extra drives.
+    pub fn auto_assign(&mut self, events: &[AbsoluteMidiEvent]) -> bool {
+        let channels = count_notes(events).into_keys().collect::<Vec<_>>();
+        let mut assigned = false;
+
+        for floppy_drive in self.floppy_drives.iter_mut().filter(|drive| drive.auto) {
+            if channels.is_empty() || floppy_drive.port_count() == 0 {
+                continue;
+            }
+
+            let port_count = floppy_drive.port_count() as usize;
+
+            for i in 0..channels.len().max(port_count) {
+                let (track, channel) = channels[i % channels.len()];
+
+                floppy_drive
+                    .tracks
+                    .entry(track)
+                    .or_default()
+                    .channels
+                    .entry(channel)
+                    .or_default()
+                    .ports
+                    .push((i % port_count) as u8);
+            }
+
+            assigned = true;
+        }
+
+        assigned
+    }
+
+    /// Cross-checks the tracks and channels mapped to the drives against the ones the events of a
+    /// MIDI file actually play notes on
+    pub fn check_mapping(&self, events: &[AbsoluteMidiEvent]) -> MappingReport {
+        let notes = count_notes(events);
+
+        let mut rows = BTreeMap::new();
+
+        for (&(track, channel), &count) in &notes {
+            rows.insert(
+                (track, channel),
+                MappingRow {
+                    track,
+                    channel,
+                    notes: count,
+                    ports: None,
+                },
+            );
+        }
+
+        for (&track, track_config) in &self.floppy_drives[0].tracks {
+            for (&channel, channel_config) in &track_config.channels {
+                rows.entry((track, channel))
+                    .or_insert(MappingRow {
+                        track,
+                        channel,
+                        notes: 0,
+                        ports: None,
+                    })
+                    .ports = Some(channel_config.ports.clone());
+            }
+        }
+
+        MappingReport {
+            rows: rows.into_values().collect(),
+        }
+    }
+}
+
+/// Parses a configuration file in the format given by its extension
+///
+/// Every format is converted to a JSON value first so they all deserialize the same way (e.g. TOML
+/// only has string keys, which serde_json will parse as the track and channel numbers).
+fn parse_config_value(path: &Path, contents: &str) -> Result<serde_json::Value> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+
+    let value = match extension {
+        "json" | "jsonc" => jsonc_parser::parse_to_serde_value(contents, &ParseOptions::default())?
+            .context("file is empty")?,
+        #[cfg(feature = "toml")]
+        "toml" => serde_json::to_value(toml::from_str::<toml::Value>(contents)?)?,
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => {
+            serde_json::to_value(serde_yaml::from_str::<serde_yaml::Value>(contents)?)?
+        }
+        _ => bail!(
+            "unsupported configuration file extension `{}` (expected {})",
+            extension,
+            SUPPORTED_EXTENSIONS.join(", ")
+        ),
+    };
+
+    Ok(value)
+}
+
+/// The configuration file extensions that can be parsed with the enabled features
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    ".json",
+    ".jsonc",
+    #[cfg(feature = "toml")]
+    ".toml",
+    #[cfg(feature = "yaml")]
+    ".yaml",
+    #[cfg(feature = "yaml")]
+    ".yml",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(file_name: &str, contents: &str) -> SongConfig {
+        serde_json::from_value(parse_config_value(Path::new(file_name), contents).unwrap()).unwrap()
+    }
+
+    #[test]
+    #[cfg(all(feature = "toml", feature = "yaml"))]
+    fn parses_every_format_the_same() {
+        let jsonc = parse(
+            "song.jsonc",
+            r#"{
+                // Comments are allowed
+                "midi": { "path": "song.mid", "parallel_mode": "distribute" },
+                "floppy_drives": [
+                    { "id": 0, "drive_count": 2, "movement": true, "tracks": { "1": { "1": [0, 1] } } }
+                ]
+            }"#,
+        );
+
+        let toml = parse(
+            "song.toml",
+            r#"
+            [midi]
+            path = "song.mid"
+            parallel_mode = "distribute"
+
+            [[floppy_drives]]
+            id = 0
+            drive_count = 2
+            movement = true
+            tracks = { 1 = { 1 = [0, 1] } }
+            "#,
+        );
+
+        let yaml = parse(
+            "song.yml",
+            r#"
+            midi:
+              path: song.mid
+              parallel_mode: distribute
+            floppy_drives:
+              - id: 0
+                drive_count: 2
+                movement: true
+                tracks:
+                  1:
+                    1: [0, 1]
+            "#,
+        );
+
+        for config in [jsonc, toml, yaml] {
+            assert_eq!(config.midi.path, PathBuf::from("song.mid"));
+            assert_eq!(config.midi.parallel_mode, ParallelMode::Distribute);
+            assert_eq!(
+                config.floppy_drives[0].tracks[&1].channels[&1].ports,
+                [0, 1]
+            );
+        }
+    }
+
+    /// Parses a config with two drives, the first with `first_drive` filled in after its id and
+    /// drive count, and the second with nothing but the required settings
+    fn parse_drives(first_drive: &str) -> SongConfig {
+        parse(
+            "song.json",
+            &format!(
+                r#"{{
+                    "midi": {{ "path": "song.mid" }},
+                    "floppy_drives": [
+                        {{ "id": 0, "drive_count": 3, {} }},
+                        {{ "id": 1, "drive_count": 1, "movement": true }}
+                    ]
+                }}"#,
+                first_drive
+            ),
+        )
+    }
+
+    #[test]
+    fn parses_drive_settings() {
+        let config = parse_drives(
+            r#""movement": [true, false, true],
+            "reset": { "passes": 5, "dwell_ms": 400 },
+            "tick_us": 10,
+            "park_on_end": true,
+            "home_track": 20"#,
+        );
+
+        config.validate().unwrap();
+
+        let [set, unset] = &config.floppy_drives[..] else {
+            panic!("expected two drives");
+        };
+
+        assert_eq!(set.movement, Movement::PerDrive(vec![true, false, true]));
+        assert_eq!(
+            set.reset,
+            ResetTiming {
+                passes: Some(5),
+                step_delay_us: None,
+                dwell_ms: Some(400),
+            }
+        );
+        assert_eq!(set.tick_us, Some(10));
+        assert!(set.park_on_end);
+        assert_eq!(set.home_track, Some(20));
+
+        assert_eq!(unset.movement, Movement::All(true));
+        assert_eq!(unset.reset, ResetTiming::default());
+        assert_eq!(unset.tick_us, None);
+        assert!(!unset.park_on_end);
+        assert_eq!(unset.home_track, None);
+
+        let invalid_drives = [
+            (
+                r#""movement": [true, false]"#,
+                "floppy drive 0 gives the movement of 2 drives, but has 3 drives",
+            ),
+            (
+                r#""movement": true, "tick_us": 200"#,
+                "floppy drive 0 ticks every 200µs, but the tick interval must be from 5µs to 100µs",
+            ),
+            (
+                r#""movement": true, "home_track": 80"#,
+                "floppy drive 0 parks on track 80, but the drives only have 80 tracks",
+            ),
+            (
+                r#""movement": true, "reset": { "passes": 10 }"#,
+                "floppy drive 0 takes 6800ms to reset, but resetting can take at most 5000ms",
+            ),
+            (
+                r#""movement": true, "reset": { "step_delay_us": 4000000000 }"#,
+                "floppy drive 0 takes 1920000600ms to reset, but resetting can take at most 5000ms",
+            ),
+        ];
+
+        for (first_drive, error) in invalid_drives {
+            assert_eq!(
+                parse_drives(first_drive)
+                    .validate()
+                    .unwrap_err()
+                    .to_string(),
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn parses_pwm_ports() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [
+                    { "id": 0, "drive_count": 2, "movement": true, "pwm": [16], "tracks": { "1": { "1": [0, 2] } } }
+                ]
+            }"#,
+        );
+
+        config.validate().unwrap();
+
+        let floppy_drive = &config.floppy_drives[0];
+
+        assert_eq!(floppy_drive.port_count(), 3);
+        assert_eq!(
+            floppy_drive.port_kinds(),
+            [
+                PortKind::Drive(0),
+                PortKind::Drive(1),
+                PortKind::Pwm { gpio: 16 }
+            ]
+        );
+
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [
+                    { "id": 0, "drive_count": 2, "movement": true, "pwm": [16], "tracks": { "1": { "1": [3] } } }
+                ]
+            }"#,
+        );
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "floppy drive 0 maps track 1 channel 1 to port 3, but only has 2 drives and 1 PWM voices"
+        );
+    }
+
+    #[test]
+    fn parses_detuned_ports() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [
+                    {
+                        "id": 0,
+                        "drive_count": 3,
+                        "movement": true,
+                        "tracks": {
+                            "1": { "1": { "ports": [0, 1], "detune": [0, 8] }, "2": [2] },
+                            "2": { "1": { "ports": [1, 2], "detune": [8, -8] } }
+                        }
+                    }
+                ]
+            }"#,
+        );
+
+        config.validate().unwrap();
+
+        assert_eq!(config.floppy_drives[0].detune_cents(), [0, 8, -8]);
+
+        let invalid_tracks = [
+            (
+                r#"{ "1": { "1": { "ports": [0, 1], "detune": [5] } } }"#,
+                "floppy drive 0 gives track 1 channel 1 the detune of 1 ports, but maps it to 2 ports",
+            ),
+            (
+                r#"{ "1": { "1": { "ports": [0], "detune": [5] }, "2": { "ports": [0], "detune": [-5] } } }"#,
+                "floppy drive 0 detunes port 0 by -5 cents for track 1 channel 2, but by 5 cents for track 1 channel 1",
+            ),
+        ];
+
+        for (tracks, error) in invalid_tracks {
+            let config = parse(
+                "song.json",
+                &format!(
+                    r#"{{
+                        "midi": {{ "path": "song.mid" }},
+                        "floppy_drives": [{{ "id": 0, "drive_count": 3, "movement": true, "tracks": {} }}]
+                    }}"#,
+                    tracks
+                ),
+            );
+
+            assert_eq!(config.validate().unwrap_err().to_string(), error);
+        }
+
+        let in_tune = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [{ "id": 0, "drive_count": 2, "movement": true, "tracks": { "1": { "1": [0, 1] } } }]
+            }"#,
+        );
+
+        assert!(in_tune.floppy_drives[0].detune_cents().is_empty());
+    }
+
+    #[test]
+    fn parses_stepper_ports() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [
+                    {
+                        "id": 0,
+                        "drive_count": 3,
+                        "movement": true,
+                        "ports": { "1": { "kind": "stepper", "steps_per_toggle": 4 }, "2": { "kind": "stepper" } }
+                    }
+                ]
+            }"#,
+        );
+
+        config.validate().unwrap();
+
+        assert_eq!(
+            config.floppy_drives[0].port_kinds(),
+            [
+                PortKind::Drive(0),
+                PortKind::Stepper {
+                    index: 1,
+                    steps_per_toggle: 4
+                },
+                PortKind::Stepper {
+                    index: 2,
+                    steps_per_toggle: 1
+                }
+            ]
+        );
+
+        let invalid_ports = [
+            (
+                r#"{ "3": { "kind": "stepper" } }"#,
+                "floppy drive 0 gives the kind of port 3, but only has 3 drives (PWM voices can't have a kind)",
+            ),
+            (
+                r#"{ "0": { "kind": "floppy", "steps_per_toggle": 2 } }"#,
+                "floppy drive 0 gives port 0 steps per toggle, but it isn't a stepper",
+            ),
+            (
+                r#"{ "0": { "kind": "stepper", "steps_per_toggle": 0 } }"#,
+                "floppy drive 0 gives port 0 zero steps per toggle",
+            ),
+        ];
+
+        for (ports, error) in invalid_ports {
+            let config = parse(
+                "song.json",
+                &format!(
+                    r#"{{
+                        "midi": {{ "path": "song.mid" }},
+                        "floppy_drives": [{{ "id": 0, "drive_count": 3, "movement": true, "ports": {} }}]
+                    }}"#,
+                    ports
+                ),
+            );
+
+            assert_eq!(config.validate().unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_ports() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [
+                    { "id": 3, "drive_count": 2, "movement": true, "tracks": { "1": { "10": [0, 2] } } }
+                ]
+            }"#,
+        );
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "floppy drive 3 maps track 1 channel 10 to port 2, but only has 2 drives"
+        );
+    }
+
+    #[test]
+    fn parses_playlists() {
+        let value = parse_config_value(
+            Path::new("playlist.jsonc"),
+            r#"{
+                "songs": [
+                    { "path": "first.json", "gap_seconds": 5 },
+                    { "path": "second.json" }
+                ],
+                "shuffle": true
+            }"#,
+        )
+        .unwrap();
+
+        let playlist: PlaylistConfig = serde_json::from_value(value).unwrap();
+
+        assert!(playlist.shuffle);
+        assert_eq!(playlist.songs[0].path, PathBuf::from("first.json"));
+        assert_eq!(playlist.songs[0].gap_seconds, Some(5.0));
+        assert_eq!(playlist.songs[1].gap_seconds, None);
+    }
+
+    #[test]
+    fn reports_unmapped_and_unused_channels() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [
+                    { "id": 0, "drive_count": 2, "movement": true, "tracks": { "1": { "1": [0], "3": [1] } } }
+                ]
+            }"#,
+        );
+
+        let note = |track, channel| AbsoluteMidiEvent {
+            time_offset: 0,
+            track,
+            channel,
+            message: LimitedMidiMessage::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+        };
+
+        let report = config.check_mapping(&[note(1, 1), note(1, 1), note(2, 1)]);
+
+        assert_eq!(
+            report.unmapped().collect::<Vec<_>>(),
+            [&MappingRow {
+                track: 2,
+                channel: 1,
+                notes: 1,
+                ports: None
+            }]
+        );
+        assert_eq!(
+            report.unused().collect::<Vec<_>>(),
+            [&MappingRow {
+                track: 1,
+                channel: 3,
+                notes: 0,
+                ports: Some(vec![1])
+            }]
+        );
+        assert_eq!(report.rows[0].notes, 2);
+    }
+
+    #[test]
+    fn shifted_notes_are_clamped_by_octaves() {
+        assert_eq!(shift_note(60, 7), 67);
+        assert_eq!(shift_note(60, -24), 36);
+        assert_eq!(shift_note(5, -12), 5);
+        assert_eq!(shift_note(3, -7), 8);
+        assert_eq!(shift_note(120, 12), 120);
+        assert_eq!(shift_note(125, 5), 118);
+
+        for note in 0..=127 {
+            for semitones in [-48, -13, 13, 48] {
+                let shifted = shift_note(note, semitones);
+
+                assert_eq!(shifted % 12, (note as i16 + semitones).rem_euclid(12) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn channel_transpose_overrides_track_and_global() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid", "transpose": 2 },
+                "floppy_drives": [
+                    {
+                        "id": 0,
+                        "drive_count": 4,
+                        "movement": true,
+                        "tracks": {
+                            "1": { "1": [0], "2": { "ports": [1], "transpose": -3 } },
+                            "2": {
+                                "transpose": 5,
+                                "octave_shift": -1,
+                                "1": [2],
+                                "2": { "ports": [3], "octave_shift": 1 }
+                            }
+                        }
+                    }
+                ]
+            }"#,
+        );
+
+        assert_eq!(config.note_shift(1, 1), 2);
+        assert_eq!(config.note_shift(1, 2), -3);
+        assert_eq!(config.note_shift(2, 1), 5 - 12);
+        assert_eq!(config.note_shift(2, 2), 5 + 12);
+        // Unmapped channels still use the global transpose
+        assert_eq!(config.note_shift(3, 1), 2);
+
+        assert_eq!(config.floppy_drives[0].tracks[&2].channels[&2].ports, [3]);
+    }
+
+    #[test]
+    fn drops_percussion_by_default() {
+        let config = |percussion: &str| {
+            parse(
+                "song.json",
+                &format!(
+                    r#"{{
+                        "midi": {{ "path": "song.mid" {} }},
+                        "floppy_drives": [{{ "id": 0, "drive_count": 1, "movement": true, "tracks": {{}} }}]
+                    }}"#,
+                    percussion
+                ),
+            )
+            .midi
+            .percussion
+        };
+
+        assert_eq!(config(""), PercussionMode::Ignore);
+        assert_eq!(config(r#", "percussion": "drop""#), PercussionMode::Ignore);
+        assert_eq!(config(r#", "percussion": "map""#), PercussionMode::Map);
+        assert_eq!(
+            config(r#", "percussion": { "click": { "note": 40 } }"#),
+            PercussionMode::Click {
+                note: 40,
+                gate_ticks: None
+            }
+        );
+    }
+
+    #[test]
+    fn auto_assigns_channels_round_robin() {
+        let mut config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid" },
+                "floppy_drives": [{ "id": 0, "drive_count": 3, "movement": true, "auto": true }]
+            }"#,
+        );
+
+        let note_on = |track, channel| AbsoluteMidiEvent {
+            time_offset: 0,
+            track,
+            channel,
+            message: LimitedMidiMessage::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+        };
+
+        // More drives than channels, so the first channel gets the spare drive
+        assert!(config.auto_assign(&[note_on(2, 1), note_on(1, 4)]));
+        assert_eq!(
+            config.floppy_drives[0].tracks_json(),
+            "{\n  \"1\": { \"4\": [0, 2] },\n  \"2\": { \"1\": [1] }\n}"
+        );
+
+        config.floppy_drives[0].tracks.clear();
+
+        // More channels than drives, so the drives are shared
+        let events = (1..=4)
+            .map(|channel| note_on(1, channel))
+            .collect::<Vec<_>>();
+        config.auto_assign(&events);
+
+        let ports = config.floppy_drives[0].tracks[&1]
+            .channels
+            .values()
+            .map(|channel| channel.ports.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(ports, [vec![0], vec![1], vec![2], vec![0]]);
+    }
+
+    #[test]
+    fn resolves_track_names() {
+        let config = |tracks: &str| {
+            parse(
+                "song.json",
+                &format!(
+                    r#"{{
+                        "midi": {{ "path": "song.mid" }},
+                        "floppy_drives": [
+                            {{ "id": 0, "drive_count": 4, "movement": true, "tracks": {} }}
+                        ]
+                    }}"#,
+                    tracks
+                ),
+            )
+        };
+
+        let track_names = BTreeMap::from([
+            (1, "Piano".to_string()),
+            (2, "Bass".to_string()),
+            (3, "Drums".to_string()),
+            (4, "Drums".to_string()),
+        ]);
+
+        let mut mapped = config(r#"{ "1": { "1": [0] }, "Bass": { "1": [1, 2] } }"#);
+        mapped.resolve_track_names(&track_names).unwrap();
+
+        assert!(mapped.floppy_drives[0].named_tracks.is_empty());
+        assert_eq!(
+            mapped.floppy_drives[0].tracks[&2].channels[&1].ports,
+            [1, 2]
+        );
+
+        let error = |tracks: &str| {
+            config(tracks)
+                .resolve_track_names(&track_names)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error(r#"{ "Guitar": { "1": [0] } }"#),
+            r#"there is no track named `Guitar`, the MIDI file has tracks 1 "Piano", 2 "Bass", 3 "Drums", 4 "Drums""#
+        );
+        assert!(error(r#"{ "Drums": { "10": [0] } }"#)
+            .starts_with("the track name `Drums` is ambiguous"));
+        assert_eq!(
+            error(r#"{ "2": { "1": [0] }, "Bass": { "1": [1] } }"#),
+            "track 2 is mapped both by its number and as `Bass`"
+        );
+    }
+
+    #[test]
+    fn folds_notes_into_track_windows() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid", "fold_octaves": true },
+                "floppy_drives": [
+                    {
+                        "id": 0,
+                        "drive_count": 2,
+                        "movement": true,
+                        "tracks": {
+                            "1": { "1": [0] },
+                            "2": { "fold_window": { "low": 24, "high": 47 }, "1": [1] }
+                        }
+                    }
+                ]
+            }"#,
+        );
+
+        config.validate().unwrap();
+
+        let note_on = |track, note| AbsoluteMidiEvent {
+            time_offset: 0,
+            track,
+            channel: 1,
+            message: LimitedMidiMessage::NoteOn {
+                note,
+                velocity: 100,
+            },
+        };
+
+        let mut events = [
+            note_on(1, 24),
+            note_on(1, 60),
+            note_on(1, 96),
+            note_on(2, 60),
+        ];
+        let report = config.fold_events(&mut events);
+
+        let notes = events
+            .iter()
+            .map(|event| match event.message {
+                LimitedMidiMessage::NoteOn { note, .. } => note,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(notes, [36, 60, 72, 36]);
+        assert_eq!(report.octaves, BTreeMap::from([(-2, 2), (1, 1)]));
+        assert_eq!(
+            report.to_string(),
+            "Folded 3 notes: 2 down 2 octaves, 1 up 1 octave"
+        );
+    }
+
+    #[test]
+    fn rejects_narrow_fold_windows() {
+        let config = parse(
+            "song.json",
+            r#"{
+                "midi": { "path": "song.mid", "fold_window": { "low": 60, "high": 66 } },
+                "floppy_drives": [
+                    { "id": 0, "drive_count": 1, "movement": true, "tracks": { "1": { "1": [0] } } }
+                ]
+            }"#,
+        );
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "fold window 60-66 is narrower than an octave"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_extensions() {
+        assert!(parse_config_value(Path::new("song.ini"), "").is_err());
+    }
+}
@@ -1,6 +1,23 @@
-use anyhow::{bail, Result};
-use floppier_proto::{FloppierC2SMessage, FloppierS2CMessage};
-use serialport::SerialPort;
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use floppier_proto::{
+    frame::{self, FrameHeader},
+    is_compatible_version, ClientState, FloppierC2SMessage, FloppierErrorKind, FloppierS2CMessage,
+    PortKind, ResetTiming, SetConfig, StatusReport, EXTENSIONS_VERSION, MAX_TIMED_EVENTS,
+    PROTO_VERSION,
+};
+use log::{trace, warn, Level, LevelFilter};
+use serialport::{SerialPort, SerialPortType};
+
+use crate::playback::{Scheduler, SystemClock};
 
 #[macro_export]
 macro_rules! pause {
@@ -24,66 +41,1512 @@ pub fn pause_impl(message: Option<&str>) {
     stdin().events().next();
 }
 
+/// Logs to stderr, including debug output if `verbose` is set (`RUST_LOG` overrides the level)
+///
+/// Lines end in `\r\n` so they still start at the left edge while the terminal is in raw mode.
+pub fn init_logger(verbose: bool) {
+    use std::io::Write;
+
+    let level = if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| {
+            let level = match record.level() {
+                Level::Error => "Error",
+                Level::Warn => "Warning",
+                Level::Info => "Info",
+                Level::Debug => "Debug",
+                Level::Trace => "Trace",
+            };
+
+            write!(buf, "{}: {}\r\n", level, record.args())
+        })
+        .init();
+}
+
+/// The USB vendor and product IDs the client enumerates with
+pub const CLIENT_USB_VID: u16 = 0x16c0;
+pub const CLIENT_USB_PID: u16 = 0x27dd;
+
+/// The USB product string the client enumerates with
+pub const CLIENT_USB_PRODUCT: &str = "Floppier Client";
+
+/// Returns the names of the serial ports that look like a client
+pub fn find_client_ports() -> Result<Vec<String>> {
+    let ports = serialport::available_ports()?
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                (info.vid == CLIENT_USB_VID && info.pid == CLIENT_USB_PID)
+                    || info.product.as_deref() == Some(CLIENT_USB_PRODUCT)
+            }
+            _ => false,
+        })
+        .map(|port| port.port_name)
+        .collect();
+
+    Ok(ports)
+}
+
+/// Returns the name of the serial port the client is connected to, if it is plugged in
+pub fn find_client_port() -> Result<Option<String>> {
+    Ok(find_client_ports()?.into_iter().next())
+}
+
+/// Finds the serial port of the only client that is plugged in
+pub fn detect_client_port() -> Result<String> {
+    let mut ports = find_client_ports()?;
+
+    match ports.len() {
+        0 => bail!("could not find a client, is it plugged in?"),
+        1 => Ok(ports.remove(0)),
+        _ => bail!(
+            "found multiple clients ({}), choose one with --serial-port",
+            ports.join(", ")
+        ),
+    }
+}
+
+/// The default time to wait for the rest of a frame once it has started arriving
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The default time to wait for the client to respond to a message
+pub const DEFAULT_RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default number of MIDI event acks that can be outstanding when using `Client::send_windowed`
+pub const DEFAULT_ACK_WINDOW: usize = 16;
+
+/// The default time between pings sent by `Client::heartbeat`
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The default time to wait for the client to answer a ping before giving up on the connection
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether an interrupt or terminate signal arrived while they were deferred
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a `DeferredInterrupts` exists
+static DEFERRING: AtomicBool = AtomicBool::new(false);
+
+/// Handles interrupt and terminate signals for as long as it exists
+///
+/// A signal exits straight away, unless interrupts are deferred (see `DeferredInterrupts`) and it's
+/// the first one. Install it once, since every handler registered runs on each signal.
+pub struct SignalHandler {
+    ids: Vec<signal_hook::SigId>,
+}
+
+impl SignalHandler {
+    pub fn install() -> Result<Self> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        let mut ids = Vec::new();
+
+        for signal in [SIGINT, SIGTERM] {
+            // Note (safety): The handler only touches atomics and exits, which are async signal safe
+            let id = unsafe {
+                signal_hook::low_level::register(signal, move || {
+                    if !DEFERRING.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst)
+                    {
+                        signal_hook::low_level::exit(128 + signal);
+                    }
+                })
+            }?;
+
+            ids.push(id);
+        }
+
+        Ok(Self { ids })
+    }
+}
+
+impl Drop for SignalHandler {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+}
+
+/// Holds off the first interrupt or terminate signal for as long as it exists, so a session with
+/// the client can be ended cleanly once the caller sees it (see `interrupted`) rather than leaving
+/// the drives latched on
+pub struct DeferredInterrupts(());
+
+impl DeferredInterrupts {
+    pub fn start() -> Self {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        DEFERRING.store(true, Ordering::SeqCst);
+
+        Self(())
+    }
+}
+
+impl Drop for DeferredInterrupts {
+    fn drop(&mut self) {
+        DEFERRING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether an interrupt or terminate signal has arrived while interrupts were deferred
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Reads key presses without blocking
+///
+/// The terminal is kept in raw mode while this exists, so output needs explicit `\r\n` line
+/// endings. Ctrl-C arrives as a key press rather than a signal in raw mode, and an interrupt or
+/// terminate signal sent some other way while interrupts are deferred is reported as Ctrl-C too,
+/// so the caller can stop the client cleanly.
+pub struct KeyReader {
+    keys: termion::input::Keys<termion::AsyncReader>,
+    _stdout: termion::raw::RawTerminal<std::io::Stdout>,
+}
+
+impl KeyReader {
+    pub fn new() -> Result<Self> {
+        use termion::input::TermRead;
+        use termion::raw::IntoRawMode;
+
+        Ok(Self {
+            keys: termion::async_stdin().keys(),
+            _stdout: std::io::stdout().into_raw_mode()?,
+        })
+    }
+
+    /// Returns the next key that has been pressed, if any
+    pub fn next_key(&mut self) -> Option<termion::event::Key> {
+        if interrupted() {
+            return Some(termion::event::Key::Ctrl('c'));
+        }
+
+        self.keys.next().and_then(|key| key.ok())
+    }
+}
+
+/// An error reported by the client, returned (wrapped in an `anyhow::Error`) when receiving a
+/// message so callers can downcast it to decide how to recover
+#[derive(Debug)]
+pub struct ClientError {
+    pub kind: FloppierErrorKind,
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FloppierErrorKind::UnexpectedMessage { state, got } => write!(
+                f,
+                "client didn't expect a {:?} message while in the {:?} state",
+                got, state
+            )?,
+            FloppierErrorKind::IncompatibleVersion { server, client } => write!(
+                f,
+                "client rejected server protocol version {:#06x} (client is {:#06x})",
+                server, client
+            )?,
+            FloppierErrorKind::DriveIndexOutOfRange { index, count } => write!(
+                f,
+                "config maps to drive {} but the client only has {} drives",
+                index, count
+            )?,
+            FloppierErrorKind::InvalidPwmPort { gpio } => write!(
+                f,
+                "config plays a PWM voice on GPIO {}, which the client can't use for it",
+                gpio
+            )?,
+            FloppierErrorKind::InvalidTickInterval { tick_us } => write!(
+                f,
+                "config ticks every {}µs, which the client can't do",
+                tick_us
+            )?,
+            FloppierErrorKind::BufferOverflow => {
+                write!(f, "client read buffer overflowed and data was lost")?
+            }
+            FloppierErrorKind::DeserializeFailed => {
+                write!(f, "client couldn't understand a message")?
+            }
+        }
+
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl ClientError {
+    /// Whether the client has forgotten the session and gone back to waiting for a hello (after
+    /// restarting or timing out the link), so it can be set up again over the same connection
+    pub fn is_waiting_for_hello(&self) -> bool {
+        matches!(
+            self.kind,
+            FloppierErrorKind::UnexpectedMessage {
+                state: ClientState::WaitingForHello,
+                ..
+            }
+        )
+    }
+}
+
+/// A message sent with `Client::send_windowed` that hasn't been acknowledged yet
+#[derive(Debug)]
+struct Unacked {
+    frame_len: usize,
+    /// The number of timed events it holds (0 if it isn't a timed batch)
+    timed_events: usize,
+}
+
+/// Keeps track of how much more the client can take, from the room it reported in its last ack
+/// and what has been sent since
+#[derive(Debug, Default)]
+struct Backpressure {
+    /// Windowed messages that haven't been acknowledged yet, oldest first
+    unacked: VecDeque<Unacked>,
+    /// How many timed events the client had queued as of its last ack
+    queued_events: usize,
+    /// How many more bytes the client's read buffer could take as of its last ack, if it reports
+    /// that
+    buffer_free: Option<usize>,
+}
+
+impl Backpressure {
+    fn pending_acks(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Whether a frame can be sent without waiting for an ack first
+    ///
+    /// Something can always be sent once everything has been acknowledged, since waiting wouldn't
+    /// tell us any more.
+    fn can_send(&self, frame_len: usize, ack_window: usize) -> bool {
+        if self.unacked.is_empty() {
+            return true;
+        }
+
+        self.unacked.len() < ack_window
+            && self
+                .buffer_room()
+                .is_none_or(|buffer_room| frame_len <= buffer_room)
+    }
+
+    fn sent(&mut self, message: &FloppierS2CMessage, frame_len: usize) {
+        let timed_events = match message {
+            FloppierS2CMessage::TimedMidiEventBatch { events, .. } => events.len(),
+            _ => 0,
+        };
+
+        self.unacked.push_back(Unacked {
+            frame_len,
+            timed_events,
+        });
+    }
+
+    /// Handles an ack for the oldest unacknowledged message, returning whether the message was an
+    /// ack
+    fn acked(&mut self, message: &FloppierC2SMessage) -> bool {
+        match *message {
+            FloppierC2SMessage::MidiEventAck | FloppierC2SMessage::MidiEventBatchAck => {}
+            FloppierC2SMessage::TimedMidiEventBatchAck { queued } => {
+                self.queued_events = queued as usize;
+            }
+            FloppierC2SMessage::Status {
+                queue_free,
+                buffer_free,
+            } => {
+                self.queued_events = MAX_TIMED_EVENTS.saturating_sub(queue_free as usize);
+                self.buffer_free = Some(buffer_free as usize);
+            }
+            _ => return false,
+        }
+
+        self.unacked.pop_front();
+
+        true
+    }
+
+    /// How many more timed events the client can queue, counting the ones on their way to it
+    fn timed_event_room(&self) -> usize {
+        let unacked_events = self
+            .unacked
+            .iter()
+            .map(|unacked| unacked.timed_events)
+            .sum::<usize>();
+
+        MAX_TIMED_EVENTS.saturating_sub(self.queued_events + unacked_events)
+    }
+
+    /// How many more bytes the client's read buffer can take, assuming none of the frames on
+    /// their way to it have been read yet (`None` if the client doesn't report it)
+    fn buffer_room(&self) -> Option<usize> {
+        let unacked_bytes = self
+            .unacked
+            .iter()
+            .map(|unacked| unacked.frame_len)
+            .sum::<usize>();
+
+        self.buffer_free
+            .map(|buffer_free| buffer_free.saturating_sub(unacked_bytes))
+    }
+}
+
+/// A connection to the client that frames are sent and received over
+pub trait Transport: Read + Write + Send {
+    /// Sets how long a read waits for data to arrive before timing out
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Whether any data has arrived that can be read without waiting
+    fn has_data(&self) -> io::Result<bool>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        SerialPort::set_timeout(self.as_mut(), timeout)?;
+
+        Ok(())
+    }
+
+    fn has_data(&self) -> io::Result<bool> {
+        Ok(self.bytes_to_read()? > 0)
+    }
+}
+
+/// For a client plugged into a serial-to-TCP bridge
+impl Transport for TcpStream {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+
+    fn has_data(&self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let peeked = self.peek(&mut [0]);
+        self.set_nonblocking(false)?;
+
+        match peeked {
+            // A closed connection counts as data, so the next read reports it
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Where the client is connected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A serial port, like the client's own USB serial port
+    Serial { port: String, baud_rate: u32 },
+    /// A socket to a serial-to-TCP bridge the client is plugged into, as `host:port`
+    Tcp { address: String },
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Serial { port, .. } => write!(f, "{}", port),
+            Endpoint::Tcp { address } => write!(f, "tcp://{}", address),
+        }
+    }
+}
+
+/// Where to find the client and how long to wait on it
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub endpoint: Endpoint,
+    /// How long the client should wait without hearing from us before giving up on the connection
+    pub link_timeout: Option<Duration>,
+    /// How long to wait for the client to respond to a message
+    pub receive_timeout: Duration,
+}
+
 pub struct Client {
-    port: Box<dyn SerialPort>,
+    transport: Box<dyn Transport>,
+    /// What has been sent with `send_windowed` and how much more the client can take
+    backpressure: Backpressure,
+    ack_window: usize,
+    read_timeout: Duration,
+    receive_timeout: Duration,
+    /// Whether to checksum the frames sent to the client (negotiated in the hello handshake)
+    checksum_frames: bool,
+    /// Frames that haven't been responded to yet, oldest first, kept so they can be sent again if
+    /// the client reports a frame error (go-back-N on a checksummed link, where the client drops
+    /// every frame after a corrupted one)
+    in_flight: VecDeque<Vec<u8>>,
+    /// The protocol version the client speaks (negotiated in the hello handshake, zero until then)
+    proto_version: u16,
+    /// The client's clock for timed events, which starts when it's ready and stops while it's
+    /// paused
+    clock: Option<Scheduler<SystemClock>>,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    /// When the last ping was sent
+    last_ping: Instant,
+    /// The cookie of the ping waiting for a pong, and when it was sent
+    pending_ping: Option<(u32, Instant)>,
+    next_ping_cookie: u32,
+    /// How long the client should wait without hearing from us before giving up on the connection
+    link_timeout: Option<Duration>,
 }
 
 impl Client {
-    pub fn new(port: Box<dyn SerialPort>) -> Self {
-        Self { port }
+    /// Connects to the client and performs the hello handshake
+    pub fn connect(options: &ConnectOptions) -> Result<Self> {
+        let mut client = Self::open(options)?;
+
+        client.hello()?;
+
+        Ok(client)
+    }
+
+    /// Connects to the client without the hello handshake, leaving it in whatever state it was in
+    /// (only `status` can be used before a hello)
+    pub fn open(options: &ConnectOptions) -> Result<Self> {
+        let transport: Box<dyn Transport> = match &options.endpoint {
+            Endpoint::Serial { port, baud_rate } => Box::new(
+                serialport::new(port, *baud_rate)
+                    .open()
+                    .with_context(|| format!("failed to open `{}`", port))?,
+            ),
+            Endpoint::Tcp { address } => {
+                let stream = TcpStream::connect(address)
+                    .with_context(|| format!("failed to connect to `{}`", address))?;
+
+                // Frames are small and the client acks each one, so don't wait to fill a packet
+                stream.set_nodelay(true)?;
+
+                Box::new(stream)
+            }
+        };
+
+        let mut client = Self::new(transport);
+        client.set_link_timeout(options.link_timeout);
+        client.set_receive_timeout(options.receive_timeout);
+
+        Ok(client)
+    }
+
+    /// Connects to the client like `connect`, then configures it like `configure`
+    pub fn connect_and_configure(options: &ConnectOptions, config: SetConfig) -> Result<Self> {
+        let mut client = Self::connect(options)?;
+
+        client.configure(config)?;
+
+        Ok(client)
+    }
+
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            backpressure: Backpressure::default(),
+            ack_window: DEFAULT_ACK_WINDOW,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            receive_timeout: DEFAULT_RECEIVE_TIMEOUT,
+            checksum_frames: false,
+            in_flight: VecDeque::new(),
+            proto_version: 0,
+            clock: None,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            last_ping: Instant::now(),
+            pending_ping: None,
+            next_ping_cookie: 0,
+            link_timeout: None,
+        }
+    }
+
+    /// Sets how long to wait for the rest of a frame once it has started arriving
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// Sets how long `receive` waits for a response from the client
+    pub fn set_receive_timeout(&mut self, receive_timeout: Duration) {
+        self.receive_timeout = receive_timeout;
+    }
+
+    /// Sets how many MIDI event acks can be outstanding before `send_windowed` blocks
+    pub fn set_ack_window(&mut self, ack_window: usize) {
+        self.ack_window = ack_window.max(1);
+    }
+
+    /// Sets how often `heartbeat` pings the client
+    pub fn set_ping_interval(&mut self, ping_interval: Duration) {
+        self.ping_interval = ping_interval;
+    }
+
+    /// Sets how long to wait for the client to answer a ping before giving up on the connection
+    pub fn set_ping_timeout(&mut self, ping_timeout: Duration) {
+        self.ping_timeout = ping_timeout;
+    }
+
+    /// Sets how long the client waits without receiving anything before it silences its drives
+    /// and gives up on the connection (sent with each config)
+    ///
+    /// This should be comfortably longer than the ping interval, since `heartbeat` has to be called
+    /// regularly for the whole time the client is playing.
+    pub fn set_link_timeout(&mut self, link_timeout: Option<Duration>) {
+        self.link_timeout = link_timeout;
+    }
+
+    pub fn link_timeout(&self) -> Option<Duration> {
+        self.link_timeout
+    }
+
+    /// Performs the hello handshake and checks that the client speaks a compatible protocol
+    pub fn hello(&mut self) -> Result<()> {
+        // The hello is always sent without a checksum since the client might not support them
+        self.checksum_frames = false;
+        self.in_flight.clear();
+
+        // The acks for anything sent in an earlier session are never coming
+        self.backpressure = Backpressure::default();
+
+        self.send(FloppierS2CMessage::Hello {
+            proto_version: PROTO_VERSION,
+        })?;
+
+        let proto_version = match self.receive()? {
+            FloppierC2SMessage::HelloAck { proto_version } => proto_version,
+            _ => bail!("expected hello ack message from client"),
+        };
+
+        ensure!(
+            is_compatible_version(proto_version),
+            "client protocol version {:#06x} is incompatible with server version {:#06x}",
+            proto_version,
+            PROTO_VERSION
+        );
+
+        self.proto_version = proto_version;
+        self.checksum_frames = self.supports(EXTENSIONS_VERSION);
+        self.pending_ping = None;
+        self.last_ping = Instant::now();
+
+        Ok(())
+    }
+
+    /// Sends a config (with the link timeout filled in) and waits for the client to finish
+    /// resetting its drives
+    ///
+    /// Settings the client is too old for are warned about, or refused if the client would reject
+    /// the whole config over them.
+    pub fn configure(&mut self, config: SetConfig) -> Result<()> {
+        let uses_velocity = config.velocity_threshold > 0 || config.velocity_dynamics;
+
+        if uses_velocity && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the velocity settings");
+        }
+
+        if config.reset != ResetTiming::default() && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the reset settings");
+        }
+
+        if !config.drive_movement.is_empty() && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the movement of each drive, only moving them if they all move");
+        }
+
+        if !config.detune_cents.is_empty() && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the detune settings, playing every port in tune");
+        }
+
+        if config.tick_us.is_some() && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the tick interval, ticking at its own");
+        }
+
+        if config.steal_oldest && !self.supports(EXTENSIONS_VERSION) {
+            warn!(
+                "client ignores stealing the oldest note, stacking notes when every drive is busy"
+            );
+        }
+
+        if config.release_ramp && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the release ramp, stopping notes dead");
+        }
+
+        if config.min_note_us > 0 && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client ignores the minimum note length, retriggering drives on every note");
+        }
+
+        if config.park_on_end && !self.supports(EXTENSIONS_VERSION) {
+            warn!("client can't park the drives, leaving the heads where each song ends");
+        }
+
+        let has_port = |matches: fn(&PortKind) -> bool| config.ports.iter().any(matches);
+
+        // Older clients would reject the ports after the drives, so there's no point sending the config
+        if has_port(|port| matches!(port, PortKind::Pwm { .. }))
+            && !self.supports(EXTENSIONS_VERSION)
+        {
+            bail!(
+                "client can't play PWM voices, remove `pwm` from the config or update the client"
+            );
+        }
+
+        if has_port(|port| matches!(port, PortKind::Stepper { .. }))
+            && !self.supports(EXTENSIONS_VERSION)
+        {
+            bail!("client can't play steppers, remove them from the config or update the client");
+        }
+
+        let config = SetConfig {
+            link_timeout_ms: self
+                .link_timeout
+                .map(|link_timeout| link_timeout.as_millis() as u32),
+            ..config
+        };
+
+        self.send(FloppierS2CMessage::SetConfig(config))?;
+
+        let FloppierC2SMessage::SetConfigAck = self.receive()? else {
+            bail!("expected set config ack message from client");
+        };
+
+        let FloppierC2SMessage::Ready = self.receive()? else {
+            bail!("expected ready message from client");
+        };
+
+        Ok(())
+    }
+
+    /// Silences every drive on the client without stopping playback, once the MIDI events sent
+    /// so far have been acknowledged
+    ///
+    /// Does nothing if the client is too old to support it.
+    pub fn all_notes_off(&mut self) -> Result<()> {
+        if !self.supports(EXTENSIONS_VERSION) {
+            return Ok(());
+        }
+
+        self.flush_acks()?;
+        self.send(FloppierS2CMessage::AllNotesOff)?;
+
+        let FloppierC2SMessage::AllNotesOffAck = self.receive()? else {
+            bail!("expected all notes off ack message from client");
+        };
+
+        Ok(())
+    }
+
+    /// Asks the client how it has been running, which works before the hello handshake so a
+    /// session in progress carries on
+    pub fn status(&mut self) -> Result<StatusReport> {
+        // Checksummed so a client too old to understand the query says so, rather than asking for
+        // the frame again
+        let checksum_frames = self.checksum_frames;
+        self.checksum_frames = true;
+
+        let sent = self.send(FloppierS2CMessage::GetStatus);
+        self.checksum_frames = checksum_frames;
+        sent?;
+
+        let message = match self.receive() {
+            Err(error)
+                if error
+                    .downcast_ref::<ClientError>()
+                    .is_some_and(|error| error.kind == FloppierErrorKind::DeserializeFailed) =>
+            {
+                bail!(
+                    "client is too old to report its status (it needs protocol version {:#06x})",
+                    EXTENSIONS_VERSION
+                );
+            }
+            message => message?,
+        };
+
+        let FloppierC2SMessage::StatusReport(report) = message else {
+            bail!("expected status report message from client");
+        };
+
+        Ok(report)
+    }
+
+    /// Whether the client speaks at least `min_version` of the protocol, and so understands
+    /// everything added up to it
+    pub fn supports(&self, min_version: u16) -> bool {
+        self.proto_version >= min_version
+    }
+
+    /// The time on the client's clock for timed events, as far as we can tell from when it said it
+    /// was ready (zero if it hasn't yet)
+    pub fn clock(&self) -> Duration {
+        self.clock
+            .as_ref()
+            .map_or(Duration::ZERO, |clock| clock.elapsed())
+    }
+
+    /// How many more timed events the client can queue without playing any early
+    pub fn timed_event_room(&self) -> usize {
+        self.backpressure.timed_event_room()
+    }
+
+    /// Stops the client (and its clock) once the MIDI events sent so far have been acknowledged
+    pub fn pause(&mut self) -> Result<()> {
+        self.flush_acks()?;
+        self.send(FloppierS2CMessage::Pause)?;
+
+        let FloppierC2SMessage::PauseAck = self.receive()? else {
+            bail!("expected pause ack message from client");
+        };
+
+        if let Some(clock) = &mut self.clock {
+            clock.pause();
+        }
+
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        self.send(FloppierS2CMessage::Resume)?;
+
+        let FloppierC2SMessage::ResumeAck = self.receive()? else {
+            bail!("expected resume ack message from client");
+        };
+
+        if let Some(clock) = &mut self.clock {
+            clock.resume();
+        }
+
+        Ok(())
+    }
+
+    /// Moves the drive heads back to their starting position once the MIDI events sent so far have
+    /// been acknowledged
+    ///
+    /// The client silences the drives while resetting them. Does nothing if the client is too old
+    /// to support it.
+    pub fn reset_drives(&mut self) -> Result<()> {
+        if !self.supports(EXTENSIONS_VERSION) {
+            warn!("client doesn't support resetting the drives");
+            return Ok(());
+        }
+
+        self.flush_acks()?;
+        self.send(FloppierS2CMessage::ResetDrives)?;
+
+        let FloppierC2SMessage::ResetDrivesAck = self.receive()? else {
+            bail!("expected reset drives ack message from client");
+        };
+
+        Ok(())
+    }
+
+    /// Plays a note on a single drive of a stack of `drive_count` drives, returning once it has
+    /// started
+    ///
+    /// Can be sent straight after the hello handshake, and puts the client into calibration until
+    /// the session is ended or configured.
+    pub fn test_drive(
+        &mut self,
+        index: u8,
+        note: u8,
+        duration: Duration,
+        drive_count: u8,
+    ) -> Result<()> {
+        ensure!(
+            self.supports(EXTENSIONS_VERSION),
+            "client doesn't support testing drives"
+        );
+
+        self.send(FloppierS2CMessage::TestDrive {
+            index,
+            note,
+            duration_ms: duration.as_millis().min(u16::MAX as u128) as u16,
+            drive_count,
+        })?;
+
+        let FloppierC2SMessage::TestDriveAck = self.receive()? else {
+            bail!("expected test drive ack message from client");
+        };
+
+        Ok(())
+    }
+
+    /// Collects any MIDI event acks that have arrived and pings the client every `ping_interval`
+    ///
+    /// Call this regularly while waiting between events. Fails if a ping isn't answered within
+    /// `ping_timeout`, since that means the client is wedged or the connection has been lost.
+    pub fn heartbeat(&mut self) -> Result<()> {
+        while let Some(message) = self.try_receive()? {
+            self.handle_ack(message)?;
+        }
+
+        if !self.supports(EXTENSIONS_VERSION) {
+            return Ok(());
+        }
+
+        match self.pending_ping {
+            Some((cookie, sent_at)) => ensure!(
+                sent_at.elapsed() < self.ping_timeout,
+                "client didn't answer ping {} within {:?}, the connection was lost",
+                cookie,
+                self.ping_timeout
+            ),
+            None if self.last_ping.elapsed() >= self.ping_interval => {
+                let cookie = self.next_ping_cookie;
+                self.next_ping_cookie = cookie.wrapping_add(1);
+
+                self.send(FloppierS2CMessage::Ping(cookie))?;
+
+                self.last_ping = Instant::now();
+                self.pending_ping = Some((cookie, self.last_ping));
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_pong(&mut self, cookie: u32) {
+        match self.pending_ping {
+            Some((pending, _)) if pending == cookie => self.pending_ping = None,
+            _ => warn!("client answered unknown ping {}", cookie),
+        }
     }
 
     pub fn send(&mut self, message: FloppierS2CMessage) -> Result<()> {
+        let frame = self.encode(&message)?;
+
+        self.send_frame(frame)
+    }
+
+    fn encode(&self, message: &FloppierS2CMessage) -> Result<Vec<u8>> {
         let mut data = Vec::new();
 
-        ciborium::into_writer(&message, &mut data)?;
+        ciborium::into_writer(message, &mut data)?;
 
-        let len = data.len() as u16;
+        trace!("Sending {:?}", message);
 
-        dbg!(&message);
-        // dbg!(&len);
-        // dbg!(&len.to_le_bytes());
-        // dbg!(&data);
+        Ok(frame::encode(&data, self.checksum_frames))
+    }
 
-        self.port.write_all(&len.to_le_bytes())?;
-        self.port.write_all(&data)?;
-        self.port.flush()?;
+    fn send_frame(&mut self, frame: Vec<u8>) -> Result<()> {
+        self.write_frame(&frame)?;
+        self.in_flight.push_back(frame);
 
         Ok(())
     }
 
-    pub fn receive(&mut self) -> Result<FloppierC2SMessage> {
-        const TIMEOUT_MS: u128 = 10_000;
+    fn write_frame(&mut self, frame: &[u8]) -> Result<()> {
+        self.transport.write_all(frame)?;
+        self.transport.flush()?;
+
+        Ok(())
+    }
+
+    /// Sends a MIDI event (or batch) without waiting for its ack
+    ///
+    /// Acks are collected whenever they have arrived, and this only blocks once `ack_window` acks
+    /// are outstanding or the client has reported its read buffer is too full to take the message.
+    /// Call `flush_acks` to wait for the rest.
+    pub fn send_windowed(&mut self, message: FloppierS2CMessage) -> Result<()> {
+        let frame = self.encode(&message)?;
 
-        let start_time = std::time::Instant::now();
+        while !self.backpressure.can_send(frame.len(), self.ack_window) {
+            let message = self.receive()?;
+            self.handle_ack(message)?;
+        }
+
+        self.backpressure.sent(&message, frame.len());
+        self.send_frame(frame)?;
+
+        while let Some(message) = self.try_receive()? {
+            self.handle_ack(message)?;
+        }
+
+        Ok(())
+    }
 
+    /// Waits for the acks of all the messages sent with `send_windowed`
+    pub fn flush_acks(&mut self) -> Result<()> {
+        while self.backpressure.pending_acks() > 0 {
+            let message = self.receive()?;
+            self.handle_ack(message)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_ack(&mut self, message: FloppierC2SMessage) -> Result<()> {
+        ensure!(
+            self.backpressure.acked(&message),
+            "expected midi event ack from client, got {:?}",
+            message
+        );
+
+        Ok(())
+    }
+
+    /// Waits for a message from the client
+    ///
+    /// Errors reported by the client are returned as a `ClientError`, and pongs are handled here
+    /// rather than returned. Fails if nothing arrives within the receive timeout.
+    pub fn receive(&mut self) -> Result<FloppierC2SMessage> {
         loop {
-            if self.port.bytes_to_read()? > 0 {
-                break;
+            if let Some(message) = self.read_response(self.receive_timeout)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Returns a message from the client if one has started arriving
+    pub fn try_receive(&mut self) -> Result<Option<FloppierC2SMessage>> {
+        while self.transport.has_data()? {
+            if let Some(message) = self.read_response(self.read_timeout)? {
+                return Ok(Some(message));
             }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the next response from the client, handling pongs itself (returning `None` for them)
+    fn read_response(&mut self, timeout: Duration) -> Result<Option<FloppierC2SMessage>> {
+        match self.read_message(timeout)? {
+            FloppierC2SMessage::Pong(cookie) => {
+                self.handle_pong(cookie);
+                Ok(None)
+            }
+            message => Ok(Some(message)),
+        }
+    }
+
+    /// Reads the next response from the client, sending frames again when the client reports a
+    /// frame error
+    fn read_message(&mut self, timeout: Duration) -> Result<FloppierC2SMessage> {
+        loop {
+            let message = self.read_frame(timeout)?;
+
+            match message {
+                FloppierC2SMessage::FrameError if self.checksum_frames => {
+                    // The client responds to frames in order and has dropped everything since the
+                    // corrupted one, so every frame in flight goes again from the oldest, after a
+                    // marker for where they start
+                    warn!(
+                        "client reported a frame error, sending {} frames again",
+                        self.in_flight.len()
+                    );
+
+                    let marker = self.encode(&FloppierS2CMessage::Retransmit)?;
+                    self.transport.write_all(&marker)?;
+
+                    for frame in &self.in_flight {
+                        self.transport.write_all(frame)?;
+                    }
+
+                    self.transport.flush()?;
+                }
+                FloppierC2SMessage::FrameError => {
+                    // Without checksums the client only rejects the frame it couldn't parse, and
+                    // responds to frames in order, so the error is for the oldest one
+                    let frame = self
+                        .in_flight
+                        .pop_front()
+                        .context("client reported a frame error with no frames in flight")?;
 
-            if start_time.elapsed().as_millis() > TIMEOUT_MS {
-                bail!("timed out waiting for client response");
+                    warn!("client reported a frame error, sending frame again");
+
+                    self.write_frame(&frame)?;
+                    self.in_flight.push_back(frame);
+                }
+                // Sent by the client on its own rather than in response to a frame
+                FloppierC2SMessage::Ready => {
+                    // The client starts its clock afresh and forgets its timed events
+                    self.clock = Some(Scheduler::new(SystemClock::new()));
+                    self.backpressure.queued_events = 0;
+
+                    return Ok(message);
+                }
+                FloppierC2SMessage::Error { kind, detail } => {
+                    self.in_flight.pop_front();
+                    return Err(ClientError { kind, detail }.into());
+                }
+                _ => {
+                    self.in_flight.pop_front();
+                    return Ok(message);
+                }
             }
         }
+    }
+
+    /// Reads a frame, waiting up to `timeout` for it to start arriving and then up to the read
+    /// timeout for the rest of it
+    fn read_frame(&mut self, timeout: Duration) -> Result<FloppierC2SMessage> {
+        let len_bytes = self.read_bytes(frame::LEN_LEN, timeout)?;
+        let header = FrameHeader::parse([len_bytes[0], len_bytes[1]]);
 
-        let len_buf = self.read_bytes(2)?;
-        let len = u16::from_le_bytes(len_buf.try_into().unwrap());
+        let mut frame = len_bytes;
+        frame.extend(self.read_bytes(header.frame_len() - frame::LEN_LEN, self.read_timeout)?);
 
-        let message_buf = self.read_bytes(len as usize)?;
-        let message = ciborium::from_reader(&message_buf[..])?;
+        let Some(payload) = header.payload(&frame) else {
+            bail!("received a frame with a bad checksum from the client");
+        };
+
+        let message = ciborium::from_reader(payload)?;
 
         Ok(message)
     }
 
-    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+    /// Reads exactly `len` bytes, which may arrive across several reads, within `timeout`
+    ///
+    /// Each read blocks in the transport for the time remaining rather than polling it.
+    fn read_bytes(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>> {
         let mut buf = vec![0u8; len];
-        let bytes_read = self.port.read(&mut buf)?;
+        let mut bytes_read = 0;
+
+        let start_time = Instant::now();
+
+        while bytes_read < len {
+            let Some(remaining) = timeout
+                .checked_sub(start_time.elapsed())
+                .filter(|remaining| !remaining.is_zero())
+            else {
+                if bytes_read == 0 {
+                    bail!("timed out waiting for client response");
+                }
 
-        if bytes_read != len {
-            bail!("expected {} bytes, got {}", len, bytes_read);
+                bail!("expected {} bytes, got {}", len, bytes_read);
+            };
+
+            self.transport.set_timeout(remaining)?;
+
+            match self.transport.read(&mut buf[bytes_read..]) {
+                // Serial ports time out rather than reading nothing, so only a socket gets here
+                Ok(0) => bail!("the connection to the client was closed"),
+                Ok(count) => bytes_read += count,
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
         }
 
         Ok(buf)
     }
 }
+
+/// Whether a read error just means no data was available yet
+fn is_retryable(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use floppier_proto::{FloppierS2CMessageKind, LimitedMidiMessage, MidiEvent};
+
+    use super::*;
+
+    /// A transport that hands out the data it was given one byte per read
+    struct FakePort {
+        data: VecDeque<u8>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl FakePort {
+        fn new(data: &[u8]) -> Box<Self> {
+            Box::new(Self {
+                data: data.iter().copied().collect(),
+                written: Arc::default(),
+            })
+        }
+    }
+
+    impl std::io::Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.data.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Err(ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    impl std::io::Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for FakePort {
+        fn set_timeout(&mut self, _: Duration) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn has_data(&self) -> std::io::Result<bool> {
+            Ok(!self.data.is_empty())
+        }
+    }
+
+    /// Frames a message the same way the client does
+    fn frame(message: &FloppierC2SMessage) -> Vec<u8> {
+        let mut data = Vec::new();
+        ciborium::into_writer(message, &mut data).unwrap();
+
+        frame::encode(&data, true)
+    }
+
+    #[test]
+    fn reassembles_fragmented_frames() {
+        let mut data = frame(&FloppierC2SMessage::SetConfigAck);
+        data.extend(frame(&FloppierC2SMessage::Ready));
+
+        let mut client = Client::new(FakePort::new(&data));
+
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::SetConfigAck
+        ));
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::Ready
+        ));
+        assert!(client.try_receive().unwrap().is_none());
+    }
+
+    #[test]
+    fn times_out_on_truncated_frame() {
+        let data = frame(&FloppierC2SMessage::SetConfigAck);
+
+        let mut client = Client::new(FakePort::new(&data[..data.len() - 1]));
+        client.set_read_timeout(Duration::from_millis(10));
+
+        assert_eq!(
+            client.receive().unwrap_err().to_string(),
+            format!(
+                "expected {} bytes, got {}",
+                data.len() - frame::LEN_LEN,
+                data.len() - frame::LEN_LEN - 1
+            )
+        );
+    }
+
+    #[test]
+    fn times_out_waiting_for_response() {
+        let mut client = Client::new(FakePort::new(&[]));
+        client.set_receive_timeout(Duration::from_millis(10));
+
+        let start_time = Instant::now();
+
+        assert_eq!(
+            client.receive().unwrap_err().to_string(),
+            "timed out waiting for client response"
+        );
+        assert!(start_time.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn sends_every_frame_in_flight_again_on_frame_error() {
+        let mut data = frame(&FloppierC2SMessage::FrameError);
+        data.extend(frame(&FloppierC2SMessage::PauseAck));
+        data.extend(frame(&FloppierC2SMessage::ResumeAck));
+
+        let port = FakePort::new(&data);
+        let written = port.written.clone();
+
+        let mut client = Client::new(port);
+        client.checksum_frames = true;
+
+        client.send(FloppierS2CMessage::Pause).unwrap();
+        client.send(FloppierS2CMessage::Resume).unwrap();
+
+        let sent = written.lock().unwrap().clone();
+        let marker = client.encode(&FloppierS2CMessage::Retransmit).unwrap();
+
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::PauseAck
+        ));
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::ResumeAck
+        ));
+        assert_eq!(
+            *written.lock().unwrap(),
+            [&sent[..], &marker[..], &sent[..]].concat()
+        );
+        assert!(client.in_flight.is_empty());
+    }
+
+    #[test]
+    fn sends_frame_again_on_frame_error() {
+        let mut data = frame(&FloppierC2SMessage::FrameError);
+        data.extend(frame(&FloppierC2SMessage::EndAck));
+
+        let port = FakePort::new(&data);
+        let written = port.written.clone();
+
+        let mut client = Client::new(port);
+        client.send(FloppierS2CMessage::End).unwrap();
+
+        let sent = written.lock().unwrap().clone();
+
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::EndAck
+        ));
+        assert_eq!(*written.lock().unwrap(), [&sent[..], &sent[..]].concat());
+    }
+
+    #[test]
+    fn only_checksums_frames_for_clients_that_support_it() {
+        for (proto_version, checksummed) in [(0x0200, false), (EXTENSIONS_VERSION, true)] {
+            // The hello ack is never checksummed, since the client doesn't know the server's
+            // version until it has sent it
+            let mut data = Vec::new();
+            ciborium::into_writer(&FloppierC2SMessage::HelloAck { proto_version }, &mut data)
+                .unwrap();
+
+            let port = FakePort::new(&frame::encode(&data, false));
+            let written = port.written.clone();
+
+            let mut client = Client::new(port);
+            client.hello().unwrap();
+
+            let hello_len = written.lock().unwrap().len();
+            client.send(FloppierS2CMessage::End).unwrap();
+
+            let written = written.lock().unwrap();
+            let header = FrameHeader::parse(
+                written[hello_len..hello_len + frame::LEN_LEN]
+                    .try_into()
+                    .unwrap(),
+            );
+
+            assert_eq!(header.checksummed, checksummed);
+        }
+    }
+
+    #[test]
+    fn client_errors_can_be_downcast() {
+        let data = frame(&FloppierC2SMessage::Error {
+            kind: FloppierErrorKind::DriveIndexOutOfRange { index: 4, count: 2 },
+            detail: None,
+        });
+
+        let mut client = Client::new(FakePort::new(&data));
+
+        let error = client.receive().unwrap_err();
+
+        assert_eq!(
+            error.downcast_ref::<ClientError>().unwrap().kind,
+            FloppierErrorKind::DriveIndexOutOfRange { index: 4, count: 2 }
+        );
+    }
+
+    #[test]
+    fn recognises_a_restarted_client() {
+        let data = frame(&FloppierC2SMessage::Error {
+            kind: FloppierErrorKind::UnexpectedMessage {
+                state: ClientState::WaitingForHello,
+                got: FloppierS2CMessageKind::MidiEventBatch,
+            },
+            detail: None,
+        });
+
+        let mut client = Client::new(FakePort::new(&data));
+
+        let error = client.receive().unwrap_err();
+
+        assert!(error
+            .downcast_ref::<ClientError>()
+            .is_some_and(ClientError::is_waiting_for_hello));
+
+        let config_error = ClientError {
+            kind: FloppierErrorKind::DriveIndexOutOfRange { index: 4, count: 2 },
+            detail: None,
+        };
+
+        assert!(!config_error.is_waiting_for_hello());
+    }
+
+    #[test]
+    fn talks_to_a_client_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let bridge = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            stream
+                .write_all(&frame(&FloppierC2SMessage::Ready))
+                .unwrap();
+        });
+
+        let mut client = Client::open(&ConnectOptions {
+            endpoint: Endpoint::Tcp { address },
+            link_timeout: None,
+            receive_timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::Ready
+        ));
+
+        bridge.join().unwrap();
+
+        assert_eq!(
+            client.receive().unwrap_err().to_string(),
+            "the connection to the client was closed"
+        );
+    }
+
+    #[test]
+    fn reports_status_without_a_hello() {
+        let report = StatusReport {
+            uptime_us: 5_000_000,
+            tick_overruns: 2,
+            max_tick_us: 23,
+            heap_free: 1024,
+            state: ClientState::PlayingMidiStream,
+            events_processed: 300,
+        };
+
+        let mut client = Client::new(FakePort::new(&frame(&FloppierC2SMessage::StatusReport(
+            report,
+        ))));
+
+        assert_eq!(client.status().unwrap(), report);
+
+        let old_client_error = frame(&FloppierC2SMessage::Error {
+            kind: FloppierErrorKind::DeserializeFailed,
+            detail: None,
+        });
+
+        let mut old_client = Client::new(FakePort::new(&old_client_error));
+
+        assert!(old_client
+            .status()
+            .unwrap_err()
+            .to_string()
+            .contains("too old"));
+    }
+
+    #[test]
+    fn pongs_are_not_returned() {
+        let mut data = frame(&FloppierC2SMessage::Pong(7));
+        data.extend(frame(&FloppierC2SMessage::EndAck));
+
+        let mut client = Client::new(FakePort::new(&data));
+        client.pending_ping = Some((7, Instant::now()));
+
+        assert!(matches!(
+            client.receive().unwrap(),
+            FloppierC2SMessage::EndAck
+        ));
+        assert_eq!(client.pending_ping, None);
+    }
+
+    #[test]
+    fn tracks_timed_event_queue() {
+        let data = frame(&FloppierC2SMessage::TimedMidiEventBatchAck { queued: 10 });
+
+        let mut client = Client::new(FakePort::new(&data));
+
+        let event = MidiEvent {
+            track: 1,
+            channel: 1,
+            message: LimitedMidiMessage::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+        };
+        let batch = |events| FloppierS2CMessage::TimedMidiEventBatch {
+            timestamp_us: 1_000,
+            events,
+        };
+
+        // The ack arrives straight away and reports what the client has queued in total
+        client.send_windowed(batch(vec![event; 3])).unwrap();
+
+        assert_eq!(client.timed_event_room(), MAX_TIMED_EVENTS - 10);
+
+        // Events still waiting for an ack count too
+        client.send_windowed(batch(vec![event; 2])).unwrap();
+
+        assert_eq!(client.timed_event_room(), MAX_TIMED_EVENTS - 12);
+    }
+
+    #[test]
+    fn holds_back_until_a_slow_client_has_room() {
+        let mut backpressure = Backpressure::default();
+        let batch = FloppierS2CMessage::TimedMidiEventBatch {
+            timestamp_us: 0,
+            events: vec![],
+        };
+        let status = |queue_free, buffer_free| FloppierC2SMessage::Status {
+            queue_free,
+            buffer_free,
+        };
+
+        // Older clients don't report their read buffer, so only the ack window holds back
+        backpressure.sent(&batch, 1_000);
+        assert!(backpressure.can_send(1_000, 2));
+
+        backpressure.sent(&batch, 1_000);
+        assert!(!backpressure.can_send(10, 2));
+
+        // The client is falling behind, with most of its read buffer still to get through
+        assert!(backpressure.acked(&status(0, 1_500)));
+        assert_eq!(backpressure.buffer_room(), Some(500));
+        assert_eq!(backpressure.timed_event_room(), 0);
+        assert!(backpressure.can_send(500, 16));
+        assert!(!backpressure.can_send(501, 16));
+
+        // Once it has caught up there's room again
+        assert!(backpressure.acked(&status(MAX_TIMED_EVENTS as u16, 4_096)));
+        assert_eq!(backpressure.pending_acks(), 0);
+        assert_eq!(backpressure.timed_event_room(), MAX_TIMED_EVENTS);
+        assert!(backpressure.can_send(5_000, 16));
+
+        assert!(!backpressure.acked(&FloppierC2SMessage::EndAck));
+    }
+
+    #[test]
+    fn defers_the_first_interrupt() {
+        let _signals = SignalHandler::install().unwrap();
+        let interrupts = DeferredInterrupts::start();
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGINT).unwrap();
+
+        assert!(interrupted());
+
+        drop(interrupts);
+    }
+
+    #[test]
+    fn unanswered_ping_is_an_error() {
+        let port = FakePort::new(&[]);
+        let written = port.written.clone();
+
+        let mut client = Client::new(port);
+        client.proto_version = PROTO_VERSION;
+        client.set_ping_interval(Duration::ZERO);
+        client.set_ping_timeout(Duration::ZERO);
+
+        client.heartbeat().unwrap();
+
+        assert!(!written.lock().unwrap().is_empty());
+        assert!(client.heartbeat().is_err());
+    }
+}
@@ -1,2 +1,6 @@
 pub mod io;
-pub mod midi;
\ No newline at end of file
+#[cfg(feature = "live")]
+pub mod live;
+pub mod midi;
+pub mod playback;
+pub mod simulate;
\ No newline at end of file
@@ -0,0 +1,173 @@
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use floppier_proto::MidiEvent;
+use midir::{MidiInput, MidiInputConnection};
+use midly::live::LiveEvent;
+
+use crate::midi::to_limited_message;
+
+const CLIENT_NAME: &str = "floppier-server";
+
+/// The names of the MIDI input ports that can be played from
+pub fn input_port_names() -> Result<Vec<String>> {
+    let midi_input = MidiInput::new(CLIENT_NAME)?;
+
+    midi_input
+        .ports()
+        .iter()
+        .map(|port| Ok(midi_input.port_name(port)?))
+        .collect()
+}
+
+/// An open MIDI input port, with its messages converted into MIDI events as they arrive
+pub struct LiveInput {
+    port_name: String,
+    events: Receiver<MidiEvent>,
+    /// Closes the port when dropped
+    _connection: MidiInputConnection<()>,
+}
+
+impl LiveInput {
+    /// Opens the input port picked by `selector` (its index in `input_port_names` or part of its
+    /// name), giving its events the track number `track`
+    pub fn open(selector: &str, track: u16, control_changes: bool) -> Result<Self> {
+        let midi_input = MidiInput::new(CLIENT_NAME)?;
+        let ports = midi_input.ports();
+
+        let names = ports
+            .iter()
+            .map(|port| Ok(midi_input.port_name(port)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        let index = select_port(&names, selector)?;
+
+        let (sender, events) = mpsc::channel();
+
+        let connection = midi_input
+            .connect(
+                &ports[index],
+                CLIENT_NAME,
+                move |_timestamp, bytes, _| {
+                    if let Some(event) = parse_event(bytes, track, control_changes) {
+                        // The receiver is only gone once we're closing anyway
+                        let _ = sender.send(event);
+                    }
+                },
+                (),
+            )
+            .map_err(|error| anyhow!("could not open MIDI input `{}`: {}", names[index], error))?;
+
+        Ok(Self {
+            port_name: names[index].clone(),
+            events,
+            _connection: connection,
+        })
+    }
+
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// Waits up to `timeout` for the next event
+    pub fn next_event(&self, timeout: Duration) -> Result<Option<MidiEvent>> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("MIDI input was disconnected"),
+        }
+    }
+
+    /// The events that have already arrived, without waiting for more
+    pub fn pending_events(&self) -> impl Iterator<Item = MidiEvent> + '_ {
+        self.events.try_iter()
+    }
+}
+
+/// Finds a port by its index or, failing that, by part of its name
+fn select_port(names: &[String], selector: &str) -> Result<usize> {
+    if let Ok(index) = selector.parse::<usize>() {
+        if index < names.len() {
+            return Ok(index);
+        }
+
+        bail!(
+            "there is no MIDI input {} (found {} inputs)",
+            index,
+            names.len()
+        );
+    }
+
+    let lowercase = selector.to_lowercase();
+
+    let mut matches = names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| name.to_lowercase().contains(&lowercase))
+        .map(|(index, _)| index);
+
+    match (matches.next(), matches.next()) {
+        (Some(index), None) => Ok(index),
+        (None, _) => bail!("no MIDI input matches `{}`", selector),
+        (Some(_), Some(_)) => bail!("more than one MIDI input matches `{}`", selector),
+    }
+}
+
+/// Converts a raw MIDI message into an event, or `None` if it isn't one the client supports
+fn parse_event(bytes: &[u8], track: u16, control_changes: bool) -> Option<MidiEvent> {
+    let LiveEvent::Midi { channel, message } = LiveEvent::parse(bytes).ok()? else {
+        return None;
+    };
+
+    Some(MidiEvent {
+        track,
+        // Channels are numbered from 1 like the ones in MIDI files
+        channel: channel.as_int() + 1,
+        message: to_limited_message(&message, control_changes)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use floppier_proto::LimitedMidiMessage;
+
+    use super::*;
+
+    #[test]
+    fn selects_ports_by_index_or_name() {
+        let names = [
+            "Midi Through:Midi Through Port-0 14:0".to_string(),
+            "Digital Piano:Digital Piano MIDI 1 20:0".to_string(),
+        ];
+
+        assert_eq!(select_port(&names, "1").unwrap(), 1);
+        assert_eq!(select_port(&names, "piano").unwrap(), 1);
+        assert!(select_port(&names, "2").is_err());
+        assert!(select_port(&names, "midi").is_err());
+        assert!(select_port(&names, "organ").is_err());
+    }
+
+    #[test]
+    fn parses_live_messages() {
+        let event = parse_event(&[0x92, 60, 100], 1, false).unwrap();
+
+        assert_eq!(event.channel, 3);
+        assert!(matches!(
+            event.message,
+            LimitedMidiMessage::NoteOn {
+                note: 60,
+                velocity: 100
+            }
+        ));
+
+        // Volume changes are only passed on when control changes are enabled
+        assert!(parse_event(&[0xB0, 7, 80], 1, false).is_none());
+        assert!(parse_event(&[0xB0, 7, 80], 1, true).is_some());
+
+        // System messages aren't supported
+        assert!(parse_event(&[0xF8], 1, false).is_none());
+    }
+}
@@ -1,173 +1,1856 @@
-use std::{path::PathBuf, thread, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{stdout, BufWriter, Write},
+    ops::{Deref, DerefMut, Range, RangeInclusive},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
-use clap::Parser;
-use floppier_proto::{FloppierC2SMessage, FloppierS2CMessage, MidiEvent, SetConfig};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use floppier_proto::{
+    FloppierC2SMessage, FloppierS2CMessage, LimitedMidiMessage, MidiEvent, ParallelMode,
+    ResetTiming, SetConfig, EXTENSIONS_VERSION, MAX_MIDI_EVENT_BATCH, MAX_PLAYABLE_NOTE,
+    MIN_PLAYABLE_NOTE,
+};
+use log::{debug, log_enabled, warn, Level};
+use termion::{clear, event::Key};
 
+#[cfg(feature = "live")]
+use floppier_server::live::{self, LiveInput};
 use floppier_server::{
-    io::Client,
-    midi::{parse_midi_file, ticks_to_microseconds},
+    io::{
+        detect_client_port, find_client_port, init_logger, Client, ClientError, ConnectOptions,
+        DeferredInterrupts, Endpoint, KeyReader, SignalHandler, DEFAULT_PING_INTERVAL,
+    },
+    midi::{
+        parse_midi_file, parse_track_names, AbsoluteMidiEvent, MidiFile, MidiParseOptions,
+        NoteHistogram,
+    },
     pause,
+    playback::{note_name, Progress, ResumeState, Scheduler, SystemClock},
+    simulate::render_wav,
 };
 
+use crate::config::{FoldReport, MappingReport, Movement, PlaylistEntry, SongConfig};
+
 mod config;
 
+/// How often to check for key presses while waiting for the next event
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often to redraw the progress line while playing
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How late events can be sent (beyond any lookahead) before warning that the link can't keep up
+const MAX_DRIFT: Duration = Duration::from_millis(20);
+
+/// How often to look for the client to reappear after losing the connection
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to wait between the songs of a playlist that doesn't give a gap
+const DEFAULT_SONG_GAP: Duration = Duration::from_secs(2);
+
+/// How long to leave the drives silent between test tones, so each drive can be told apart
+const TEST_TONE_GAP: Duration = Duration::from_millis(250);
+
+/// The range of playback speeds, so the scaled event times stay within what a `Duration` can hold
+const SPEED_RANGE: RangeInclusive<f64> = 0.01..=100.0;
+
 /// Server program to drive Floppier hardware client
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct FloppierArgs {
-    /// Path to the MIDI configuration file
-    #[arg(short, long)]
-    pub path: PathBuf,
+    /// Show debug output, like the timing of each tick (set `RUST_LOG` for finer control)
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Play a song, or a playlist of songs, on the client
+    Play(PlayArgs),
+    /// Hold a single note on a stack of drives, without needing a song configuration
+    Hold(HoldArgs),
+    /// Home the drive heads of a song configuration's stack
+    Reset(ResetArgs),
+    /// Play a test tone on each drive in turn, to check that every drive works and is where the
+    /// configuration expects it
+    Test(TestArgs),
+    /// Print how the client has been running (its uptime, timer overruns and free memory), without
+    /// disturbing a session in progress
+    Status(StatusArgs),
+}
 
-    /// Verbose output
+/// How to connect to the client, shared by every command that does
+#[derive(Args, Debug)]
+pub struct ConnectionArgs {
+    /// Serial port the client is connected to (detected automatically if not given)
     #[arg(short, long)]
-    pub verbose: bool,
+    pub serial_port: Option<String>,
 
-    /// Serial port configuration
-    #[arg(short, long, default_value = "/dev/ttyUSB0")]
-    pub serial_port: String,
+    /// Detect the serial port of the client by its USB IDs (the default when no port is given)
+    #[arg(long, conflicts_with = "serial_port")]
+    pub auto: bool,
 
     /// Serial port baud rate
     #[arg(short, long, default_value_t = 115_200)]
     pub baud_rate: u32,
+
+    /// Connect to the client through a serial-to-TCP bridge instead of a serial port
+    #[arg(
+        long,
+        value_name = "tcp://HOST:PORT",
+        value_parser = parse_transport,
+        conflicts_with_all = ["serial_port", "auto"],
+    )]
+    pub transport: Option<String>,
+
+    /// Silence the drives if the client hears nothing from the server for this long, in case the
+    /// connection is lost mid-song (0 to never time out)
+    #[arg(long, default_value_t = 5_000, value_parser = parse_link_timeout)]
+    pub link_timeout_ms: u64,
+
+    /// Time to wait for the client to respond to a message before giving up on it
+    #[arg(long, default_value_t = 10_000)]
+    pub receive_timeout_ms: u64,
 }
 
-fn main() -> Result<()> {
-    /* Parse the CLI arguments and the passed in cong configuration */
+impl ConnectionArgs {
+    /// The options to connect to the client at an endpoint with
+    fn options(&self, endpoint: Endpoint) -> ConnectOptions {
+        ConnectOptions {
+            endpoint,
+            link_timeout: (self.link_timeout_ms > 0)
+                .then(|| Duration::from_millis(self.link_timeout_ms)),
+            receive_timeout: Duration::from_millis(self.receive_timeout_ms),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PlayArgs {
+    /// Path to the song configuration file, or a playlist file or directory of song configuration
+    /// files to play back to back
+    #[arg(short, long)]
+    pub path: PathBuf,
+
+    /// Wait for each MIDI event to be acknowledged before sending the next one
+    #[arg(long)]
+    pub sync_acks: bool,
+
+    /// Send events this far ahead of time for the client to play on its own clock, which keeps
+    /// fast passages tight despite USB and scheduling jitter (0 to send each event when it's due)
+    #[arg(long, default_value_t = 0)]
+    pub lookahead_ms: u64,
+
+    /// Print the events that would be sent instead of connecting to the client
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Refuse to play a song that has notes on tracks or channels the configuration doesn't map to
+    /// any drives
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Print the range of notes each track and channel plays, and how many the drives can't play,
+    /// instead of playing the song (the path can be a MIDI file or a song configuration)
+    #[arg(long, conflicts_with_all = ["dry_run", "simulate"])]
+    pub analyze: bool,
+
+    /// Write the song's events, metadata and tempo changes to a JSON file instead of connecting to
+    /// the client
+    #[arg(long, value_name = "JSON_PATH", conflicts_with_all = ["dry_run", "simulate", "analyze"])]
+    pub export_json: Option<PathBuf>,
+
+    /// Render what the drives would sound like to a WAV file instead of connecting to the client
+    #[arg(long, value_name = "WAV_PATH", conflicts_with = "dry_run")]
+    pub simulate: Option<PathBuf>,
+
+    /// Playback speed multiplier (e.g. 0.5 for half speed). Only the timing of the events changes,
+    /// the drives still play every note at its true pitch.
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    pub speed: f64,
+
+    /// Start playing from this far into the song (seconds or mm:ss)
+    #[arg(long, value_parser = parse_timestamp)]
+    pub start_at: Option<Duration>,
+
+    /// Stop playing once this far into the song (seconds or mm:ss)
+    #[arg(long, value_parser = parse_timestamp)]
+    pub stop_at: Option<Duration>,
+
+    /// Only play the events of this track (can be given more than once)
+    #[arg(long = "only-track", value_name = "TRACK")]
+    pub only_tracks: Vec<u16>,
+
+    /// Only play the events on this channel (can be given more than once)
+    #[arg(
+        long = "only-channel",
+        value_name = "CHANNEL",
+        value_parser = clap::value_parser!(u8).range(1..=16),
+    )]
+    pub only_channels: Vec<u8>,
+
+    /// Play the song this many times, or forever if no count (or 0) is given
+    #[arg(long = "loop", value_name = "COUNT")]
+    pub loop_count: Option<Option<u32>>,
+
+    /// Time to wait between the end of the song and playing it again when looping
+    #[arg(long, default_value_t = 1_000)]
+    pub loop_gap_ms: u64,
+
+    /// Home the drive heads between plays when looping, so they don't wander out of place over
+    /// many loops
+    #[arg(long)]
+    pub reset_between_loops: bool,
+
+    /// Play MIDI input from a keyboard or other MIDI device as it arrives, instead of the song's
+    /// MIDI file
+    #[cfg(feature = "live")]
+    #[arg(long, conflicts_with_all = [
+        "dry_run", "simulate", "analyze", "export_json", "start_at", "stop_at", "loop_count",
+        "only_tracks", "only_channels",
+    ])]
+    pub live: bool,
+
+    /// MIDI input port to play live (its index or part of its name). The ports are listed to pick
+    /// from if not given.
+    #[cfg(feature = "live")]
+    #[arg(long, requires = "live")]
+    pub midi_input: Option<String>,
+
+    /// Track number of the song configuration to map live MIDI input through
+    #[cfg(feature = "live")]
+    #[arg(long, default_value_t = 1)]
+    pub live_track: u16,
+
+    /// Exit when the connection to the client is lost instead of reconnecting and resuming the
+    /// track
+    #[arg(long)]
+    pub no_resume: bool,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct HoldArgs {
+    /// How many drives are stacked on the client
+    #[arg(long, default_value_t = 3)]
+    pub drive_count: u8,
+
+    /// The note to hold on every drive
+    #[arg(
+        long,
+        default_value_t = 72,
+        value_parser = clap::value_parser!(u8).range(MIN_PLAYABLE_NOTE as i64..=MAX_PLAYABLE_NOTE as i64),
+    )]
+    pub note: u8,
+
+    /// How long to hold the note for
+    #[arg(long, default_value_t = 300)]
+    pub seconds: u64,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ResetArgs {
+    /// Path to the song configuration file of the stack to home
+    #[arg(short, long)]
+    pub path: PathBuf,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    /// Path to the song configuration file of the stack to test
+    #[arg(short, long)]
+    pub path: PathBuf,
+
+    /// The note to play on each drive
+    #[arg(
+        long,
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u8).range(MIN_PLAYABLE_NOTE as i64..=MAX_PLAYABLE_NOTE as i64),
+    )]
+    pub note: u8,
+
+    /// How long to play the test tone on each drive
+    #[arg(long, default_value_t = 1_000)]
+    pub duration_ms: u16,
 
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+/// A song that has been loaded and is ready to play
+struct Song {
+    /// Path to the song configuration file
+    path: PathBuf,
+    config: SongConfig,
+    midi_file: MidiFile,
+    mapping: MappingReport,
+    /// Whether the tracks were mapped to the drives automatically
+    auto_assigned: bool,
+    /// How many notes were folded into range, if folding is enabled
+    folds: Option<FoldReport>,
+    /// The events to play (between `--start-at` and `--stop-at`)
+    events: Range<usize>,
+    /// How long to wait after the song before playing the next one
+    gap: Duration,
+}
+
+impl Song {
+    /// The name of the song from the MIDI file, or the name of its configuration file
+    fn name(&self) -> String {
+        match self.midi_file.metadata.track_name() {
+            Some(name) => name.to_string(),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+/// How playing a song ended
+enum PlaybackEnd {
+    /// Every event was played
+    Finished,
+    /// The user asked to stop playing
+    Stopped,
+    /// The connection to the client was lost and the user gave up waiting for it to come back
+    Disconnected,
+}
+
+fn main() -> Result<()> {
     let args = FloppierArgs::parse();
-    let config = config::parse_song_config(&args)?;
 
-    /* Parse the midi file into a more easily consumable representation */
+    init_logger(args.verbose);
 
-    let midi_file = parse_midi_file(&config.midi.path)?;
+    let _signals = SignalHandler::install()?;
 
-    println!();
-    println!("Parsed MIDI file");
-    println!("================");
-    println!("{}", &midi_file.metadata);
-    println!();
+    match &args.command {
+        Command::Play(args) => play(args),
+        Command::Hold(args) => hold(args),
+        Command::Reset(args) => reset(args),
+        Command::Test(args) => test_drives(args),
+        Command::Status(args) => status(args),
+    }
+}
+
+/// Plays the songs of a song configuration or playlist, or analyzes, prints, simulates or exports
+/// them instead
+fn play(args: &PlayArgs) -> Result<()> {
+    /* Work out which songs to play */
+
+    if args.analyze {
+        return analyze(&args.path);
+    }
+
+    #[cfg(feature = "live")]
+    if args.live {
+        return play_live(args);
+    }
+
+    let (entries, is_playlist) = match config::parse_playlist(&args.path)? {
+        Some(mut playlist) => {
+            if playlist.shuffle {
+                fastrand::shuffle(&mut playlist.songs);
+            }
+
+            (playlist.songs, true)
+        }
+        None => (
+            vec![PlaylistEntry {
+                path: args.path.clone(),
+                gap_seconds: None,
+            }],
+            false,
+        ),
+    };
+
+    /* Parse each song configuration and its midi file */
+
+    let mut songs = Vec::new();
+
+    for entry in &entries {
+        match load_song(entry, args) {
+            Ok(song) => songs.push(song),
+            // One broken song shouldn't stop the rest of the playlist
+            Err(error) if is_playlist => {
+                warn!("skipping `{}`: {:#}", entry.path.display(), error)
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    if songs.is_empty() {
+        bail!("none of the songs in the playlist could be loaded");
+    }
+
+    for (index, song) in songs.iter().enumerate() {
+        println!();
+
+        if is_playlist {
+            println!("Song {}/{}: {}", index + 1, songs.len(), song.name());
+        }
+
+        println!("Parsed MIDI file");
+        println!("================");
+        println!("{}", &song.midi_file.metadata);
+        println!("Timing: {}", song.midi_file.timing);
+
+        let duration = song.midi_file.duration().as_secs();
+        println!("Duration: {}:{:02}", duration / 60, duration % 60);
+        println!();
+
+        println!("Track Mapping");
+        println!("================");
+        print!("{}", song.mapping);
+        println!();
+
+        if song.auto_assigned {
+            println!(
+                "The tracks were mapped automatically, copy them into `tracks` to change them:"
+            );
+            println!("{}", song.config.floppy_drives[0].tracks_json());
+            println!();
+        }
+
+        if let Some(folds) = &song.folds {
+            debug!("{}", folds);
+        }
+
+        if song.midi_file.filtered_events > 0 {
+            debug!(
+                "Filtered {} note events below the minimum velocity",
+                song.midi_file.filtered_events
+            );
+        }
+
+        for row in song.mapping.unmapped() {
+            warn!(
+                "track {} channel {} has {} notes but isn't mapped to any drives",
+                row.track, row.channel, row.notes
+            );
+        }
+
+        // Tracks and channels left out with `--only-track` or `--only-channel` have no notes on
+        // purpose
+        let filtered_out = |track, channel| {
+            (!args.only_tracks.is_empty() && !args.only_tracks.contains(&track))
+                || (!args.only_channels.is_empty() && !args.only_channels.contains(&channel))
+        };
+
+        for row in song
+            .mapping
+            .unused()
+            .filter(|row| !filtered_out(row.track, row.channel))
+        {
+            warn!(
+                "track {} channel {} is mapped to drives but has no notes (is it a typo?)",
+                row.track, row.channel
+            );
+        }
+
+        if args.dry_run {
+            dry_run(song, args);
+        }
+    }
+
+    if args.dry_run {
+        return Ok(());
+    }
+
+    if let Some(path) = &args.simulate {
+        let [song] = &songs[..] else {
+            bail!("only a single song can be simulated");
+        };
+
+        println!("Simulating drives...");
+
+        render_wav(
+            &to_set_config(&song.config),
+            &simulated_events(song, args),
+            path,
+        )?;
+
+        println!("Wrote {}", path.display());
+
+        return Ok(());
+    }
+
+    if let Some(path) = &args.export_json {
+        let [song] = &songs[..] else {
+            bail!("only a single song can be exported");
+        };
+
+        let file =
+            File::create(path).with_context(|| format!("failed to create `{}`", path.display()))?;
+
+        song.midi_file.write_json(BufWriter::new(file))?;
+
+        println!("Wrote {}", path.display());
+
+        return Ok(());
+    }
 
     /* Pause the program and wait for the user to initiate the serial communication */
 
     pause!("Press any key to start the serial connection...");
 
-    /* List Available Serial Ports */
+    println!(
+        "Configuring client with ID {}...",
+        songs[0].config.floppy_drives[0].id
+    );
 
-    println!();
-    for port in serialport::available_ports()? {
-        println!("{:?}", port);
+    let _interrupts = DeferredInterrupts::start();
+
+    let mut client = connect_and_configure(&args.connection, to_set_config(&songs[0].config))?;
+    let mut client = EndGuard::new(&mut client);
+
+    if args.lookahead_ms > 0 && !client.supports(EXTENSIONS_VERSION) {
+        warn!("client can't queue timed events, sending each event when it's due");
     }
-    println!();
 
-    /* Open a serial connection with the supplied settings */
+    println!("Press any key to play the track...");
 
-    let port = args.serial_port;
-    let baud_rate = args.baud_rate;
+    let mut keys = KeyReader::new()?;
 
-    println!();
-    println!("Serial Connection");
+    // Keep the connection alive so the client doesn't time out while we wait
+    let key = loop {
+        if let Some(key) = keys.next_key() {
+            break key;
+        }
+
+        client.heartbeat()?;
+        thread::sleep(KEY_POLL_INTERVAL);
+    };
+
+    if key == Key::Ctrl('c') {
+        drop(keys);
+        return client.finish();
+    }
+
+    print!("Playing track!\r\n");
+
+    /* Send the MIDI events to the client */
+
+    print!("Press space or p to pause or resume, m to mute the current notes, or q to stop\r\n");
+
+    let mut stopped = false;
+
+    for (index, song) in songs.iter().enumerate() {
+        if index > 0 {
+            /* Get the client ready for the next song */
+
+            let previous = &songs[index - 1];
+
+            if wait_for(
+                &mut client,
+                &mut keys,
+                &mut Scheduler::new(SystemClock::new()),
+                previous.gap,
+                &ResumeState::default(),
+                &mut ProgressLine::hidden(),
+            )?
+            .is_none()
+            {
+                stopped = true;
+                break;
+            }
+
+            client.all_notes_off()?;
+
+            // The drive mapping only needs sending again if it changed
+            if to_set_config(&song.config) != to_set_config(&previous.config) {
+                reconfigure(&mut client, &song.config)?;
+            }
+        }
+
+        if is_playlist {
+            print!("Song {}/{}: {}\r\n", index + 1, songs.len(), song.name());
+        }
+
+        match play_song(&mut client, &mut keys, song, args)? {
+            PlaybackEnd::Finished => {}
+            PlaybackEnd::Stopped => {
+                stopped = true;
+                break;
+            }
+            // There's no client left to stop
+            PlaybackEnd::Disconnected => return Ok(()),
+        }
+    }
+
+    drop(keys);
+
+    // Silence the drives straight away rather than leaving the last notes latched until the
+    // client has caught up with the end
+    if stopped {
+        client.all_notes_off()?;
+    }
+
+    client.finish()
+}
+
+/// Prints the notes each track and channel of a MIDI file plays, given the file or a song
+/// configuration that plays it
+fn analyze(path: &Path) -> Result<()> {
+    let is_midi_file = path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("mid") || extension.eq_ignore_ascii_case("midi")
+    });
+
+    let midi_path = if is_midi_file {
+        path.to_path_buf()
+    } else {
+        config::parse_song_config(path)?.midi.path
+    };
+
+    // The file is analyzed as it is, before any config transposes or drops its notes
+    let midi_file = parse_midi_file(&midi_path, &MidiParseOptions::default())?;
+
+    println!("Note Histogram");
     println!("================");
-    println!("Port: {}", port);
-    println!("Baud Rate: {}", baud_rate);
-    println!();
+    print!("{}", NoteHistogram::new(&midi_file.events));
 
-    let serial_port = serialport::new(port, baud_rate).open()?;
-    let mut client = Client::new(serial_port);
+    Ok(())
+}
 
-    /* Check client connection */
+/// Plays a test tone on each drive of a song configuration's stack in turn, so a dead drive or one
+/// in the wrong place stands out
+fn test_drives(args: &TestArgs) -> Result<()> {
+    let config = config::parse_song_config(&args.path)?;
+    let drive_count = config.floppy_drives[0].drive_count;
+    let duration = Duration::from_millis(args.duration_ms as u64);
 
-    println!("Connecting to client...");
+    let _interrupts = DeferredInterrupts::start();
+
+    let mut client = connect(&args.connection)?;
+    let mut client = EndGuard::new(&mut client);
+
+    println!(
+        "Testing {} drives with {}...",
+        drive_count,
+        note_name(args.note)
+    );
+    println!("Press q to stop");
+
+    let mut keys = KeyReader::new()?;
+
+    'drives: for index in 0..drive_count {
+        print!("Drive {}\r\n", index);
+
+        client.test_drive(index, args.note, duration, drive_count)?;
+
+        let next_drive_at = Instant::now() + duration + TEST_TONE_GAP;
+
+        while Instant::now() < next_drive_at {
+            if let Some(Key::Char('q') | Key::Ctrl('c')) = keys.next_key() {
+                break 'drives;
+            }
+
+            client.heartbeat()?;
+            thread::sleep(KEY_POLL_INTERVAL);
+        }
+    }
+
+    drop(keys);
+
+    client.all_notes_off()?;
 
-    client.send(FloppierS2CMessage::Hello)?;
+    client.finish()
+}
 
-    let FloppierC2SMessage::HelloAck = client.receive()? else {
-        bail!("expected hello ack message from client");
+/// Holds a note on every drive of a stack until it's been held long enough or the user stops it
+fn hold(args: &HoldArgs) -> Result<()> {
+    let config = SetConfig {
+        parallel_mode: ParallelMode::Collapse,
+        steal_oldest: false,
+        movement: true,
+        drive_movement: Vec::new(),
+        drive_count: args.drive_count,
+        ports: Vec::new(),
+        detune_cents: Vec::new(),
+        tracks: BTreeMap::from([(1, BTreeMap::from([(1, (0..args.drive_count).collect())]))]),
+        synthesize_interval_us: None,
+        velocity_threshold: 0,
+        velocity_dynamics: false,
+        release_ramp: false,
+        min_note_us: 0,
+        link_timeout_ms: None,
+        reset: ResetTiming::default(),
+        park_on_end: false,
+        home_track: None,
+        tick_us: None,
     };
 
-    println!("Client connection established!");
+    println!("Configuring client...");
 
-    /* Send client configuration (pre-start) */
+    let _interrupts = DeferredInterrupts::start();
 
-    let floppy_drive = &config.floppy_drives[0];
+    let mut client = connect_and_configure(&args.connection, config)?;
+    let mut client = EndGuard::new(&mut client);
 
-    println!("Configuring client with ID {}...", floppy_drive.id);
+    println!("Holding {}, press q to stop", note_name(args.note));
 
-    client.send(FloppierS2CMessage::SetConfig(SetConfig {
-        parallel_mode: config.midi.parallel_mode,
-        movement: floppy_drive.movement,
-        drive_count: floppy_drive.drive_count,
-        tracks: floppy_drive
-            .tracks
-            .iter()
-            .map(|(track, channels)| {
-                (
-                    *track,
-                    channels
-                        .iter()
-                        .map(|(channel, drives)| (*channel, drives.clone()))
-                        .collect(),
-                )
-            })
-            .collect(),
+    client.send_windowed(FloppierS2CMessage::MidiEvent(MidiEvent {
+        track: 1,
+        channel: 1,
+        message: LimitedMidiMessage::NoteOn {
+            note: args.note,
+            velocity: 100,
+        },
     }))?;
 
-    let FloppierC2SMessage::SetConfigAck = client.receive()? else {
-        bail!("expected set config ack message from client");
-    };
+    let mut keys = KeyReader::new()?;
+    let stop_at = Instant::now() + Duration::from_secs(args.seconds);
 
-    println!("Client configured!");
+    while Instant::now() < stop_at {
+        if let Some(Key::Char('q') | Key::Ctrl('c')) = keys.next_key() {
+            break;
+        }
+
+        client.heartbeat()?;
+        thread::sleep(KEY_POLL_INTERVAL);
+    }
+
+    drop(keys);
 
-    /* Wait for client to finish resetting */
+    client.all_notes_off()?;
 
-    println!("Waiting for client to finish resetting...");
+    client.finish()
+}
+
+/// Homes the drive heads of a song configuration's stack, which the client does whenever it's
+/// configured
+fn reset(args: &ResetArgs) -> Result<()> {
+    let config = config::parse_song_config(&args.path)?;
 
-    let FloppierC2SMessage::Ready = client.receive()? else {
-        bail!("expected ready message from client");
+    // Only the drives are needed to home them, so the tracks aren't resolved or sent
+    let set_config = SetConfig {
+        tracks: BTreeMap::new(),
+        ..to_set_config(&config)
     };
 
-    println!("Client ready!");
+    println!("Resetting client with ID {}...", config.floppy_drives[0].id);
 
-    pause!("Press any key to play the track...");
+    let _interrupts = DeferredInterrupts::start();
 
-    println!("Playing track!");
+    let mut client = connect_and_configure(&args.connection, set_config)?;
 
-    /* Send the MIDI events to the client */
+    end(&mut client)
+}
 
-    // TODO: Group the events by their time offsets
-    //       https://docs.rs/itertools/latest/itertools/trait.Itertools.html#method.group_by
+/// Asks the client how it has been running and prints the report
+///
+/// There's no hello handshake, so the client carries on with whatever it was doing.
+fn status(args: &StatusArgs) -> Result<()> {
+    let options = connect_options(&args.connection)?;
 
-    let mut last_tick = 0;
+    let report = Client::open(&options)?.status()?;
+    let uptime = Duration::from_micros(report.uptime_us);
 
-    for event in midi_file.events {
-        let delta = event.time_offset - last_tick;
-        last_tick = event.time_offset;
+    println!("Client Status");
+    println!("================");
+    println!("State: {:?}", report.state);
+    println!(
+        "Uptime: {}:{:02}:{:02}",
+        uptime.as_secs() / 3600,
+        uptime.as_secs() / 60 % 60,
+        uptime.as_secs() % 60
+    );
+    println!("Timer overruns: {}", report.tick_overruns);
+    println!("Longest tick: {}µs", report.max_tick_us);
+    println!("Events played: {}", report.events_processed);
+    println!("Free heap: {} bytes", report.heap_free);
 
-        if delta > 0 {
-            thread::sleep(Duration::from_micros(ticks_to_microseconds(
-                delta,
-                midi_file.ticks_per_beat,
-                midi_file.beats_per_minute,
-            )));
-        }
+    Ok(())
+}
 
-        client.send(FloppierS2CMessage::MidiEvent(MidiEvent {
-            track: event.track,
-            channel: event.channel,
-            message: event.message,
-        }))?;
+/// Works out the serial port of the client (unless it's connected through TCP) and prints the
+/// connection settings
+fn connect_options(connection: &ConnectionArgs) -> Result<ConnectOptions> {
+    if let Some(address) = &connection.transport {
+        println!();
+        println!("TCP Connection");
+        println!("================");
+        println!("Address: {}", address);
+        println!();
 
-        let FloppierC2SMessage::MidiEventAck = client.receive()? else {
-            bail!("expected midi event ack from client");
-        };
+        return Ok(connection.options(Endpoint::Tcp {
+            address: address.clone(),
+        }));
     }
 
-    client.send(FloppierS2CMessage::End)?;
+    /* List Available Serial Ports */
 
-    let FloppierC2SMessage::EndAck = client.receive()? else {
-        bail!("expected end ack message from client");
+    println!();
+    for port in serialport::available_ports()? {
+        println!("{:?}", port);
+    }
+    println!();
+
+    let port = match &connection.serial_port {
+        Some(port) => port.clone(),
+        None => detect_client_port()?,
     };
 
-    Ok(())
+    println!();
+    println!("Serial Connection");
+    println!("================");
+    println!("Port: {}", port);
+    println!("Baud Rate: {}", connection.baud_rate);
+    println!();
+
+    Ok(connection.options(Endpoint::Serial {
+        port,
+        baud_rate: connection.baud_rate,
+    }))
+}
+
+/// Opens the serial connection to the client and performs the hello handshake
+fn connect(connection: &ConnectionArgs) -> Result<Client> {
+    let options = connect_options(connection)?;
+
+    println!("Connecting to client...");
+
+    let client = Client::connect(&options)?;
+
+    println!("Client connection established!");
+
+    Ok(client)
+}
+
+/// Opens the serial connection to the client, performs the hello handshake and sends it a config,
+/// waiting for it to finish resetting
+fn connect_and_configure(connection: &ConnectionArgs, config: SetConfig) -> Result<Client> {
+    let options = connect_options(connection)?;
+
+    println!("Connecting to client...");
+
+    let client = Client::connect_and_configure(&options, config)?;
+
+    println!("Client ready!");
+
+    Ok(client)
+}
+
+/// Plays MIDI input on the client as it arrives, until the user stops it
+#[cfg(feature = "live")]
+fn play_live(args: &PlayArgs) -> Result<()> {
+    let mut config = config::parse_song_config(&args.path)?;
+
+    config
+        .resolve_track_names(&Default::default())
+        .context("tracks can't be mapped by name when playing live MIDI input")?;
+
+    if config.floppy_drives[0].auto {
+        bail!("tracks can't be mapped automatically when playing live MIDI input");
+    }
+
+    let selector = match &args.midi_input {
+        Some(selector) => selector.clone(),
+        None => prompt_midi_input()?,
+    };
+
+    let input = LiveInput::open(&selector, args.live_track, config.midi.control_changes)?;
+
+    println!("Opened MIDI input {}", input.port_name());
+
+    println!(
+        "Configuring client with ID {}...",
+        config.floppy_drives[0].id
+    );
+
+    let _interrupts = DeferredInterrupts::start();
+
+    let mut client = connect_and_configure(&args.connection, to_set_config(&config))?;
+    let mut client = EndGuard::new(&mut client);
+
+    println!("Playing live! Press m to mute the current notes, or q to stop");
+
+    let mut keys = KeyReader::new()?;
+
+    loop {
+        match keys.next_key() {
+            Some(Key::Char('m')) => client.all_notes_off()?,
+            Some(Key::Char('q') | Key::Ctrl('c')) => break,
+            _ => {}
+        }
+
+        client.heartbeat()?;
+
+        let Some(event) = input.next_event(KEY_POLL_INTERVAL)? else {
+            continue;
+        };
+
+        // Send anything else that arrived at the same time along with it
+        let mut events = vec![event];
+        events.extend(input.pending_events().take(MAX_MIDI_EVENT_BATCH - 1));
+
+        for event in &events {
+            debug!("{:?}", event);
+        }
+
+        let message = if events.len() == 1 {
+            FloppierS2CMessage::MidiEvent(events.remove(0))
+        } else {
+            FloppierS2CMessage::MidiEventBatch(events)
+        };
+
+        // Acks are collected by the heartbeat instead of waiting on them, to keep the latency down
+        client.send_windowed(message)?;
+    }
+
+    drop(keys);
+
+    client.all_notes_off()?;
+
+    client.finish()
+}
+
+/// Lists the MIDI input ports and asks the user to pick one
+#[cfg(feature = "live")]
+fn prompt_midi_input() -> Result<String> {
+    use std::io::{self, Write};
+
+    let names = live::input_port_names()?;
+
+    if names.is_empty() {
+        bail!("no MIDI input ports found");
+    }
+
+    println!();
+    println!("MIDI Inputs");
+    println!("================");
+    for (index, name) in names.iter().enumerate() {
+        println!("{}: {}", index, name);
+    }
+    println!();
+
+    print!("Pick a MIDI input by index or name: ");
+    io::stdout().flush()?;
+
+    let mut selector = String::new();
+    io::stdin().read_line(&mut selector)?;
+
+    Ok(selector.trim().to_string())
+}
+
+/// Parses a song configuration and its MIDI file
+fn load_song(entry: &PlaylistEntry, args: &PlayArgs) -> Result<Song> {
+    let mut config = config::parse_song_config(&entry.path)?;
+
+    let track_names = parse_track_names(&config.midi.path)?;
+    config.resolve_track_names(&track_names)?;
+
+    let mut midi_file = parse_midi_file(
+        &config.midi.path,
+        &MidiParseOptions {
+            control_changes: config.midi.control_changes,
+            percussion: Some(config.midi.percussion),
+            min_velocity: config.midi.min_velocity,
+            channel_min_velocity: config.channel_min_velocity(),
+            sustain: config.midi.sustain,
+            release_dangling_notes: config.midi.release_dangling_notes,
+        },
+    )?;
+
+    // Before the tracks are assigned, so the drives all go to the tracks being played
+    midi_file.filter_events(&args.only_tracks, &args.only_channels);
+
+    let auto_assigned = config.auto_assign(&midi_file.events);
+
+    let floppy_drive = &config.floppy_drives[0];
+
+    for track in &args.only_tracks {
+        if !floppy_drive.tracks.contains_key(track) {
+            warn!(
+                "only playing track {}, but it isn't mapped to any drives",
+                track
+            );
+        }
+    }
+
+    for channel in &args.only_channels {
+        let mapped = floppy_drive
+            .tracks
+            .values()
+            .any(|track_config| track_config.channels.contains_key(channel));
+
+        if !mapped {
+            warn!(
+                "only playing channel {}, but it isn't mapped to any drives",
+                channel
+            );
+        }
+    }
+
+    // Octaves are folded last so they bring back notes the config transposed out of range too
+    config.transpose_events(&mut midi_file.events);
+
+    let folds = config
+        .midi
+        .fold_octaves
+        .then(|| config.fold_events(&mut midi_file.events));
+
+    let mapping = config.check_mapping(&midi_file.events);
+
+    if args.strict {
+        if let Some(row) = mapping.unmapped().next() {
+            bail!(
+                "track {} channel {} has {} notes but isn't mapped to any drives",
+                row.track,
+                row.channel,
+                row.notes
+            );
+        }
+    }
+
+    let events = midi_file.events_between(args.start_at.unwrap_or_default(), args.stop_at);
+
+    let gap = match entry.gap_seconds {
+        Some(seconds) => Duration::try_from_secs_f64(seconds)
+            .with_context(|| format!("invalid gap of {} seconds", seconds))?,
+        None => DEFAULT_SONG_GAP,
+    };
+
+    Ok(Song {
+        path: entry.path.clone(),
+        config,
+        midi_file,
+        mapping,
+        auto_assigned,
+        folds,
+        events,
+        gap,
+    })
+}
+
+/// Plays a song from `--start-at` to `--stop-at`, as many times as `--loop` asks for
+fn play_song(
+    client: &mut Client,
+    keys: &mut KeyReader,
+    song: &Song,
+    args: &PlayArgs,
+) -> Result<PlaybackEnd> {
+    let Song {
+        config,
+        midi_file,
+        events,
+        ..
+    } = song;
+
+    let start_at = args.start_at.unwrap_or_default();
+
+    // `None` plays the song forever
+    let plays = match args.loop_count {
+        None => Some(1),
+        Some(None | Some(0)) => None,
+        Some(count) => count,
+    };
+
+    let lookahead = Duration::from_millis(args.lookahead_ms);
+
+    let mut play = 0;
+
+    loop {
+        let mut scheduler = Scheduler::new(SystemClock::new());
+        let mut resume_state = ResumeState::default();
+        let mut progress = ProgressLine::new(song, args);
+
+        // Fast-forward to the start point without sending anything, then start with the programs,
+        // controls and notes in effect there, so skipping ahead doesn't leave drives silent until
+        // their next note on
+        for event in &midi_file.events[..events.start] {
+            resume_state.apply(&to_midi_event(event));
+        }
+
+        replay(client, &resume_state)?;
+
+        let mut end = Duration::ZERO;
+
+        // Whether the last group was sent too late, so falling behind is only reported once each
+        // time it happens rather than for every group until it catches up
+        let mut behind = false;
+
+        // Events at the same tick (e.g. the notes of a chord) are sent together so they aren't
+        // staggered by an ack round-trip each
+        for group in
+            midi_file.events[events.clone()].chunk_by(|a, b| a.time_offset == b.time_offset)
+        {
+            let target = midi_file
+                .event_time(&group[0])
+                .saturating_sub(start_at)
+                .div_f64(args.speed);
+
+            end = target;
+
+            loop {
+                let timed = !lookahead.is_zero() && client.supports(EXTENSIONS_VERSION);
+
+                // Fall back to sending just in time while the client's queue is full
+                let send_at = if timed && client.timed_event_room() >= group.len() {
+                    target.saturating_sub(lookahead)
+                } else {
+                    target
+                };
+
+                let played = wait_for(
+                    client,
+                    keys,
+                    &mut scheduler,
+                    send_at,
+                    &resume_state,
+                    &mut progress,
+                )
+                .and_then(|drift| {
+                    let Some(drift) = drift else {
+                        return Ok(false);
+                    };
+
+                    debug!("Tick {} (drift: {:?})", group[0].time_offset, drift);
+
+                    // Timed events still play on time as long as they arrive within the lookahead
+                    let allowed_drift = if timed { lookahead } else { Duration::ZERO } + MAX_DRIFT;
+                    let late = drift > allowed_drift;
+
+                    if late && !behind {
+                        progress.clear();
+                        print!(
+                            "Falling behind at tick {} ({:?} late), the link can't keep up\r\n",
+                            group[0].time_offset, drift
+                        );
+                    }
+
+                    behind = late;
+
+                    // The client's clock and the scheduler are paused together, so they stay the
+                    // same distance apart
+                    let timestamp = timed
+                        .then(|| (client.clock() + target).saturating_sub(scheduler.elapsed()));
+
+                    send_group(client, group, timestamp, args)?;
+
+                    Ok(true)
+                });
+
+                let error = match played {
+                    Ok(true) => break,
+                    Ok(false) => {
+                        progress.clear();
+                        return Ok(PlaybackEnd::Stopped);
+                    }
+                    Err(error) if args.no_resume => {
+                        progress.clear();
+                        return Err(error);
+                    }
+                    Err(error) => error,
+                };
+
+                progress.clear();
+                scheduler.pause();
+
+                let restarted = error
+                    .downcast_ref::<ClientError>()
+                    .is_some_and(ClientError::is_waiting_for_hello);
+
+                // A client that restarted is still connected, so it only needs setting up again
+                let set_up = restarted && {
+                    print!("Client restarted, setting it up again...\r\n");
+
+                    set_up_again(client, config, &resume_state)
+                        .inspect_err(|error| {
+                            print!("Couldn't set up client again: {:#}\r\n", error)
+                        })
+                        .is_ok()
+                };
+
+                if !set_up {
+                    print!("Lost connection to client: {:#}\r\n", error);
+
+                    let Some(new_client) = reconnect(keys, args, config, &resume_state)? else {
+                        return Ok(PlaybackEnd::Disconnected);
+                    };
+
+                    *client = new_client;
+                }
+
+                scheduler.resume();
+
+                print!("Resuming from tick {}\r\n", group[0].time_offset);
+            }
+
+            for event in group {
+                resume_state.apply(&to_midi_event(event));
+            }
+
+            progress.sent(group, &resume_state, config);
+        }
+
+        play += 1;
+
+        if plays.is_some_and(|plays| play >= plays) {
+            progress.clear();
+            return Ok(PlaybackEnd::Finished);
+        }
+
+        /* Get ready to play the song again */
+
+        let gap = Duration::from_millis(args.loop_gap_ms);
+
+        let waited = wait_for(
+            client,
+            keys,
+            &mut scheduler,
+            end + gap,
+            &resume_state,
+            &mut progress,
+        )?;
+
+        progress.clear();
+
+        if waited.is_none() {
+            return Ok(PlaybackEnd::Stopped);
+        }
+
+        client.all_notes_off()?;
+
+        if args.reset_between_loops {
+            client.reset_drives()?;
+        }
+
+        print!("Playing the track again ({} played)\r\n", play);
+    }
+}
+
+/// Sends a group of events at the same tick to the client, in as few batches as possible
+///
+/// The events are queued by the client to play at `timestamp` on its clock if one is given, or
+/// played as soon as they arrive if not.
+fn send_group(
+    client: &mut Client,
+    group: &[AbsoluteMidiEvent],
+    timestamp: Option<Duration>,
+    args: &PlayArgs,
+) -> Result<()> {
+    for batch in group.chunks(MAX_MIDI_EVENT_BATCH) {
+        let mut events = batch.iter().map(to_midi_event);
+
+        let message = match timestamp {
+            Some(timestamp) => FloppierS2CMessage::TimedMidiEventBatch {
+                timestamp_us: timestamp.as_micros() as u64,
+                events: events.collect(),
+            },
+            None if batch.len() == 1 => FloppierS2CMessage::MidiEvent(events.next().unwrap()),
+            None => FloppierS2CMessage::MidiEventBatch(events.collect()),
+        };
+
+        client.send_windowed(message)?;
+
+        if args.sync_acks {
+            client.flush_acks()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints every event with the time it would be sent at and the drives it would play on, timed
+/// the same way as playback (from the start point, at the playback speed)
+fn dry_run(song: &Song, args: &PlayArgs) {
+    let floppy_drive = &song.config.floppy_drives[0];
+
+    for (time, event) in simulated_events(song, args) {
+        let drives = floppy_drive
+            .tracks
+            .get(&event.track)
+            .and_then(|track| track.channels.get(&event.channel))
+            .map(|channel| &channel.ports);
+
+        let mut line = format!(
+            "{}:{:02}.{:03} track {} channel {} {:?} -> ",
+            time.as_secs() / 60,
+            time.as_secs() % 60,
+            time.subsec_millis(),
+            event.track,
+            event.channel,
+            event.message
+        );
+
+        match drives {
+            Some(drives) => line += &format!("drives {:?}", drives),
+            None => line += "no drives",
+        }
+
+        if let LimitedMidiMessage::NoteOn { note, .. } = event.message {
+            if !(MIN_PLAYABLE_NOTE..=MAX_PLAYABLE_NOTE).contains(&note) {
+                line += " (unplayable note)";
+            }
+        }
+
+        println!("{}", line);
+    }
+}
+
+/// The events of a song with the time after the start of playback each is sent at, as they would
+/// be sent by `play_song` (without looping)
+fn simulated_events(song: &Song, args: &PlayArgs) -> Vec<(Duration, MidiEvent)> {
+    let start_at = args.start_at.unwrap_or_default();
+
+    let mut resume_state = ResumeState::default();
+
+    for event in &song.midi_file.events[..song.events.start] {
+        resume_state.apply(&to_midi_event(event));
+    }
+
+    let held = resume_state
+        .replay_events()
+        .into_iter()
+        .map(|event| (Duration::ZERO, event));
+
+    let events = song.midi_file.events[song.events.clone()]
+        .iter()
+        .map(|event| {
+            let time = song
+                .midi_file
+                .event_time(event)
+                .saturating_sub(start_at)
+                .div_f64(args.speed);
+
+            (time, to_midi_event(event))
+        });
+
+    held.chain(events).collect()
+}
+
+/// Parses a time into the song given in seconds (`90`, `90.5`) or minutes and seconds (`1:30`)
+fn parse_timestamp(timestamp: &str) -> Result<Duration, String> {
+    let (minutes, seconds) = match timestamp.split_once(':') {
+        Some((minutes, seconds)) => (
+            minutes
+                .parse::<u64>()
+                .map_err(|_| format!("invalid minutes `{}`", minutes))?,
+            seconds,
+        ),
+        None => (0, timestamp),
+    };
+
+    let seconds = seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid seconds `{}`", seconds))?;
+
+    minutes
+        .checked_mul(60)
+        .and_then(|minutes| Duration::from_secs(minutes).checked_add(seconds))
+        .ok_or_else(|| format!("timestamp `{}` is too far into the song", timestamp))
+}
+
+fn parse_transport(transport: &str) -> Result<String, String> {
+    match transport.strip_prefix("tcp://") {
+        Some(address) if address.contains(':') => Ok(address.to_owned()),
+        _ => Err("expected tcp://HOST:PORT".to_owned()),
+    }
+}
+
+fn parse_link_timeout(link_timeout_ms: &str) -> Result<u64, String> {
+    let link_timeout_ms = link_timeout_ms
+        .parse::<u64>()
+        .map_err(|error| error.to_string())?;
+
+    // The client has to get at least one ping before it times out
+    if link_timeout_ms != 0 && Duration::from_millis(link_timeout_ms) <= DEFAULT_PING_INTERVAL {
+        return Err(format!(
+            "link timeout must be longer than the {}ms ping interval (or 0 to disable it)",
+            DEFAULT_PING_INTERVAL.as_millis()
+        ));
+    }
+
+    Ok(link_timeout_ms)
+}
+
+fn parse_speed(speed: &str) -> Result<f64, String> {
+    speed
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| SPEED_RANGE.contains(speed))
+        .ok_or_else(|| {
+            format!(
+                "speed must be a number from {} to {}, got `{}`",
+                SPEED_RANGE.start(),
+                SPEED_RANGE.end(),
+                speed
+            )
+        })
+}
+
+/// Waits until `target` after the start of playback while handling key presses and keeping the
+/// connection alive
+///
+/// Returns the drift once the target has been reached, or `None` if the user asked to stop playing.
+fn wait_for(
+    client: &mut Client,
+    keys: &mut KeyReader,
+    scheduler: &mut Scheduler<SystemClock>,
+    target: Duration,
+    resume_state: &ResumeState,
+    progress: &mut ProgressLine,
+) -> Result<Option<Duration>> {
+    loop {
+        match keys.next_key() {
+            Some(Key::Char(' ' | 'p')) => {
+                progress.clear();
+                toggle_pause(client, scheduler, resume_state)?;
+            }
+            Some(Key::Char('m')) => client.all_notes_off()?,
+            Some(Key::Char('q') | Key::Ctrl('c')) => return Ok(None),
+            _ => {}
+        }
+
+        client.heartbeat()?;
+        progress.draw(scheduler);
+
+        if let Some(drift) = scheduler.wait_towards(target, KEY_POLL_INTERVAL) {
+            return Ok(Some(drift));
+        }
+    }
+}
+
+/// The progress line shown while a song plays, redrawn in place with a carriage return
+struct ProgressLine {
+    progress: Progress,
+    /// Where playback started in the song, and how fast it's playing
+    start_at: Duration,
+    speed: f64,
+    /// Hidden with `--verbose`, since the debug output would keep breaking it up
+    enabled: bool,
+    /// When the line was last drawn, if it's on screen
+    last_drawn: Option<Instant>,
+}
+
+impl ProgressLine {
+    fn new(song: &Song, args: &PlayArgs) -> Self {
+        Self {
+            progress: Progress {
+                total: song.midi_file.duration(),
+                drive_notes: vec![None; song.config.floppy_drives[0].port_count() as usize],
+                ..Default::default()
+            },
+            start_at: args.start_at.unwrap_or_default(),
+            speed: args.speed,
+            enabled: !log_enabled!(Level::Debug),
+            last_drawn: None,
+        }
+    }
+
+    /// A progress line that's never drawn
+    fn hidden() -> Self {
+        Self {
+            progress: Progress::default(),
+            start_at: Duration::ZERO,
+            speed: 1.0,
+            enabled: false,
+            last_drawn: None,
+        }
+    }
+
+    /// Updates the line after a group of events has been sent
+    fn sent(
+        &mut self,
+        group: &[AbsoluteMidiEvent],
+        resume_state: &ResumeState,
+        config: &SongConfig,
+    ) {
+        self.progress.tick = group[0].time_offset;
+        self.progress.events_sent += group.len();
+
+        self.progress.drive_notes.fill(None);
+
+        // Each held note is shown on the first free drive of its channel
+        for (track, channel, note) in resume_state.held_notes() {
+            let Some(channel_config) = config.floppy_drives[0]
+                .tracks
+                .get(&track)
+                .and_then(|track| track.channels.get(&channel))
+            else {
+                continue;
+            };
+
+            let free_drive = channel_config.ports.iter().find(|&&port| {
+                self.progress
+                    .drive_notes
+                    .get(port as usize)
+                    .is_some_and(|drive_note| drive_note.is_none())
+            });
+
+            if let Some(&port) = free_drive {
+                self.progress.drive_notes[port as usize] = Some(note);
+            }
+        }
+    }
+
+    /// Redraws the line if it's been long enough since it was last drawn
+    fn draw(&mut self, scheduler: &Scheduler<SystemClock>) {
+        if !self.enabled
+            || self
+                .last_drawn
+                .is_some_and(|drawn| drawn.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+
+        self.progress.elapsed = self.start_at + scheduler.elapsed().mul_f64(self.speed);
+        self.progress.paused = scheduler.is_paused();
+
+        print!("\r{}{}", self.progress, clear::UntilNewline);
+        stdout().flush().ok();
+
+        self.last_drawn = Some(Instant::now());
+    }
+
+    /// Clears the line so something else can be printed in its place (it's drawn again on the next
+    /// update)
+    fn clear(&mut self) {
+        if self.last_drawn.take().is_some() {
+            print!("\r{}", clear::CurrentLine);
+            stdout().flush().ok();
+        }
+    }
+}
+
+fn to_midi_event(event: &AbsoluteMidiEvent) -> MidiEvent {
+    MidiEvent {
+        track: event.track,
+        channel: event.channel,
+        message: event.message,
+    }
+}
+
+/// Configures the client for the next song in a playlist
+fn reconfigure(client: &mut Client, config: &SongConfig) -> Result<()> {
+    client.flush_acks()?;
+
+    // Older clients only take a config straight after the hello handshake
+    if !client.supports(EXTENSIONS_VERSION) {
+        end(client)?;
+        client.hello()?;
+    }
+
+    client.configure(to_set_config(config))
+}
+
+fn to_set_config(config: &SongConfig) -> SetConfig {
+    let floppy_drive = &config.floppy_drives[0];
+
+    let (movement, drive_movement) = match &floppy_drive.movement {
+        Movement::All(movement) => (*movement, Vec::new()),
+        // Older clients move every drive or none of them, so only move them all if they all move
+        Movement::PerDrive(movement) => (movement.iter().all(|moves| *moves), movement.clone()),
+    };
+
+    SetConfig {
+        parallel_mode: config.midi.parallel_mode,
+        steal_oldest: config.midi.steal_oldest,
+        movement,
+        drive_movement,
+        drive_count: floppy_drive.drive_count,
+        ports: floppy_drive.port_kinds(),
+        detune_cents: floppy_drive.detune_cents(),
+        tracks: floppy_drive
+            .tracks
+            .iter()
+            .map(|(track, track_config)| {
+                (
+                    *track,
+                    track_config
+                        .channels
+                        .iter()
+                        .map(|(channel, channel_config)| (*channel, channel_config.ports.clone()))
+                        .collect(),
+                )
+            })
+            .collect(),
+        synthesize_interval_us: config.midi.synthesize_interval_us,
+        velocity_threshold: config.midi.velocity_threshold,
+        velocity_dynamics: config.midi.velocity_dynamics,
+        release_ramp: config.midi.release_ramp,
+        min_note_us: config.midi.min_note_us,
+        link_timeout_ms: None,
+        reset: floppy_drive.reset,
+        park_on_end: floppy_drive.park_on_end,
+        home_track: floppy_drive.home_track,
+        tick_us: floppy_drive.tick_us,
+    }
+}
+
+/// Ends the session once the client has caught up with the events sent so far
+fn end(client: &mut Client) -> Result<()> {
+    client.flush_acks()?;
+
+    client.send(FloppierS2CMessage::End)?;
+
+    let mut message = client.receive()?;
+
+    // Newer clients report how they kept up before acknowledging
+    if let FloppierC2SMessage::Diagnostics {
+        overruns,
+        max_overrun_us,
+    } = message
+    {
+        print_diagnostics(overruns, max_overrun_us);
+        message = client.receive()?;
+    }
+
+    let FloppierC2SMessage::EndAck = message else {
+        bail!("expected end ack message from client");
+    };
+
+    Ok(())
+}
+
+/// Sends `End` if the session stops with an error, so the client silences the drives instead of
+/// leaving them latched on the last notes
+///
+/// Without an error, `finish` ends the session normally.
+struct EndGuard<'a> {
+    client: &'a mut Client,
+    ended: bool,
+}
+
+impl<'a> EndGuard<'a> {
+    fn new(client: &'a mut Client) -> Self {
+        Self {
+            client,
+            ended: false,
+        }
+    }
+
+    /// Ends the session once the client has caught up (see `end`)
+    fn finish(mut self) -> Result<()> {
+        self.ended = true;
+
+        end(self.client)
+    }
+}
+
+impl Deref for EndGuard<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        self.client
+    }
+}
+
+impl DerefMut for EndGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client
+    }
+}
+
+impl Drop for EndGuard<'_> {
+    fn drop(&mut self) {
+        if self.ended {
+            return;
+        }
+
+        // The client may be gone or out of step, so this doesn't wait for the ack
+        match self.client.send(FloppierS2CMessage::End) {
+            Ok(()) => println!("Ended the session early to silence the drives"),
+            Err(error) => debug!("Couldn't end the session: {:#}", error),
+        }
+    }
+}
+
+fn print_diagnostics(overruns: u32, max_overrun_us: u32) {
+    println!();
+    println!("Client Diagnostics");
+    println!("================");
+
+    if overruns == 0 {
+        println!("Timer overruns: none");
+        return;
+    }
+
+    println!(
+        "Timer overruns: {} (worst by {}µs)",
+        overruns, max_overrun_us
+    );
+    println!("The client couldn't keep up, try using fewer drives or a coarser timer resolution");
+}
+
+/// Waits for the client to reappear, then sets it up again and brings it back to the point in the
+/// song described by `resume_state`
+///
+/// Returns `None` if the user gave up waiting.
+fn reconnect(
+    keys: &mut KeyReader,
+    args: &PlayArgs,
+    config: &SongConfig,
+    resume_state: &ResumeState,
+) -> Result<Option<Client>> {
+    let endpoint = match &args.connection.transport {
+        // The bridge is still there while the client restarts behind it
+        Some(address) => Endpoint::Tcp {
+            address: address.clone(),
+        },
+        None => {
+            print!("Waiting for client to reconnect (press q to stop)...\r\n");
+
+            let port = loop {
+                if let Some(Key::Char('q') | Key::Ctrl('c')) = keys.next_key() {
+                    return Ok(None);
+                }
+
+                if let Some(port) = find_client_port()? {
+                    break port;
+                }
+
+                thread::sleep(RECONNECT_POLL_INTERVAL);
+            };
+
+            Endpoint::Serial {
+                port,
+                baud_rate: args.connection.baud_rate,
+            }
+        }
+    };
+
+    print!("Reconnecting to client on {}...\r\n", endpoint);
+
+    let mut client =
+        Client::connect_and_configure(&args.connection.options(endpoint), to_set_config(config))?;
+
+    replay(&mut client, resume_state)?;
+
+    Ok(Some(client))
+}
+
+/// Does the hello handshake and sends the config again, then brings the client back to the point
+/// in the song described by `resume_state`
+fn set_up_again(
+    client: &mut Client,
+    config: &SongConfig,
+    resume_state: &ResumeState,
+) -> Result<()> {
+    client.hello()?;
+    client.configure(to_set_config(config))?;
+    replay(client, resume_state)
+}
+
+/// Sounds the notes that were held at the point described by `resume_state` again
+fn replay(client: &mut Client, resume_state: &ResumeState) -> Result<()> {
+    for batch in resume_state.replay_events().chunks(MAX_MIDI_EVENT_BATCH) {
+        client.send_windowed(FloppierS2CMessage::MidiEventBatch(batch.to_vec()))?;
+    }
+
+    client.flush_acks()
+}
+
+/// Pauses or resumes both the client and the playback clock
+///
+/// The client silences its drives when paused, so the notes held at the pause point are sounded
+/// again on resume.
+fn toggle_pause(
+    client: &mut Client,
+    scheduler: &mut Scheduler<SystemClock>,
+    resume_state: &ResumeState,
+) -> Result<()> {
+    if scheduler.is_paused() {
+        client.resume()?;
+
+        replay(client, resume_state)?;
+
+        scheduler.resume();
+        print!("Resumed\r\n");
+    } else {
+        scheduler.pause();
+        client.pause()?;
+
+        print!("Paused\r\n");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn parses_subcommands() {
+        FloppierArgs::command().debug_assert();
+
+        let args =
+            FloppierArgs::parse_from(["floppier", "hold", "-v", "--note", "60", "-s", "COM3"]);
+
+        let Command::Hold(hold) = args.command else {
+            panic!("expected the hold command");
+        };
+
+        assert!(args.verbose);
+        assert_eq!(hold.note, 60);
+        assert_eq!(hold.connection.serial_port.as_deref(), Some("COM3"));
+        assert!(FloppierArgs::try_parse_from(["floppier", "hold", "--note", "127"]).is_err());
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("1:30"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("0:02.5"), Ok(Duration::from_millis(2_500)));
+        assert!(parse_timestamp("-1").is_err());
+        assert!(parse_timestamp("a:10").is_err());
+        assert!(parse_timestamp("1e300").is_err());
+        assert!(parse_timestamp(&format!("{}:00", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn rejects_speeds_out_of_range() {
+        assert_eq!(parse_speed("0.25"), Ok(0.25));
+        assert_eq!(parse_speed("100"), Ok(100.0));
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("-2").is_err());
+        assert!(parse_speed("inf").is_err());
+        assert!(parse_speed("NaN").is_err());
+        assert!(parse_speed("1e-300").is_err());
+        assert!(parse_speed("1000").is_err());
+    }
+
+    #[test]
+    fn parses_transports() {
+        assert_eq!(
+            parse_transport("tcp://floppier.local:4000"),
+            Ok("floppier.local:4000".to_owned())
+        );
+        assert!(parse_transport("floppier.local:4000").is_err());
+        assert!(parse_transport("tcp://floppier.local").is_err());
+        assert!(parse_transport("udp://floppier.local:4000").is_err());
+    }
+
+    #[test]
+    fn link_timeout_outlasts_pings() {
+        assert_eq!(parse_link_timeout("5000"), Ok(5_000));
+        assert_eq!(parse_link_timeout("0"), Ok(0));
+        assert!(parse_link_timeout("2000").is_err());
+        assert!(parse_link_timeout("soon").is_err());
+    }
 }
@@ -1,11 +1,23 @@
-use std::{fmt::Display, path::Path};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    io::Write,
+    ops::{Range, RangeInclusive},
+    path::Path,
+    time::Duration,
+};
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{ensure, Context, Result};
+use log::{debug, trace, warn};
 use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
 
-use floppier_proto::LimitedMidiMessage;
+use floppier_proto::{LimitedMidiMessage, MAX_PLAYABLE_NOTE, MIN_PLAYABLE_NOTE};
 
-#[derive(Debug)]
+use crate::playback::note_name;
+
+#[derive(Debug, Serialize)]
 pub struct AbsoluteMidiEvent {
     pub time_offset: u32,
     pub track: u16,
@@ -13,30 +25,340 @@ pub struct AbsoluteMidiEvent {
     pub message: LimitedMidiMessage,
 }
 
+#[derive(Serialize)]
 pub struct MidiFile {
     pub metadata: MidiMetadata,
-    pub ticks_per_beat: u16,
-    pub beats_per_minute: f64,
+    pub timing: MidiTiming,
     pub num_tracks: u16,
     pub events: Vec<AbsoluteMidiEvent>,
+    /// The number of note ons and offs dropped for being below the minimum velocity
+    pub filtered_events: usize,
+    /// The name of each data track that has one, keyed by the track number the events use
+    pub track_names: BTreeMap<u16, String>,
 }
 
-pub fn parse_midi_file<P: AsRef<Path>>(midi_path: P) -> Result<MidiFile> {
+impl MidiFile {
+    /// The tempo changes of the file (only meaningful for metrical timing)
+    pub fn tempo_map(&self) -> Option<&TempoMap> {
+        match &self.timing {
+            MidiTiming::Metrical { tempo_map, .. } => Some(tempo_map),
+            MidiTiming::Timecode { .. } => None,
+        }
+    }
+
+    /// The time from the start of the file until the last event
+    pub fn duration(&self) -> Duration {
+        self.events
+            .last()
+            .map_or(Duration::ZERO, |event| self.event_time(event))
+    }
+
+    /// The time from the start of the file until an event
+    pub fn event_time(&self, event: &AbsoluteMidiEvent) -> Duration {
+        Duration::from_micros(self.timing.ticks_to_microseconds(0, event.time_offset))
+    }
+
+    /// Writes the events, metadata and timing of the file as JSON (for visualizers and other tools)
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+
+        Ok(())
+    }
+
+    /// Drops the events that aren't on one of `tracks` and one of `channels` (either can be empty
+    /// to keep every track or channel)
+    pub fn filter_events(&mut self, tracks: &[u16], channels: &[u8]) {
+        self.events.retain(|event| {
+            (tracks.is_empty() || tracks.contains(&event.track))
+                && (channels.is_empty() || channels.contains(&event.channel))
+        });
+    }
+
+    /// The indices of the events from `start` (inclusive) until `stop` (exclusive), measured from
+    /// the start of the file
+    pub fn events_between(&self, start: Duration, stop: Option<Duration>) -> Range<usize> {
+        let first = self
+            .events
+            .partition_point(|event| self.event_time(event) < start);
+        let last = stop.map_or(self.events.len(), |stop| {
+            self.events
+                .partition_point(|event| self.event_time(event) < stop)
+        });
+
+        first..last.max(first)
+    }
+}
+
+/// How the ticks in a MIDI file map to wall-clock time
+#[derive(Debug, Clone, Serialize)]
+pub enum MidiTiming {
+    /// Ticks are a subdivision of a beat, whose length depends on the tempo at that point
+    Metrical {
+        ticks_per_beat: u16,
+        tempo_map: TempoMap,
+    },
+    /// Ticks are a subdivision of an SMPTE frame, independent of the tempo
+    Timecode {
+        frames_per_second: f32,
+        subframes: u8,
+    },
+}
+
+impl MidiTiming {
+    /// Returns the number of microseconds between two ticks
+    pub fn ticks_to_microseconds(&self, start_tick: u32, end_tick: u32) -> u64 {
+        match self {
+            MidiTiming::Metrical {
+                ticks_per_beat,
+                tempo_map,
+            } => tempo_map.ticks_to_microseconds(start_tick, end_tick, *ticks_per_beat),
+            MidiTiming::Timecode {
+                frames_per_second,
+                subframes,
+            } => timecode_ticks_to_microseconds(
+                end_tick.saturating_sub(start_tick),
+                *frames_per_second,
+                *subframes,
+            ),
+        }
+    }
+}
+
+impl Display for MidiTiming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidiTiming::Metrical { ticks_per_beat, .. } => {
+                write!(f, "metrical ({} ticks per beat)", ticks_per_beat)
+            }
+            MidiTiming::Timecode {
+                frames_per_second,
+                subframes,
+            } => write!(
+                f,
+                "SMPTE {} fps ({} ticks per frame)",
+                frames_per_second, subframes
+            ),
+        }
+    }
+}
+
+/// Ordered list of tempo changes in a MIDI file
+#[derive(Debug, Clone, Serialize)]
+pub struct TempoMap {
+    /// Pairs of (tick, tempo in microseconds per beat), sorted by tick and always starting at tick 0
+    pub changes: Vec<(u32, u32)>,
+}
+
+impl TempoMap {
+    /// Default tempo of 120 bpm used until the first tempo event
+    pub const DEFAULT_TEMPO: u32 = 500_000;
+
+    /// Builds a tempo map from the tempo events of all the given tracks
+    ///
+    /// Tempo events are usually only in the first track, but nothing stops a file from putting them
+    /// anywhere so every track is checked.
+    pub fn from_tracks(tracks: &[Track]) -> Self {
+        Self::from_offset_tracks(tracks.iter().map(|track| (0, track)))
+    }
+
+    /// Builds a tempo map for a sequential file, where each track starts when the one before it
+    /// ends
+    pub fn from_sequential_tracks(tracks: &[Track]) -> Self {
+        Self::from_offset_tracks(sequential_offsets(tracks).zip(tracks))
+    }
+
+    /// Builds a tempo map from the tempo events of tracks that each start at the given tick
+    fn from_offset_tracks<'a>(tracks: impl Iterator<Item = (u32, &'a Track<'a>)>) -> Self {
+        let mut events = Vec::new();
+
+        for (offset, track) in tracks {
+            let mut absolute_time = offset;
+
+            for TrackEvent { delta, kind } in track.iter() {
+                absolute_time += delta.as_int();
+
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = kind {
+                    events.push((absolute_time, tempo.as_int()));
+                }
+            }
+        }
+
+        // Stable sort so that tempo events at the same tick stay in track order
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut changes = vec![(0, Self::DEFAULT_TEMPO)];
+
+        for (absolute_time, tempo) in events {
+            match changes.last_mut() {
+                // A tempo change at the same tick as the previous one replaces it
+                Some((tick, last_tempo)) if *tick == absolute_time => *last_tempo = tempo,
+                _ => changes.push((absolute_time, tempo)),
+            }
+        }
+
+        Self { changes }
+    }
+
+    /// The slowest and fastest tempos in beats per minute
+    pub fn bpm_range(&self) -> (f64, f64) {
+        self.changes
+            .iter()
+            .map(|(_, tempo)| tempo_to_bpm(*tempo))
+            .fold((f64::MAX, f64::MIN), |(min, max), bpm| {
+                (min.min(bpm), max.max(bpm))
+            })
+    }
+
+    /// The number of times the tempo changes after the initial tempo
+    pub fn num_changes(&self) -> usize {
+        self.changes.len() - 1
+    }
+
+    /// Integrates the tempo between two ticks and returns the number of microseconds between them
+    ///
+    /// The tempos are whole microseconds per beat, so this is done in integers and only rounded once
+    /// at the end. Event times measured from the start of the song then stay exact however long it
+    /// is, rather than picking up floating point error.
+    pub fn ticks_to_microseconds(
+        &self,
+        start_tick: u32,
+        end_tick: u32,
+        ticks_per_beat: u16,
+    ) -> u64 {
+        // Microseconds multiplied by the ticks per beat
+        let mut tick_microseconds = 0u64;
+
+        for (i, (segment_start, tempo)) in self.changes.iter().enumerate() {
+            let segment_end = self
+                .changes
+                .get(i + 1)
+                .map(|(tick, _)| *tick)
+                .unwrap_or(u32::MAX);
+
+            let start = start_tick.max(*segment_start);
+            let end = end_tick.min(segment_end);
+
+            if start < end {
+                tick_microseconds += (end - start) as u64 * *tempo as u64;
+            }
+        }
+
+        let ticks_per_beat = ticks_per_beat.max(1) as u64;
+
+        (tick_microseconds + ticks_per_beat / 2) / ticks_per_beat
+    }
+}
+
+/// Options that control which events are kept when parsing a MIDI file
+#[derive(Debug, Default, Clone)]
+pub struct MidiParseOptions {
+    /// Whether to keep control change messages (volume, sustain, etc.)
+    pub control_changes: bool,
+
+    /// What to do with the drums on the percussion channel (passed through like any other channel
+    /// if not set)
+    pub percussion: Option<PercussionMode>,
+
+    /// Notes played with a lower velocity than this are dropped along with their note offs (to
+    /// filter out ghost notes)
+    pub min_velocity: u8,
+
+    /// Overrides of `min_velocity` for some (track, channel) pairs
+    pub channel_min_velocity: BTreeMap<(u16, u8), u8>,
+
+    /// Whether to hold notes while the sustain pedal is down, by moving their note offs to when
+    /// the pedal is lifted
+    pub sustain: bool,
+
+    /// Whether to release the notes a track never releases at the end of the track (they're
+    /// warned about either way)
+    pub release_dangling_notes: bool,
+}
+
+/// The controller of the sustain (damper) pedal, which is down for values of 64 and up
+pub const SUSTAIN_PEDAL_CONTROL: u8 = 64;
+
+/// The channel General MIDI reserves for drums, where the note numbers pick a drum instead of a
+/// pitch
+pub const PERCUSSION_CHANNEL: u8 = 10;
+
+/// The note drum hits are played as in `PercussionMode::Rhythm` (C2, low enough to hear the
+/// individual steps)
+pub const RHYTHM_NOTE: u8 = 36;
+
+/// How to play the percussion channel, since its note numbers make for garbage pitches
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PercussionMode {
+    /// Drop every event on the percussion channel
+    #[default]
+    #[serde(alias = "drop")]
+    Ignore,
+    /// Play the percussion channel like any other channel
+    Map,
+    /// Play every drum at the same pitch
+    FixedPitch(u8),
+    /// Play every drum hit as a short click (a 128th note of `RHYTHM_NOTE`), ignoring how long
+    /// the drum is held for
+    Rhythm,
+    /// Play every drum hit as a click of `note`, released `gate_ticks` after it starts (a 128th
+    /// note if not given)
+    Click {
+        note: u8,
+        #[serde(default)]
+        gate_ticks: Option<u32>,
+    },
+}
+
+pub fn parse_midi_file<P: AsRef<Path>>(
+    midi_path: P,
+    options: &MidiParseOptions,
+) -> Result<MidiFile> {
     let midi_file = std::fs::read(midi_path)?;
     let smf = Smf::parse(&midi_file)?;
 
-    /* Get Header Data */
+    parse_smf(&smf, options)
+}
 
-    dbg!(smf.header);
+/// Reads just the names of the data tracks of a MIDI file, keyed by the track number their events
+/// get (so the tracks of a song configuration can be given by name)
+pub fn parse_track_names<P: AsRef<Path>>(midi_path: P) -> Result<BTreeMap<u16, String>> {
+    let midi_file = std::fs::read(midi_path)?;
+    let smf = Smf::parse(&midi_file)?;
 
-    let (Format::Parallel | Format::SingleTrack) = smf.header.format else {
-        bail!("only parallel format is supported");
-    };
+    Ok(data_track_names(&smf))
+}
 
-    let Timing::Metrical(ticks_per_beat) = smf.header.timing else {
-        bail!("only metrical timing is supported");
+/// The first track name in each data track, numbered the same way as the events of the tracks
+fn data_track_names(smf: &Smf) -> BTreeMap<u16, String> {
+    // The first track of a parallel file only holds the metadata, so its name is the song's
+    let data_tracks = match smf.header.format {
+        Format::Parallel => &smf.tracks[1.min(smf.tracks.len())..],
+        Format::SingleTrack | Format::Sequential => &smf.tracks[..],
     };
 
+    data_tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, track)| {
+            let name = track.iter().find_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => Some(name),
+                _ => None,
+            })?;
+
+            Some((
+                (i + 1) as u16,
+                String::from_utf8_lossy(name).trim().to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn parse_smf(smf: &Smf, options: &MidiParseOptions) -> Result<MidiFile> {
+    /* Get Header Data */
+
+    trace!("MIDI header: {:?}", smf.header);
+
     /* Parse Metadata Track */
 
     let meta_track = smf
@@ -44,30 +366,77 @@ pub fn parse_midi_file<P: AsRef<Path>>(midi_path: P) -> Result<MidiFile> {
         .first()
         .with_context(|| "could not get first track")?;
 
-    let (first_non_meta_index, metadata) = parse_track_metadata(meta_track)?;
-
     /* Calculate Tempo Values */
 
-    let ticks_per_beat = ticks_per_beat.as_int();
-    let beats_per_minute = tempo_to_bpm(metadata.tempo);
+    let tempo_map = match smf.header.format {
+        Format::Sequential => TempoMap::from_sequential_tracks(&smf.tracks),
+        Format::SingleTrack | Format::Parallel => TempoMap::from_tracks(&smf.tracks),
+    };
+
+    let metadata = parse_track_metadata(meta_track, &tempo_map)?;
+
+    let timing = match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => MidiTiming::Metrical {
+            ticks_per_beat: ticks_per_beat.as_int(),
+            tempo_map,
+        },
+        Timing::Timecode(fps, subframes) => MidiTiming::Timecode {
+            frames_per_second: fps.as_f32(),
+            subframes,
+        },
+    };
 
     /* Absolutize the time for each track */
 
+    let mut filtered_events = 0;
+
     let data_tracks = match smf.header.format {
-        // Single track with metadata at the beginning
-        Format::SingleTrack => {
-            vec![absolutize_track(
-                &meta_track[first_non_meta_index..].to_vec(),
-                1,
-            )]
+        // Single track with metadata mixed in
+        Format::SingleTrack => vec![absolutize_track(
+            meta_track,
+            1,
+            options,
+            &mut filtered_events,
+        )],
+        // Metadata track + data tracks
+        Format::Parallel => {
+            // Any MIDI events mixed in with the metadata belong to the first data track
+            let meta_track_events = absolutize_track(meta_track, 1, options, &mut filtered_events);
+
+            let mut data_tracks = smf.tracks[1..]
+                .iter()
+                .enumerate()
+                .map(|(i, track)| {
+                    absolutize_track(track, (i + 1) as u16, options, &mut filtered_events)
+                })
+                .collect::<Vec<_>>();
+
+            match data_tracks.first_mut() {
+                Some(first_track) => first_track.extend(meta_track_events),
+                None if !meta_track_events.is_empty() => data_tracks.push(meta_track_events),
+                None => {}
+            }
+
+            data_tracks
         }
-        // Single metadata track + data tracks
-        Format::Parallel => smf.tracks[1..]
+        // Every track is played after the one before it, so they're shifted to start where the
+        // previous ones end
+        Format::Sequential => smf
+            .tracks
             .iter()
+            .zip(sequential_offsets(&smf.tracks))
             .enumerate()
-            .map(|(i, track)| absolutize_track(track, (i + 1) as u16))
-            .collect::<Vec<_>>(),
-        Format::Sequential => unimplemented!(),
+            .map(|(i, (track, offset))| {
+                let mut events =
+                    absolutize_track(track, (i + 1) as u16, options, &mut filtered_events);
+
+                for event in &mut events {
+                    event.time_offset += offset;
+                }
+
+                events
+            })
+            .collect(),
     };
 
     let num_tracks = data_tracks.len() as u16;
@@ -87,6 +456,16 @@ pub fn parse_midi_file<P: AsRef<Path>>(midi_path: P) -> Result<MidiFile> {
         events.extend(track);
     }
 
+    if let Some(percussion) = options.percussion {
+        // About a 128th note, or 30ms for timecode timing
+        let click_ticks = match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int() as u32 / 32,
+            Timing::Timecode(fps, subframes) => (fps.as_f32() * subframes as f32 * 0.03) as u32,
+        };
+
+        apply_percussion_mode(&mut events, percussion, click_ticks.max(1));
+    }
+
     events.sort_by_key(|e| e.time_offset);
 
     // for event in &events {
@@ -95,10 +474,21 @@ pub fn parse_midi_file<P: AsRef<Path>>(midi_path: P) -> Result<MidiFile> {
 
     Ok(MidiFile {
         metadata,
-        ticks_per_beat,
-        beats_per_minute,
+        timing,
         num_tracks,
         events,
+        filtered_events,
+        track_names: data_track_names(smf),
+    })
+}
+
+/// The tick each track of a sequential file starts at, which is where the tracks before it end
+fn sequential_offsets<'a>(tracks: &'a [Track]) -> impl Iterator<Item = u32> + 'a {
+    tracks.iter().scan(0, |offset, track| {
+        let start = *offset;
+        *offset += track.iter().map(|event| event.delta.as_int()).sum::<u32>();
+
+        Some(start)
     })
 }
 
@@ -110,23 +500,132 @@ pub fn tempo_to_bpm(tempo: u32) -> f64 {
     beats_per_second * 60.0
 }
 
-/// Takes a number of ticks and returns the number of microseconds that many ticks represents
-pub fn ticks_to_microseconds(ticks: u32, ticks_per_beat: u16, beats_per_minute: f64) -> u64 {
-    let beats = ticks as f64 / ticks_per_beat as f64;
-    let seconds = beats / beats_per_minute * 60.0;
-    let microseconds = seconds * 1_000_000.0;
+/// Takes a number of ticks in an SMPTE timed file and returns the number of microseconds that many
+/// ticks represents, rounded to the nearest microsecond
+pub fn timecode_ticks_to_microseconds(ticks: u32, frames_per_second: f32, subframes: u8) -> u64 {
+    let ticks_per_second = frames_per_second as f64 * subframes as f64;
 
-    microseconds as u64
+    (ticks as f64 * 1_000_000.0 / ticks_per_second).round() as u64
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MidiMetadata {
     track_name: Option<String>,
     text: Vec<String>,
     copyright: Vec<String>,
-    tempo: u32,
-    time_signature: (u8, u8, u8, u8),
-    key_signature: (i8, bool),
+    /// Slowest and fastest tempo in beats per minute
+    tempo_range: (f64, f64),
+    tempo_changes: usize,
+    /// Every time signature with the tick it starts at, in order (never empty)
+    time_signatures: Vec<(u32, TimeSignature)>,
+    /// Every key signature with the tick it starts at, in order (never empty)
+    key_signatures: Vec<(u32, KeySignature)>,
+}
+
+impl MidiMetadata {
+    /// The name of the first track, which is usually the name of the song
+    pub fn track_name(&self) -> Option<&str> {
+        self.track_name.as_deref()
+    }
+
+    /// The time signature the song starts in
+    pub fn time_signature(&self) -> TimeSignature {
+        self.time_signatures[0].1
+    }
+
+    /// Every time signature with the tick it starts at
+    pub fn time_signatures(&self) -> &[(u32, TimeSignature)] {
+        &self.time_signatures
+    }
+
+    /// The key signature the song starts in
+    pub fn key_signature(&self) -> KeySignature {
+        self.key_signatures[0].1
+    }
+
+    /// Every key signature with the tick it starts at
+    pub fn key_signatures(&self) -> &[(u32, KeySignature)] {
+        &self.key_signatures
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    /// The power of two of the denominator (3 for eighth notes)
+    pub denominator_power: u8,
+    pub clocks_per_tick: u8,
+    pub thirty_seconds_per_beat: u8,
+}
+
+impl TimeSignature {
+    pub fn denominator(&self) -> u32 {
+        2u32.pow(self.denominator_power as u32)
+    }
+}
+
+impl Default for TimeSignature {
+    /// 4/4, which the MIDI spec says to assume when a file has no time signature
+    fn default() -> Self {
+        Self {
+            numerator: 4,
+            denominator_power: 2,
+            clocks_per_tick: 24,
+            thirty_seconds_per_beat: 8,
+        }
+    }
+}
+
+impl Display for TimeSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator())
+    }
+}
+
+/// A key signature, C major by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct KeySignature {
+    /// The number of sharps, or flats if negative
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+impl Display for KeySignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.sharps.abs(),
+            if self.sharps < 0 {
+                "flat(s)"
+            } else {
+                "sharp(s)"
+            },
+            if self.minor { "minor" } else { "major" }
+        )
+    }
+}
+
+/// Writes signatures as "first → second → ... (n changes)", or just the one if it never changes
+fn write_signatures<T: Display>(
+    f: &mut std::fmt::Formatter<'_>,
+    signatures: &[(u32, T)],
+) -> std::fmt::Result {
+    for (i, (_, signature)) in signatures.iter().enumerate() {
+        if i > 0 {
+            write!(f, " → ")?;
+        }
+
+        write!(f, "{}", signature)?;
+    }
+
+    match signatures.len() {
+        0 | 1 => {}
+        2 => write!(f, " (1 change)")?,
+        len => write!(f, " ({} changes)", len - 1)?,
+    }
+
+    Ok(())
 }
 
 impl Display for MidiMetadata {
@@ -143,67 +642,62 @@ impl Display for MidiMetadata {
             writeln!(f, "Copyright: {}", txt)?;
         }
 
-        writeln!(f, "Tempo: {} bpm", tempo_to_bpm(self.tempo))?;
-        writeln!(
-            f,
-            "Time Signature: {}/{} ({} clocks per tick, {} 32nd notes per beat)",
-            self.time_signature.0,
-            2u32.pow(self.time_signature.1 as u32),
-            self.time_signature.2,
-            self.time_signature.3
-        )?;
-        write!(
-            f,
-            "Key Signature: {} {} {}",
-            self.key_signature.0.abs(),
-            if self.key_signature.0 < 0 {
-                "flat(s)"
-            } else {
-                "sharp(s)"
-            },
-            if self.key_signature.1 {
-                "minor"
-            } else {
-                "major"
-            }
-        )?;
+        let (min_bpm, max_bpm) = self.tempo_range;
 
-        Ok(())
+        if self.tempo_changes == 0 {
+            writeln!(f, "Tempo: {} bpm", min_bpm)?;
+        } else {
+            writeln!(
+                f,
+                "Tempo: {:.0}–{:.0} bpm ({} changes)",
+                min_bpm, max_bpm, self.tempo_changes
+            )?;
+        }
+
+        write!(f, "Time Signature: ")?;
+        write_signatures(f, &self.time_signatures)?;
+
+        if let [(_, time_signature)] = self.time_signatures[..] {
+            write!(
+                f,
+                " ({} clocks per tick, {} 32nd notes per beat)",
+                time_signature.clocks_per_tick, time_signature.thirty_seconds_per_beat
+            )?;
+        }
+
+        write!(f, "\nKey Signature: ")?;
+        write_signatures(f, &self.key_signatures)
     }
 }
 
-/// Parses the metadata from the given track and returns the index of the first
-/// non-metadata event as well as the parsed metadata
-fn parse_track_metadata(track: &Track) -> Result<(usize, MidiMetadata)> {
+/// Parses the metadata from the meta events anywhere in the given track
+///
+/// Only the first track name is kept. Every time and key signature is kept along with the tick it
+/// starts at, except ones that repeat the signature already in effect.
+fn parse_track_metadata(track: &Track, tempo_map: &TempoMap) -> Result<MidiMetadata> {
     let mut track_name = None;
     let mut text = Vec::new();
     let mut copyright = Vec::new();
-    let mut tempo = None;
-    let mut time_signature = None;
-    let mut key_signature = None;
+    let mut time_signatures = Vec::new();
+    let mut key_signatures = Vec::new();
 
     assert!(!track.is_empty());
 
-    let mut next_index = 0;
+    let mut ticks = 0u32;
 
-    for (i, TrackEvent { delta, kind }) in track.iter().enumerate() {
-        assert_eq!(
-            delta.as_int(),
-            0,
-            "metadata track should have no delta time"
-        );
+    for TrackEvent { delta, kind } in track.iter() {
+        trace!("Metadata event at tick {}: {:?}", ticks, kind);
 
-        dbg!(kind);
+        ticks += delta.as_int();
 
+        // MIDI events are picked up as data by `absolutize_track`
         let TrackEventKind::Meta(msg) = kind else {
-            next_index = i;
-            break;
+            continue;
         };
 
         match msg {
             MetaMessage::TrackName(name) => {
-                assert_eq!(track_name, None, "only one track name is supported");
-                track_name = Some(String::from_utf8_lossy(name).to_string());
+                track_name.get_or_insert_with(|| String::from_utf8_lossy(name).to_string());
             }
             MetaMessage::Text(txt) => {
                 text.push(String::from_utf8_lossy(txt).to_string());
@@ -211,78 +705,464 @@ fn parse_track_metadata(track: &Track) -> Result<(usize, MidiMetadata)> {
             MetaMessage::Copyright(txt) => {
                 copyright.push(String::from_utf8_lossy(txt).to_string());
             }
-            MetaMessage::Tempo(tmp) => {
-                assert_eq!(tempo, None, "only one tempo is supported");
-                tempo = Some(tmp.as_int());
-            }
+            // Tempo changes are collected separately into the tempo map
+            MetaMessage::Tempo(_) => {}
             MetaMessage::TimeSignature(
                 numerator,
                 denominator,
                 clocks_per_tick,
                 thirty_seconds_per_beat,
             ) => {
-                assert_eq!(time_signature, None, "only one time signature is supported");
-                time_signature = Some((
-                    *numerator,
-                    *denominator,
-                    *clocks_per_tick,
-                    *thirty_seconds_per_beat,
-                ));
+                push_signature(
+                    &mut time_signatures,
+                    ticks,
+                    TimeSignature {
+                        numerator: *numerator,
+                        denominator_power: *denominator,
+                        clocks_per_tick: *clocks_per_tick,
+                        thirty_seconds_per_beat: *thirty_seconds_per_beat,
+                    },
+                );
             }
             MetaMessage::KeySignature(key, scale) => {
-                assert_eq!(key_signature, None, "only one key signature is supported");
-                key_signature = Some((*key, *scale));
+                push_signature(
+                    &mut key_signatures,
+                    ticks,
+                    KeySignature {
+                        sharps: *key,
+                        minor: *scale,
+                    },
+                );
             }
             MetaMessage::EndOfTrack => {}
             MetaMessage::SequencerSpecific(data) => {
-                eprintln!("Unused SequencerSpecific metadata: {:?}", data)
+                debug!("Unused SequencerSpecific metadata: {:?}", data)
             }
             MetaMessage::SmpteOffset(smpte_time) => {
-                eprintln!("Unused SmpteOffset: {:?}", smpte_time)
+                debug!("Unused SmpteOffset: {:?}", smpte_time)
             }
             MetaMessage::MidiChannel(channel) => {
-                eprintln!("Unused MidiChannel: {}", channel)
+                debug!("Unused MidiChannel: {}", channel)
             }
             MetaMessage::MidiPort(port) => {
-                eprintln!("Unused MidiPort: {}", port)
-            }
-            _ => {
-                unimplemented!("unsupported meta event: {:?}", msg)
+                debug!("Unused MidiPort: {}", port)
             }
+            // Markers, lyrics, cue points, etc. don't affect playback
+            _ => {}
         }
     }
 
-    // Default BPM is 120 = 500_000 microseconds per beat
-    if tempo.is_none() {
-        tempo = Some(500_000)
-    };
-
     // ensure!(
     //     track_name.is_some(),
     //     "metadata track must have a track name"
     // );
-    ensure!(
-        time_signature.is_some(),
-        "metadata track must have a time signature"
+
+    // Songs without a signature at the start are in the default one until their first
+    if time_signatures.first().is_none_or(|&(ticks, _)| ticks > 0) {
+        time_signatures.insert(0, (0, TimeSignature::default()));
+    }
+
+    if key_signatures.first().is_none_or(|&(ticks, _)| ticks > 0) {
+        key_signatures.insert(0, (0, KeySignature::default()));
+    }
+
+    Ok(MidiMetadata {
+        track_name,
+        text,
+        copyright,
+        tempo_range: tempo_map.bpm_range(),
+        tempo_changes: tempo_map.num_changes(),
+        time_signatures,
+        key_signatures,
+    })
+}
+
+/// Adds a signature starting at `ticks`, unless it's the one already in effect
+///
+/// A signature at the same tick as the previous one replaces it, since that one never took effect.
+fn push_signature<T: PartialEq>(signatures: &mut Vec<(u32, T)>, ticks: u32, signature: T) {
+    match signatures.last_mut() {
+        Some((_, last)) if *last == signature => {}
+        Some((last_ticks, last)) if *last_ticks == ticks => *last = signature,
+        _ => signatures.push((ticks, signature)),
+    }
+}
+
+/// Rewrites the events on the percussion channel as described by `mode`
+///
+/// The clicks of `PercussionMode::Rhythm` (and `PercussionMode::Click` without a gate) are
+/// released `click_ticks` after they start, so the events need sorting by time afterwards. If a
+/// click starts before the previous one on its track and channel has been released, that release
+/// moves to the end of the new click rather than cutting it short, so the events of each track
+/// must be in time order.
+pub fn apply_percussion_mode(
+    events: &mut Vec<AbsoluteMidiEvent>,
+    mode: PercussionMode,
+    click_ticks: u32,
+) {
+    let is_percussion = |event: &AbsoluteMidiEvent| event.channel == PERCUSSION_CHANNEL;
+
+    let (click_note, click_ticks) = match mode {
+        PercussionMode::Ignore => {
+            events.retain(|event| !is_percussion(event));
+            return;
+        }
+        PercussionMode::Map => return,
+        PercussionMode::FixedPitch(pitch) => {
+            for event in events.iter_mut().filter(|event| is_percussion(event)) {
+                if let LimitedMidiMessage::NoteOn { note, .. }
+                | LimitedMidiMessage::NoteOff { note, .. } = &mut event.message
+                {
+                    *note = pitch;
+                }
+            }
+
+            return;
+        }
+        PercussionMode::Rhythm => (RHYTHM_NOTE, click_ticks),
+        PercussionMode::Click { note, gate_ticks } => (note, gate_ticks.unwrap_or(click_ticks)),
+    };
+
+    let mut releases: Vec<AbsoluteMidiEvent> = Vec::new();
+
+    // The index in `releases` of the last click's release on each (track, channel)
+    let mut last_releases: BTreeMap<(u16, u8), usize> = BTreeMap::new();
+
+    events.retain_mut(|event| {
+        if !is_percussion(event) {
+            return true;
+        }
+
+        match event.message {
+            LimitedMidiMessage::NoteOn { velocity, .. } if velocity > 0 => {
+                event.message = LimitedMidiMessage::NoteOn {
+                    note: click_note,
+                    velocity,
+                };
+
+                let release_at = event.time_offset + click_ticks;
+
+                match last_releases.get(&(event.track, event.channel)) {
+                    // The last click would be released during this one and cut it short
+                    Some(&index) if releases[index].time_offset >= event.time_offset => {
+                        releases[index].time_offset = release_at;
+                    }
+                    _ => {
+                        last_releases.insert((event.track, event.channel), releases.len());
+
+                        releases.push(AbsoluteMidiEvent {
+                            time_offset: release_at,
+                            track: event.track,
+                            channel: event.channel,
+                            message: LimitedMidiMessage::NoteOff {
+                                note: click_note,
+                                velocity: 0,
+                            },
+                        });
+                    }
+                }
+
+                true
+            }
+            // The clicks are released on their own
+            LimitedMidiMessage::NoteOn { .. } | LimitedMidiMessage::NoteOff { .. } => false,
+            _ => true,
+        }
+    });
+
+    events.extend(releases);
+}
+
+/// Emulates the sustain pedal by holding the notes released while it's down until it's lifted
+///
+/// The events must be in time order. A note struck again while it's being sustained is released
+/// just before it's struck, and any notes still sustained at the end are released with the last
+/// event. The pedal's control changes are left in place.
+pub fn apply_sustain_pedal(events: &mut Vec<AbsoluteMidiEvent>) {
+    // The (track, channel) pairs with the pedal down
+    let mut pedal_down = BTreeSet::new();
+    // The notes released while the pedal was down, keyed by (track, channel, note)
+    let mut sustained = BTreeSet::new();
+
+    let release = |time_offset, track, channel, note| AbsoluteMidiEvent {
+        time_offset,
+        track,
+        channel,
+        message: LimitedMidiMessage::NoteOff { note, velocity: 0 },
+    };
+
+    let end = events.last().map_or(0, |event| event.time_offset);
+    let mut sustained_events = Vec::with_capacity(events.len());
+
+    for event in events.drain(..) {
+        let AbsoluteMidiEvent {
+            time_offset,
+            track,
+            channel,
+            message,
+        } = event;
+
+        match message {
+            LimitedMidiMessage::ControlChange {
+                control: SUSTAIN_PEDAL_CONTROL,
+                value,
+            } => {
+                if value >= 64 {
+                    pedal_down.insert((track, channel));
+                } else if pedal_down.remove(&(track, channel)) {
+                    let lifted = sustained
+                        .extract_if(.., |&(t, c, _)| (t, c) == (track, channel))
+                        .map(|(track, channel, note)| release(time_offset, track, channel, note));
+
+                    sustained_events.extend(lifted);
+                }
+            }
+            LimitedMidiMessage::NoteOff { note, .. } if pedal_down.contains(&(track, channel)) => {
+                sustained.insert((track, channel, note));
+                continue;
+            }
+            LimitedMidiMessage::NoteOn { note, .. }
+                if sustained.remove(&(track, channel, note)) =>
+            {
+                sustained_events.push(release(time_offset, track, channel, note));
+            }
+            _ => {}
+        }
+
+        sustained_events.push(event);
+    }
+
+    sustained_events.extend(
+        sustained
+            .into_iter()
+            .map(|(track, channel, note)| release(end, track, channel, note)),
     );
 
-    Ok((
-        next_index,
-        MidiMetadata {
-            track_name,
-            text,
-            copyright,
-            tempo: tempo.unwrap(),
-            time_signature: time_signature.unwrap(),
-            key_signature: key_signature.unwrap_or((0, false)), // Default to C major
+    *events = sustained_events;
+}
+
+/// Warns about the notes that are struck but never released, which would leave a drive playing
+/// until the end of the song, returning how many there are
+///
+/// The events must be in time order. If `release` is set, the notes are released at `end`.
+pub fn check_note_offs(events: &mut Vec<AbsoluteMidiEvent>, end: u32, release: bool) -> usize {
+    // The tick each held note was first struck at, keyed by (track, channel, note)
+    let mut held = BTreeMap::new();
+
+    for event in events.iter() {
+        match event.message {
+            LimitedMidiMessage::NoteOn { note, .. } => {
+                held.entry((event.track, event.channel, note))
+                    .or_insert(event.time_offset);
+            }
+            LimitedMidiMessage::NoteOff { note, .. } => {
+                held.remove(&(event.track, event.channel, note));
+            }
+            _ => {}
+        }
+    }
+
+    for (&(track, channel, note), time_offset) in &held {
+        warn!(
+            "track {} channel {} plays {} at tick {} but never releases it",
+            track,
+            channel,
+            note_name(note),
+            time_offset
+        );
+    }
+
+    if release {
+        events.extend(
+            held.keys()
+                .map(|&(track, channel, note)| AbsoluteMidiEvent {
+                    time_offset: end,
+                    track,
+                    channel,
+                    message: LimitedMidiMessage::NoteOff { note, velocity: 0 },
+                }),
+        );
+    }
+
+    held.len()
+}
+
+/// Moves a note by whole octaves until it is inside `window`, so notes the drives can't play
+/// aren't silently dropped by the client
+///
+/// The window must be at least an octave wide.
+pub fn fold_note(mut note: u8, window: RangeInclusive<u8>) -> u8 {
+    debug_assert!(window.end() - window.start() >= 11);
+
+    while note < *window.start() {
+        note += 12;
+    }
+
+    while note > *window.end() {
+        note -= 12;
+    }
+
+    note
+}
+
+/// How often each track and channel plays each note, to help pick the drive counts and
+/// transpositions of a configuration
+#[derive(Debug, Default)]
+pub struct NoteHistogram {
+    /// Every (track, channel) pair that plays notes
+    pub rows: Vec<NoteHistogramRow>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct NoteHistogramRow {
+    pub track: u16,
+    pub channel: u8,
+    /// The number of times each note is played (never empty)
+    pub counts: BTreeMap<u8, usize>,
+}
+
+impl NoteHistogram {
+    pub fn new(events: &[AbsoluteMidiEvent]) -> Self {
+        let mut counts = BTreeMap::<(u16, u8), BTreeMap<u8, usize>>::new();
+
+        for event in events {
+            if let LimitedMidiMessage::NoteOn { note, velocity } = event.message {
+                if velocity > 0 {
+                    *counts
+                        .entry((event.track, event.channel))
+                        .or_default()
+                        .entry(note)
+                        .or_default() += 1;
+                }
+            }
+        }
+
+        let rows = counts
+            .into_iter()
+            .map(|((track, channel), counts)| NoteHistogramRow {
+                track,
+                channel,
+                counts,
+            })
+            .collect();
+
+        Self { rows }
+    }
+}
+
+impl NoteHistogramRow {
+    pub fn notes(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    pub fn lowest(&self) -> u8 {
+        *self.counts.keys().next().unwrap()
+    }
+
+    pub fn highest(&self) -> u8 {
+        *self.counts.keys().next_back().unwrap()
+    }
+
+    /// The note played the most (the lowest of them if there's a tie)
+    pub fn most_common(&self) -> u8 {
+        self.counts
+            .iter()
+            .max_by_key(|(note, count)| (**count, Reverse(**note)))
+            .map(|(note, _)| *note)
+            .unwrap()
+    }
+
+    /// The number of notes played that are too low or high for the drives
+    pub fn unplayable(&self) -> usize {
+        self.counts
+            .iter()
+            .filter(|(note, _)| !(MIN_PLAYABLE_NOTE..=MAX_PLAYABLE_NOTE).contains(*note))
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+impl Display for NoteHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<8}{:<10}{:<8}{:<10}{:<10}{:<13}Unplayable",
+            "Track", "Channel", "Notes", "Lowest", "Highest", "Most Common"
+        )?;
+
+        let name = |note: u8| format!("{} ({})", note_name(note), note);
+
+        for row in &self.rows {
+            writeln!(
+                f,
+                "{:<8}{:<10}{:<8}{:<10}{:<10}{:<13}{}",
+                row.track,
+                row.channel,
+                row.notes(),
+                name(row.lowest()),
+                name(row.highest()),
+                name(row.most_common()),
+                row.unplayable()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts a MIDI message into the subset the client understands, or `None` if it isn't supported
+///
+/// Control changes are only converted if `control_changes` is set.
+pub fn to_limited_message(
+    message: &MidiMessage,
+    control_changes: bool,
+) -> Option<LimitedMidiMessage> {
+    let message = match *message {
+        // A note on without a velocity is a note off by convention
+        MidiMessage::NoteOn { key, vel } if vel == 0 => LimitedMidiMessage::NoteOff {
+            note: key.as_int(),
+            velocity: 0,
+        },
+        MidiMessage::NoteOn { key, vel } => LimitedMidiMessage::NoteOn {
+            note: key.as_int(),
+            velocity: vel.as_int(),
+        },
+        MidiMessage::NoteOff { key, vel } => LimitedMidiMessage::NoteOff {
+            note: key.as_int(),
+            velocity: vel.as_int(),
+        },
+        MidiMessage::ProgramChange { program } => LimitedMidiMessage::ProgramChange {
+            program: program.as_int(),
         },
-    ))
+        MidiMessage::Controller { controller, value } if control_changes => {
+            LimitedMidiMessage::ControlChange {
+                control: controller.as_int(),
+                value: value.as_int(),
+            }
+        }
+        MidiMessage::PitchBend { bend } => LimitedMidiMessage::PitchBend {
+            value: bend.as_int(),
+        },
+        _ => return None,
+    };
+
+    Some(message)
 }
 
-fn absolutize_track(track: &Track, track_number: u16) -> Vec<AbsoluteMidiEvent> {
+/// Converts the events of a track to absolute times, dropping the ones that can't be played and
+/// counting the notes dropped for being too quiet in `filtered_events`
+fn absolutize_track(
+    track: &Track,
+    track_number: u16,
+    options: &MidiParseOptions,
+    filtered_events: &mut usize,
+) -> Vec<AbsoluteMidiEvent> {
     let mut absolute_time = 0;
     let mut events = Vec::with_capacity(track.len());
 
+    // The (channel, note) pairs whose last note on was dropped, so the note off is dropped too
+    let mut dropped_notes = BTreeSet::new();
+
     for (i, TrackEvent { delta, kind }) in track.iter().enumerate() {
         // Accumulate the absolute time
         let delta_ticks = delta.as_int();
@@ -291,47 +1171,52 @@ fn absolutize_track(track: &Track, track_number: u16) -> Vec<AbsoluteMidiEvent>
         // Only MIDI events are supported
         let (channel_number, message) = match kind {
             TrackEventKind::Midi { channel, message } => (channel.as_int() + 1, message),
+            // Tempo changes are handled by the tempo map
+            TrackEventKind::Meta(MetaMessage::Tempo(_)) => continue,
             TrackEventKind::Meta(MetaMessage::EndOfTrack) => {
                 if i != track.len() - 1 {
-                    eprintln!("Warning: end of track message not at end of track");
+                    warn!("end of track message not at end of track");
                 }
 
                 continue;
             }
+            // Meta events in the first track are handled by `parse_track_metadata`, and the rest
+            // (track names, markers, etc.) don't affect playback
+            TrackEventKind::Meta(_) => continue,
             _ => {
-                eprintln!(
-                    "Warning: non-midi message in data track not supported ({:?})",
-                    kind
-                );
+                warn!("non-midi message in data track not supported ({:?})", kind);
                 continue;
             }
         };
 
-        // Convert the MIDI message into our MIDI representation
-        let message = match message {
-            MidiMessage::NoteOn { key, vel } => LimitedMidiMessage::NoteOn {
-                note: key.as_int(),
-                velocity: vel.as_int(),
-            },
-            MidiMessage::NoteOff { key, vel } => LimitedMidiMessage::NoteOff {
-                note: key.as_int(),
-                velocity: vel.as_int(),
-            },
-            // MidiMessage::ProgramChange { program } => LimitedMidiMessage::ProgramChange {
-            //     program: program.as_int(),
-            // },
-            // MidiMessage::Controller { controller, value } => LimitedMidiMessage::ControlChange {
-            //     control: controller.as_int(),
-            //     value: value.as_int(),
-            // },
-            // MidiMessage::PitchBend { bend } => LimitedMidiMessage::PitchBend {
-            //     value: bend.as_int(),
-            // },
-            _ => {
-                eprintln!("Warning: unsupported MIDI message ({:?})", message);
+        // Convert the MIDI message into our MIDI representation (the sustain pedal is needed to
+        // emulate it, even if control changes aren't sent)
+        let Some(message) = to_limited_message(message, options.control_changes || options.sustain)
+        else {
+            debug!("Unsupported MIDI message ({:?})", message);
+            continue;
+        };
+
+        let min_velocity = options
+            .channel_min_velocity
+            .get(&(track_number, channel_number))
+            .copied()
+            .unwrap_or(options.min_velocity);
+
+        match message {
+            LimitedMidiMessage::NoteOn { note, velocity } if velocity < min_velocity => {
+                dropped_notes.insert((channel_number, note));
+                *filtered_events += 1;
                 continue;
             }
-        };
+            LimitedMidiMessage::NoteOff { note, .. }
+                if dropped_notes.remove(&(channel_number, note)) =>
+            {
+                *filtered_events += 1;
+                continue;
+            }
+            _ => {}
+        }
 
         // Push the event back to the list of events
         events.push(AbsoluteMidiEvent {
@@ -342,5 +1227,738 @@ fn absolutize_track(track: &Track, track_number: u16) -> Vec<AbsoluteMidiEvent>
         })
     }
 
+    // Before the sustain pedal moves the note offs, so a note sustained to the end isn't mistaken
+    // for one that's never released
+    check_note_offs(&mut events, absolute_time, options.release_dangling_notes);
+
+    if options.sustain {
+        apply_sustain_pedal(&mut events);
+
+        if !options.control_changes {
+            events
+                .retain(|event| !matches!(event.message, LimitedMidiMessage::ControlChange { .. }));
+        }
+    }
+
     events
 }
+
+#[cfg(test)]
+mod tests {
+    use midly::Header;
+
+    use super::*;
+
+    #[test]
+    fn ticks_to_microseconds_across_tempo_changes() {
+        // 120 bpm for the first beat, then 60 bpm
+        let tempo_map = TempoMap {
+            changes: vec![(0, 500_000), (96, 1_000_000)],
+        };
+
+        assert_eq!(tempo_map.ticks_to_microseconds(0, 96, 96), 500_000);
+        assert_eq!(tempo_map.ticks_to_microseconds(96, 192, 96), 1_000_000);
+        assert_eq!(tempo_map.ticks_to_microseconds(48, 144, 96), 750_000);
+        assert_eq!(tempo_map.num_changes(), 1);
+        assert_eq!(tempo_map.bpm_range(), (60.0, 120.0));
+    }
+
+    #[test]
+    fn ticks_backwards_are_no_time() {
+        let metrical = MidiTiming::Metrical {
+            ticks_per_beat: 96,
+            tempo_map: TempoMap {
+                changes: vec![(0, 500_000)],
+            },
+        };
+        let timecode = MidiTiming::Timecode {
+            frames_per_second: 25.0,
+            subframes: 40,
+        };
+
+        assert_eq!(metrical.ticks_to_microseconds(96, 0), 0);
+        assert_eq!(timecode.ticks_to_microseconds(96, 0), 0);
+    }
+
+    #[test]
+    fn long_songs_do_not_drift() {
+        // Not a whole number of microseconds per tick, or an exact number of beats per minute
+        let tempo_map = TempoMap {
+            changes: vec![(0, 500_001)],
+        };
+
+        assert_eq!(
+            tempo_map.ticks_to_microseconds(0, 480 * 10_000, 480),
+            5_000_010_000
+        );
+        assert_eq!(tempo_map.ticks_to_microseconds(0, 1, 480), 1_042);
+
+        // Every event is still on the beat an hour in
+        let mut last = 0;
+
+        for beat in 0..7_200 {
+            let time = tempo_map.ticks_to_microseconds(0, beat * 480, 480);
+
+            assert_eq!(time, beat as u64 * 500_001);
+            assert!(time >= last);
+
+            last = time;
+        }
+    }
+
+    #[test]
+    fn parses_late_tempo_in_metadata_track() {
+        let event = |delta: u32, kind| TrackEvent {
+            delta: delta.into(),
+            kind,
+        };
+        let note = |delta: u32, message| {
+            event(
+                delta,
+                TrackEventKind::Midi {
+                    channel: 0.into(),
+                    message,
+                },
+            )
+        };
+
+        let track = vec![
+            event(0, TrackEventKind::Meta(MetaMessage::TrackName(b"Song"))),
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
+            ),
+            note(
+                0,
+                MidiMessage::NoteOn {
+                    key: 60.into(),
+                    vel: 100.into(),
+                },
+            ),
+            note(
+                96,
+                MidiMessage::NoteOff {
+                    key: 60.into(),
+                    vel: 0.into(),
+                },
+            ),
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::Tempo(1_000_000.into())),
+            ),
+            note(
+                0,
+                MidiMessage::NoteOn {
+                    key: 64.into(),
+                    vel: 100.into(),
+                },
+            ),
+            note(
+                96,
+                MidiMessage::NoteOff {
+                    key: 64.into(),
+                    vel: 0.into(),
+                },
+            ),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ];
+
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(96.into())),
+            tracks: vec![track],
+        };
+
+        let midi_file = parse_smf(&smf, &MidiParseOptions::default()).unwrap();
+
+        assert_eq!(midi_file.metadata.track_name.as_deref(), Some("Song"));
+        assert_eq!(midi_file.num_tracks, 1);
+        assert_eq!(midi_file.events.len(), 4);
+        assert!(midi_file.events.iter().all(|event| event.track == 1));
+        assert_eq!(
+            midi_file.tempo_map().unwrap().changes,
+            vec![(0, TempoMap::DEFAULT_TEMPO), (96, 1_000_000)]
+        );
+        assert_eq!(midi_file.duration(), Duration::from_millis(1_500));
+        assert_eq!(
+            midi_file.events_between(
+                Duration::from_millis(500),
+                Some(Duration::from_millis(1_500))
+            ),
+            1..3
+        );
+        assert_eq!(midi_file.events_between(Duration::from_secs(5), None), 4..4);
+    }
+
+    #[test]
+    fn defaults_signatures_until_the_first_one() {
+        let event = |delta: u32, message| TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Meta(message),
+        };
+
+        let tracks = vec![vec![
+            event(96, MetaMessage::TimeSignature(3, 2, 24, 8)),
+            event(0, MetaMessage::EndOfTrack),
+        ]];
+
+        let metadata = parse_track_metadata(&tracks[0], &TempoMap::from_tracks(&tracks)).unwrap();
+
+        assert_eq!(
+            metadata.time_signatures(),
+            [
+                (0, TimeSignature::default()),
+                (
+                    96,
+                    TimeSignature {
+                        numerator: 3,
+                        denominator_power: 2,
+                        clocks_per_tick: 24,
+                        thirty_seconds_per_beat: 8
+                    }
+                )
+            ]
+        );
+        assert_eq!(metadata.key_signatures(), [(0, KeySignature::default())]);
+    }
+
+    #[test]
+    fn plays_sequential_tracks_one_after_another() {
+        let smf = Smf::parse(include_bytes!("../tests/fixtures/sequential.mid")).unwrap();
+        let mut midi_file = parse_smf(&smf, &MidiParseOptions::default()).unwrap();
+
+        assert_eq!(midi_file.metadata.track_name.as_deref(), Some("Sequential"));
+        assert_eq!(midi_file.num_tracks, 2);
+
+        // The second track starts where the first one ends (after its last note and a rest)
+        let events = midi_file
+            .events
+            .iter()
+            .map(|event| (event.time_offset, event.track, event.message))
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            events[..],
+            [
+                (0, 1, LimitedMidiMessage::NoteOn { note: 60, .. }),
+                (96, 1, LimitedMidiMessage::NoteOff { note: 60, .. }),
+                (192, 2, LimitedMidiMessage::NoteOn { note: 64, .. }),
+                (288, 2, LimitedMidiMessage::NoteOff { note: 64, .. }),
+            ]
+        ));
+
+        // The tempo change at the start of the second track is moved along with it
+        assert_eq!(
+            midi_file.tempo_map().unwrap().changes,
+            vec![(0, 500_000), (192, 1_000_000)]
+        );
+        assert_eq!(midi_file.duration(), Duration::from_secs(2));
+
+        // Either track can be played on its own
+        midi_file.filter_events(&[2], &[1]);
+
+        assert_eq!(midi_file.events.len(), 2);
+        assert!(midi_file.events.iter().all(|event| event.track == 2));
+
+        midi_file.filter_events(&[], &[2]);
+
+        assert!(midi_file.events.is_empty());
+    }
+
+    #[test]
+    fn drops_ghost_notes_with_their_note_offs() {
+        let note = |channel: u8, message| TrackEvent {
+            delta: 10.into(),
+            kind: TrackEventKind::Midi {
+                channel: channel.into(),
+                message,
+            },
+        };
+        let note_on = |channel, key: u8, vel: u8| {
+            note(
+                channel,
+                MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: vel.into(),
+                },
+            )
+        };
+
+        let track = vec![
+            note_on(0, 60, 100),
+            note_on(0, 62, 1),
+            // Note ons without a velocity are note offs
+            note_on(0, 62, 0),
+            note_on(0, 60, 0),
+            // Channel 2 lets quieter notes through
+            note_on(1, 62, 10),
+            note(
+                1,
+                MidiMessage::NoteOff {
+                    key: 62.into(),
+                    vel: 64.into(),
+                },
+            ),
+        ];
+
+        let options = MidiParseOptions {
+            min_velocity: 20,
+            channel_min_velocity: BTreeMap::from([((1, 2), 5)]),
+            ..Default::default()
+        };
+
+        let mut filtered_events = 0;
+        let events = absolutize_track(&track, 1, &options, &mut filtered_events);
+
+        assert_eq!(filtered_events, 2);
+        assert!(matches!(
+            events
+                .iter()
+                .map(|event| (event.channel, event.message))
+                .collect::<Vec<_>>()[..],
+            [
+                (
+                    1,
+                    LimitedMidiMessage::NoteOn {
+                        note: 60,
+                        velocity: 100
+                    }
+                ),
+                (1, LimitedMidiMessage::NoteOff { note: 60, .. }),
+                (
+                    2,
+                    LimitedMidiMessage::NoteOn {
+                        note: 62,
+                        velocity: 10
+                    }
+                ),
+                (2, LimitedMidiMessage::NoteOff { note: 62, .. }),
+            ]
+        ));
+    }
+
+    #[test]
+    fn releases_dangling_notes_at_the_end_of_the_track() {
+        let event = |time_offset, message| AbsoluteMidiEvent {
+            time_offset,
+            track: 1,
+            channel: 1,
+            message,
+        };
+        let note_on = |time_offset, note| {
+            event(
+                time_offset,
+                LimitedMidiMessage::NoteOn {
+                    note,
+                    velocity: 100,
+                },
+            )
+        };
+        let note_off = |time_offset, note| {
+            event(
+                time_offset,
+                LimitedMidiMessage::NoteOff { note, velocity: 0 },
+            )
+        };
+
+        let track = || {
+            vec![
+                note_on(0, 60),
+                note_on(0, 64),
+                note_off(96, 60),
+                // Struck again without a note off in between, which the one note off releases
+                note_on(96, 67),
+                note_on(144, 67),
+                note_off(192, 67),
+            ]
+        };
+
+        let mut events = track();
+        assert_eq!(check_note_offs(&mut events, 288, false), 1);
+        assert_eq!(events.len(), 6);
+
+        let mut events = track();
+        assert_eq!(check_note_offs(&mut events, 288, true), 1);
+        assert_eq!(note_events(&events[6..]), [(288, 64, false)]);
+
+        // Nothing is left dangling once they're released
+        assert_eq!(check_note_offs(&mut events, 288, false), 0);
+    }
+
+    /// The (time, note, whether it's a note on) of each note event
+    fn note_events(events: &[AbsoluteMidiEvent]) -> Vec<(u32, u8, bool)> {
+        events
+            .iter()
+            .filter_map(|event| match event.message {
+                LimitedMidiMessage::NoteOn { note, .. } => Some((event.time_offset, note, true)),
+                LimitedMidiMessage::NoteOff { note, .. } => Some((event.time_offset, note, false)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pedal_holds_notes_until_lifted() {
+        let event = |delta: u32, message| TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message,
+            },
+        };
+        let pedal = |delta, value: u8| {
+            event(
+                delta,
+                MidiMessage::Controller {
+                    controller: SUSTAIN_PEDAL_CONTROL.into(),
+                    value: value.into(),
+                },
+            )
+        };
+        let note_on = |delta, key: u8| {
+            event(
+                delta,
+                MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: 100.into(),
+                },
+            )
+        };
+        let note_off = |delta, key: u8| {
+            event(
+                delta,
+                MidiMessage::NoteOff {
+                    key: key.into(),
+                    vel: 0.into(),
+                },
+            )
+        };
+
+        let track = vec![
+            pedal(0, 127),
+            note_on(0, 60),
+            note_off(10, 60),
+            note_on(10, 62),
+            // Striking a sustained note again releases it first
+            note_on(10, 60),
+            note_off(10, 60),
+            note_off(0, 62),
+            pedal(10, 0),
+        ];
+
+        let options = MidiParseOptions {
+            sustain: true,
+            ..Default::default()
+        };
+
+        let events = absolutize_track(&track, 1, &options, &mut 0);
+
+        assert_eq!(
+            note_events(&events),
+            [
+                (0, 60, true),
+                (20, 62, true),
+                (30, 60, false),
+                (30, 60, true),
+                (50, 60, false),
+                (50, 62, false),
+            ]
+        );
+        // The pedal itself isn't sent unless control changes are
+        assert_eq!(events.len(), 6);
+    }
+
+    #[test]
+    fn pumping_the_pedal_releases_notes_each_time() {
+        let event = |time_offset, message| AbsoluteMidiEvent {
+            time_offset,
+            track: 1,
+            channel: 1,
+            message,
+        };
+        let pedal = |time_offset, value| {
+            event(
+                time_offset,
+                LimitedMidiMessage::ControlChange {
+                    control: SUSTAIN_PEDAL_CONTROL,
+                    value,
+                },
+            )
+        };
+        let note_on = |time_offset, note| {
+            event(
+                time_offset,
+                LimitedMidiMessage::NoteOn {
+                    note,
+                    velocity: 100,
+                },
+            )
+        };
+        let note_off = |time_offset, note| {
+            event(
+                time_offset,
+                LimitedMidiMessage::NoteOff { note, velocity: 0 },
+            )
+        };
+
+        let mut events = vec![
+            note_on(0, 60),
+            pedal(0, 127),
+            note_off(10, 60),
+            // Lifted and pressed again straight away
+            pedal(10, 0),
+            pedal(10, 127),
+            note_on(10, 62),
+            note_off(20, 62),
+            pedal(20, 30),
+            pedal(20, 100),
+            note_on(30, 64),
+            // The pedal is never lifted again
+            note_off(40, 64),
+        ];
+
+        apply_sustain_pedal(&mut events);
+
+        assert_eq!(
+            note_events(&events),
+            [
+                (0, 60, true),
+                (10, 60, false),
+                (10, 62, true),
+                (20, 62, false),
+                (30, 64, true),
+                (40, 64, false),
+            ]
+        );
+        assert_eq!(events.len(), 11);
+    }
+
+    #[test]
+    fn applies_percussion_modes() {
+        let drum = |time_offset, message| AbsoluteMidiEvent {
+            time_offset,
+            track: 2,
+            channel: PERCUSSION_CHANNEL,
+            message,
+        };
+
+        let events = || {
+            vec![
+                drum(
+                    0,
+                    LimitedMidiMessage::NoteOn {
+                        note: 42,
+                        velocity: 90,
+                    },
+                ),
+                drum(
+                    100,
+                    LimitedMidiMessage::NoteOff {
+                        note: 42,
+                        velocity: 0,
+                    },
+                ),
+                AbsoluteMidiEvent {
+                    time_offset: 100,
+                    track: 1,
+                    channel: 1,
+                    message: LimitedMidiMessage::NoteOn {
+                        note: 60,
+                        velocity: 90,
+                    },
+                },
+            ]
+        };
+
+        let mut ignored = events();
+        apply_percussion_mode(&mut ignored, PercussionMode::Ignore, 10);
+        assert_eq!(ignored.len(), 1);
+        assert_eq!(ignored[0].channel, 1);
+
+        let mut mapped = events();
+        apply_percussion_mode(&mut mapped, PercussionMode::Map, 10);
+        assert_eq!(mapped.len(), 3);
+        assert!(matches!(
+            mapped[0].message,
+            LimitedMidiMessage::NoteOn { note: 42, .. }
+        ));
+
+        let mut fixed = events();
+        apply_percussion_mode(&mut fixed, PercussionMode::FixedPitch(48), 10);
+        assert!(matches!(
+            fixed[1].message,
+            LimitedMidiMessage::NoteOff { note: 48, .. }
+        ));
+
+        let mut rhythm = events();
+        apply_percussion_mode(&mut rhythm, PercussionMode::Rhythm, 10);
+        rhythm.sort_by_key(|event| event.time_offset);
+
+        let rhythm = rhythm
+            .iter()
+            .map(|event| (event.time_offset, event.channel, event.message))
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            rhythm[..],
+            [
+                (
+                    0,
+                    PERCUSSION_CHANNEL,
+                    LimitedMidiMessage::NoteOn {
+                        note: RHYTHM_NOTE,
+                        velocity: 90
+                    }
+                ),
+                (
+                    10,
+                    PERCUSSION_CHANNEL,
+                    LimitedMidiMessage::NoteOff {
+                        note: RHYTHM_NOTE,
+                        ..
+                    }
+                ),
+                (100, 1, LimitedMidiMessage::NoteOn { note: 60, .. }),
+            ]
+        ));
+
+        let mut clicks = events();
+        apply_percussion_mode(
+            &mut clicks,
+            PercussionMode::Click {
+                note: 50,
+                gate_ticks: Some(4),
+            },
+            10,
+        );
+
+        assert!(matches!(
+            clicks[..],
+            [
+                AbsoluteMidiEvent {
+                    time_offset: 0,
+                    message: LimitedMidiMessage::NoteOn { note: 50, .. },
+                    ..
+                },
+                AbsoluteMidiEvent { channel: 1, .. },
+                AbsoluteMidiEvent {
+                    time_offset: 4,
+                    message: LimitedMidiMessage::NoteOff { note: 50, .. },
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn holds_clicks_closer_together_than_the_gate() {
+        let hit = |time_offset| AbsoluteMidiEvent {
+            time_offset,
+            track: 2,
+            channel: PERCUSSION_CHANNEL,
+            message: LimitedMidiMessage::NoteOn {
+                note: 38,
+                velocity: 90,
+            },
+        };
+
+        // The second hit lands 4 ticks into the first one's 10 tick gate, and the third one well
+        // after the second has been released
+        let mut events = vec![hit(0), hit(4), hit(30)];
+
+        apply_percussion_mode(
+            &mut events,
+            PercussionMode::Click {
+                note: 50,
+                gate_ticks: Some(10),
+            },
+            10,
+        );
+        events.sort_by_key(|event| event.time_offset);
+
+        assert_eq!(
+            note_events(&events),
+            [
+                (0, 50, true),
+                (4, 50, true),
+                (14, 50, false),
+                (30, 50, true),
+                (40, 50, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn folds_notes_into_window() {
+        let playable = MIN_PLAYABLE_NOTE..=MAX_PLAYABLE_NOTE;
+
+        assert_eq!(fold_note(0, playable.clone()), 12);
+        assert_eq!(fold_note(11, playable.clone()), 23);
+        assert_eq!(fold_note(60, playable.clone()), 60);
+        assert_eq!(fold_note(120, playable.clone()), 108);
+        assert_eq!(fold_note(127, playable.clone()), 115);
+
+        assert_eq!(fold_note(24, 36..=83), 36);
+        assert_eq!(fold_note(96, 36..=83), 72);
+        assert_eq!(fold_note(83, 36..=83), 83);
+
+        for window in [playable, 36..=83, 40..=51] {
+            for note in 0..=127 {
+                let folded = fold_note(note, window.clone());
+
+                assert!(window.contains(&folded));
+                assert_eq!(folded % 12, note % 12);
+            }
+        }
+    }
+
+    #[test]
+    fn counts_notes_per_channel() {
+        let note_on = |track, channel, note| AbsoluteMidiEvent {
+            time_offset: 0,
+            track,
+            channel,
+            message: LimitedMidiMessage::NoteOn {
+                note,
+                velocity: 100,
+            },
+        };
+
+        let events = [
+            note_on(1, 1, 60),
+            note_on(1, 1, 64),
+            note_on(1, 1, 64),
+            note_on(1, 1, 5),
+            note_on(1, 1, 60),
+            note_on(2, 3, 127),
+            // Note offs aren't counted
+            AbsoluteMidiEvent {
+                message: LimitedMidiMessage::NoteOn {
+                    note: 40,
+                    velocity: 0,
+                },
+                ..note_on(2, 3, 0)
+            },
+        ];
+
+        let histogram = NoteHistogram::new(&events);
+
+        let [lead, bass] = &histogram.rows[..] else {
+            panic!("expected two rows, got {:?}", histogram.rows);
+        };
+
+        assert_eq!((lead.track, lead.channel), (1, 1));
+        assert_eq!(lead.notes(), 5);
+        assert_eq!((lead.lowest(), lead.highest()), (5, 64));
+        // 60 and 64 are tied, so the lower one wins
+        assert_eq!(lead.most_common(), 60);
+        assert_eq!(lead.unplayable(), 1);
+
+        assert_eq!(bass.counts, BTreeMap::from([(127, 1)]));
+        assert_eq!(bass.unplayable(), 1);
+
+        assert_eq!(
+            histogram.to_string().lines().nth(1),
+            Some("1       1         5       F-1 (5)   E4 (64)   C4 (60)      1")
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    thread,
+    time::{Duration, Instant},
+};
+
+use floppier_proto::{LimitedMidiMessage, MidiEvent};
+
+/// A source of time for the playback scheduler
+pub trait Clock {
+    /// The time elapsed since some fixed point (only differences between calls matter)
+    fn now(&self) -> Duration;
+
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The real wall clock
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Schedules events relative to the start of playback
+///
+/// Each event is waited for by its absolute time from the start instead of the time since the last
+/// event, so the time spent sending events and any oversleeping don't add up over a song.
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    start: Duration,
+    /// When the playback clock was paused, if it is paused
+    paused_at: Option<Duration>,
+}
+
+impl<C: Clock> Scheduler<C> {
+    /// Starts the playback clock
+    pub fn new(clock: C) -> Self {
+        let start = clock.now();
+
+        Self {
+            clock,
+            start,
+            paused_at: None,
+        }
+    }
+
+    /// Stops the playback clock until `resume` is called
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.clock.now());
+        }
+    }
+
+    /// Restarts the playback clock from where it was paused
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.start += self.clock.now() - paused_at;
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Sleeps until the given time after the start of playback and returns how late we woke up
+    /// (the drift between the actual and scheduled time)
+    ///
+    /// If the target time has already passed this returns immediately.
+    pub fn wait_until(&mut self, target: Duration) -> Duration {
+        loop {
+            if let Some(drift) = self.wait_towards(target, Duration::MAX) {
+                return drift;
+            }
+        }
+    }
+
+    /// Like `wait_until`, but sleeps for at most `max_sleep` so the caller can do other work (like
+    /// checking for input) while waiting
+    ///
+    /// Returns the drift once the target time has been reached, or `None` if it hasn't been yet.
+    pub fn wait_towards(&mut self, target: Duration, max_sleep: Duration) -> Option<Duration> {
+        if self.is_paused() {
+            self.clock.sleep(max_sleep.min(Duration::from_millis(10)));
+            return None;
+        }
+
+        let elapsed = self.elapsed();
+
+        if let Some(remaining) = target.checked_sub(elapsed) {
+            if remaining > max_sleep {
+                self.clock.sleep(max_sleep);
+                return None;
+            }
+
+            self.clock.sleep(remaining);
+        }
+
+        Some(self.elapsed().saturating_sub(target))
+    }
+
+    /// The time since the start of playback, not counting any time spent paused
+    pub fn elapsed(&self) -> Duration {
+        self.paused_at.unwrap_or_else(|| self.clock.now()) - self.start
+    }
+}
+
+/// Control change numbers from this one onwards are channel mode messages (all notes off, etc.)
+///
+/// https://www.midi.org/specifications-old/item/table-3-control-change-messages-data-bytes-2
+const FIRST_CHANNEL_MODE_CONTROL: u8 = 120;
+
+const CONTROL_ALL_SOUND_OFF: u8 = 120;
+const CONTROL_ALL_NOTES_OFF: u8 = 123;
+
+/// The notes, programs and controls the client has been sent so far, used to bring a reconnected client
+/// back to the same point in the song
+#[derive(Debug, Default)]
+pub struct ResumeState {
+    /// The velocity of each held note, keyed by (track, channel, note)
+    held_notes: BTreeMap<(u16, u8, u8), u8>,
+    /// The current program of each (track, channel)
+    programs: BTreeMap<(u16, u8), u8>,
+    /// The last value of each controller, keyed by (track, channel, control)
+    controls: BTreeMap<(u16, u8, u8), u8>,
+}
+
+impl ResumeState {
+    /// Updates the state with an event that has been sent to the client
+    pub fn apply(&mut self, event: &MidiEvent) {
+        let MidiEvent {
+            track,
+            channel,
+            message,
+        } = *event;
+
+        match message {
+            LimitedMidiMessage::NoteOn { note, velocity } if velocity > 0 => {
+                self.held_notes.insert((track, channel, note), velocity);
+            }
+            LimitedMidiMessage::NoteOn { note, .. } | LimitedMidiMessage::NoteOff { note, .. } => {
+                self.held_notes.remove(&(track, channel, note));
+            }
+            LimitedMidiMessage::ProgramChange { program } => {
+                self.programs.insert((track, channel), program);
+            }
+            LimitedMidiMessage::ControlChange {
+                control: CONTROL_ALL_SOUND_OFF | CONTROL_ALL_NOTES_OFF,
+                ..
+            } => {
+                self.held_notes.retain(|&(note_track, note_channel, _), _| {
+                    (note_track, note_channel) != (track, channel)
+                });
+            }
+            // Other channel mode messages (omni and poly mode, etc.) are one-off actions rather than
+            // state
+            LimitedMidiMessage::ControlChange { control, value }
+                if control < FIRST_CHANNEL_MODE_CONTROL =>
+            {
+                self.controls.insert((track, channel, control), value);
+            }
+            _ => {}
+        }
+    }
+
+    /// The notes being held, as (track, channel, note)
+    pub fn held_notes(&self) -> impl Iterator<Item = (u16, u8, u8)> + '_ {
+        self.held_notes.keys().copied()
+    }
+
+    /// The events that put a freshly configured client into this state (the programs and controls
+    /// first so the notes are played with them)
+    pub fn replay_events(&self) -> Vec<MidiEvent> {
+        let programs = self
+            .programs
+            .iter()
+            .map(|(&(track, channel), &program)| MidiEvent {
+                track,
+                channel,
+                message: LimitedMidiMessage::ProgramChange { program },
+            });
+
+        let controls = self
+            .controls
+            .iter()
+            .map(|(&(track, channel, control), &value)| MidiEvent {
+                track,
+                channel,
+                message: LimitedMidiMessage::ControlChange { control, value },
+            });
+
+        let notes = self
+            .held_notes
+            .iter()
+            .map(|(&(track, channel, note), &velocity)| MidiEvent {
+                track,
+                channel,
+                message: LimitedMidiMessage::NoteOn { note, velocity },
+            });
+
+        programs.chain(controls).chain(notes).collect()
+    }
+}
+
+/// How far playback has got, shown on a single line that's redrawn as the song plays
+#[derive(Debug, Default, Clone)]
+pub struct Progress {
+    /// The time into the song
+    pub elapsed: Duration,
+    /// The length of the song
+    pub total: Duration,
+    /// The tick of the last events sent
+    pub tick: u32,
+    pub events_sent: usize,
+    /// The note each drive is playing, if any (only an estimate, since the client decides which
+    /// of a channel's drives plays each note)
+    pub drive_notes: Vec<Option<u8>>,
+    pub paused: bool,
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes_seconds = |time: Duration| (time.as_secs() / 60, time.as_secs() % 60);
+
+        let (elapsed_minutes, elapsed_seconds) = minutes_seconds(self.elapsed);
+        let (total_minutes, total_seconds) = minutes_seconds(self.total);
+
+        write!(
+            f,
+            "{} {}:{:02} / {}:{:02} | tick {} | {} events | drives",
+            if self.paused { "Paused " } else { "Playing" },
+            elapsed_minutes,
+            elapsed_seconds,
+            total_minutes,
+            total_seconds,
+            self.tick,
+            self.events_sent
+        )?;
+
+        for note in &self.drive_notes {
+            match note {
+                Some(note) => write!(f, " {:<3}", note_name(*note))?,
+                None => write!(f, " -- ")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The scientific pitch name of a MIDI note (60 is C4)
+pub fn note_name(note: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+
+    format!("{}{}", NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock that only moves when slept on, always oversleeping by a fixed amount
+    struct MockClock {
+        now: Duration,
+        overshoot: Duration,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration + self.overshoot;
+        }
+    }
+
+    #[test]
+    fn oversleeping_does_not_accumulate() {
+        let mut scheduler = Scheduler::new(MockClock {
+            now: Duration::from_secs(5),
+            overshoot: Duration::from_millis(2),
+        });
+
+        for i in 1..=100 {
+            let drift = scheduler.wait_until(Duration::from_millis(i * 10));
+
+            assert_eq!(drift, Duration::from_millis(2));
+        }
+
+        assert_eq!(scheduler.elapsed(), Duration::from_millis(1_002));
+    }
+
+    #[test]
+    fn paused_time_is_not_counted() {
+        let mut scheduler = Scheduler::new(MockClock {
+            now: Duration::ZERO,
+            overshoot: Duration::ZERO,
+        });
+
+        scheduler.wait_until(Duration::from_millis(100));
+        scheduler.pause();
+
+        let max_sleep = Duration::from_millis(10);
+
+        for _ in 0..50 {
+            assert_eq!(
+                scheduler.wait_towards(Duration::from_millis(200), max_sleep),
+                None
+            );
+        }
+
+        assert_eq!(scheduler.elapsed(), Duration::from_millis(100));
+
+        scheduler.resume();
+
+        assert_eq!(
+            scheduler.wait_until(Duration::from_millis(200)),
+            Duration::ZERO
+        );
+        assert_eq!(scheduler.clock.now, Duration::from_millis(700));
+    }
+
+    #[test]
+    fn late_events_are_sent_immediately() {
+        let mut scheduler = Scheduler::new(MockClock {
+            now: Duration::ZERO,
+            overshoot: Duration::ZERO,
+        });
+
+        // Simulate a slow send
+        scheduler.clock.now = Duration::from_millis(50);
+
+        let drift = scheduler.wait_until(Duration::from_millis(20));
+
+        assert_eq!(drift, Duration::from_millis(30));
+        assert_eq!(scheduler.elapsed(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn replays_held_notes_and_programs() {
+        let event = |message| MidiEvent {
+            track: 1,
+            channel: 2,
+            message,
+        };
+
+        let mut state = ResumeState::default();
+
+        state.apply(&event(LimitedMidiMessage::ProgramChange { program: 30 }));
+        state.apply(&event(LimitedMidiMessage::ControlChange {
+            control: 7,
+            value: 50,
+        }));
+        state.apply(&event(LimitedMidiMessage::ControlChange {
+            control: 7,
+            value: 80,
+        }));
+        // All notes off isn't replayed
+        state.apply(&event(LimitedMidiMessage::ControlChange {
+            control: 123,
+            value: 0,
+        }));
+        state.apply(&event(LimitedMidiMessage::NoteOn {
+            note: 60,
+            velocity: 100,
+        }));
+        state.apply(&event(LimitedMidiMessage::NoteOn {
+            note: 64,
+            velocity: 90,
+        }));
+        state.apply(&event(LimitedMidiMessage::NoteOff {
+            note: 60,
+            velocity: 0,
+        }));
+        state.apply(&event(LimitedMidiMessage::NoteOn {
+            note: 67,
+            velocity: 80,
+        }));
+        // A note on with no velocity is a note off
+        state.apply(&event(LimitedMidiMessage::NoteOn {
+            note: 67,
+            velocity: 0,
+        }));
+
+        let replayed = state
+            .replay_events()
+            .into_iter()
+            .map(|event| event.message)
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            replayed[..],
+            [
+                LimitedMidiMessage::ProgramChange { program: 30 },
+                LimitedMidiMessage::ControlChange {
+                    control: 7,
+                    value: 80
+                },
+                LimitedMidiMessage::NoteOn {
+                    note: 64,
+                    velocity: 90
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn all_notes_off_releases_the_channel() {
+        let note_on = |channel, note| MidiEvent {
+            track: 1,
+            channel,
+            message: LimitedMidiMessage::NoteOn {
+                note,
+                velocity: 100,
+            },
+        };
+
+        for control in [120, 123] {
+            let mut state = ResumeState::default();
+
+            state.apply(&note_on(1, 60));
+            state.apply(&note_on(1, 64));
+            state.apply(&note_on(2, 67));
+            state.apply(&MidiEvent {
+                track: 1,
+                channel: 1,
+                message: LimitedMidiMessage::ControlChange { control, value: 0 },
+            });
+
+            assert_eq!(state.held_notes().collect::<Vec<_>>(), [(1, 2, 67)]);
+        }
+    }
+
+    #[test]
+    fn formats_progress() {
+        let progress = Progress {
+            elapsed: Duration::from_secs(83),
+            total: Duration::from_secs(225),
+            tick: 1_920,
+            events_sent: 42,
+            drive_notes: vec![Some(60), None, Some(61), Some(21)],
+            paused: false,
+        };
+
+        assert_eq!(
+            progress.to_string(),
+            "Playing 1:23 / 3:45 | tick 1920 | 42 events | drives C4  --  C#4 A0 "
+        );
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(127), "G9");
+    }
+}
@@ -0,0 +1,922 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use floppier_proto::{
+    LimitedMidiMessage, MidiEvent, ParallelMode, PortKind, SetConfig, MAX_PLAYABLE_NOTE,
+    MIN_PLAYABLE_NOTE,
+};
+
+/// The sample rate of the rendered audio
+pub const SAMPLE_RATE: u32 = 44_100;
+
+/// How often the client ticks its drives if the config doesn't give a tick interval (same as the
+/// client)
+const DEFAULT_TIMER_RESOLUTION_US: u64 = 20;
+
+/// How long each note of a chord is played for in `ParallelMode::Synthesize` if the config doesn't
+/// specify an interval (same as the client)
+const DEFAULT_SYNTHESIZE_INTERVAL_US: u32 = 25_000;
+
+/// The maximum number of held notes remembered per drive (same as the client)
+const NOTE_STACK_DEPTH: usize = 8;
+
+/// General MIDI programs from this one onwards are percussive, so the client doesn't play them
+const FIRST_PERCUSSIVE_PROGRAM: u8 = 112;
+
+const CONTROL_VOLUME: u8 = 7;
+const CONTROL_SUSTAIN: u8 = 64;
+const CONTROL_ALL_SOUND_OFF: u8 = 120;
+const CONTROL_ALL_NOTES_OFF: u8 = 123;
+
+/// The volume a channel starts with (the General MIDI default)
+const DEFAULT_VOLUME: u8 = 100;
+
+/// Channels with a volume below this are silenced (same as the client)
+const MIN_AUDIBLE_VOLUME: u8 = 8;
+
+/// Control values from this one onwards turn a pedal on
+const PEDAL_ON_VALUE: u8 = 64;
+
+/// The number of ticks over which the drive select duty cycle repeats (same as the client)
+const DUTY_WINDOW_TICKS: u32 = 50;
+
+/// The number of semitones a full pitch bend moves a note by (same as the client)
+const PITCH_BEND_RANGE_SEMITONES: f32 = 2.0;
+
+/// How long a note dies away for after a note off with the highest release velocity (same as the
+/// client)
+const MAX_RELEASE_US: u64 = 50_000;
+
+/// Each half period of a note dying away is longer than the last by this power of two of it (same
+/// as the client)
+const RELEASE_SLOWDOWN_SHIFT: u32 = 3;
+
+/// How long the click of a single head step lasts
+const CLICK_SAMPLES: u32 = 44;
+
+/// How long to keep rendering after the last event so the final notes ring out
+const TAIL: Duration = Duration::from_secs(1);
+
+/// A model of the client's drive stack, for hearing roughly what a song configuration sounds like
+/// without the hardware
+///
+/// This duplicates the note handling of `floppier-client` (the note stacks, parallel modes and step
+/// timing of `FloppyDrive`), so any change to how the client plays notes needs making here too.
+///
+/// PWM voices and steppers are modelled as drives that click once a period, which is a rough
+/// stand-in for either.
+pub struct Simulator {
+    tracks: BTreeMap<u16, BTreeMap<u8, Vec<u8>>>,
+    parallel_mode: ParallelMode,
+    steal_oldest: bool,
+    velocity_threshold: u8,
+    velocity_dynamics: bool,
+    release_ramp: bool,
+    min_note_ticks: u32,
+    drives: Vec<SimulatedDrive>,
+    channels: BTreeMap<(u16, u8), SimulatedChannel>,
+    synthesize_interval_ticks: u32,
+    synthesize_tick: u32,
+    timer_resolution_us: u64,
+}
+
+/// The state of a (track, channel) pair, like the client's `ChannelState`
+#[derive(Debug)]
+struct SimulatedChannel {
+    program: u8,
+    volume: u8,
+    sustain: bool,
+    /// The notes released while the sustain pedal was held
+    sustained: Vec<u8>,
+    /// Index into the mapped drives to look for an idle drive from when using
+    /// `ParallelMode::Distribute`
+    next_drive: usize,
+    /// The value of `note_ons` when each mapped drive last started a note, by drive
+    started_at: BTreeMap<usize, u32>,
+    /// Incremented on every distributed note on, used to find the oldest sounding note
+    note_ons: u32,
+}
+
+impl Default for SimulatedChannel {
+    fn default() -> Self {
+        Self {
+            program: 0,
+            volume: DEFAULT_VOLUME,
+            sustain: false,
+            sustained: Vec::new(),
+            next_drive: 0,
+            started_at: BTreeMap::new(),
+            note_ons: 0,
+        }
+    }
+}
+
+/// What plays a port, which changes how it handles notes like the client's instruments
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum SimulatedInstrument {
+    #[default]
+    FloppyDrive,
+    Stepper,
+    /// A PWM voice, which plays every note rather than just the ones drives can
+    Pwm,
+}
+
+#[derive(Debug, Default)]
+struct SimulatedDrive {
+    /// The held notes, with the most recent last
+    stack: Vec<u8>,
+    note: Option<u8>,
+    pitch_bend: f32,
+    /// Cents the port is tuned by, on top of the pitch bend
+    detune_cents: i16,
+    /// Half the period of the note, in fractions of a tick (`TICK_FRACTIONS` to a tick) like the
+    /// client
+    half_ticks: u32,
+    note_tick: u32,
+    period_tick: u32,
+    step: bool,
+    /// How many ticks of each duty window the drive is selected for (all of them if not set)
+    select_ticks: Option<u32>,
+    instrument: SimulatedInstrument,
+    timer_resolution_us: u64,
+    /// How many ticks are left before the note dies away, if it has been released
+    release_ticks: u32,
+    /// How much longer each half period is than the note's while it dies away
+    release_extra_ticks: u32,
+}
+
+/// A drive (or other instrument) for each port of a config
+fn simulated_ports(config: &SetConfig, timer_resolution_us: u64) -> Vec<SimulatedDrive> {
+    let drive = |instrument| SimulatedDrive {
+        instrument,
+        timer_resolution_us,
+        ..Default::default()
+    };
+
+    let mut drives: Vec<SimulatedDrive> = if config.ports.is_empty() {
+        (0..config.drive_count)
+            .map(|_| drive(SimulatedInstrument::FloppyDrive))
+            .collect()
+    } else {
+        config
+            .ports
+            .iter()
+            .map(|port| {
+                drive(match port {
+                    PortKind::Drive(_) => SimulatedInstrument::FloppyDrive,
+                    PortKind::Stepper { .. } => SimulatedInstrument::Stepper,
+                    PortKind::Pwm { .. } => SimulatedInstrument::Pwm,
+                })
+            })
+            .collect()
+    };
+
+    for (drive, cents) in drives.iter_mut().zip(&config.detune_cents) {
+        drive.detune_cents = *cents;
+    }
+
+    drives
+}
+
+impl Simulator {
+    pub fn new(config: &SetConfig) -> Self {
+        let timer_resolution_us = config
+            .tick_us
+            .map_or(DEFAULT_TIMER_RESOLUTION_US, |tick_us| tick_us as u64);
+
+        let synthesize_interval_ticks = config
+            .synthesize_interval_us
+            .unwrap_or(DEFAULT_SYNTHESIZE_INTERVAL_US)
+            / timer_resolution_us as u32;
+
+        Self {
+            tracks: config.tracks.clone(),
+            parallel_mode: config.parallel_mode,
+            steal_oldest: config.steal_oldest,
+            velocity_threshold: config.velocity_threshold,
+            velocity_dynamics: config.velocity_dynamics,
+            release_ramp: config.release_ramp,
+            min_note_ticks: config.min_note_us / timer_resolution_us as u32,
+            drives: simulated_ports(config, timer_resolution_us),
+            channels: BTreeMap::new(),
+            synthesize_interval_ticks: synthesize_interval_ticks.max(1),
+            synthesize_tick: 0,
+            timer_resolution_us,
+        }
+    }
+
+    /// How often `tick` needs calling in µs, like the client's timer
+    pub fn timer_resolution_us(&self) -> u64 {
+        self.timer_resolution_us
+    }
+
+    pub fn drive_count(&self) -> usize {
+        self.drives.len()
+    }
+
+    /// The note each drive is playing
+    pub fn notes(&self) -> Vec<Option<u8>> {
+        self.drives.iter().map(|drive| drive.note).collect()
+    }
+
+    /// Plays an event the same way the client would
+    pub fn apply(&mut self, event: &MidiEvent) {
+        let MidiEvent {
+            track,
+            channel,
+            message,
+        } = *event;
+
+        let Some(drives) = self
+            .tracks
+            .get(&track)
+            .and_then(|channels| channels.get(&channel))
+        else {
+            return;
+        };
+
+        let drives = drives
+            .iter()
+            .map(|drive| *drive as usize)
+            .collect::<Vec<_>>();
+
+        let channel = self.channels.entry((track, channel)).or_default();
+
+        match message {
+            LimitedMidiMessage::NoteOn { note, velocity } if velocity > 0 => {
+                if channel.program >= FIRST_PERCUSSIVE_PROGRAM
+                    || channel.volume < MIN_AUDIBLE_VOLUME
+                    || velocity < self.velocity_threshold
+                {
+                    return;
+                }
+
+                channel.sustained.retain(|sustained| *sustained != note);
+
+                let playing_drives = match self.parallel_mode {
+                    ParallelMode::Distribute => {
+                        allocate_drive(channel, &mut self.drives, &drives, note, self.steal_oldest)
+                            .into_iter()
+                            .collect()
+                    }
+                    ParallelMode::Collapse | ParallelMode::Synthesize => drives,
+                };
+
+                for i in playing_drives {
+                    if !self.drives[i].push(note, self.min_note_ticks) {
+                        continue;
+                    }
+
+                    if self.velocity_dynamics {
+                        let select_ticks =
+                            (DUTY_WINDOW_TICKS * velocity.min(127) as u32).div_ceil(127);
+                        self.drives[i].select_ticks = Some(select_ticks.max(1));
+                    }
+                }
+            }
+            LimitedMidiMessage::NoteOn { note, .. } | LimitedMidiMessage::NoteOff { note, .. } => {
+                if channel.sustain {
+                    channel.sustained.push(note);
+                    return;
+                }
+
+                let release_velocity = match message {
+                    LimitedMidiMessage::NoteOff { velocity, .. } if self.release_ramp => velocity,
+                    _ => 0,
+                };
+
+                for i in drives {
+                    self.drives[i].release(note, release_velocity);
+                }
+            }
+            LimitedMidiMessage::ProgramChange { program } => channel.program = program,
+            LimitedMidiMessage::ControlChange {
+                control: CONTROL_SUSTAIN,
+                value,
+            } => {
+                channel.sustain = value >= PEDAL_ON_VALUE;
+
+                if !channel.sustain {
+                    for note in channel.sustained.drain(..) {
+                        for i in &drives {
+                            self.drives[*i].release(note, 0);
+                        }
+                    }
+                }
+            }
+            LimitedMidiMessage::ControlChange {
+                control: control @ (CONTROL_VOLUME | CONTROL_ALL_SOUND_OFF | CONTROL_ALL_NOTES_OFF),
+                value,
+            } => {
+                if control == CONTROL_VOLUME {
+                    channel.volume = value;
+
+                    if value >= MIN_AUDIBLE_VOLUME {
+                        return;
+                    }
+                }
+
+                channel.next_drive = 0;
+                channel.sustained.clear();
+
+                for i in drives {
+                    self.drives[i].stack.clear();
+                    self.drives[i].set_note(None);
+                }
+            }
+            LimitedMidiMessage::ControlChange { .. } => {}
+            LimitedMidiMessage::PitchBend { value } => {
+                let semitones = value as f32 / 8192.0 * PITCH_BEND_RANGE_SEMITONES;
+
+                for i in drives {
+                    self.drives[i].pitch_bend = semitones;
+                    self.drives[i].update_half_ticks();
+                }
+            }
+        }
+    }
+
+    /// Advances the drives by one timer tick and returns the drives whose heads stepped
+    pub fn tick(&mut self) -> Vec<usize> {
+        if self.parallel_mode == ParallelMode::Synthesize {
+            self.cycle_chords();
+        }
+
+        self.drives
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, drive)| drive.tick().then_some(i))
+            .collect()
+    }
+
+    fn cycle_chords(&mut self) {
+        self.synthesize_tick += 1;
+
+        if self.synthesize_tick < self.synthesize_interval_ticks {
+            return;
+        }
+
+        self.synthesize_tick = 0;
+
+        for drive in &mut self.drives {
+            if drive.stack.len() > 1 {
+                let next_index = drive
+                    .note
+                    .and_then(|note| drive.stack.iter().position(|held| *held == note))
+                    .map_or(0, |i| (i + 1) % drive.stack.len());
+
+                drive.set_note(Some(drive.stack[next_index]));
+            }
+        }
+    }
+}
+
+/// Picks the drive for a new note like the client's `DriveAllocator`
+fn allocate_drive(
+    channel: &mut SimulatedChannel,
+    simulated_drives: &mut [SimulatedDrive],
+    drives: &[usize],
+    note: u8,
+    steal_oldest: bool,
+) -> Option<usize> {
+    if drives.is_empty() {
+        return None;
+    }
+
+    channel.note_ons = channel.note_ons.wrapping_add(1);
+
+    let count = drives.len();
+
+    let index = match drives
+        .iter()
+        .position(|drive| simulated_drives[*drive].stack.contains(&note))
+    {
+        Some(index) => index,
+        None => {
+            let idle = (0..count)
+                .map(|offset| (channel.next_drive + offset) % count)
+                .find(|i| simulated_drives[drives[*i]].stack.is_empty());
+
+            let index = match idle {
+                Some(index) => index,
+                None if steal_oldest => {
+                    let oldest = (0..count)
+                        .max_by_key(|i| {
+                            let started_at = channel.started_at.get(&drives[*i]).copied();
+                            channel.note_ons.wrapping_sub(started_at.unwrap_or(0))
+                        })
+                        .unwrap();
+
+                    simulated_drives[drives[oldest]].stack.clear();
+                    oldest
+                }
+                None => channel.next_drive % count,
+            };
+
+            channel.next_drive = (index + 1) % count;
+            index
+        }
+    };
+
+    channel.started_at.insert(drives[index], channel.note_ons);
+
+    Some(drives[index])
+}
+
+impl SimulatedDrive {
+    /// Holds a note, returning whether the drive switched to it (a drive that only just changed
+    /// note holds off for `min_note_ticks` like on the client, while other instruments never do)
+    fn push(&mut self, note: u8, min_note_ticks: u32) -> bool {
+        self.stack.retain(|held| *held != note);
+
+        if self.stack.len() == NOTE_STACK_DEPTH {
+            self.stack.remove(0);
+        }
+
+        self.stack.push(note);
+
+        let can_retrigger = self.instrument != SimulatedInstrument::FloppyDrive
+            || self.note.is_none()
+            || self.release_ticks > 0
+            || self.note_tick >= min_note_ticks;
+
+        if can_retrigger {
+            self.set_note(Some(note));
+        }
+
+        can_retrigger
+    }
+
+    fn release(&mut self, note: u8, release_velocity: u8) {
+        if !self.stack.contains(&note) {
+            return;
+        }
+
+        self.stack.retain(|held| *held != note);
+
+        // Only retune the drive if the sounding note was released
+        if self.note.is_some_and(|note| self.stack.contains(&note)) {
+            return;
+        }
+
+        let release_ticks =
+            MAX_RELEASE_US * release_velocity.min(127) as u64 / 127 / self.timer_resolution_us;
+
+        match self.stack.last() {
+            Some(top) => self.set_note(Some(*top)),
+            // Only drives die away, everything else is cut off like on the client
+            None if self.instrument != SimulatedInstrument::FloppyDrive || release_ticks == 0 => {
+                self.set_note(None)
+            }
+            None => self.release_ticks = release_ticks as u32,
+        }
+    }
+
+    fn set_note(&mut self, note: Option<u8>) {
+        self.note = note.filter(|note| {
+            self.instrument == SimulatedInstrument::Pwm
+                || (MIN_PLAYABLE_NOTE..=MAX_PLAYABLE_NOTE).contains(note)
+        });
+
+        if self.note.is_none() {
+            self.pitch_bend = 0.0;
+        }
+
+        self.update_half_ticks();
+        self.note_tick = 0;
+        self.period_tick = 0;
+        self.step = true;
+        self.release_ticks = 0;
+        self.release_extra_ticks = 0;
+    }
+
+    fn update_half_ticks(&mut self) {
+        let Some(note) = self.note else {
+            self.half_ticks = 0;
+            return;
+        };
+
+        let half_ticks = half_ticks(note, self.timer_resolution_us);
+        let semitones = self.pitch_bend + self.detune_cents as f32 / 100.0;
+
+        let bent_half_ticks = if semitones == 0.0 {
+            half_ticks
+        } else {
+            (half_ticks as f32 / 2f32.powf(semitones / 12.0)).round() as u32
+        };
+
+        self.half_ticks = bent_half_ticks.max(TICK_FRACTIONS);
+    }
+
+    /// Returns whether the head stepped (once per period of the note)
+    fn tick(&mut self) -> bool {
+        if self.release_ticks > 0 {
+            self.release_ticks -= 1;
+
+            if self.release_ticks == 0 {
+                self.set_note(None);
+            }
+        }
+
+        if self.note.is_none() {
+            return false;
+        }
+
+        self.note_tick += 1;
+
+        // The drive is only selected from the second tick of a note
+        if self.note_tick <= 1 {
+            return false;
+        }
+
+        let selected = self
+            .select_ticks
+            .is_none_or(|select_ticks| self.note_tick % DUTY_WINDOW_TICKS < select_ticks);
+
+        self.period_tick += TICK_FRACTIONS;
+
+        let half_ticks = self.half_ticks + self.release_extra_ticks;
+
+        if self.period_tick < half_ticks {
+            return false;
+        }
+
+        // The fraction of a tick the step was late by carries over, like on the client
+        self.period_tick -= half_ticks;
+
+        if self.release_ticks > 0 {
+            self.release_extra_ticks += half_ticks >> RELEASE_SLOWDOWN_SHIFT;
+        }
+
+        // Steps are skipped while the drive isn't selected
+        if !selected {
+            return false;
+        }
+
+        self.step = !self.step;
+
+        !self.step
+    }
+}
+
+/// How many parts the client splits each timer tick into when timing the steps
+const TICK_FRACTIONS: u32 = 1 << 16;
+
+/// Half the period of a note in fractions of a timer tick, like the client works out from the
+/// frequency of the note
+fn half_ticks(note: u8, timer_resolution_us: u64) -> u32 {
+    let frequency = 440.0 * 2f64.powf((note as f64 - 69.0) / 12.0);
+    let half_period_us = 500_000.0 / frequency;
+
+    (half_period_us * TICK_FRACTIONS as f64 / timer_resolution_us as f64).round() as u32
+}
+
+/// Plays the events (each with the time after the start it is sent at) through a `Simulator` and
+/// writes the clicks of the drive heads to a WAV file
+pub fn render_wav(config: &SetConfig, events: &[(Duration, MidiEvent)], path: &Path) -> Result<()> {
+    let mut simulator = Simulator::new(config);
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut writer = hound::WavWriter::create(path, spec)
+        .with_context(|| format!("could not create `{}`", path.display()))?;
+
+    let end = events.last().map_or(Duration::ZERO, |(time, _)| *time) + TAIL;
+    let sample_count = (end.as_secs_f64() * SAMPLE_RATE as f64) as u64;
+
+    // Leave some headroom so every drive clicking at once doesn't clip
+    let amplitude = (i16::MAX as f32 * 0.8 / simulator.drive_count().max(1) as f32) as i16;
+
+    let mut events = events.iter().peekable();
+    let mut clicks = vec![0; simulator.drive_count()];
+    let mut tick = 0;
+
+    let timer_resolution_us = simulator.timer_resolution_us();
+
+    for sample in 0..sample_count {
+        // Catch the drives up with the time of this sample
+        let sample_tick = sample * 1_000_000 / (SAMPLE_RATE as u64 * timer_resolution_us);
+
+        while tick < sample_tick {
+            let now = Duration::from_micros(tick * timer_resolution_us);
+
+            while let Some((_, event)) = events.next_if(|(time, _)| *time <= now) {
+                simulator.apply(event);
+            }
+
+            for drive in simulator.tick() {
+                clicks[drive] = CLICK_SAMPLES;
+            }
+
+            tick += 1;
+        }
+
+        // Each click is a short square pulse, up then down
+        let mut value = 0i32;
+
+        for remaining in &mut clicks {
+            if *remaining == 0 {
+                continue;
+            }
+
+            value += if *remaining > CLICK_SAMPLES / 2 {
+                amplitude as i32
+            } else {
+                -(amplitude as i32)
+            };
+
+            *remaining -= 1;
+        }
+
+        writer.write_sample(value.clamp(i16::MIN as i32, i16::MAX as i32) as i16)?;
+    }
+
+    writer.finalize()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use floppier_proto::ResetTiming;
+
+    use super::*;
+
+    fn config(parallel_mode: ParallelMode, drive_count: u8) -> SetConfig {
+        SetConfig {
+            parallel_mode,
+            steal_oldest: false,
+            movement: true,
+            drive_movement: Vec::new(),
+            drive_count,
+            ports: Vec::new(),
+            detune_cents: Vec::new(),
+            tracks: BTreeMap::from([(1, BTreeMap::from([(1, (0..drive_count).collect())]))]),
+            synthesize_interval_us: None,
+            velocity_threshold: 0,
+            velocity_dynamics: false,
+            release_ramp: false,
+            min_note_us: 0,
+            link_timeout_ms: None,
+            reset: ResetTiming::default(),
+            park_on_end: false,
+            home_track: None,
+            tick_us: None,
+        }
+    }
+
+    fn note_on(note: u8) -> MidiEvent {
+        MidiEvent {
+            track: 1,
+            channel: 1,
+            message: LimitedMidiMessage::NoteOn {
+                note,
+                velocity: 100,
+            },
+        }
+    }
+
+    #[test]
+    fn matches_client_note_periods() {
+        // The same whole ticks the client's note period table gives, plus the fraction left over
+        assert_eq!(
+            half_ticks(69, DEFAULT_TIMER_RESOLUTION_US) / TICK_FRACTIONS,
+            56
+        );
+        assert_eq!(
+            half_ticks(60, DEFAULT_TIMER_RESOLUTION_US) / TICK_FRACTIONS,
+            95
+        );
+        assert_eq!(
+            half_ticks(12, DEFAULT_TIMER_RESOLUTION_US) / TICK_FRACTIONS,
+            61156 / 40
+        );
+    }
+
+    #[test]
+    fn steps_once_per_period() {
+        // The half periods alternate between 56 and 57 ticks to play A4 at 440Hz on average (or
+        // between 113 and 114 at a finer tick interval)
+        for tick_us in [None, Some(10)] {
+            let mut simulator = Simulator::new(&SetConfig {
+                tick_us,
+                ..config(ParallelMode::Collapse, 1)
+            });
+
+            simulator.apply(&note_on(69));
+
+            let ticks_per_second = 1_000_000 / simulator.timer_resolution_us();
+            let steps = (0..ticks_per_second)
+                .filter(|_| !simulator.tick().is_empty())
+                .count();
+
+            assert_eq!(steps, 440);
+        }
+    }
+
+    #[test]
+    fn respects_parallel_mode() {
+        let mut distribute = Simulator::new(&config(ParallelMode::Distribute, 2));
+        let mut collapse = Simulator::new(&config(ParallelMode::Collapse, 2));
+
+        for simulator in [&mut distribute, &mut collapse] {
+            simulator.apply(&note_on(60));
+            simulator.apply(&note_on(64));
+        }
+
+        assert_eq!(distribute.notes(), [Some(60), Some(64)]);
+        assert_eq!(collapse.notes(), [Some(64), Some(64)]);
+    }
+
+    #[test]
+    fn steals_the_oldest_note_only_when_asked() {
+        let mut stack = Simulator::new(&config(ParallelMode::Distribute, 2));
+        let mut steal = Simulator::new(&SetConfig {
+            steal_oldest: true,
+            ..config(ParallelMode::Distribute, 2)
+        });
+
+        for simulator in [&mut stack, &mut steal] {
+            simulator.apply(&note_on(60));
+            simulator.apply(&note_on(64));
+            simulator.apply(&note_on(67));
+            simulator.apply(&MidiEvent {
+                track: 1,
+                channel: 1,
+                message: LimitedMidiMessage::NoteOff {
+                    note: 67,
+                    velocity: 0,
+                },
+            });
+        }
+
+        // The stacked note falls back to the one underneath, the stolen one is gone for good
+        assert_eq!(stack.notes(), [Some(60), Some(64)]);
+        assert_eq!(steal.notes(), [None, Some(64)]);
+    }
+
+    #[test]
+    fn sustain_holds_notes() {
+        let mut simulator = Simulator::new(&config(ParallelMode::Collapse, 1));
+
+        let event = |message| MidiEvent {
+            track: 1,
+            channel: 1,
+            message,
+        };
+
+        simulator.apply(&event(LimitedMidiMessage::ControlChange {
+            control: CONTROL_SUSTAIN,
+            value: 127,
+        }));
+        simulator.apply(&note_on(60));
+        simulator.apply(&event(LimitedMidiMessage::NoteOff {
+            note: 60,
+            velocity: 0,
+        }));
+
+        assert_eq!(simulator.notes(), [Some(60)]);
+
+        simulator.apply(&event(LimitedMidiMessage::ControlChange {
+            control: CONTROL_SUSTAIN,
+            value: 0,
+        }));
+
+        assert_eq!(simulator.notes(), [None]);
+    }
+
+    #[test]
+    fn released_notes_die_away() {
+        let mut simulator = Simulator::new(&SetConfig {
+            release_ramp: true,
+            ..config(ParallelMode::Collapse, 1)
+        });
+
+        let note_off = |velocity| MidiEvent {
+            track: 1,
+            channel: 1,
+            message: LimitedMidiMessage::NoteOff { note: 69, velocity },
+        };
+
+        simulator.apply(&note_on(69));
+        simulator.apply(&note_off(127));
+
+        // Still stepping, until the release runs out
+        assert_eq!(simulator.notes(), [Some(69)]);
+
+        let release_ticks = MAX_RELEASE_US / simulator.timer_resolution_us();
+        let steps = (0..release_ticks)
+            .filter(|_| !simulator.tick().is_empty())
+            .count();
+
+        assert!(steps > 0);
+        assert!(steps < 22);
+        assert_eq!(simulator.notes(), [None]);
+
+        simulator.apply(&note_on(69));
+        simulator.apply(&note_off(0));
+
+        assert_eq!(simulator.notes(), [None]);
+    }
+
+    #[test]
+    fn holds_off_retriggering_a_drive() {
+        let mut simulator = Simulator::new(&SetConfig {
+            min_note_us: 10_000,
+            ..config(ParallelMode::Collapse, 1)
+        });
+
+        simulator.apply(&note_on(60));
+        simulator.apply(&note_on(62));
+
+        assert_eq!(simulator.notes(), [Some(60)]);
+
+        for _ in 0..10_000 / simulator.timer_resolution_us() {
+            simulator.tick();
+        }
+
+        simulator.apply(&note_on(64));
+
+        assert_eq!(simulator.notes(), [Some(64)]);
+
+        // The held back note is played once the notes after it are released
+        simulator.apply(&MidiEvent {
+            track: 1,
+            channel: 1,
+            message: LimitedMidiMessage::NoteOff {
+                note: 64,
+                velocity: 0,
+            },
+        });
+
+        assert_eq!(simulator.notes(), [Some(62)]);
+    }
+
+    #[test]
+    fn retriggers_and_cuts_off_a_stepper_straight_away() {
+        let mut simulator = Simulator::new(&SetConfig {
+            min_note_us: 10_000,
+            release_ramp: true,
+            ports: vec![PortKind::Stepper {
+                index: 0,
+                steps_per_toggle: 1,
+            }],
+            ..config(ParallelMode::Collapse, 1)
+        });
+
+        simulator.apply(&note_on(60));
+        simulator.apply(&note_on(62));
+
+        assert_eq!(simulator.notes(), [Some(62)]);
+
+        for note in [60, 62] {
+            simulator.apply(&MidiEvent {
+                track: 1,
+                channel: 1,
+                message: LimitedMidiMessage::NoteOff {
+                    note,
+                    velocity: 127,
+                },
+            });
+        }
+
+        assert_eq!(simulator.notes(), [None]);
+    }
+
+    #[test]
+    fn velocity_gates_and_softens_notes() {
+        let mut config = config(ParallelMode::Collapse, 1);
+        config.velocity_threshold = 20;
+        config.velocity_dynamics = true;
+
+        let mut simulator = Simulator::new(&config);
+
+        let note_on = |velocity| MidiEvent {
+            track: 1,
+            channel: 1,
+            message: LimitedMidiMessage::NoteOn { note: 69, velocity },
+        };
+
+        simulator.apply(&note_on(10));
+
+        assert_eq!(simulator.notes(), [None]);
+
+        let count_steps = |simulator: &mut Simulator| {
+            (0..10_000).filter(|_| !simulator.tick().is_empty()).count()
+        };
+
+        simulator.apply(&note_on(127));
+        let loud_steps = count_steps(&mut simulator);
+
+        simulator.apply(&note_on(32));
+        let quiet_steps = count_steps(&mut simulator);
+
+        assert!(quiet_steps > 0);
+        assert!(quiet_steps < loud_steps / 2);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use floppier_proto::LimitedMidiMessage;
+use floppier_server::midi::{
+    parse_midi_file, tempo_to_bpm, timecode_ticks_to_microseconds, KeySignature, MidiFile,
+    MidiParseOptions, TempoMap,
+};
+
+fn parse_fixture(name: &str) -> MidiFile {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+
+    parse_midi_file(&path, &MidiParseOptions::default()).unwrap()
+}
+
+/// The (time offset, track, channel, message) of every event, for comparing against expectations
+fn events(midi_file: &MidiFile) -> Vec<(u32, u16, u8, LimitedMidiMessage)> {
+    midi_file
+        .events
+        .iter()
+        .map(|event| (event.time_offset, event.track, event.channel, event.message))
+        .collect()
+}
+
+#[test]
+fn parses_single_track_files() {
+    let midi_file = parse_fixture("single_track.mid");
+
+    assert_eq!(midi_file.metadata.track_name(), Some("Single"));
+    assert_eq!(midi_file.num_tracks, 1);
+
+    // The text, marker, tempo and end of track events are stripped
+    assert!(matches!(
+        events(&midi_file)[..],
+        [
+            (0, 1, 1, LimitedMidiMessage::ProgramChange { program: 5 }),
+            (
+                0,
+                1,
+                1,
+                LimitedMidiMessage::NoteOn {
+                    note: 60,
+                    velocity: 100
+                }
+            ),
+            (480, 1, 1, LimitedMidiMessage::NoteOff { note: 60, .. }),
+            (480, 1, 1, LimitedMidiMessage::NoteOn { note: 62, .. }),
+            (960, 1, 1, LimitedMidiMessage::NoteOff { note: 62, .. }),
+        ]
+    ));
+
+    // Two beats at 120 bpm
+    assert_eq!(midi_file.duration(), Duration::from_secs(1));
+}
+
+#[test]
+fn parses_parallel_files() {
+    let midi_file = parse_fixture("parallel.mid");
+
+    assert_eq!(midi_file.metadata.track_name(), Some("Parallel"));
+    assert_eq!(midi_file.num_tracks, 2);
+
+    // The metadata track's name is the song's, not a data track's
+    assert_eq!(
+        midi_file.track_names,
+        BTreeMap::from([(1, "Lead".to_string()), (2, "Bass".to_string())])
+    );
+
+    let events = events(&midi_file);
+
+    assert_eq!(events.len(), 6);
+    assert_eq!(events.first().unwrap().0, 0);
+    assert_eq!(events.last().unwrap().0, 384);
+
+    // The tracks are merged in time order, numbered from the first track after the metadata
+    assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    assert!(
+        events
+            .iter()
+            .all(|(_, track, channel, _)| (*track, *channel) == (1, 1)
+                || (*track, *channel) == (2, 2))
+    );
+
+    assert_eq!(
+        midi_file.tempo_map().unwrap().changes,
+        vec![(0, 500_000), (192, 250_000)]
+    );
+
+    // Two beats at 120 bpm, then two at 240 bpm
+    assert_eq!(midi_file.duration(), Duration::from_millis(1_500));
+}
+
+#[test]
+fn parses_smpte_files() {
+    let midi_file = parse_fixture("smpte.mid");
+
+    assert_eq!(
+        midi_file.timing.to_string(),
+        "SMPTE 25 fps (40 ticks per frame)"
+    );
+    assert!(midi_file.tempo_map().is_none());
+
+    // Each tick is a millisecond regardless of the tempo
+    let times = midi_file
+        .events
+        .iter()
+        .map(|event| midi_file.event_time(event))
+        .collect::<Vec<_>>();
+
+    assert_eq!(times, [0, 500, 750, 1_000].map(Duration::from_millis));
+}
+
+#[test]
+fn keeps_every_signature_change() {
+    let midi_file = parse_fixture("meter_changes.mid");
+    let metadata = &midi_file.metadata;
+
+    // The repeated 7/8 is dropped, and the 4/4 at the same tick as it takes its place
+    let time_signatures = metadata
+        .time_signatures()
+        .iter()
+        .map(|(ticks, signature)| (*ticks, signature.to_string()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        time_signatures,
+        [(0, "4/4"), (384, "7/8"), (552, "4/4")].map(|(ticks, s)| (ticks, s.to_string()))
+    );
+    assert_eq!(metadata.time_signature().to_string(), "4/4");
+    assert_eq!(
+        metadata.key_signatures()[1],
+        (
+            384,
+            KeySignature {
+                sharps: -1,
+                minor: true
+            }
+        )
+    );
+
+    let banner = metadata.to_string();
+
+    assert!(banner.contains("Time Signature: 4/4 → 7/8 → 4/4 (2 changes)\n"));
+    assert!(banner.ends_with("Key Signature: 0 sharp(s) major → 1 flat(s) minor (1 change)"));
+    assert_eq!(midi_file.events.len(), 6);
+}
+
+#[test]
+fn exports_events_as_json() {
+    let midi_file = parse_fixture("parallel.mid");
+
+    let mut json = Vec::new();
+    midi_file.write_json(&mut json).unwrap();
+
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+    assert_eq!(json["metadata"]["track_name"], "Parallel");
+    assert_eq!(
+        json["timing"]["Metrical"]["tempo_map"]["changes"],
+        serde_json::json!([[0, 500_000], [192, 250_000]])
+    );
+    assert_eq!(json["track_names"]["2"], "Bass");
+
+    let events = json["events"].as_array().unwrap();
+
+    assert_eq!(events.len(), 6);
+    assert_eq!(
+        events[0],
+        serde_json::json!({
+            "time_offset": 0,
+            "track": 1,
+            "channel": 1,
+            "message": { "NoteOn": { "note": 60, "velocity": 100 } }
+        })
+    );
+}
+
+#[test]
+fn converts_tempos_to_bpm() {
+    assert_eq!(tempo_to_bpm(500_000), 120.0);
+    assert_eq!(tempo_to_bpm(1_000_000), 60.0);
+    assert_eq!(tempo_to_bpm(250_000), 240.0);
+}
+
+#[test]
+fn converts_ticks_to_microseconds() {
+    // 120 and 60 bpm
+    let steady = |tempo| TempoMap {
+        changes: vec![(0, tempo)],
+    };
+
+    assert_eq!(steady(500_000).ticks_to_microseconds(0, 480, 480), 500_000);
+    assert_eq!(
+        steady(1_000_000).ticks_to_microseconds(0, 96, 96),
+        1_000_000
+    );
+    assert_eq!(steady(500_000).ticks_to_microseconds(96, 96, 96), 0);
+
+    // 25 fps with 40 subframes is a millisecond per tick
+    assert_eq!(timecode_ticks_to_microseconds(1_000, 25.0, 40), 1_000_000);
+
+    let tempo_map = TempoMap {
+        changes: vec![(0, 500_000), (96, 250_000)],
+    };
+
+    assert_eq!(tempo_map.ticks_to_microseconds(0, 96, 96), 500_000);
+    assert_eq!(tempo_map.ticks_to_microseconds(96, 192, 96), 250_000);
+    assert_eq!(tempo_map.ticks_to_microseconds(48, 144, 96), 375_000);
+}